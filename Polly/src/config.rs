@@ -7,21 +7,60 @@
 // API Endpoints
 pub const TAGO_URL: &str = "http://apis.data.go.kr/1613000/BusRouteInfoInqireService";
 pub const OSRM_URL: &str = "http://router.project-osrm.org/route/v1/driving";
+pub const HOLIDAY_URL: &str = "http://apis.data.go.kr/B090041/openapi/service/SpcdeInfoService";
 
 // Constants for the Wonju Bus Information System website.
 pub const BASE_URL: &str = "http://its.wonju.go.kr/bus/bus04.do";
 pub const DETAIL_URL: &str = "http://its.wonju.go.kr/bus/bus04Detail.do";
 
+// Schedule crawler timeouts in seconds and retry count
+pub const SCHEDULE_MAIN_TIMEOUT_SECS: u64 = 30;
+pub const SCHEDULE_DETAIL_TIMEOUT_SECS: u64 = 10;
+pub const SCHEDULE_MAX_RETRIES: u32 = 2;
+
+/// `onclick` handlers (regex fragments) on the main schedule page whose first argument is a route id
+pub const SCHEDULE_DETAIL_HANDLERS: &[&str] = &["goDetail", "fnDetail", "viewDetail"];
+/// Attributes holding the route id when no `onclick` handler matches
+pub const SCHEDULE_ROUTE_ID_ATTRS: &[&str] = &["data-routeid", "data-route-id", "data-id"];
+
+/// A day type with fewer times than this share of its route-number family's median is flagged by `check-schedules`
+pub const SCHEDULE_MIN_PEER_RATIO: f64 = 0.5;
+
 // Concurrency settings for async tasks
 pub const CONCURRENCY_FETCH: usize = 10;
 pub const CONCURRENCY_SNAP: usize = 4;
 
+// Features buffered between Phase 2 and the combined output writer
+pub const COMBINED_WRITER_BUFFER: usize = 16;
+
+/// Files each `--writers` task writes per blocking call (and queues per task)
+pub const FILE_WRITE_BATCH: usize = 32;
+
+// Per-request timeouts in seconds (OSRM long-route requests take longer than TAGO calls)
+pub const OSRM_TIMEOUT_SECS: u64 = 60;
+pub const TAGO_TIMEOUT_SECS: u64 = 15;
+
+/// Items per page of a TAGO list request
+pub const TAGO_PAGE_ROWS: usize = 2048;
+/// Page size retried with when a full-size TAGO list page comes back truncated or malformed
+pub const TAGO_FALLBACK_ROWS: usize = 200;
+
 // OSRM chunk size (number of stops per request)
 pub const OSRM_CHUNK_SIZE: usize = 120;
 
+/// Upper bound on how long to honor an OSRM `Retry-After` header
+pub const OSRM_MAX_RETRY_AFTER: std::time::Duration = std::time::Duration::from_secs(60);
+
 /// Default snapping radius for OSRM in meters
 pub const OSRM_SNAP_RADIUS: f64 = 30.0;
 
+/// Farthest a stop is moved onto the road between its neighbours, in meters
+pub const OSRM_CORRIDOR_MAX_SHIFT: f64 = 90.0;
+
+/// Interior stops closer than this to the straight line between their neighbours, in meters,
+/// are taken to be on the road and get no OSRM corridor request
+pub const OSRM_SANITIZE_MIN_OFFSET: f64 = 15.0;
+
 /// OSRM Overview setting: full, simplified, or false
 pub const OSRM_OVERVIEW: &str = "full";
 
@@ -30,3 +69,21 @@ pub const OSRM_GEOMETRIES: &str = "geojson";
 
 /// OSRM Continue Straight setting: forces the route to keep going straight at waypoints
 pub const OSRM_CONTINUE_STRAIGHT: bool = true;
+
+/// Number of coordinates on each side of the turning point excluded from the self-intersection check
+pub const GEOMETRY_TURN_WINDOW: usize = 20;
+
+/// Self-intersections tolerated before a route is flagged by `--check-geometry`
+pub const GEOMETRY_MAX_SELF_INTERSECTIONS: usize = 2;
+
+/// Default longest step, in meters, between consecutive vertices of a snapped line before it counts as a gap
+pub const GEOMETRY_MAX_GAP_M: f64 = 500.0;
+
+/// Largest vertex shift, in meters, still treated as the same geometry when a route is re-derived
+pub const GEOMETRY_EQUAL_TOLERANCE_M: f64 = 1.0;
+
+/// Share of a route's stops that should be found in `stationMap.json` before a warning is raised
+pub const STATION_MAP_MIN_MATCH_RATE: f64 = 0.5;
+
+/// Stations per block of the on-disk station index (`--station-index`); one key per block is kept in memory
+pub const STATION_INDEX_STRIDE: usize = 256;
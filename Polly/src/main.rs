@@ -10,25 +10,74 @@ mod route;
 mod schedule;
 mod utils;
 
+use std::num::NonZeroUsize;
+
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 
-use route::RouteArgs;
-use schedule::ScheduleArgs;
+use route::{
+    CombineArgs, ExportAnnouncementsArgs, ExportFlatgeobufArgs, FailureKind, ListRoutesArgs,
+    RebuildMapArgs, ReindexArgs, RouteArgs, TripPreviewArgs, ValidateArgs,
+};
+use schedule::{
+    CheckSchedulesArgs, CheckStopOrderArgs, CrossCheckArgs, ExportRouteSummaryCsvArgs,
+    FetchHolidaysArgs, HeadwaysArgs, ScheduleArgs,
+};
 
 #[derive(Parser)]
 #[command(author, version, about)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Cap on concurrently running tasks across all phases (bounds the built-in per-phase limits)
+    #[arg(long, global = true, value_name = "N")]
+    max_concurrency: Option<NonZeroUsize>,
+
+    /// On failure, print `{"error", "kind", "route"}` as JSON to stderr and exit with a code for the
+    /// kind: 2 quota, 3 auth, 4 network, 5 parse, 1 other
+    #[arg(long, global = true)]
+    json_errors: bool,
+
+    /// Send all outbound HTTP requests through this proxy (overrides `HTTP_PROXY`/`HTTPS_PROXY`)
+    #[arg(long, global = true, value_name = "URL")]
+    proxy: Option<String>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Bus Route Information Collection and Snapping
-    Route(RouteArgs),
+    Route(Box<RouteArgs>),
+    /// List Available Routes for a City
+    ListRoutes(ListRoutesArgs),
+    /// Repair Stop Indices of Derived GeoJSON Files
+    Reindex(ReindexArgs),
+    /// Rebuild routeMap.json and Related Files from the Raw Cache
+    RebuildMap(RebuildMapArgs),
+    /// Combine Several Cities' Outputs into One Dataset
+    Combine(CombineArgs),
+    /// Preview Stop Times for the First Trip of a Day
+    TripPreview(TripPreviewArgs),
+    /// Export Derived Routes to a FlatGeobuf File
+    ExportFlatgeobuf(ExportFlatgeobufArgs),
+    /// Export Per-Direction Stop Lists for Announcement Systems
+    ExportAnnouncements(ExportAnnouncementsArgs),
+    /// Cross-Check routeDetails.json Against the Derived Routes
+    Validate(ValidateArgs),
     /// Bus Schedule Crawling
     Schedule(ScheduleArgs),
+    /// Check Crawled Schedules for Partial Coverage
+    CheckSchedules(CheckSchedulesArgs),
+    /// Compute Route Headways from Crawled Schedules
+    Headways(HeadwaysArgs),
+    /// Cross-Check Crawled Stop Sequences Against the TAGO Stop Order
+    CheckStopOrder(CheckStopOrderArgs),
+    /// Report Route Numbers with a Schedule but No Geometry, or the Reverse
+    CrossCheck(CrossCheckArgs),
+    /// Export One CSV Row per Route with Its Distances and First and Last Bus
+    ExportRouteSummaryCsv(ExportRouteSummaryCsvArgs),
+    /// Fetch and Cache the Public Holidays of a Year
+    FetchHolidays(FetchHolidaysArgs),
 }
 
 #[tokio::main]
@@ -41,15 +90,91 @@ async fn main() -> Result<()> {
 
     // Parse command-line arguments
     let cli = Cli::parse();
+    let json_errors = cli.json_errors;
+
+    let Err(err) = run(cli).await else {
+        return Ok(());
+    };
+    if !json_errors {
+        return Err(err);
+    }
+
+    let (kind, route) = FailureKind::of(&err);
+    let report = serde_json::json!({
+        "error": format!("{:#}", err),
+        "kind": kind.name(),
+        "route": route,
+    });
+    eprintln!("{}", report);
+    std::process::exit(kind.exit_code());
+}
+
+/// Runs the selected command.
+async fn run(cli: Cli) -> Result<()> {
+    if let Some(max) = cli.max_concurrency {
+        utils::concurrency::set_limit(max.get());
+    }
+    if let Some(proxy) = &cli.proxy {
+        utils::http::set_proxy(proxy)?;
+    }
+
     match cli.command {
         Commands::Route(args) => {
-            route::run(args).await.context("Route processing failed")?;
+            route::run(*args).await.context("Route processing failed")?;
+        }
+        Commands::ListRoutes(args) => {
+            route::list_routes(args)
+                .await
+                .context("Route listing failed")?;
+        }
+        Commands::Reindex(args) => {
+            route::reindex(args).await.context("Reindex failed")?;
+        }
+        Commands::RebuildMap(args) => {
+            route::rebuild_map(args)
+                .await
+                .context("Map rebuild failed")?;
+        }
+        Commands::Combine(args) => {
+            route::combine(args).context("Combine failed")?;
+        }
+        Commands::TripPreview(args) => {
+            route::trip_preview(args).context("Trip preview failed")?;
+        }
+        Commands::ExportFlatgeobuf(args) => {
+            route::export_flatgeobuf(args).context("FlatGeobuf export failed")?;
+        }
+        Commands::ExportAnnouncements(args) => {
+            route::export_announcements(args).context("Announcement export failed")?;
+        }
+        Commands::Validate(args) => {
+            route::validate(args).context("Validation failed")?;
         }
         Commands::Schedule(args) => {
             schedule::run(args)
                 .await
                 .context("Schedule processing failed")?;
         }
+        Commands::CheckSchedules(args) => {
+            schedule::check_schedules(args).context("Schedule check failed")?;
+        }
+        Commands::Headways(args) => {
+            schedule::headways(args).context("Headway computation failed")?;
+        }
+        Commands::CheckStopOrder(args) => {
+            schedule::check_stop_order(args).context("Stop order check failed")?;
+        }
+        Commands::CrossCheck(args) => {
+            schedule::cross_check(args).context("Cross-check failed")?;
+        }
+        Commands::ExportRouteSummaryCsv(args) => {
+            schedule::export_route_summary_csv(args).context("Route summary export failed")?;
+        }
+        Commands::FetchHolidays(args) => {
+            schedule::fetch_holidays(args)
+                .await
+                .context("Holiday fetch failed")?;
+        }
     }
 
     Ok(())
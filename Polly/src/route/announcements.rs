@@ -0,0 +1,185 @@
+//! Stop lists for announcement systems (`export-announcements`).
+//!
+//! Projects the stops of every derived route into one ordered list per direction
+//! (`ud`), each stop with its position in the direction and the name of the next
+//! stop, for in-vehicle or app announcements that need neither coordinates nor
+//! schedules. The last stop of a direction has no next stop: a route that turns
+//! around starts its other direction as a separate list.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::Local;
+use log::info;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::route::model::{FrontendStop, RouteFeatureCollection};
+use crate::utils::layout::LayoutArgs;
+use crate::utils::write_atomic;
+
+/// Arguments for exporting announcement stop lists
+#[derive(clap::Args)]
+pub struct ExportAnnouncementsArgs {
+    /// Output directory containing `polylines/`
+    #[arg(short, long, default_value = "./storage")]
+    output_dir: PathBuf,
+
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// City whose routes to export, with a layout that nests files by city
+    #[arg(long)]
+    city_code: Option<String>,
+
+    /// Destination file (default: `announcements.json` beside the mapping files)
+    #[arg(long, value_name = "PATH")]
+    output: Option<PathBuf>,
+}
+
+/// One stop of a direction's announcement list
+#[derive(Debug, PartialEq, Serialize)]
+struct Announcement {
+    /// 1-based position within the direction
+    ord: usize,
+    name: String,
+    /// Name of the following stop; `None` at the end of the direction
+    next: Option<String>,
+}
+
+/// A route's stops as one announcement list per direction code, in route order
+fn announcements(stops: &[FrontendStop]) -> BTreeMap<i64, Vec<Announcement>> {
+    let mut names: BTreeMap<i64, Vec<&str>> = BTreeMap::new();
+    for stop in stops {
+        names.entry(stop.up_down).or_default().push(&stop.name);
+    }
+    names
+        .into_iter()
+        .map(|(up_down, names)| {
+            let list = names
+                .iter()
+                .enumerate()
+                .map(|(i, name)| Announcement {
+                    ord: i + 1,
+                    name: name.to_string(),
+                    next: names.get(i + 1).map(|next| next.to_string()),
+                })
+                .collect();
+            (up_down, list)
+        })
+        .collect()
+}
+
+/// Every feature of the derived files in `dir`, including ones whose geometry lives in another file
+fn load_features(dir: &Path) -> Result<Vec<(String, String, Vec<FrontendStop>)>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "geojson"))
+        .collect();
+    paths.sort();
+
+    let mut routes = Vec::new();
+    for path in paths {
+        let content = fs::read_to_string(&path)?;
+        let collection: RouteFeatureCollection = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid route file {}", path.display()))?;
+        routes.extend(collection.features.into_iter().map(|f| {
+            let props = f.properties;
+            (props.route_id, props.route_no, props.stops)
+        }));
+    }
+    Ok(routes)
+}
+
+/// Writes the announcement lists of every derived route to one JSON file.
+pub fn export_announcements(args: ExportAnnouncementsArgs) -> Result<()> {
+    let layout = args.layout.resolve()?;
+    let root = layout.root(&args.output_dir, args.city_code.as_deref())?;
+    let features = load_features(&layout.polylines_dir(&root))?;
+    if features.is_empty() {
+        anyhow::bail!("No derived routes found; run `route` first.");
+    }
+
+    let routes: BTreeMap<String, _> = features
+        .into_iter()
+        .map(|(route_id, route_no, stops)| {
+            let directions: BTreeMap<String, _> = announcements(&stops)
+                .into_iter()
+                .map(|(up_down, list)| (up_down.to_string(), list))
+                .collect();
+            (
+                route_id,
+                json!({ "route_no": route_no, "directions": directions }),
+            )
+        })
+        .collect();
+
+    let output = args
+        .output
+        .unwrap_or_else(|| root.join("announcements.json"));
+    let contents = json!({
+        "lastUpdated": Local::now().format("%Y-%m-%d").to_string(),
+        "routes": routes,
+    });
+    write_atomic(&output, serde_json::to_string_pretty(&contents)?.as_bytes())
+        .with_context(|| format!("Failed to write {}", output.display()))?;
+    info!(
+        "Wrote announcements of {} routes to {}",
+        routes.len(),
+        output.display()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop(name: &str, up_down: i64) -> FrontendStop {
+        FrontendStop {
+            id: name.to_string(),
+            name: name.to_string(),
+            ord: 0,
+            up_down,
+            eta_offset_s: None,
+            paired_stop_idx: None,
+            side: None,
+        }
+    }
+
+    #[test]
+    fn test_announcements_split_at_the_direction_change() {
+        let stops = [
+            stop("터미널", 0),
+            stop("시청", 0),
+            stop("원주역", 0),
+            stop("시청", 1),
+            stop("터미널", 1),
+        ];
+        let lists = announcements(&stops);
+
+        assert_eq!(lists.len(), 2);
+        let up = &lists[&0];
+        assert_eq!(up.len(), 3);
+        assert_eq!(up[0].next.as_deref(), Some("시청"));
+        // The turn-around stop ends its direction rather than pointing into the other one
+        assert_eq!(
+            up[2],
+            Announcement {
+                ord: 3,
+                name: "원주역".to_string(),
+                next: None
+            }
+        );
+        let down = &lists[&1];
+        assert_eq!((down[0].ord, down[0].name.as_str()), (1, "시청"));
+        assert_eq!(down[1].next, None);
+
+        assert!(announcements(&[]).is_empty());
+    }
+}
@@ -0,0 +1,414 @@
+//! Merging of several single-city output directories into one regional dataset.
+//!
+//! Each city is named after its directory. Route numbers repeat across cities, so
+//! route-number keys become `<city>:<route_no>`. TAGO route and node ids carry a
+//! city prefix and normally do not collide; when they do, the collision is
+//! reported and a route id is renamed `<city>_<route_id>`, while a station keeps
+//! the first city's entry. Derived files are copied to `polylines/<city>/`.
+//!
+//! With `--layout`, each city directory is the one holding its mapping files (the
+//! city code directory of a nested layout), and the layout's subdirectory names are
+//! used both to read the cities and to write the combined dataset.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::Local;
+use log::{info, warn};
+use serde_json::{Map, Value, json};
+
+use crate::route::fetch::merge_map_files;
+use crate::utils::layout::{Layout, LayoutArgs};
+use crate::utils::{ensure_dir, safe_file_name};
+
+/// Arguments for combining city outputs
+#[derive(clap::Args)]
+pub struct CombineArgs {
+    /// City output directories, followed by the directory to write the combined dataset to
+    #[arg(required = true, num_args = 2.., value_name = "DIR")]
+    dirs: Vec<PathBuf>,
+
+    #[command(flatten)]
+    layout: LayoutArgs,
+}
+
+/// Combined map file sections, keyed like the per-city files
+#[derive(Default)]
+struct Combined {
+    route_numbers: Map<String, Value>,
+    route_variants: Map<String, Value>,
+    route_details: Map<String, Value>,
+    route_stops: Map<String, Value>,
+    stations: Map<String, Value>,
+    routes: Map<String, Value>,
+    /// City each route id and node id was first taken from
+    route_owner: HashMap<String, String>,
+    station_owner: HashMap<String, String>,
+    collisions: Vec<String>,
+}
+
+pub fn combine(args: CombineArgs) -> Result<()> {
+    let layout = args.layout.resolve()?;
+    let (out_dir, city_dirs) = args
+        .dirs
+        .split_last()
+        .context("Expected city directories and an output directory")?;
+
+    let mut cities: Vec<(String, PathBuf)> = Vec::with_capacity(city_dirs.len());
+    for dir in city_dirs {
+        let name = fs::canonicalize(dir)
+            .with_context(|| format!("Failed to read {}", dir.display()))?
+            .file_name()
+            .map(|name| safe_file_name(&name.to_string_lossy()))
+            .with_context(|| format!("Cannot name a city after {}", dir.display()))?;
+        if cities.iter().any(|(existing, _)| *existing == name) {
+            anyhow::bail!("Two city directories are named {}", name);
+        }
+        cities.push((name, dir.clone()));
+    }
+
+    let collisions = combine_dirs(&cities, out_dir, &layout)?;
+    for collision in &collisions {
+        warn!("{}", collision);
+    }
+    info!(
+        "Combined {} cities into {} ({} collisions).",
+        cities.len(),
+        out_dir.display(),
+        collisions.len()
+    );
+    Ok(())
+}
+
+/// Merges the map files and derived routes of each `(city, dir)` into `out_dir`.
+///
+/// Returns a description of every route id or node id collision between cities.
+fn combine_dirs(
+    cities: &[(String, PathBuf)],
+    out_dir: &Path,
+    layout: &Layout,
+) -> Result<Vec<String>> {
+    let mut combined = Combined::default();
+    for (city, dir) in cities {
+        combined.add_city(city, dir, &layout.polylines)?;
+        copy_polylines(
+            &layout.polylines_dir(dir),
+            &layout.polylines_dir(out_dir).join(city),
+            city,
+            &layout.polylines,
+        )?;
+    }
+
+    ensure_dir(out_dir)?;
+    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    let updates = vec![
+        (
+            out_dir.join("routeMap.json"),
+            vec![
+                ("route_numbers", Value::Object(combined.route_numbers)),
+                ("route_variants", Value::Object(combined.route_variants)),
+            ],
+        ),
+        (
+            out_dir.join("routeDetails.json"),
+            vec![("route_details", Value::Object(combined.route_details))],
+        ),
+        (
+            out_dir.join("routeStops.json"),
+            vec![("route_stops", Value::Object(combined.route_stops))],
+        ),
+        (
+            out_dir.join("stationMap.json"),
+            vec![("stations", Value::Object(combined.stations))],
+        ),
+        (
+            out_dir.join("routeIndex.json"),
+            vec![("routes", Value::Object(combined.routes))],
+        ),
+    ];
+    merge_map_files(out_dir, updates, &timestamp)?;
+
+    Ok(combined.collisions)
+}
+
+impl Combined {
+    fn add_city(&mut self, city: &str, dir: &Path, polylines: &str) -> Result<()> {
+        let route_map = read_section(dir, "routeMap.json", "route_numbers", true)?;
+        let variants = read_section(dir, "routeMap.json", "route_variants", false)?;
+        let details = read_section(dir, "routeDetails.json", "route_details", false)?;
+        let route_stops = read_section(dir, "routeStops.json", "route_stops", false)?;
+        let stations = read_section(dir, "stationMap.json", "stations", false)?;
+        let routes = read_section(dir, "routeIndex.json", "routes", false)?;
+
+        // Route ids already taken by an earlier city get this city's prefix
+        let mut renamed: HashMap<String, String> = HashMap::new();
+        let route_ids = route_map
+            .values()
+            .filter_map(Value::as_array)
+            .flatten()
+            .filter_map(Value::as_str)
+            .chain(routes.keys().map(String::as_str))
+            .chain(details.keys().map(String::as_str));
+        for route_id in route_ids {
+            if renamed.contains_key(route_id) {
+                continue;
+            }
+            let new_id = match self.route_owner.get(route_id) {
+                Some(owner) if owner != city => {
+                    let new_id = format!("{}_{}", city, route_id);
+                    self.collisions.push(format!(
+                        "Route id {} exists in {} and {}; renamed to {} for {}",
+                        route_id, owner, city, new_id, city
+                    ));
+                    new_id
+                }
+                _ => {
+                    self.route_owner
+                        .insert(route_id.to_string(), city.to_string());
+                    route_id.to_string()
+                }
+            };
+            renamed.insert(route_id.to_string(), new_id);
+        }
+        let rename = |id: &str| renamed.get(id).cloned().unwrap_or_else(|| id.to_string());
+
+        for (route_no, ids) in route_map {
+            let ids: Vec<String> = ids
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .map(rename)
+                .collect();
+            self.route_numbers
+                .insert(format!("{}:{}", city, route_no), json!(ids));
+        }
+        for (route_no, mut list) in variants {
+            for variant in list.as_array_mut().into_iter().flatten() {
+                relocate(variant, city, polylines, &rename);
+            }
+            self.route_variants
+                .insert(format!("{}:{}", city, route_no), list);
+        }
+        for (route_id, detail) in details {
+            self.route_details.insert(rename(&route_id), detail);
+        }
+        for (route_no, stops) in route_stops {
+            self.route_stops
+                .insert(format!("{}:{}", city, route_no), stops);
+        }
+        for (route_id, mut entry) in routes {
+            relocate(&mut entry, city, polylines, &rename);
+            self.routes.insert(rename(&route_id), entry);
+        }
+
+        // Stations shared by neighbouring cities' routes are expected; only differing data is a collision
+        for (node_id, station) in stations {
+            match self.station_owner.get(&node_id) {
+                Some(owner) => {
+                    if self.stations[&node_id] != station {
+                        self.collisions.push(format!(
+                            "Node id {} differs between {} and {}; keeping {}'s",
+                            node_id, owner, city, owner
+                        ));
+                    }
+                }
+                None => {
+                    self.station_owner.insert(node_id.clone(), city.to_string());
+                    self.stations.insert(node_id, station);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Points an entry's `route_id`, `geojson` and `geometry_ref` at the combined dataset, whose
+/// derived files are in the `polylines` directory
+fn relocate(entry: &mut Value, city: &str, polylines: &str, rename: &impl Fn(&str) -> String) {
+    if let Some(route_id) = entry["route_id"].as_str() {
+        entry["route_id"] = json!(rename(route_id));
+    }
+    for key in ["geojson", "geometry_ref"] {
+        if let Some(path) = entry.get(key).and_then(Value::as_str) {
+            entry[key] = json!(relocated(path, city, polylines));
+        }
+    }
+}
+
+/// A derived file path moved into the city's subdirectory of `polylines`
+fn relocated(path: &str, city: &str, polylines: &str) -> String {
+    let file = path
+        .strip_prefix(polylines)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .unwrap_or(path);
+    format!("{}/{}/{}", polylines, city, file)
+}
+
+/// One top-level object of a map file, empty if the file or key is missing unless `required`
+fn read_section(dir: &Path, file: &str, key: &str, required: bool) -> Result<Map<String, Value>> {
+    let path = dir.join(file);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(_) if !required => return Ok(Map::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let json: Value = serde_json::from_str(&content)
+        .with_context(|| format!("Invalid JSON in {}", path.display()))?;
+    Ok(json[key].as_object().cloned().unwrap_or_default())
+}
+
+/// Copies every `.geojson` file of `from` into `to`, relocating the `geometry_ref` of its features
+fn copy_polylines(from: &Path, to: &Path, city: &str, polylines: &str) -> Result<()> {
+    let Ok(entries) = fs::read_dir(from) else {
+        warn!("No derived routes in {}", from.display());
+        return Ok(());
+    };
+    ensure_dir(to)?;
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if path.extension().is_none_or(|ext| ext != "geojson") {
+            continue;
+        }
+        let target = to.join(path.file_name().unwrap());
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if !content.contains("geometry_ref") {
+            fs::write(&target, content)
+                .with_context(|| format!("Failed to copy {}", path.display()))?;
+            continue;
+        }
+
+        let mut collection: Value = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid route file {}", path.display()))?;
+        for feature in collection["features"].as_array_mut().into_iter().flatten() {
+            if let Some(geometry_ref) = feature["geometry_ref"].as_str() {
+                feature["geometry_ref"] = json!(relocated(geometry_ref, city, polylines));
+            }
+        }
+        fs::write(&target, serde_json::to_string(&collection)?)
+            .with_context(|| format!("Failed to copy {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_city(dir: &Path, route_id: &str, station: Value) {
+        fs::create_dir_all(dir.join("polylines")).unwrap();
+        let geojson = format!("polylines/{}.geojson", route_id);
+        let files = [
+            (
+                "routeMap.json",
+                json!({
+                    "route_numbers": { "1": [route_id] },
+                    "route_variants": { "1": [{ "route_id": route_id, "geojson": geojson }] }
+                }),
+            ),
+            (
+                "routeDetails.json",
+                json!({ "route_details": { route_id: { "sequence": [] } } }),
+            ),
+            (
+                "stationMap.json",
+                json!({ "stations": { "SHARED": station } }),
+            ),
+            (
+                "routeIndex.json",
+                json!({ "routes": { route_id: { "route_no": "1", "geojson": geojson } } }),
+            ),
+        ];
+        for (name, contents) in files {
+            fs::write(dir.join(name), contents.to_string()).unwrap();
+        }
+        fs::write(dir.join(&geojson), "{}").unwrap();
+    }
+
+    #[test]
+    fn test_relocate_keeps_the_layout_polylines_dir() {
+        let mut entry = json!({ "route_id": "R1", "geojson": "data/geojson/R1.geojson" });
+        relocate(&mut entry, "wonju", "data/geojson", &|id: &str| {
+            id.to_string()
+        });
+        assert_eq!(entry["geojson"], "data/geojson/wonju/R1.geojson");
+
+        let mut entry = json!({ "geojson": "data/geojson/R2.geojson", "geometry_ref": "data/geojson/R1.geojson" });
+        relocate(&mut entry, "wonju", "data/geojson", &|id: &str| {
+            id.to_string()
+        });
+        assert_eq!(entry["geometry_ref"], "data/geojson/wonju/R1.geojson");
+        assert!(entry.get("route_id").is_none());
+    }
+
+    #[test]
+    fn test_copy_polylines_relocates_geometry_refs() {
+        let base = std::env::temp_dir().join(format!("polly-combine-refs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let from = base.join("polylines");
+        fs::create_dir_all(&from).unwrap();
+        fs::write(from.join("R1.geojson"), "{}").unwrap();
+        fs::write(
+            from.join("R2.geojson"),
+            json!({ "features": [{ "geometry_ref": "polylines/R1.geojson" }] }).to_string(),
+        )
+        .unwrap();
+
+        let to = base.join("out/polylines/wonju");
+        copy_polylines(&from, &to, "wonju", "polylines").unwrap();
+        assert_eq!(fs::read_to_string(to.join("R1.geojson")).unwrap(), "{}");
+        let copied: Value =
+            serde_json::from_str(&fs::read_to_string(to.join("R2.geojson")).unwrap()).unwrap();
+        assert_eq!(
+            copied["features"][0]["geometry_ref"],
+            "polylines/wonju/R1.geojson"
+        );
+
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn test_combine_namespaces_cities_and_reports_collisions() {
+        let base = std::env::temp_dir().join(format!("polly-combine-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        write_city(&base.join("wonju"), "R1", json!({ "nodenm": "A" }));
+        write_city(&base.join("chuncheon"), "R1", json!({ "nodenm": "B" }));
+
+        let cities = vec![
+            ("wonju".to_string(), base.join("wonju")),
+            ("chuncheon".to_string(), base.join("chuncheon")),
+        ];
+        let out = base.join("combined");
+        let collisions = combine_dirs(&cities, &out, &Layout::default()).unwrap();
+        assert_eq!(collisions.len(), 2);
+
+        let read = |name: &str| -> Value {
+            serde_json::from_str(&fs::read_to_string(out.join(name)).unwrap()).unwrap()
+        };
+        let route_map = read("routeMap.json");
+        assert_eq!(route_map["route_numbers"]["wonju:1"], json!(["R1"]));
+        assert_eq!(
+            route_map["route_numbers"]["chuncheon:1"],
+            json!(["chuncheon_R1"])
+        );
+        assert_eq!(
+            route_map["route_variants"]["chuncheon:1"][0]["geojson"],
+            "polylines/chuncheon/R1.geojson"
+        );
+
+        let routes = read("routeIndex.json")["routes"].clone();
+        assert_eq!(routes.as_object().unwrap().len(), 2);
+        assert_eq!(
+            routes["chuncheon_R1"]["geojson"],
+            "polylines/chuncheon/R1.geojson"
+        );
+        assert_eq!(read("stationMap.json")["stations"]["SHARED"]["nodenm"], "A");
+        assert!(out.join("polylines/wonju/R1.geojson").exists());
+        assert!(out.join("polylines/chuncheon/R1.geojson").exists());
+
+        let _ = fs::remove_dir_all(&base);
+    }
+}
@@ -0,0 +1,148 @@
+//! Error type for the route pipeline.
+//!
+//! Distinguishes failures that doom the whole run (the service key is rejected or
+//! the daily quota is used up) from ones that only affect a single route, so the
+//! processing loops can abort early instead of failing every remaining route the
+//! same way. Everything else is carried as an `anyhow::Error` in `Other`.
+
+use std::path::PathBuf;
+
+/// A failure while fetching or processing routes
+#[derive(Debug, thiserror::Error)]
+pub enum RouteError {
+    #[error("TAGO API request quota exceeded")]
+    QuotaExceeded,
+
+    #[error("TAGO API rejected the service key (check SERVICE_KEY)")]
+    AuthFailed,
+
+    #[error("network request failed: {0}")]
+    NetworkTransient(#[from] reqwest::Error),
+
+    #[error("failed to parse {}", .0.display())]
+    ParseFailed(PathBuf, #[source] serde_json::Error),
+
+    #[error("route {0} has no derivable geometry")]
+    NoGeometry(String),
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl RouteError {
+    /// Whether every further request would fail the same way, so the run should stop
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, RouteError::QuotaExceeded | RouteError::AuthFailed)
+    }
+
+    /// Maps a data.go.kr result code to the error it signals, if any.
+    ///
+    /// `22` is the request-limit error; `20`, `30`, `31` and `32` reject the key
+    /// (access denied, unregistered, expired, unregistered IP).
+    pub fn from_tago_code(code: &str) -> Option<Self> {
+        match code {
+            "22" => Some(RouteError::QuotaExceeded),
+            "20" | "30" | "31" | "32" => Some(RouteError::AuthFailed),
+            _ => None,
+        }
+    }
+}
+
+pub type Result<T, E = RouteError> = std::result::Result<T, E>;
+
+/// Category of a failed run, reported by `--json-errors`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    Quota,
+    Auth,
+    Network,
+    Parse,
+    Other,
+}
+
+impl FailureKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            FailureKind::Quota => "quota",
+            FailureKind::Auth => "auth",
+            FailureKind::Network => "network",
+            FailureKind::Parse => "parse",
+            FailureKind::Other => "other",
+        }
+    }
+
+    /// Process exit code for this kind; `1` like any other failure for `Other`
+    pub fn exit_code(self) -> i32 {
+        match self {
+            FailureKind::Quota => 2,
+            FailureKind::Auth => 3,
+            FailureKind::Network => 4,
+            FailureKind::Parse => 5,
+            FailureKind::Other => 1,
+        }
+    }
+
+    /// Classifies an error by the first typed cause in its chain, with the route it names, if any
+    pub fn of(err: &anyhow::Error) -> (Self, Option<String>) {
+        for cause in err.chain() {
+            if let Some(route_error) = cause.downcast_ref::<RouteError>() {
+                return match route_error {
+                    RouteError::QuotaExceeded => (FailureKind::Quota, None),
+                    RouteError::AuthFailed => (FailureKind::Auth, None),
+                    RouteError::NetworkTransient(_) => (FailureKind::Network, None),
+                    RouteError::ParseFailed(path, _) => {
+                        // Raw cache files are named `<route_no>_<route_id>.json`; report the
+                        // route id like `NoGeometry` does
+                        let route = path.file_stem().map(|stem| {
+                            let stem = stem.to_string_lossy();
+                            match stem.rsplit_once('_') {
+                                Some((_, route_id)) => route_id.to_string(),
+                                None => stem.into_owned(),
+                            }
+                        });
+                        (FailureKind::Parse, route)
+                    }
+                    RouteError::NoGeometry(route_id) => {
+                        (FailureKind::Other, Some(route_id.clone()))
+                    }
+                    RouteError::Other(inner) => Self::of(inner),
+                };
+            }
+            if cause.is::<reqwest::Error>() {
+                return (FailureKind::Network, None);
+            }
+            if cause.is::<serde_json::Error>() {
+                return (FailureKind::Parse, None);
+            }
+        }
+        (FailureKind::Other, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_failure_kind_of_wrapped_errors() {
+        let quota = Err::<(), _>(anyhow::Error::from(RouteError::QuotaExceeded))
+            .context("Route processing failed")
+            .unwrap_err();
+        assert_eq!(FailureKind::of(&quota), (FailureKind::Quota, None));
+        assert_eq!(FailureKind::Quota.exit_code(), 2);
+
+        let parse = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        let parse = anyhow::Error::from(RouteError::ParseFailed(
+            PathBuf::from("cache/34_WJB251000001.json"),
+            parse,
+        ));
+        assert_eq!(
+            FailureKind::of(&parse),
+            (FailureKind::Parse, Some("WJB251000001".to_string()))
+        );
+
+        let other = anyhow::anyhow!("disk full");
+        assert_eq!(FailureKind::of(&other), (FailureKind::Other, None));
+    }
+}
@@ -0,0 +1,269 @@
+//! FlatGeobuf export of derived routes.
+//!
+//! Reads the GeoJSON files in `polylines/` back and writes every route into a
+//! single `.fgb` file with a spatial index, keeping only the key properties.
+//! Stops can optionally be written to a second point file. The writer is only
+//! compiled with the `flatgeobuf` feature; without it, the command fails with a
+//! clear error.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde_json::{Value, json};
+
+use crate::route::model::{RouteFeature, RouteFeatureCollection};
+use crate::utils::layout::LayoutArgs;
+
+/// Arguments for exporting derived routes to FlatGeobuf
+#[derive(clap::Args)]
+pub struct ExportFlatgeobufArgs {
+    /// Output directory containing `polylines/`
+    #[arg(short, long, default_value = "./storage")]
+    output_dir: PathBuf,
+
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// City whose routes to export, with a layout that nests files by city
+    #[arg(long)]
+    city_code: Option<String>,
+
+    /// Destination file (default: `routes.fgb` beside the mapping files)
+    #[arg(long, value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    /// Also write the stops of every route as points to this file
+    #[arg(long, value_name = "PATH")]
+    stops: Option<PathBuf>,
+}
+
+/// Which layer a `.fgb` file holds
+#[derive(Clone, Copy)]
+enum Layer {
+    Routes,
+    Stops,
+}
+
+/// Reads every derived route in `dir`, sorted by file name, following `geometry_ref`s from `root`.
+/// Stub routes without geometry are skipped, and `--latlon-order` files are swapped back to WGS84 axes.
+fn load_routes(dir: &Path, root: &Path) -> Result<Vec<RouteFeature>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "geojson"))
+        .collect();
+    paths.sort();
+
+    let mut features = Vec::new();
+    for path in paths {
+        let collection = RouteFeatureCollection::read_resolved(&path, root)?;
+        for mut feature in collection.features {
+            feature.swap_to_lonlat();
+            if feature.geometry.coordinates.len() >= 2 {
+                features.push(feature);
+            } else {
+                warn!(
+                    "Skipping route {}: it has no geometry",
+                    feature.properties.route_id
+                );
+            }
+        }
+    }
+
+    Ok(features)
+}
+
+/// Route lines with `route_id`, `route_no` and `total_dist`
+fn routes_collection(features: &[RouteFeature]) -> Value {
+    let features: Vec<Value> = features
+        .iter()
+        .map(|f| {
+            json!({
+                "type": "Feature",
+                "geometry": { "type": "LineString", "coordinates": f.geometry.coordinates },
+                "properties": {
+                    "route_id": f.properties.route_id,
+                    "route_no": f.properties.route_no,
+                    "total_dist": f.properties.meta.total_dist,
+                }
+            })
+        })
+        .collect();
+    json!({ "type": "FeatureCollection", "features": features })
+}
+
+/// One point per stop of every route, placed on the route geometry
+fn stops_collection(features: &[RouteFeature]) -> Value {
+    let mut points = Vec::new();
+    for f in features {
+        let coords = &f.geometry.coordinates;
+        let stop_to_coord = &f.properties.indices.stop_to_coord;
+        for (stop, &coord_idx) in f.properties.stops.iter().zip(stop_to_coord) {
+            let Some(coord) = coords.get(coord_idx) else {
+                continue;
+            };
+            points.push(json!({
+                "type": "Feature",
+                "geometry": { "type": "Point", "coordinates": coord },
+                "properties": {
+                    "stop_id": stop.id,
+                    "name": stop.name,
+                    "route_id": f.properties.route_id,
+                    "ord": stop.ord,
+                }
+            }));
+        }
+    }
+    json!({ "type": "FeatureCollection", "features": points })
+}
+
+/// Writes a GeoJSON FeatureCollection as an indexed, WGS84 `.fgb` file.
+#[cfg(feature = "flatgeobuf")]
+fn write_fgb(path: &Path, layer: Layer, collection: &Value) -> Result<()> {
+    use std::io::BufWriter;
+
+    use flatgeobuf::{ColumnType, FgbCrs, FgbWriter, FgbWriterOptions, GeometryType};
+    use geozero::GeozeroDatasource;
+    use geozero::geojson::GeoJsonReader;
+
+    let (name, geometry_type, columns): (_, _, &[(&str, ColumnType)]) = match layer {
+        Layer::Routes => (
+            "routes",
+            GeometryType::LineString,
+            &[
+                ("route_id", ColumnType::String),
+                ("route_no", ColumnType::String),
+                ("total_dist", ColumnType::Double),
+            ],
+        ),
+        Layer::Stops => (
+            "stops",
+            GeometryType::Point,
+            &[
+                ("stop_id", ColumnType::String),
+                ("name", ColumnType::String),
+                ("route_id", ColumnType::String),
+                ("ord", ColumnType::Long),
+            ],
+        ),
+    };
+
+    let mut fgb = FgbWriter::create_with_options(
+        name,
+        geometry_type,
+        FgbWriterOptions {
+            write_index: true,
+            crs: FgbCrs {
+                code: 4326,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    )?;
+    for &(column, column_type) in columns {
+        fgb.add_column(column, column_type, |_, col| col.nullable = false);
+    }
+
+    let bytes = serde_json::to_vec(collection)?;
+    GeoJsonReader(bytes.as_slice()).process(&mut fgb)?;
+    fgb.write(BufWriter::new(fs::File::create(path)?))?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "flatgeobuf"))]
+fn write_fgb(_path: &Path, _layer: Layer, _collection: &Value) -> Result<()> {
+    anyhow::bail!("FlatGeobuf export requires building with `--features flatgeobuf`")
+}
+
+/// Writes all derived routes (and optionally their stops) to FlatGeobuf files.
+pub fn export_flatgeobuf(args: ExportFlatgeobufArgs) -> Result<()> {
+    let layout = args.layout.resolve()?;
+    let root = layout.root(&args.output_dir, args.city_code.as_deref())?;
+    let features = load_routes(&layout.polylines_dir(&root), &root)?;
+    if features.is_empty() {
+        anyhow::bail!("No derived routes found; run `route` first.");
+    }
+
+    let output = args.output.unwrap_or_else(|| root.join("routes.fgb"));
+    write_fgb(&output, Layer::Routes, &routes_collection(&features))?;
+    info!("Wrote {} routes to {}", features.len(), output.display());
+
+    if let Some(stops_path) = args.stops {
+        let stops = stops_collection(&features);
+        write_fgb(&stops_path, Layer::Stops, &stops)?;
+        info!(
+            "Wrote {} stops to {}",
+            stops["features"].as_array().map_or(0, Vec::len),
+            stops_path.display()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "flatgeobuf"))]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    use flatgeobuf::FgbReader;
+
+    fn route_file(id: &str, lon: f64) -> Value {
+        json!({
+            "type": "FeatureCollection",
+            "features": [{
+                "type": "Feature",
+                "id": id,
+                "properties": {
+                    "route_id": id, "route_no": "1",
+                    "stops": [
+                        { "id": "A", "name": "A", "ord": 1, "ud": 0 },
+                        { "id": "B", "name": "B", "ord": 2, "ud": 0 }
+                    ],
+                    "turn_idx": 1, "stop_to_coord": [0, 1],
+                    "total_dist": 1000.0, "total_time": 60.0, "source_ver": ""
+                },
+                "geometry": { "type": "LineString", "coordinates": [[lon, 37.3], [lon, 37.31]] }
+            }]
+        })
+    }
+
+    #[test]
+    fn test_export_flatgeobuf_round_trip() {
+        let dir = std::env::temp_dir().join(format!("polly-fgb-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("polylines")).unwrap();
+        for (i, id) in ["R1", "R2", "R3"].iter().enumerate() {
+            let route = route_file(id, 127.9 + i as f64 * 0.01);
+            fs::write(
+                dir.join("polylines").join(format!("{}.geojson", id)),
+                route.to_string(),
+            )
+            .unwrap();
+        }
+
+        let stops_path = dir.join("stops.fgb");
+        export_flatgeobuf(ExportFlatgeobufArgs {
+            output_dir: dir.clone(),
+            layout: LayoutArgs::default(),
+            city_code: None,
+            output: None,
+            stops: Some(stops_path.clone()),
+        })
+        .unwrap();
+
+        let count = |path: &Path| {
+            let mut file = BufReader::new(fs::File::open(path).unwrap());
+            let fgb = FgbReader::open(&mut file).unwrap().select_all().unwrap();
+            fgb.features_count()
+        };
+        assert_eq!(count(&dir.join("routes.fgb")), Some(3));
+        assert_eq!(count(&stops_path), Some(6));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,167 +1,889 @@
 use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use chrono::Local;
-use serde_json::{json, Value};
+use fd_lock::RwLock;
+use log::{debug, warn};
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Map, Value};
 
-use crate::route::model::{BusRouteProcessor, RawRouteFile, RawStop, RouteProcessData};
-use crate::utils::{extract_items, parse_flexible_string};
+use crate::config::{TAGO_FALLBACK_ROWS, TAGO_PAGE_ROWS};
+use crate::route::error::{self, RouteError};
+use crate::route::model::{
+    BusRouteProcessor, MapFile, OsrmInstance, RawRouteFile, RawStop, RouteIndexEntry,
+    RouteProcessData,
+};
+use crate::route::output::mirror_output;
+use crate::route::process::sort_stops;
+use crate::utils::s3::S3Target;
+use crate::utils::service_key::{ServiceKey, key_form};
+use crate::utils::{
+    extract_items, normalize_route_no, parse_flexible_f64, parse_flexible_string, write_atomic,
+};
 
 impl BusRouteProcessor {
-    pub async fn get_all_routes(&self) -> Result<Vec<Value>> {
-        let params = [
-            ("cityCode", self.city_code.as_str()),
-            ("numOfRows", "2048"),
-            ("pageNo", "1"),
-            ("serviceKey", self.service_key.as_str()),
-            ("_type", "json"),
-        ];
+    /// Calls a TAGO endpoint with the service key and JSON output added to `params`.
+    /// `None` if the response is not JSON.
+    async fn tago_get(
+        &self,
+        endpoint: &str,
+        params: &[(&str, &str)],
+    ) -> error::Result<Option<Value>> {
+        let url = format!("{}/{}", self.tago_base_url, endpoint);
+        data_go_kr_get(
+            &self.client,
+            &url,
+            params,
+            &self.service_key,
+            self.tago_timeout,
+        )
+        .await
+    }
+
+    /// Fetches every item of a TAGO list, following `totalCount` over further pages.
+    /// `None` if the response is not JSON.
+    ///
+    /// Large pages occasionally come back truncated or malformed under load, so when a
+    /// full-size page is not JSON the list is fetched again in pages of `TAGO_FALLBACK_ROWS`.
+    async fn tago_get_items(
+        &self,
+        endpoint: &str,
+        params: &[(&str, &str)],
+    ) -> error::Result<Option<Vec<Value>>> {
+        if let Some(items) = self
+            .tago_get_pages(endpoint, params, TAGO_PAGE_ROWS)
+            .await?
+        {
+            return Ok(Some(items));
+        }
+        warn!(
+            "TAGO {} response is not valid JSON; retrying {} rows per page",
+            endpoint, TAGO_FALLBACK_ROWS
+        );
+        self.tago_get_pages(endpoint, params, TAGO_FALLBACK_ROWS)
+            .await
+    }
+
+    /// Fetches a TAGO list `rows` items per page, until `totalCount` items are collected.
+    /// `None` as soon as a page is not JSON.
+    async fn tago_get_pages(
+        &self,
+        endpoint: &str,
+        params: &[(&str, &str)],
+        rows: usize,
+    ) -> error::Result<Option<Vec<Value>>> {
+        let rows_param = rows.to_string();
+        let mut items = Vec::new();
+        for page_no in 1.. {
+            let page_param = page_no.to_string();
+            let mut query = params.to_vec();
+            query.push(("numOfRows", &rows_param));
+            query.push(("pageNo", &page_param));
+            let Some(json) = self.tago_get(endpoint, &query).await? else {
+                return Ok(None);
+            };
+
+            let page = extract_items(&json)?;
+            let page_len = page.len();
+            items.extend(page);
+            // A short page is the last one, also when the server caps `numOfRows` below `rows`
+            let total = parse_flexible_f64(&json["response"]["body"]["totalCount"]);
+            if page_len < rows || total.is_none_or(|total| items.len() as f64 >= total) {
+                break;
+            }
+            debug!(
+                "TAGO {}: {} of {:?} items after page {}",
+                endpoint,
+                items.len(),
+                total,
+                page_no
+            );
+        }
+        Ok(Some(items))
+    }
 
-        let url = format!("{}/getRouteNoList", self.tago_base_url);
-        let resp: reqwest::Response = self.client.get(&url).query(&params).send().await?;
-        let json: Value = resp.json().await?;
+    pub async fn get_all_routes(&self) -> error::Result<Vec<Value>> {
+        let params = [("cityCode", self.city_code.as_str())];
+        let items = self
+            .tago_get_items("getRouteNoList", &params)
+            .await?
+            .context("TAGO route list response is not valid JSON")?;
 
-        extract_items(&json)
+        Ok(items)
     }
 
-    pub async fn fetch_and_save_raw(&self, route_info: Value) -> Result<Option<RouteProcessData>> {
+    pub async fn fetch_and_save_raw(
+        &self,
+        route_info: Value,
+    ) -> error::Result<Option<RouteProcessData>> {
         let route_id = route_info["routeid"]
             .as_str()
             .unwrap_or_default()
             .to_string();
-        let route_no = parse_flexible_string(&route_info["routeno"]);
+        let route_no = normalize_route_no(&parse_flexible_string(&route_info["routeno"]));
 
         if route_no == "UNKNOWN" || route_id.is_empty() {
             return Ok(None);
         }
 
         // Fetch Stops
-        let params = [
-            ("cityCode", self.city_code.as_str()),
-            ("routeId", route_id.as_str()),
-            ("numOfRows", "2048"),
-            ("serviceKey", self.service_key.as_str()),
-            ("_type", "json"),
-        ];
-
-        let url = format!("{}/getRouteAcctoThrghSttnList", self.tago_base_url);
-        let resp: reqwest::Response = self.client.get(&url).query(&params).send().await?;
-
-        let json: Value = match resp.json().await {
-            Ok(v) => v,
-            Err(_) => return Ok(None),
+        let Some(items) = self.fetch_stop_items(&route_id, None).await? else {
+            return Ok(None);
         };
-
-        let items = extract_items(&json)?;
         if items.is_empty() {
             return Ok(None);
         }
 
+        // Make sure the configured keys match this feed before converting anything
+        let fields = &self.field_map;
+        let missing: Vec<&str> = fields
+            .required_keys()
+            .into_iter()
+            .filter(|key| items[0].get(*key).is_none())
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow::anyhow!(
+                "TAGO stop item for route {} is missing mapped key(s) {:?}; check --field-map",
+                route_id,
+                missing
+            )
+            .into());
+        }
+
         // Convert to internal RawStop
-        let mut stops: Vec<RawStop> = items
+        let mut stops: Vec<RawStop> = items.iter().map(|item| self.raw_stop(item)).collect();
+
+        sort_stops(&mut stops);
+
+        // Some feeds only return one direction per call; ask for the other one explicitly
+        let single_direction = stops.iter().all(|s| s.up_down_cd == stops[0].up_down_cd);
+        if self.options.both_directions && single_direction {
+            // Codes are still raw here, so the return leg may be coded 0, 1 or 2
+            let observed = stops[0].up_down_cd;
+            for &opposite in opposite_codes(observed) {
+                let items = self
+                    .fetch_stop_items(&route_id, Some(opposite))
+                    .await?
+                    .unwrap_or_default();
+                let mut other: Vec<RawStop> = items
+                    .iter()
+                    .map(|item| self.raw_stop(item))
+                    .filter(|s| s.up_down_cd == opposite)
+                    .collect();
+                debug!(
+                    "Route {}: {} stop(s) fetched for updowncd {}",
+                    route_id,
+                    other.len(),
+                    opposite
+                );
+                if !other.is_empty() {
+                    append_direction(&mut stops, &mut other);
+                    break;
+                }
+            }
+        }
+
+        // Save RAW file
+        let raw_file = RawRouteFile {
+            route_id,
+            route_no,
+            fetched_at: Local::now().to_rfc3339(),
+            route_tp: route_info["routetp"]
+                .as_str()
+                .filter(|tp| !tp.is_empty())
+                .map(str::to_string),
+            stops,
+        };
+        self.save_raw(&raw_file).await?;
+
+        Ok(Some(route_process_data(&raw_file)))
+    }
+
+    /// Writes a raw route file to the cache as `<route_no>_<route_id>.json`.
+    pub async fn save_raw(&self, raw_file: &RawRouteFile) -> Result<()> {
+        let file_path = self
+            .raw_dir
+            .join(format!("{}_{}.json", raw_file.route_no, raw_file.route_id));
+        let contents = serde_json::to_string_pretty(raw_file)?;
+        tokio::fs::write(&file_path, contents)
+            .await
+            .with_context(|| format!("Failed to write {}", file_path.display()))
+    }
+
+    /// Fetches the stop items of a route, for one direction only if `up_down_cd` is given.
+    /// `None` if the response is not JSON.
+    async fn fetch_stop_items(
+        &self,
+        route_id: &str,
+        up_down_cd: Option<i64>,
+    ) -> error::Result<Option<Vec<Value>>> {
+        let up_down_cd = up_down_cd.map(|cd| cd.to_string());
+        let mut params = vec![("cityCode", self.city_code.as_str()), ("routeId", route_id)];
+        if let Some(cd) = &up_down_cd {
+            params.push((self.field_map.up_down_cd.as_str(), cd));
+        }
+
+        self.tago_get_items("getRouteAcctoThrghSttnList", &params)
+            .await
+    }
+
+    /// Converts a TAGO stop item using the configured field names
+    fn raw_stop(&self, item: &Value) -> RawStop {
+        let fields = &self.field_map;
+        RawStop {
+            node_id: item[&fields.node_id].as_str().unwrap_or("").to_string(),
+            node_nm: item[&fields.node_nm].as_str().unwrap_or("").to_string(),
+            node_ord: item[&fields.node_ord].as_i64().unwrap_or(0),
+            node_no: parse_flexible_string(&item[&fields.node_no]),
+            gps_lat: item[&fields.gps_lat].as_f64().unwrap_or(0.0),
+            gps_long: item[&fields.gps_long].as_f64().unwrap_or(0.0),
+            up_down_cd: item[&fields.up_down_cd]
+                .as_i64()
+                .or_else(|| {
+                    item[&fields.up_down_cd]
+                        .as_str()
+                        .and_then(|s| s.parse().ok())
+                })
+                .unwrap_or(0),
+        }
+    }
+
+    /// Merges the routes derived this run into `routeIndex.json`, keyed by route ID.
+    ///
+    /// Routes skipped or filtered out of this run keep their previous entries.
+    pub async fn save_route_index(&self, index: &BTreeMap<String, RouteIndexEntry>) -> Result<()> {
+        let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        let base_dir = self.mapping_file.parent().unwrap().to_path_buf();
+
+        let updates = vec![(
+            base_dir.join("routeIndex.json"),
+            vec![("routes", serde_json::to_value(index)?)],
+        )];
+
+        let written =
+            tokio::task::spawn_blocking(move || merge_map_files(&base_dir, updates, &timestamp))
+                .await??;
+
+        for (path, contents) in written {
+            self.mirror_output(&path, contents).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Records the OSRM instances that answered this run in `runManifest.json`, replacing the previous run's.
+    pub async fn save_run_manifest(&self, osrm_instances: &[(OsrmInstance, usize)]) -> Result<()> {
+        let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        let base_dir = self.mapping_file.parent().unwrap().to_path_buf();
+
+        let instances: Vec<Value> = osrm_instances
             .iter()
-            .map(|item| RawStop {
-                node_id: item["nodeid"].as_str().unwrap_or("").to_string(),
-                node_nm: item["nodenm"].as_str().unwrap_or("").to_string(),
-                node_ord: item["nodeord"].as_i64().unwrap_or(0),
-                node_no: parse_flexible_string(&item["nodeno"]),
-                gps_lat: item["gpslati"].as_f64().unwrap_or(0.0),
-                gps_long: item["gpslong"].as_f64().unwrap_or(0.0),
-                up_down_cd: item["updowncd"]
-                    .as_i64()
-                    .or_else(|| item["updowncd"].as_str().and_then(|s| s.parse().ok()))
-                    .unwrap_or(0),
+            .map(|(instance, responses)| {
+                let mut entry = serde_json::to_value(instance).unwrap_or_default();
+                entry["responses"] = json!(responses);
+                entry
             })
             .collect();
+        let osrm = json!({ "base_url": self.osrm_base_url, "instances": instances });
+        let updates = vec![(base_dir.join("runManifest.json"), vec![("osrm", osrm)])];
 
-        stops.sort_by_key(|s| s.node_ord);
+        let written =
+            tokio::task::spawn_blocking(move || merge_map_files(&base_dir, updates, &timestamp))
+                .await??;
 
-        // Generate Metadata for routeMap.json
-        let sequence_meta: Vec<Value> = stops
-            .iter()
-            .map(|s| {
+        for (path, contents) in written {
+            self.mirror_output(&path, contents).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Saves the selected mapping files among `routeMap.json`, `routeDetails.json`,
+/// `routeStops.json` and `stationMap.json` to `base_dir`, uploading them to `s3` if given.
+///
+/// Entries are merged into the existing files under a lock on the output directory,
+/// so concurrent runs writing to the same directory keep each other's entries.
+pub async fn save_route_map_json(
+    base_dir: &Path,
+    s3: Option<&S3Target>,
+    map: &BTreeMap<String, Vec<String>>,
+    variants: &BTreeMap<String, Vec<Value>>,
+    details: &HashMap<String, Value>,
+    stops: &BTreeMap<String, Value>,
+    files: &[MapFile],
+) -> Result<()> {
+    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    let base_dir = base_dir.to_path_buf();
+
+    // Distinct stops served by each route number across all of its variants
+    let route_stops: BTreeMap<&String, Vec<Value>> = map
+        .iter()
+        .filter(|_| files.contains(&MapFile::RouteStops))
+        .map(|(route_no, ids)| {
+            let sequences: Vec<Vec<&str>> = ids
+                .iter()
+                .filter_map(|id| details.get(id)?["sequence"].as_array())
+                .map(|seq| seq.iter().filter_map(|s| s["nodeid"].as_str()).collect())
+                .collect();
+            let union = stop_union(&sequences)
+                .into_iter()
+                .map(|node_id| {
+                    let name = stops.get(node_id).map(|s| &s["nodenm"]);
+                    json!({ "nodeid": node_id, "nodenm": name })
+                })
+                .collect();
+            (route_no, union)
+        })
+        .collect();
+
+    let mut updates = Vec::with_capacity(files.len());
+    for &file in &MapFile::ALL {
+        if !files.contains(&file) {
+            continue;
+        }
+        let sections = match file {
+            MapFile::RouteMap => vec![
+                ("route_numbers", serde_json::to_value(map)?),
+                ("route_variants", serde_json::to_value(variants)?),
+            ],
+            MapFile::RouteDetails => vec![("route_details", serde_json::to_value(details)?)],
+            MapFile::RouteStops => vec![("route_stops", serde_json::to_value(&route_stops)?)],
+            MapFile::StationMap => vec![("stations", serde_json::to_value(stops)?)],
+        };
+        updates.push((base_dir.join(file.file_name()), sections));
+    }
+
+    // File locking blocks, so keep the whole read-modify-write off the async workers
+    let written = {
+        let base_dir = base_dir.clone();
+        tokio::task::spawn_blocking(move || merge_map_files(&base_dir, updates, &timestamp))
+            .await??
+    };
+
+    for (path, contents) in written {
+        mirror_output(s3, &base_dir, &path, contents).await?;
+    }
+
+    Ok(())
+}
+
+/// The mapping-file entries of one raw route
+pub fn route_process_data(raw: &RawRouteFile) -> RouteProcessData {
+    let stops = &raw.stops;
+
+    // Generate Metadata for routeMap.json
+    let sequence_meta: Vec<Value> = stops
+        .iter()
+        .map(|s| {
+            json!({
+                "nodeid": s.node_id, "nodeord": s.node_ord, "updowncd": s.up_down_cd
+            })
+        })
+        .collect();
+
+    let stops_map_data: Vec<(String, Value)> = stops
+        .iter()
+        .map(|s| {
+            (
+                s.node_id.clone(),
                 json!({
-                    "nodeid": s.node_id, "nodeord": s.node_ord, "updowncd": s.up_down_cd
+                    "nodenm": s.node_nm, "nodeno": s.node_no,
+                    "gpslati": s.gps_lat, "gpslong": s.gps_long
+                }),
+            )
+        })
+        .collect();
+
+    // Describe this geometry variant of the route number for routeMap.json; its `geojson` path
+    // depends on the layout and naming, and is set by `RouteMapData::finish`
+    let variant = json!({
+        "route_id": raw.route_id,
+        "first_stop": stops.first().map(|s| s.node_nm.as_str()),
+        "last_stop": stops.last().map(|s| s.node_nm.as_str()),
+        "stop_count": stops.len(),
+    });
+
+    let mut details = json!({ "routeno": raw.route_no, "sequence": sequence_meta });
+    if let Some(route_tp) = &raw.route_tp {
+        details["routetp"] = json!(route_tp);
+    }
+
+    RouteProcessData {
+        route_id: raw.route_id.clone(),
+        route_no: raw.route_no.clone(),
+        details,
+        variant,
+        stops_map: stops_map_data,
+    }
+}
+
+/// Ordered union of several stop sequences, deduplicated by node id.
+///
+/// A stop missing from the union so far is inserted right after its predecessor in its own
+/// sequence, so every sequence's relative order is kept (e.g. a short-turn variant's stops
+/// land within the full route's order).
+fn stop_union<'a>(sequences: &[Vec<&'a str>]) -> Vec<&'a str> {
+    let mut union: Vec<&str> = Vec::new();
+    for sequence in sequences {
+        let mut insert_at = 0;
+        for &node_id in sequence {
+            match union.iter().position(|&id| id == node_id) {
+                Some(pos) => insert_at = pos + 1,
+                None => {
+                    union.insert(insert_at, node_id);
+                    insert_at += 1;
+                }
+            }
+        }
+    }
+    union
+}
+
+/// Direction codes that may hold the other direction of a route seen only with `observed`.
+///
+/// Feeds code directions as `0`/`1` or `1`/`2`, so a lone `1` may pair with either; `0` is tried
+/// first.
+fn opposite_codes(observed: i64) -> &'static [i64] {
+    match observed {
+        0 | 2 => &[1],
+        1 => &[0, 2],
+        _ => &[],
+    }
+}
+
+/// Appends the stops of a separately fetched direction after `stops`.
+///
+/// The outbound direction (the lower `updowncd`) goes first. `nodeord` values restart for the
+/// second direction on some feeds, so they are shifted past the first direction's when they overlap.
+fn append_direction(stops: &mut Vec<RawStop>, other: &mut Vec<RawStop>) {
+    if other.is_empty() {
+        return;
+    }
+    other.sort_by_key(|s| s.node_ord);
+    if stops
+        .first()
+        .is_some_and(|s| other[0].up_down_cd < s.up_down_cd)
+    {
+        std::mem::swap(stops, other);
+    }
+
+    let last_ord = stops.last().map_or(0, |s| s.node_ord);
+    let shift = if other[0].node_ord <= last_ord {
+        last_ord - other[0].node_ord + 1
+    } else {
+        0
+    };
+    stops.extend(other.drain(..).map(|mut s| {
+        s.node_ord += shift;
+        s
+    }));
+}
+
+/// Reads a TAGO response body as JSON, or `None` if it is not JSON.
+///
+/// Key and quota problems are reported as HTTP statuses or, by the data.go.kr gateway, as an
+/// XML body with a `returnReasonCode` even when JSON was requested. Both become the matching
+/// `RouteError` instead of an empty item list.
+async fn read_tago_json(resp: reqwest::Response) -> error::Result<Option<Value>> {
+    match resp.status() {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => return Err(RouteError::AuthFailed),
+        StatusCode::TOO_MANY_REQUESTS => return Err(RouteError::QuotaExceeded),
+        _ => {}
+    }
+
+    let body = resp.text().await?;
+    if let Some(err) = tago_result_code(&body).and_then(|code| RouteError::from_tago_code(&code)) {
+        return Err(err);
+    }
+    Ok(serde_json::from_str(&body).ok())
+}
+
+/// Calls a data.go.kr endpoint (TAGO, the holiday API) with `service_key` and JSON output added
+/// to `params`. `None` if the response is not JSON.
+///
+/// A rejected key that looks URL-encoded is decoded and the call retried once.
+pub async fn data_go_kr_get(
+    client: &Client,
+    url: &str,
+    params: &[(&str, &str)],
+    service_key: &ServiceKey,
+    timeout: Duration,
+) -> error::Result<Option<Value>> {
+    loop {
+        let key = service_key.get();
+        let mut query = params.to_vec();
+        query.push(("serviceKey", &key));
+        query.push(("_type", "json"));
+
+        debug!("data.go.kr request: {}", redacted_url(url, &query));
+        let resp = client
+            .get(url)
+            .query(&query)
+            .timeout(timeout)
+            .send()
+            .await?;
+        match read_tago_json(resp).await {
+            Err(RouteError::AuthFailed) if service_key.switch_to_decoded(&key) => {
+                warn!(
+                    "data.go.kr rejected the {} service key; retrying with it decoded. \
+                     Set DATA_GO_KR_SERVICE_KEY to the \"Decoding\" key to skip this.",
+                    key_form(&key)
+                );
+            }
+            result => return result,
+        }
+    }
+}
+
+/// The request URL with its query, `serviceKey` replaced by `***` so it can be logged
+fn redacted_url(url: &str, params: &[(&str, &str)]) -> String {
+    let params = params
+        .iter()
+        .map(|&(key, value)| (key, if key == "serviceKey" { "***" } else { value }));
+    match reqwest::Url::parse_with_params(url, params) {
+        Ok(url) => url.to_string(),
+        Err(_) => url.to_string(),
+    }
+}
+
+/// The result code of a TAGO response, from the JSON header or the gateway's XML error body
+fn tago_result_code(body: &str) -> Option<String> {
+    if let Ok(json) = serde_json::from_str::<Value>(body) {
+        return json["response"]["header"]["resultCode"]
+            .as_str()
+            .map(str::to_string);
+    }
+    let start = body.find("<returnReasonCode>")? + "<returnReasonCode>".len();
+    let len = body[start..].find('<')?;
+    Some(body[start..start + len].trim().to_string())
+}
+
+/// Merges each `(file, [(top-level key, entries)])` update into the existing file and writes it atomically.
+///
+/// Holds an exclusive lock on `<base_dir>/.maps.lock` for the whole read-modify-write.
+/// Returns the written contents of each file.
+pub fn merge_map_files(
+    base_dir: &Path,
+    updates: Vec<(PathBuf, Vec<(&'static str, Value)>)>,
+    timestamp: &str,
+) -> Result<Vec<(PathBuf, String)>> {
+    let lock_file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(base_dir.join(".maps.lock"))?;
+    let mut lock = RwLock::new(lock_file);
+    let _guard = lock.write()?;
+
+    let mut written = Vec::with_capacity(updates.len());
+    for (path, sections) in updates {
+        let mut file = match fs::read_to_string(&path) {
+            Ok(existing) => match serde_json::from_str::<Value>(&existing) {
+                Ok(Value::Object(map)) => map,
+                Ok(_) => Map::new(),
+                Err(e) => {
+                    warn!("Replacing unreadable {}: {}", path.display(), e);
+                    Map::new()
+                }
+            },
+            Err(_) => Map::new(),
+        };
+
+        file.insert("lastUpdated".to_string(), json!(timestamp));
+        for (key, entries) in sections {
+            let mut merged = file
+                .get(key)
+                .and_then(Value::as_object)
+                .cloned()
+                .unwrap_or_default();
+            if let Value::Object(entries) = entries {
+                merged.extend(entries);
+            }
+            file.insert(key.to_string(), Value::Object(merged));
+        }
+        let contents = serde_json::to_string_pretty(&file)?;
+
+        write_atomic(&path, contents.as_bytes())?;
+        written.push((path, contents));
+    }
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route::model::RouteOptions;
+    use crate::route::test_support::{json_response, mock_server, response, test_processor};
+
+    #[test]
+    fn test_stop_union_keeps_variant_order() {
+        let full = vec!["A", "B", "C", "D"];
+        // A short-turn variant with a detour stop between B and C
+        let short = vec!["B", "X", "C"];
+        let extension = vec!["D", "E"];
+
+        assert_eq!(
+            stop_union(&[full, short, extension]),
+            vec!["A", "B", "X", "C", "D", "E"]
+        );
+    }
+
+    #[test]
+    fn test_route_details_keep_route_type() {
+        let raw = RawRouteFile {
+            route_id: "WJB1".to_string(),
+            route_no: "34-1".to_string(),
+            fetched_at: String::new(),
+            route_tp: Some("간선버스".to_string()),
+            stops: Vec::new(),
+        };
+        assert_eq!(route_process_data(&raw).details["routetp"], "간선버스");
+
+        // Raw files cached before the type was recorded still load, without one
+        let cached: RawRouteFile = serde_json::from_value(json!({
+            "route_id": "WJB1", "route_no": "34-1", "fetched_at": "", "stops": []
+        }))
+        .unwrap();
+        assert!(route_process_data(&cached).details.get("routetp").is_none());
+    }
+
+    #[test]
+    fn test_redacted_url_hides_service_key() {
+        let url = redacted_url(
+            "http://apis.example/getRouteNoList",
+            &[("cityCode", "32020"), ("serviceKey", "secret%2Bkey")],
+        );
+        assert_eq!(
+            url,
+            "http://apis.example/getRouteNoList?cityCode=32020&serviceKey=***"
+        );
+    }
+
+    #[test]
+    fn test_tago_error_codes_are_classified() {
+        let xml = "<OpenAPI_ServiceResponse><cmmMsgHeader>\
+            <errMsg>SERVICE ERROR</errMsg>\
+            <returnAuthMsg>SERVICE_KEY_IS_NOT_REGISTERED_ERROR</returnAuthMsg>\
+            <returnReasonCode>30</returnReasonCode>\
+            </cmmMsgHeader></OpenAPI_ServiceResponse>";
+        let code = tago_result_code(xml).unwrap();
+        assert!(matches!(
+            RouteError::from_tago_code(&code),
+            Some(RouteError::AuthFailed)
+        ));
+
+        let quota = json!({ "response": { "header": { "resultCode": "22" } } }).to_string();
+        let code = tago_result_code(&quota).unwrap();
+        assert!(RouteError::from_tago_code(&code).unwrap().is_fatal());
+
+        let ok = json!({ "response": { "header": { "resultCode": "00" }, "body": {} } });
+        assert!(RouteError::from_tago_code(&tago_result_code(&ok.to_string()).unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_concurrent_map_saves_merge() {
+        let dir = std::env::temp_dir().join(format!("polly-maps-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // Two "runs" saving disjoint routes into the same directory at the same time
+        let handles: Vec<_> = ["A", "B"]
+            .into_iter()
+            .map(|run| {
+                let dir = dir.clone();
+                std::thread::spawn(move || {
+                    for i in 0..20 {
+                        let route_no = format!("{}{}", run, i);
+                        let entries = json!({ route_no.clone(): [format!("ID_{}", route_no)] });
+                        let updates =
+                            vec![(dir.join("routeMap.json"), vec![("route_numbers", entries)])];
+                        merge_map_files(&dir, updates, "2025-01-01 00:00:00").unwrap();
+                    }
                 })
             })
             .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
 
-        let stops_map_data: Vec<(String, Value)> = stops
-            .iter()
-            .map(|s| {
-                (
-                    s.node_id.clone(),
+        let saved: Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("routeMap.json")).unwrap()).unwrap();
+        let route_numbers = saved["route_numbers"].as_object().unwrap();
+        assert_eq!(route_numbers.len(), 40);
+        assert_eq!(route_numbers["B19"], json!(["ID_B19"]));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Fetches route "1" with `--both-directions` from a feed coding its directions as `codes`,
+    /// which answers only the requested direction and ignores codes it does not use.
+    async fn fetch_both_directions(codes: [i64; 2]) -> RawRouteFile {
+        let url = mock_server(move |_, request| {
+            let up_down = codes
+                .into_iter()
+                .find(|cd| request.contains(&format!("updowncd={}", cd)))
+                .unwrap_or(codes[0]);
+            let items: Vec<Value> = (0..3)
+                .map(|i| {
                     json!({
-                        "nodenm": s.node_nm, "nodeno": s.node_no,
-                        "gpslati": s.gps_lat, "gpslong": s.gps_long
-                    }),
-                )
-            })
-            .collect();
+                        "nodeid": format!("N{}_{}", up_down, i), "nodenm": "Stop",
+                        "nodeord": i + 1, "nodeno": 100 + i,
+                        "gpslati": 37.34, "gpslong": 127.92 + 0.001 * i as f64,
+                        "updowncd": up_down
+                    })
+                })
+                .collect();
+            json_response(
+                &json!({ "response": { "body": { "items": { "item": items } } } }).to_string(),
+            )
+        })
+        .await;
 
-        // Save RAW file
-        let raw_file = RawRouteFile {
-            route_id: route_id.clone(),
-            route_no: route_no.clone(),
-            fetched_at: Local::now().to_rfc3339(),
-            stops,
+        let raw_dir = std::env::temp_dir().join(format!(
+            "polly-both-dirs-{}-{}",
+            codes[0],
+            std::process::id()
+        ));
+        fs::create_dir_all(&raw_dir).unwrap();
+        let processor = BusRouteProcessor {
+            raw_dir: raw_dir.clone(),
+            ..test_processor(
+                &url,
+                RouteOptions {
+                    both_directions: true,
+                    ..Default::default()
+                },
+            )
         };
 
-        let file_path = self.raw_dir.join(format!("{}_{}.json", route_no, route_id));
-        tokio::fs::write(file_path, serde_json::to_string_pretty(&raw_file)?).await?;
+        let route = json!({ "routeid": "WJB251000001", "routeno": "1" });
+        processor.fetch_and_save_raw(route).await.unwrap().unwrap();
 
-        Ok(Some(RouteProcessData {
-            route_id,
-            route_no: route_no.clone(),
-            details: json!({ "routeno": route_no, "sequence": sequence_meta }),
-            stops_map: stops_map_data,
-        }))
+        let raw =
+            serde_json::from_str(&fs::read_to_string(raw_dir.join("1_WJB251000001.json")).unwrap())
+                .unwrap();
+        let _ = fs::remove_dir_all(&raw_dir);
+        raw
     }
 
-    pub async fn save_route_map_json(
-        &self,
-        map: &BTreeMap<String, Vec<String>>,
-        details: &HashMap<String, Value>,
-        stops: &BTreeMap<String, Value>,
-    ) -> Result<()> {
-        let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    #[tokio::test]
+    async fn test_both_directions_fetches_return_trip() {
+        // Outbound by default on a 0/1 feed
+        let raw = fetch_both_directions([0, 1]).await;
+        let directions: Vec<i64> = raw.stops.iter().map(|s| s.up_down_cd).collect();
+        assert_eq!(directions, [0, 0, 0, 1, 1, 1]);
+        let ords: Vec<i64> = raw.stops.iter().map(|s| s.node_ord).collect();
+        assert_eq!(ords, [1, 2, 3, 4, 5, 6]);
+        assert_eq!(raw.stops[3].node_id, "N1_0");
 
-        // Get base directory for all mapping files
-        let base_dir = self.mapping_file.parent().unwrap();
-
-        // Save routeMap.json (route_numbers only)
-        let route_map = json!({
-            "lastUpdated": timestamp,
-            "route_numbers": map,
-        });
-        tokio::fs::write(
-            &self.mapping_file,
-            serde_json::to_string_pretty(&route_map)?,
-        )
-        .await?;
-
-        // Save routeDetails.json
-        let route_details = json!({
-            "lastUpdated": timestamp,
-            "route_details": details,
-        });
-        tokio::fs::write(
-            base_dir.join("routeDetails.json"),
-            serde_json::to_string_pretty(&route_details)?,
-        )
-        .await?;
-
-        // Save stationMap.json
-        let station_map = json!({
-            "lastUpdated": timestamp,
-            "stations": stops,
-        });
-        tokio::fs::write(
-            base_dir.join("stationMap.json"),
-            serde_json::to_string_pretty(&station_map)?,
-        )
-        .await?;
+        // A 1/2 feed has no direction 0, so the return leg is found under 2
+        let raw = fetch_both_directions([1, 2]).await;
+        let directions: Vec<i64> = raw.stops.iter().map(|s| s.up_down_cd).collect();
+        assert_eq!(directions, [1, 1, 1, 2, 2, 2]);
+        assert_eq!(raw.stops[3].node_id, "N2_0");
 
-        Ok(())
+        // Outbound first even when the feed defaults to the return leg
+        let raw = fetch_both_directions([2, 1]).await;
+        let directions: Vec<i64> = raw.stops.iter().map(|s| s.up_down_cd).collect();
+        assert_eq!(directions, [1, 1, 1, 2, 2, 2]);
+        let ords: Vec<i64> = raw.stops.iter().map(|s| s.node_ord).collect();
+        assert_eq!(ords, [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[tokio::test]
+    async fn test_encoded_service_key_is_retried_decoded() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Only the decoded key `abc+def==` is registered; it arrives as `abc%2Bdef%3D%3D`
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        let url = mock_server(move |_, request| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            if request.contains("serviceKey=abc%2Bdef%3D%3D&") {
+                let body = json!({ "response": { "body": { "items": { "item": [
+                    { "routeid": "WJB251000001", "routeno": "1" }
+                ] } } } });
+                json_response(&body.to_string())
+            } else {
+                response("401 Unauthorized", &[], "")
+            }
+        })
+        .await;
+
+        let processor = BusRouteProcessor {
+            service_key: "abc%2Bdef%3D%3D".into(),
+            ..test_processor(&url, RouteOptions::default())
+        };
+
+        let routes = processor.get_all_routes().await.unwrap();
+        assert_eq!(routes.len(), 1);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(processor.service_key.get(), "abc+def==");
+
+        // Later calls use the decoded key directly; a decoded key is not retried
+        processor.get_all_routes().await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        let processor = BusRouteProcessor {
+            service_key: "wrong".into(),
+            ..processor
+        };
+        assert!(matches!(
+            processor.get_all_routes().await,
+            Err(RouteError::AuthFailed)
+        ));
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_large_page_is_refetched_in_small_pages() {
+        use std::sync::{Arc, Mutex};
+
+        // 450 routes; pages larger than 500 rows are cut off mid-body
+        let pages = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&pages);
+        let url = mock_server(move |_, request| {
+            let param = |name: &str| -> usize {
+                let start = request.find(&format!("{}=", name)).unwrap() + name.len() + 1;
+                let end = request[start..].find(['&', ' ']).unwrap() + start;
+                request[start..end].parse().unwrap()
+            };
+            let (rows, page_no) = (param("numOfRows"), param("pageNo"));
+            seen.lock().unwrap().push((rows, page_no));
+
+            let items: Vec<Value> = ((page_no - 1) * rows..(page_no * rows).min(450))
+                .map(|i| json!({ "routeid": format!("WJB{:06}", i), "routeno": i.to_string() }))
+                .collect();
+            let mut body = json!({ "response": { "body": {
+                "items": { "item": items }, "numOfRows": rows, "pageNo": page_no,
+                "totalCount": 450
+            } } })
+            .to_string();
+            if rows > 500 {
+                body.truncate(body.len() / 2);
+            }
+            json_response(&body)
+        })
+        .await;
+        let processor = test_processor(&url, RouteOptions::default());
+
+        let routes = processor.get_all_routes().await.unwrap();
+        assert_eq!(routes.len(), 450);
+        assert_eq!(routes[0]["routeid"], "WJB000000");
+        assert_eq!(routes[449]["routeid"], "WJB000449");
+        assert_eq!(
+            *pages.lock().unwrap(),
+            vec![
+                (TAGO_PAGE_ROWS, 1),
+                (TAGO_FALLBACK_ROWS, 1),
+                (TAGO_FALLBACK_ROWS, 2),
+                (TAGO_FALLBACK_ROWS, 3)
+            ]
+        );
     }
 }
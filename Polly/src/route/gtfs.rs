@@ -0,0 +1,302 @@
+//! Route stops from a local GTFS feed (`route --input gtfs:<dir>`).
+//!
+//! Replaces the TAGO fetch of Phase 1: each GTFS route becomes one `RawRouteFile`,
+//! after which snapping and derivation run as usual. A route's stops are those of
+//! its longest trip in direction 0 followed by its longest trip in direction 1
+//! (`direction_id` becomes `updowncd`), so out-and-back routes keep their turn.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use chrono::Local;
+use csv::StringRecord;
+use log::warn;
+
+use crate::route::model::{RawRouteFile, RawStop};
+use crate::utils::normalize_route_no;
+
+/// Where Phase 1 gets route stops from
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum InputSource {
+    /// The TAGO API
+    #[default]
+    Tago,
+    /// A directory holding an unzipped GTFS feed
+    Gtfs(PathBuf),
+}
+
+impl FromStr for InputSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "tago" {
+            return Ok(Self::Tago);
+        }
+        match s.strip_prefix("gtfs:") {
+            Some(path) if !path.is_empty() => Ok(Self::Gtfs(PathBuf::from(path))),
+            _ => Err(format!("expected `tago` or `gtfs:<dir>`, got {:?}", s)),
+        }
+    }
+}
+
+/// A GTFS CSV file, read one record at a time
+struct Table {
+    name: &'static str,
+    columns: HashMap<String, usize>,
+    reader: csv::Reader<fs::File>,
+}
+
+impl Table {
+    fn open(dir: &Path, name: &'static str) -> Result<Self> {
+        let path = dir.join(name);
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_path(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let columns = reader
+            .headers()
+            .with_context(|| format!("Invalid header in {}", path.display()))?
+            .iter()
+            .enumerate()
+            .map(|(i, column)| (column.to_string(), i))
+            .collect();
+        Ok(Self {
+            name,
+            columns,
+            reader,
+        })
+    }
+
+    /// Index of a column the GTFS spec requires
+    fn required(&self, column: &str) -> Result<usize> {
+        self.columns
+            .get(column)
+            .copied()
+            .with_context(|| format!("{} has no `{}` column", self.name, column))
+    }
+
+    fn optional(&self, column: &str) -> Option<usize> {
+        self.columns.get(column).copied()
+    }
+
+    /// The remaining records, skipping blank ones
+    fn records(&mut self) -> impl Iterator<Item = Result<StringRecord>> + '_ {
+        let name = self.name;
+        self.reader
+            .records()
+            .map(move |record| record.with_context(|| format!("Invalid record in {}", name)))
+            .filter(|record| {
+                record
+                    .as_ref()
+                    .map_or(true, |r| r.iter().any(|field| !field.is_empty()))
+            })
+    }
+
+    /// All remaining records, for the tables small enough to keep in memory
+    fn rows(&mut self) -> Result<Vec<StringRecord>> {
+        self.records().collect()
+    }
+}
+
+/// A record's value in column `idx`, empty if the record is short or the column is absent
+fn cell(row: &StringRecord, idx: Option<usize>) -> &str {
+    idx.and_then(|i| row.get(i)).unwrap_or("")
+}
+
+/// Reads the routes of a GTFS feed in `dir`, keeping only `route_filter` if given.
+pub fn read_routes(dir: &Path, route_filter: Option<&str>) -> Result<Vec<RawRouteFile>> {
+    let mut routes = Table::open(dir, "routes.txt")?;
+    let (route_id_col, short_name_col) = (
+        routes.required("route_id")?,
+        routes.optional("route_short_name"),
+    );
+    let route_rows = routes.rows()?;
+    let route_numbers: BTreeMap<&str, String> = route_rows
+        .iter()
+        .map(|row| {
+            let route_id = cell(row, Some(route_id_col));
+            let short_name = cell(row, short_name_col);
+            let route_no = if short_name.is_empty() {
+                route_id
+            } else {
+                short_name
+            };
+            (route_id, normalize_route_no(route_no))
+        })
+        .filter(|(_, route_no)| {
+            route_filter.is_none_or(|filter| *route_no == normalize_route_no(filter))
+        })
+        .collect();
+
+    // Trip id -> (route id, direction)
+    let mut trips = Table::open(dir, "trips.txt")?;
+    let (trip_route_col, trip_id_col, direction_col) = (
+        trips.required("route_id")?,
+        trips.required("trip_id")?,
+        trips.optional("direction_id"),
+    );
+    let trip_rows = trips.rows()?;
+    let trip_routes: HashMap<&str, (&str, i64)> = trip_rows
+        .iter()
+        .filter(|row| route_numbers.contains_key(cell(row, Some(trip_route_col))))
+        .map(|row| {
+            let direction = cell(row, direction_col).parse().unwrap_or(0);
+            (
+                cell(row, Some(trip_id_col)),
+                (cell(row, Some(trip_route_col)), direction),
+            )
+        })
+        .collect();
+
+    // By far the largest table, so only the selected trips' records are kept
+    let mut stop_times = Table::open(dir, "stop_times.txt")?;
+    let (st_trip_col, st_stop_col, st_seq_col) = (
+        stop_times.required("trip_id")?,
+        stop_times.required("stop_id")?,
+        stop_times.required("stop_sequence")?,
+    );
+    let mut trip_stops: HashMap<&str, Vec<(u32, String)>> = HashMap::new();
+    for row in stop_times.records() {
+        let row = row?;
+        if let Some((&trip_id, _)) = trip_routes.get_key_value(cell(&row, Some(st_trip_col))) {
+            let seq = cell(&row, Some(st_seq_col)).parse().unwrap_or(u32::MAX);
+            trip_stops
+                .entry(trip_id)
+                .or_default()
+                .push((seq, cell(&row, Some(st_stop_col)).to_string()));
+        }
+    }
+
+    // The longest trip of each route and direction; ties go to the smallest trip id
+    let mut longest: BTreeMap<(&str, i64), (&str, usize)> = BTreeMap::new();
+    for (trip_id, stops) in &trip_stops {
+        let key = trip_routes[trip_id];
+        let candidate = (*trip_id, stops.len());
+        longest
+            .entry(key)
+            .and_modify(|best| {
+                if candidate.1 > best.1 || (candidate.1 == best.1 && candidate.0 < best.0) {
+                    *best = candidate;
+                }
+            })
+            .or_insert(candidate);
+    }
+
+    let mut stops = Table::open(dir, "stops.txt")?;
+    let (stop_id_col, name_col, code_col, lat_col, lon_col) = (
+        stops.required("stop_id")?,
+        stops.optional("stop_name"),
+        stops.optional("stop_code"),
+        stops.required("stop_lat")?,
+        stops.required("stop_lon")?,
+    );
+    let stop_records = stops.rows()?;
+    let stop_rows: HashMap<&str, &StringRecord> = stop_records
+        .iter()
+        .map(|row| (cell(row, Some(stop_id_col)), row))
+        .collect();
+
+    let fetched_at = Local::now().to_rfc3339();
+    let mut result = Vec::new();
+    for (route_id, route_no) in &route_numbers {
+        let mut raw_stops = Vec::new();
+        for ((_, direction), (trip_id, _)) in
+            longest.range((*route_id, i64::MIN)..=(*route_id, i64::MAX))
+        {
+            let mut sequence = trip_stops[trip_id].clone();
+            sequence.sort_by_key(|&(seq, _)| seq);
+            for (_, stop_id) in sequence {
+                let Some(row) = stop_rows.get(stop_id.as_str()) else {
+                    warn!(
+                        "GTFS stop {} of trip {} is not in stops.txt",
+                        stop_id, trip_id
+                    );
+                    continue;
+                };
+                raw_stops.push(RawStop {
+                    node_id: stop_id,
+                    node_nm: cell(row, name_col).to_string(),
+                    node_ord: raw_stops.len() as i64 + 1,
+                    node_no: cell(row, code_col).to_string(),
+                    gps_lat: cell(row, Some(lat_col)).parse().unwrap_or_default(),
+                    gps_long: cell(row, Some(lon_col)).parse().unwrap_or_default(),
+                    up_down_cd: *direction,
+                });
+            }
+        }
+
+        if raw_stops.is_empty() {
+            warn!(
+                "GTFS route {} ({}) has no trips with stops",
+                route_no, route_id
+            );
+            continue;
+        }
+        result.push(RawRouteFile {
+            route_id: route_id.to_string(),
+            route_no: route_no.clone(),
+            fetched_at: fetched_at.clone(),
+            route_tp: None,
+            stops: raw_stops,
+        });
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_routes_handles_quotes_and_bom() {
+        let dir = std::env::temp_dir().join(format!("polly-gtfs-csv-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let files = [
+            (
+                "routes.txt",
+                "\u{feff}route_id,route_short_name\r\nR1, 1 \r\n\r\nR2,2\r\n",
+            ),
+            (
+                "trips.txt",
+                "route_id,trip_id,direction_id\nR1,T1,0\nR2,T2,0\n",
+            ),
+            (
+                "stop_times.txt",
+                "trip_id,stop_id,stop_sequence\nT1,S2,2\nT2,S3,1\nT1,S1,1\n",
+            ),
+            (
+                "stops.txt",
+                "stop_id,stop_name,stop_lat,stop_lon\n\
+                 S1,\"Main St, North\",37.34,127.92\n\
+                 S2,\"Say \"\"Hi\"\"\",37.35,127.93\n",
+            ),
+        ];
+        for (name, contents) in files {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+
+        let routes = read_routes(&dir, Some("1")).unwrap();
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].route_id, "R1");
+        let names: Vec<&str> = routes[0].stops.iter().map(|s| s.node_nm.as_str()).collect();
+        assert_eq!(names, ["Main St, North", "Say \"Hi\""]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_input_source_parses_gtfs_dir() {
+        assert_eq!(
+            "gtfs:feeds/wonju".parse(),
+            Ok(InputSource::Gtfs(PathBuf::from("feeds/wonju")))
+        );
+        assert_eq!("tago".parse(), Ok(InputSource::Tago));
+        assert!("gtfs:".parse::<InputSource>().is_err());
+    }
+}
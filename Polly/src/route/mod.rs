@@ -4,24 +4,76 @@
 //! information. It fetches raw route data from a public API, saves it,
 //! and processes it into GeoJSON format suitable for frontend applications.
 
+mod announcements;
+mod combine;
+mod error;
+mod export_fgb;
 mod fetch;
+mod gtfs;
 mod model;
 mod osrm;
+mod output;
+mod preview;
 mod process;
+mod profile;
+mod reindex;
+mod stations;
+#[cfg(test)]
+mod test_support;
+mod validate;
+mod writer;
+
+pub use announcements::{ExportAnnouncementsArgs, export_announcements};
+pub use combine::{CombineArgs, combine};
+pub use error::FailureKind;
+pub use export_fgb::{ExportFlatgeobufArgs, export_flatgeobuf};
+pub use fetch::data_go_kr_get;
+pub use preview::{TripPreviewArgs, trip_preview};
+pub use validate::{ValidateArgs, validate};
 
 use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::path::PathBuf;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use futures::stream::{self, StreamExt};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use serde_json::Value;
 
-use crate::config::{CONCURRENCY_FETCH, CONCURRENCY_SNAP, OSRM_URL, TAGO_URL};
-use crate::route::model::BusRouteProcessor;
-use crate::utils::{ensure_dir, get_env, parse_flexible_string, resolve_url};
+use crate::config::{
+    COMBINED_WRITER_BUFFER, CONCURRENCY_FETCH, CONCURRENCY_SNAP, FILE_WRITE_BATCH,
+    GEOMETRY_EQUAL_TOLERANCE_M, OSRM_TIMEOUT_SECS, OSRM_URL, TAGO_TIMEOUT_SECS, TAGO_URL,
+};
+use crate::route::error::RouteError;
+use crate::route::fetch::{route_process_data, save_route_map_json};
+use crate::route::gtfs::InputSource;
+use crate::route::model::{
+    BusRouteProcessor, Direction, FileNames, GeometryOwners, MapFile, NameBy, RawRouteFile,
+    RouteIndexEntry, RouteMapData, RouteOptions, TagoFieldMap,
+};
+use crate::route::output::{
+    ArchiveSink, CombinedSink, FileSink, MultiSink, OutputSink, PooledFileSink, S3Sink, StdoutSink,
+};
+use crate::route::profile::Profile;
+use crate::route::reindex::reindex_file;
+use crate::route::stations::StationMap;
+use crate::route::writer::{
+    WriterFailure, spawn_archive_writer, spawn_combined_writer, spawn_file_writers,
+};
+use crate::utils::boundary::Boundary;
+use crate::utils::concurrency;
+use crate::utils::http;
+use crate::utils::layout::LayoutArgs;
+use crate::utils::s3::S3Target;
+use crate::utils::service_key::{KeyForm, key_form};
+use crate::utils::{
+    ensure_dir, get_env, normalize_route_no, parse_flexible_string, parse_interval, resolve_url,
+    write_atomic,
+};
 
 // ============================================================================
 // Argument Structure
@@ -33,52 +85,715 @@ pub struct RouteArgs {
     #[arg(long, default_value = "32020")]
     city_code: String,
 
+    /// JSON profile mapping city codes to TAGO service keys; a listed city overrides `DATA_GO_KR_SERVICE_KEY`
+    #[arg(long, value_name = "PATH")]
+    profile: Option<PathBuf>,
+
+    /// TAGO service key; takes precedence over `--profile`, `DATA_GO_KR_SERVICE_KEY` and `.env`
+    #[arg(long, value_name = "KEY")]
+    service_key: Option<String>,
+
+    /// TAGO API base URL; takes precedence over `TAGO_API_URL` (environment, then `.env`) and the default
+    #[arg(long, value_name = "URL")]
+    tago_url: Option<String>,
+
+    /// OSRM route service URL; takes precedence over `OSRM_API_URL` (environment, then `.env`) and the default
+    #[arg(long, value_name = "URL")]
+    osrm_url: Option<String>,
+
     /// Specific route number (if not specified, all)
     #[arg(short, long)]
     route: Option<String>,
 
+    /// Where Phase 1 reads route stops from: `tago` (the API) or `gtfs:<DIR>` (an unzipped GTFS feed)
+    #[arg(long, default_value = "tago", value_name = "SOURCE")]
+    input: InputSource,
+
     /// Output directory
     #[arg(short, long, default_value = "./storage")]
     output_dir: PathBuf,
 
-    /// Update station map only and skip snapping
-    #[arg(long)]
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// Update station map only and skip snapping (writes `stationMap.json` alone)
+    #[arg(long, conflicts_with = "emit")]
     station_map_only: bool,
 
+    /// Mapping files to write after fetching, comma-separated (default: all)
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "FILES")]
+    emit: Vec<MapFile>,
+
     /// Snap route paths using OSRM only (skip Tago API)
     #[arg(long)]
     osrm_only: bool,
+
+    /// Skip OSRM entirely and connect the stops with straight lines (rough, offline geometry)
+    #[arg(long)]
+    no_snap: bool,
+
+    /// Per-request timeout for OSRM calls, in seconds
+    #[arg(long, default_value_t = OSRM_TIMEOUT_SECS)]
+    osrm_timeout: u64,
+
+    /// Per-request timeout for TAGO API calls, in seconds
+    #[arg(long, default_value_t = TAGO_TIMEOUT_SECS)]
+    tago_timeout: u64,
+
+    /// Emit per-coordinate cumulative distance (GTFS `shape_dist_traveled`)
+    #[arg(long)]
+    cumulative_dist: bool,
+
+    /// Warn on routes whose geometry crosses itself outside the turnaround (O(n²) per route)
+    #[arg(long)]
+    check_geometry: bool,
+
+    /// Derive only one leg of each route
+    #[arg(long, value_enum, default_value_t = Direction::Both)]
+    direction: Direction,
+
+    /// Request the next OSRM chunk while the current one is merged (results stay in order)
+    #[arg(long)]
+    prefetch_chunks: bool,
+
+    /// Write stub files (empty LineString, `status: "no_geometry"`) for routes without geometry
+    #[arg(long)]
+    include_empty_routes: bool,
+
+    /// Estimate per-stop arrival offsets (`eta_offset_s`) at this constant speed
+    #[arg(long, value_name = "KMH")]
+    speed_kmh: Option<f64>,
+
+    /// Warn on routes whose `total_dist` exceeds this many kilometers (usually a mis-snapped coordinate)
+    #[arg(long, value_name = "KM")]
+    max_dist_km: Option<f64>,
+
+    /// Do not write routes over `--max-dist-km`; they count as failed
+    #[arg(long, requires = "max_dist_km")]
+    skip_over_max_dist: bool,
+
+    /// Warn on snapped routes with a step longer than this many meters between vertices (a stitching gap, e.g. 500)
+    #[arg(long, value_name = "METERS")]
+    max_gap_m: Option<f64>,
+
+    /// Keep the previous output of a re-derived route whose vertices all moved less than this many meters
+    #[arg(long, value_name = "METERS", default_value_t = GEOMETRY_EQUAL_TOLERANCE_M)]
+    equal_tolerance_m: f64,
+
+    /// Flag stretches between consecutive stops longer than this many meters as express (`segment_flags`)
+    #[arg(long, value_name = "METERS")]
+    express_threshold_m: Option<f64>,
+
+    /// Keep full OSRM coordinate precision instead of rounding to 6 decimal places
+    #[arg(long)]
+    no_round: bool,
+
+    /// NON-STANDARD: write coordinates as [lat, lon] for naive Leaflet use (marked `coord_order: "latlon"`)
+    #[arg(long)]
+    latlon_order: bool,
+
+    /// Name derived files by TAGO route id or by route number (`_1`, `_2`, ... for variants)
+    #[arg(long, value_enum, default_value_t = NameBy::RouteId)]
+    name_by: NameBy,
+
+    /// Stamp each derived route with `version`, a run counter kept in `<output_dir>/.run_version`
+    #[arg(long)]
+    run_version: bool,
+
+    /// Fetch the opposite direction separately when a route's stops all share one `updowncd`
+    /// (for feeds that return one direction per call)
+    #[arg(long)]
+    both_directions: bool,
+
+    /// Request OSRM annotations and keep per-segment speed, duration, distance and node ids
+    #[arg(long)]
+    osrm_annotations: bool,
+
+    /// Write `stop_to_coord` as differences under `stop_to_coord_delta` (smaller files for long routes)
+    #[arg(long)]
+    delta_stop_to_coord: bool,
+
+    /// Write the geometry's coordinate at `turn_idx` (where the bus turns around) as `turn_point`
+    #[arg(long)]
+    turn_point: bool,
+
+    /// Write the `data_version` of the OSRM data each route was snapped on as `osrm_data_version`
+    #[arg(long)]
+    osrm_version_in_meta: bool,
+
+    /// Look stations up in an on-disk index instead of loading `stationMap.json` into memory (large networks)
+    #[arg(long)]
+    station_index: bool,
+
+    /// Keep running, refreshing everything every INTERVAL (e.g. `90`, `30s`, `10m`, `6h`)
+    #[arg(long, value_name = "INTERVAL", value_parser = parse_interval)]
+    watch: Option<Duration>,
+
+    /// Fail the run if any data-quality warning was recorded (for CI validation)
+    #[arg(long)]
+    strict: bool,
+
+    /// Stop deriving at the first route that fails and exit with its error (for debugging)
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// Clip route geometry to the polygon(s) in this GeoJSON file
+    #[arg(long, value_name = "BOUNDARY")]
+    clip: Option<PathBuf>,
+
+    /// Skip routes whose derived file is newer than their cache file
+    #[arg(long)]
+    skip_existing: bool,
+
+    /// Write coordinates shared byte for byte by several routes once; the others reference that file
+    #[arg(long, conflicts_with_all = ["skip_existing", "watch", "stdout"])]
+    dedup_geometry: bool,
+
+    /// Print each derived route as one line of GeoJSON to stdout instead of writing `polylines/`
+    #[arg(long)]
+    stdout: bool,
+
+    /// Write `polylines/` through N writer tasks that batch files per blocking call
+    /// (fewer round trips on network filesystems)
+    #[arg(long, value_name = "N", conflicts_with_all = ["stdout", "archive"])]
+    writers: Option<NonZeroUsize>,
+
+    /// Write derived routes into one tar archive instead of `polylines/`
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["stdout", "skip_existing", "watch"])]
+    archive: Option<PathBuf>,
+
+    /// Also write all routes into one file, streamed as they are derived
+    /// (`.geojsonl`/`.ndjson` for one Feature per line, otherwise a FeatureCollection)
+    #[arg(long, value_name = "PATH")]
+    combined: Option<PathBuf>,
+
+    /// JSON file remapping TAGO item keys to stop fields (e.g. `{"gps_lat": "gpsLat"}`)
+    #[arg(long, value_name = "PATH")]
+    field_map: Option<PathBuf>,
+
+    /// Mirror generated files to an S3-compatible bucket (`bucket/prefix`; requires the `s3` feature)
+    #[arg(long, value_name = "BUCKET/PREFIX")]
+    s3: Option<String>,
+
+    /// Gzip-compress objects uploaded with `--s3`
+    #[arg(long, requires = "s3")]
+    s3_gzip: bool,
+}
+
+/// Arguments for listing the routes of a city
+#[derive(clap::Args)]
+pub struct ListRoutesArgs {
+    /// City code to list (default: Wonju -> 32020)
+    #[arg(long, default_value = "32020")]
+    city_code: String,
+
+    /// JSON profile mapping city codes to TAGO service keys; a listed city overrides `DATA_GO_KR_SERVICE_KEY`
+    #[arg(long, value_name = "PATH")]
+    profile: Option<PathBuf>,
+
+    /// TAGO service key; takes precedence over `--profile`, `DATA_GO_KR_SERVICE_KEY` and `.env`
+    #[arg(long, value_name = "KEY")]
+    service_key: Option<String>,
+
+    /// TAGO API base URL; takes precedence over `TAGO_API_URL` (environment, then `.env`) and the default
+    #[arg(long, value_name = "URL")]
+    tago_url: Option<String>,
+
+    /// Print as JSON instead of a table
+    #[arg(long)]
+    json: bool,
+
+    /// Per-request timeout for TAGO API calls, in seconds
+    #[arg(long, default_value_t = TAGO_TIMEOUT_SECS)]
+    tago_timeout: u64,
+}
+
+/// Arguments for repairing indices of derived GeoJSON files
+#[derive(clap::Args)]
+pub struct ReindexArgs {
+    /// Specific route id to repair (if not specified, all)
+    #[arg(short, long)]
+    route: Option<String>,
+
+    /// Output directory containing `polylines/` and `stationMap.json`
+    #[arg(short, long, default_value = "./storage")]
+    output_dir: PathBuf,
+
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// City whose files to repair, with a layout that nests files by city
+    #[arg(long)]
+    city_code: Option<String>,
+}
+
+/// Arguments for rebuilding the mapping files from the raw cache
+#[derive(clap::Args)]
+pub struct RebuildMapArgs {
+    /// Output directory containing `cache/`
+    #[arg(short, long, default_value = "./storage")]
+    output_dir: PathBuf,
+
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// City whose map files to rebuild, with a layout that nests files by city
+    #[arg(long)]
+    city_code: Option<String>,
+
+    /// File naming scheme recorded in `routeMap.json` (match the one used for `route`)
+    #[arg(long, value_enum, default_value_t = NameBy::RouteId)]
+    name_by: NameBy,
+}
+
+/// Service key and endpoints for a run.
+///
+/// Each value comes from the first of: the command line, `--profile` (service key only), the
+/// environment, `.env` (loaded without overriding the environment) and the built-in default.
+pub struct RouteConfig {
+    pub service_key: String,
+    pub tago_base_url: String,
+    pub osrm_base_url: String,
+}
+
+impl RouteConfig {
+    pub fn from_env() -> Result<Self> {
+        let service_key = get_env("DATA_GO_KR_SERVICE_KEY");
+        if service_key.is_empty() {
+            anyhow::bail!("DATA_GO_KR_SERVICE_KEY is missing!");
+        }
+
+        Ok(Self::with_service_key(service_key))
+    }
+
+    /// The key listed for `city_code` in the `--profile` file, or else the one from the environment
+    pub fn for_city(profile: Option<&Path>, city_code: &str) -> Result<Self> {
+        if let Some(path) = profile
+            && let Some(service_key) = Profile::load(path)?.service_key(city_code)?
+        {
+            info!(
+                "Using the service key of city {} from profile {}",
+                city_code,
+                path.display()
+            );
+            return Ok(Self::with_service_key(service_key));
+        }
+
+        Self::from_env()
+    }
+
+    /// The key from `--service-key` if given, or else the one [`for_city`](Self::for_city) finds
+    pub fn resolve(
+        service_key: Option<&str>,
+        profile: Option<&Path>,
+        city_code: &str,
+    ) -> Result<Self> {
+        match service_key {
+            Some(key) if !key.is_empty() => Ok(Self::with_service_key(key.to_string())),
+            _ => Self::for_city(profile, city_code),
+        }
+    }
+
+    /// Replaces the endpoints given on the command line
+    pub fn with_urls(mut self, tago_url: Option<&str>, osrm_url: Option<&str>) -> Self {
+        if let Some(url) = tago_url {
+            self.tago_base_url = url.to_string();
+        }
+        if let Some(url) = osrm_url {
+            self.osrm_base_url = url.to_string();
+        }
+        self
+    }
+
+    /// Endpoints from the environment with the given service key
+    fn with_service_key(service_key: String) -> Self {
+        match key_form(&service_key) {
+            _ if service_key.is_empty() => {}
+            KeyForm::Decoded => debug!("Service key appears to be decoded"),
+            form => warn!(
+                "Service key appears to be {}; data.go.kr's \"Decoding\" key is expected. \
+                 Rejected requests are retried once with the key decoded.",
+                form
+            ),
+        }
+
+        Self {
+            service_key,
+            tago_base_url: resolve_url("TAGO_API_URL", TAGO_URL),
+            osrm_base_url: resolve_url("OSRM_API_URL", OSRM_URL),
+        }
+    }
 }
 
 // ============================================================================
 // Main Execution
 // ============================================================================
 
+/// Recomputes `stop_to_coord` and `turn_idx` of derived files against their existing geometry.
+pub async fn reindex(args: ReindexArgs) -> Result<()> {
+    let layout = args.layout.resolve()?;
+    let root = layout.root(&args.output_dir, args.city_code.as_deref())?;
+    let derived_dir = layout.polylines_dir(&root);
+    let station_map = StationMap::load(&root.join("stationMap.json"), false).await?;
+    if station_map.is_empty() {
+        anyhow::bail!(
+            "`stationMap.json` is missing or empty; run `route --station-map-only` first."
+        );
+    }
+
+    let mut repaired = 0usize;
+    for entry in fs::read_dir(&derived_dir)?.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "geojson") {
+            continue;
+        }
+        if let Some(ref target) = args.route
+            && path.file_stem().is_none_or(|stem| stem != target.as_str())
+        {
+            continue;
+        }
+
+        match reindex_file(&path, &station_map).await {
+            Ok(true) => {
+                repaired += 1;
+                info!("Repaired {:?}", path.file_name().unwrap());
+            }
+            Ok(false) => {}
+            Err(e) => error!("Reindex failed: {:?}", e),
+        }
+    }
+
+    info!("Reindex complete: {} file(s) repaired.", repaired);
+
+    Ok(())
+}
+
+/// Rebuilds `routeMap.json`, `routeDetails.json`, `routeStops.json` and `stationMap.json`
+/// from the raw cache files, without calling TAGO.
+pub async fn rebuild_map(args: RebuildMapArgs) -> Result<()> {
+    let layout = args.layout.resolve()?;
+    let root = layout.root(&args.output_dir, args.city_code.as_deref())?;
+    let raw_dir = layout.cache_dir(&root);
+
+    let mut map_data = RouteMapData::default();
+    let mut count = 0usize;
+    for entry in fs::read_dir(&raw_dir)
+        .with_context(|| format!("Failed to read {}", raw_dir.display()))?
+        .filter_map(|e| e.ok())
+    {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+
+        let raw: RawRouteFile = match fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| Ok(serde_json::from_str(&content)?))
+        {
+            Ok(raw) => raw,
+            Err(e) => {
+                error!("Skipping {}: {:?}", path.display(), e);
+                continue;
+            }
+        };
+        map_data.add(route_process_data(&raw));
+        count += 1;
+    }
+    if count == 0 {
+        anyhow::bail!("No raw route files found in {}", raw_dir.display());
+    }
+
+    map_data.finish(args.name_by, &layout);
+    save_route_map_json(
+        &root,
+        None,
+        &map_data.route_numbers,
+        &map_data.variants,
+        &map_data.details,
+        &map_data.stops,
+        &MapFile::ALL,
+    )
+    .await?;
+
+    info!(
+        "Rebuilt routeMap.json with {} routes from {} cache files.",
+        map_data.route_numbers.len(),
+        count
+    );
+
+    Ok(())
+}
+
+/// Lists route numbers and ids for a city using a single `getRouteNoList` call.
+pub async fn list_routes(args: ListRoutesArgs) -> Result<()> {
+    let config = RouteConfig::resolve(
+        args.service_key.as_deref(),
+        args.profile.as_deref(),
+        &args.city_code,
+    )?
+    .with_urls(args.tago_url.as_deref(), None);
+
+    let processor = BusRouteProcessor {
+        service_key: config.service_key.as_str().into(),
+        city_code: args.city_code,
+        tago_base_url: config.tago_base_url,
+        osrm_base_url: config.osrm_base_url,
+        tago_timeout: Duration::from_secs(args.tago_timeout),
+        ..BusRouteProcessor::new(http::client()?)
+    };
+
+    let mut routes: Vec<(String, String)> = processor
+        .get_all_routes()
+        .await?
+        .iter()
+        .map(|r| {
+            (
+                parse_flexible_string(&r["routeno"]),
+                parse_flexible_string(&r["routeid"]),
+            )
+        })
+        .collect();
+    routes.sort();
+
+    if args.json {
+        let list: Vec<Value> = routes
+            .iter()
+            .map(|(no, id)| serde_json::json!({ "routeno": no, "routeid": id }))
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&list)?);
+    } else {
+        println!("{:<12} ROUTE_ID", "ROUTE_NO");
+        for (no, id) in &routes {
+            println!("{:<12} {}", no, id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Hashes of the raw stop lists derived in earlier watch cycles, by raw file path
+type RouteHashes = std::sync::Mutex<HashMap<PathBuf, u64>>;
+
+/// What happened to one raw file in a cycle
+enum Outcome {
+    Skipped,
+    Unchanged,
+    Derived(RouteIndexEntry),
+}
+
+/// Per-cycle counts, logged in watch mode
+#[derive(Default)]
+struct CycleSummary {
+    derived: usize,
+    unchanged: usize,
+    failed: usize,
+}
+
+/// Increments and returns the run counter stored in `<output_dir>/.run_version`
+fn next_run_version(output_dir: &Path) -> Result<u64> {
+    let path = output_dir.join(".run_version");
+    let previous: u64 = match fs::read_to_string(&path) {
+        Ok(content) => content
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid run counter in {}", path.display()))?,
+        Err(_) => 0,
+    };
+    let version = previous + 1;
+    write_atomic(&path, version.to_string().as_bytes())?;
+    Ok(version)
+}
+
+/// Hashes the stop list of a raw route file, ignoring its fetch timestamp.
+async fn raw_stops_hash(path: &Path) -> Result<(String, u64)> {
+    let raw: RawRouteFile = serde_json::from_str(&tokio::fs::read_to_string(path).await?)?;
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(&raw.stops)?.hash(&mut hasher);
+    Ok((raw.route_id, hasher.finish()))
+}
+
+/// The normalized route number of a cache file named `<route_no>_<route_id>.json`
+fn cached_route_no(raw_path: &Path) -> Option<&str> {
+    let stem = raw_path.file_stem()?.to_str()?;
+    stem.split_once('_').map(|(route_no, _)| route_no)
+}
+
+/// Whether the derived file for a cache file exists and is at least as new as it.
+///
+/// Cache files are named `<route_no>_<route_id>.json`.
+async fn derived_is_current(derived_dir: &Path, raw_path: &Path, file_names: &FileNames) -> bool {
+    async fn modified(path: &Path) -> Option<SystemTime> {
+        tokio::fs::metadata(path).await.ok()?.modified().ok()
+    }
+
+    let Some((_, route_id)) = raw_path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.rsplit_once('_'))
+    else {
+        return false;
+    };
+    let derived = derived_dir.join(format!("{}.geojson", file_names.stem(route_id)));
+
+    match (modified(&derived).await, modified(raw_path).await) {
+        (Some(derived), Some(raw)) => derived >= raw,
+        _ => false,
+    }
+}
+
 pub async fn run(args: RouteArgs) -> Result<()> {
+    // A GTFS feed replaces every TAGO call, so no service key is needed
+    let config = match args.input {
+        InputSource::Tago => RouteConfig::resolve(
+            args.service_key.as_deref(),
+            args.profile.as_deref(),
+            &args.city_code,
+        )?,
+        InputSource::Gtfs(_) => RouteConfig::with_service_key(String::new()),
+    };
+    let config = config.with_urls(args.tago_url.as_deref(), args.osrm_url.as_deref());
+    run_with_config(args, config).await
+}
+
+/// Runs the route pipeline with an explicit service key and endpoints.
+pub async fn run_with_config(args: RouteArgs, config: RouteConfig) -> Result<()> {
+    if let Some(speed) = args.speed_kmh
+        && (speed.is_nan() || speed <= 0.0)
+    {
+        anyhow::bail!("--speed-kmh must be positive, got {}", speed);
+    }
+    if let Some(max_km) = args.max_dist_km
+        && (max_km.is_nan() || max_km <= 0.0)
+    {
+        anyhow::bail!("--max-dist-km must be positive, got {}", max_km);
+    }
+    if let Some(max_gap) = args.max_gap_m
+        && (max_gap.is_nan() || max_gap <= 0.0)
+    {
+        anyhow::bail!("--max-gap-m must be positive, got {}", max_gap);
+    }
+    if let Some(threshold) = args.express_threshold_m
+        && (threshold.is_nan() || threshold <= 0.0)
+    {
+        anyhow::bail!("--express-threshold-m must be positive, got {}", threshold);
+    }
+
+    let Some(interval) = args.watch else {
+        run_cycle(&args, &config, None).await?;
+        return Ok(());
+    };
+
+    // Watch mode: refetch every cycle, but only re-derive routes whose stops changed
+    let hashes = RouteHashes::default();
+    for cycle in 1.. {
+        let started = Instant::now();
+        match run_cycle(&args, &config, Some(&hashes)).await {
+            Ok(summary) => info!(
+                "Cycle {} finished in {:.1}s: {} derived, {} unchanged, {} failed.",
+                cycle,
+                started.elapsed().as_secs_f64(),
+                summary.derived,
+                summary.unchanged,
+                summary.failed
+            ),
+            Err(e) => error!("Cycle {} failed: {:?}", cycle, e),
+        }
+
+        info!("Next cycle in {}s.", interval.as_secs());
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    info!("Watch stopped.");
+    Ok(())
+}
+
+/// Runs both pipeline phases once. With `hashes` (watch mode), the cache is always
+/// refreshed and routes whose stops are unchanged since the last cycle are not re-derived.
+async fn run_cycle(
+    args: &RouteArgs,
+    config: &RouteConfig,
+    hashes: Option<&RouteHashes>,
+) -> Result<CycleSummary> {
     // Setup Directories
-    let raw_dir = args.output_dir.join("cache");
-    let derived_dir = args.output_dir.join("polylines");
+    let layout = args.layout.resolve()?;
+    let root = layout.root(&args.output_dir, Some(&args.city_code))?;
+    let raw_dir = layout.cache_dir(&root);
+    let derived_dir = layout.polylines_dir(&root);
 
     ensure_dir(&raw_dir)?;
     ensure_dir(&derived_dir)?;
 
-    let service_key = get_env("DATA_GO_KR_SERVICE_KEY");
-    if service_key.is_empty() {
-        anyhow::bail!("DATA_GO_KR_SERVICE_KEY is missing!");
-    }
+    let s3 = match args.s3.as_deref() {
+        Some(spec) => Some(Arc::new(S3Target::new(spec, args.s3_gzip).await?)),
+        None => None,
+    };
+
+    let field_map: TagoFieldMap = match &args.field_map {
+        Some(path) => serde_json::from_str(&fs::read_to_string(path)?)
+            .with_context(|| format!("Invalid field map {}", path.display()))?,
+        None => TagoFieldMap::default(),
+    };
+
+    let clip = match &args.clip {
+        Some(path) => Some(Arc::new(Boundary::from_file(path)?)),
+        None => None,
+    };
+
+    let run_version = (args.run_version && !args.station_map_only)
+        .then(|| next_run_version(&root))
+        .transpose()?;
 
     let processor = Arc::new(BusRouteProcessor {
-        client: reqwest::Client::new(),
-        service_key,
+        service_key: config.service_key.as_str().into(),
         city_code: args.city_code.clone(),
         raw_dir: raw_dir.clone(),
         derived_dir: derived_dir.clone(),
-        mapping_file: args.output_dir.join("routeMap.json"),
-        tago_base_url: resolve_url("TAGO_API_URL", TAGO_URL),
-        osrm_base_url: resolve_url("OSRM_API_URL", OSRM_URL),
+        mapping_file: root.join("routeMap.json"),
+        layout: layout.clone(),
+        tago_base_url: config.tago_base_url.clone(),
+        osrm_base_url: config.osrm_base_url.clone(),
+        osrm_timeout: Duration::from_secs(args.osrm_timeout),
+        tago_timeout: Duration::from_secs(args.tago_timeout),
+        options: RouteOptions {
+            cumulative_dist: args.cumulative_dist,
+            check_geometry: args.check_geometry,
+            direction: args.direction,
+            prefetch_chunks: args.prefetch_chunks,
+            include_empty_routes: args.include_empty_routes,
+            speed_kmh: args.speed_kmh,
+            max_dist_km: args.max_dist_km,
+            skip_over_max_dist: args.skip_over_max_dist,
+            max_gap_m: args.max_gap_m,
+            equal_tolerance_m: args.equal_tolerance_m,
+            express_threshold_m: args.express_threshold_m,
+            no_round: args.no_round,
+            clip,
+            latlon_order: args.latlon_order,
+            delta_stop_to_coord: args.delta_stop_to_coord,
+            turn_point: args.turn_point,
+            osrm_version_in_meta: args.osrm_version_in_meta,
+            osrm_annotations: args.osrm_annotations,
+            no_snap: args.no_snap,
+            both_directions: args.both_directions,
+            version: run_version,
+        },
+        field_map,
+        s3,
+        ..BusRouteProcessor::new(http::client()?)
     });
 
     // [Phase 1] Data Collection (Raw Save)
+    let map_files: Vec<MapFile> = if args.station_map_only {
+        vec![MapFile::StationMap]
+    } else if args.emit.is_empty() {
+        MapFile::ALL.to_vec()
+    } else {
+        args.emit.clone()
+    };
+
     if !args.osrm_only {
         // Check if cache already exists
         let cache_file_count = fs::read_dir(&raw_dir)?
@@ -86,60 +801,81 @@ pub async fn run(args: RouteArgs) -> Result<()> {
             .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "json"))
             .count();
 
-        if cache_file_count == 0 {
-            // No cache exists, fetch from API
-            info!("Cache does not exist, fetching Raw Data to {:?}]", raw_dir);
-
-            let routes = processor.get_all_routes().await?;
-            let target_routes: Vec<Value> = if let Some(target_no) = args.route.as_ref() {
-                routes
-                    .into_iter()
-                    .filter(|r| parse_flexible_string(&r["routeno"]) == *target_no)
-                    .collect()
-            } else {
-                routes
-            };
-
-            info!("Targeting {} routes...", target_routes.len());
-
-            let mut route_stream = stream::iter(target_routes)
-                .map(|route| {
-                    let proc = Arc::clone(&processor);
-                    async move { proc.fetch_and_save_raw(route).await }
-                })
-                .buffer_unordered(CONCURRENCY_FETCH);
+        // A local feed is cheap to reread, so it always replaces the cache
+        let gtfs_input = matches!(args.input, InputSource::Gtfs(_));
+        if cache_file_count == 0 || hashes.is_some() || gtfs_input {
+            // No cache exists (or watch mode wants fresh data), fetch from API
+            info!("Fetching Raw Data to {:?}", raw_dir);
 
             // Aggregation for routeMap.json
-            let mut all_stops = BTreeMap::new();
-            let mut route_details_map = HashMap::new();
-            let mut route_mapping: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            let mut map_data = RouteMapData::default();
             let mut count = 0usize;
 
-            while let Some(result) = route_stream.next().await {
-                match result {
-                    Ok(Some(data)) => {
+            match &args.input {
+                InputSource::Gtfs(dir) => {
+                    for raw in gtfs::read_routes(dir, args.route.as_deref())? {
+                        processor.save_raw(&raw).await?;
+                        map_data.add(route_process_data(&raw));
                         count += 1;
-                        route_details_map.insert(data.route_id.clone(), data.details);
-                        route_mapping
-                            .entry(data.route_no)
-                            .or_default()
-                            .push(data.route_id);
-                        for (id, val) in data.stops_map {
-                            all_stops.insert(id, val);
-                        }
-                        if count.is_multiple_of(10) {
-                            debug!(".");
+                    }
+                    info!("Read {} routes from GTFS feed {}.", count, dir.display());
+                }
+                InputSource::Tago => {
+                    let routes = processor.get_all_routes().await?;
+                    let target_routes: Vec<Value> = if let Some(target_no) = args.route.as_ref() {
+                        routes
+                            .into_iter()
+                            .filter(|r| {
+                                normalize_route_no(&parse_flexible_string(&r["routeno"]))
+                                    == normalize_route_no(target_no)
+                            })
+                            .collect()
+                    } else {
+                        routes
+                    };
+
+                    info!("Targeting {} routes...", target_routes.len());
+
+                    let mut route_stream = stream::iter(target_routes)
+                        .map(|route| {
+                            let proc = Arc::clone(&processor);
+                            async move {
+                                let _permit = concurrency::acquire().await;
+                                proc.fetch_and_save_raw(route).await
+                            }
+                        })
+                        .buffer_unordered(concurrency::bounded(CONCURRENCY_FETCH));
+
+                    while let Some(result) = route_stream.next().await {
+                        match result {
+                            Ok(Some(data)) => {
+                                count += 1;
+                                map_data.add(data);
+                                if count.is_multiple_of(10) {
+                                    debug!(".");
+                                }
+                            }
+                            Ok(None) => {}
+                            // A rejected key or exhausted quota fails every remaining route too
+                            Err(e) if e.is_fatal() => return Err(e.into()),
+                            Err(e) => error!("Error: {:?}", e),
                         }
                     }
-                    Ok(None) => {}
-                    Err(e) => error!("Error: {:?}", e),
+                    info!("Processed {} raw routes.", count);
                 }
             }
-            info!("Processed {} raw routes.", count);
 
-            processor
-                .save_route_map_json(&route_mapping, &route_details_map, &all_stops)
-                .await?;
+            map_data.finish(args.name_by, &layout);
+            save_route_map_json(
+                &root,
+                processor.s3.as_deref(),
+                &map_data.route_numbers,
+                &map_data.variants,
+                &map_data.details,
+                &map_data.stops,
+                &map_files,
+            )
+            .await?;
         } else {
             // Cache exists, skip API calls
             info!(
@@ -148,10 +884,10 @@ pub async fn run(args: RouteArgs) -> Result<()> {
             );
 
             // Verify that routeMap.json exists
-            let route_map_path = args.output_dir.join("routeMap.json");
-            if !route_map_path.exists() {
+            let route_map_path = root.join("routeMap.json");
+            if map_files.contains(&MapFile::RouteMap) && !route_map_path.exists() {
                 anyhow::bail!(
-                    "`routeMap.json` not found. Run without cache or delete {} to regenerate.",
+                    "`routeMap.json` not found. Run `rebuild-map` to restore it from {}.",
                     raw_dir.display()
                 );
             }
@@ -159,7 +895,7 @@ pub async fn run(args: RouteArgs) -> Result<()> {
 
         if args.station_map_only {
             info!("Station map generated.");
-            return Ok(());
+            return Ok(CycleSummary::default());
         }
     }
 
@@ -170,16 +906,68 @@ pub async fn run(args: RouteArgs) -> Result<()> {
     );
 
     // Load stationMap.json for accurate coordinates
-    let station_map_path = args.output_dir.join("stationMap.json");
-    let station_map: HashMap<String, Value> = if station_map_path.exists() {
-        let content = tokio::fs::read_to_string(&station_map_path).await?;
-        let json: Value = serde_json::from_str(&content)?;
-        serde_json::from_value(json["stations"].clone()).unwrap_or_default()
-    } else {
-        HashMap::new()
-    };
+    let station_map = StationMap::load(&root.join("stationMap.json"), args.station_index).await?;
     let station_map_arc = Arc::new(station_map);
 
+    // File names by route number need every variant of a number, so they come from routeMap.json
+    let file_names = match args.name_by {
+        NameBy::RouteId => FileNames::default(),
+        NameBy::RouteNo => {
+            let route_map_path = root.join("routeMap.json");
+            let route_map: Value = serde_json::from_str(
+                &fs::read_to_string(&route_map_path)
+                    .with_context(|| format!("Failed to read {}", route_map_path.display()))?,
+            )?;
+            let route_numbers =
+                serde_json::from_value(route_map["route_numbers"].clone()).unwrap_or_default();
+            FileNames::new(NameBy::RouteNo, &route_numbers)
+        }
+    };
+    let file_names = Arc::new(file_names);
+
+    // Select output destinations once
+    let mut sinks: Vec<Box<dyn OutputSink>> = Vec::new();
+    let mut file_writer = None;
+    if args.stdout {
+        sinks.push(Box::new(StdoutSink));
+    } else if let Some(path) = &args.archive {
+        let (tx, rx) = tokio::sync::mpsc::channel(COMBINED_WRITER_BUFFER);
+        sinks.push(Box::new(ArchiveSink { tx }));
+        file_writer = Some(spawn_archive_writer(path.clone(), rx));
+    } else if let Some(writers) = args.writers {
+        let (tx, rx) = tokio::sync::mpsc::channel(writers.get() * FILE_WRITE_BATCH);
+        sinks.push(Box::new(PooledFileSink {
+            dir: processor.derived_dir.clone(),
+            tx,
+        }));
+        file_writer = Some(spawn_file_writers(writers.get(), rx));
+    } else {
+        sinks.push(Box::new(FileSink {
+            dir: processor.derived_dir.clone(),
+        }));
+    }
+    if let Some(target) = &processor.s3 {
+        sinks.push(Box::new(S3Sink {
+            target: Arc::clone(target),
+            prefix: layout.polylines.clone(),
+        }));
+    }
+
+    // Combined output is streamed by a dedicated writer task
+    let combined_writer = args.combined.as_ref().map(|path| {
+        let (tx, rx) = tokio::sync::mpsc::channel(COMBINED_WRITER_BUFFER);
+        let failure = WriterFailure::default();
+        sinks.push(Box::new(CombinedSink {
+            tx,
+            failure: failure.clone(),
+        }));
+        spawn_combined_writer(path.clone(), rx, failure)
+    });
+
+    let sink: Arc<dyn OutputSink> = Arc::new(MultiSink(sinks));
+    let geometry_owners = args.dedup_geometry.then(GeometryOwners::default);
+    let geometry_owners = geometry_owners.as_ref();
+
     // Read all JSONs from `cache/`
     let raw_entries: Vec<_> = fs::read_dir(&raw_dir)?.filter_map(|e| e.ok()).collect();
 
@@ -187,39 +975,550 @@ pub async fn run(args: RouteArgs) -> Result<()> {
     let mut snap_stream = stream::iter(raw_entries)
         .map(|entry| {
             let proc = Arc::clone(&processor);
-            let specific = args.route.clone();
+            let specific = args.route.as_deref().map(normalize_route_no);
             let smap = Arc::clone(&station_map_arc);
+            let sink = Arc::clone(&sink);
+            let file_names = Arc::clone(&file_names);
 
             async move {
+                let _permit = concurrency::acquire().await;
                 let path = entry.path();
                 if path.extension().is_some_and(|ext| ext == "json") {
-                    let fname = path.file_name().unwrap().to_string_lossy();
-
                     // Filter check
                     if let Some(ref target) = specific
-                        && !fname.starts_with(target)
-                        && !fname.contains(target)
+                        && cached_route_no(&path) != Some(target.as_str())
                     {
-                        return Ok(());
+                        return Ok::<_, RouteError>(Outcome::Skipped);
                     }
 
-                    info!("Processing {}...", fname);
+                    if args.skip_existing
+                        && derived_is_current(&proc.derived_dir, &path, &file_names).await
+                    {
+                        return Ok(Outcome::Unchanged);
+                    }
+
+                    // Skip routes whose stops are unchanged since they were last derived
+                    let stops_hash = match hashes {
+                        Some(hashes) => {
+                            let (route_id, hash) = raw_stops_hash(&path).await?;
+                            let stem = file_names.stem(&route_id);
+                            let derived = proc.derived_dir.join(format!("{}.geojson", stem));
+                            let previous = hashes.lock().unwrap().get(&path).copied();
+                            if previous == Some(hash) && derived.exists() {
+                                return Ok(Outcome::Unchanged);
+                            }
+                            Some(hash)
+                        }
+                        None => None,
+                    };
 
-                    proc.process_raw_to_derived(&path, &smap).await
+                    info!(
+                        "Processing {}...",
+                        path.file_name().unwrap().to_string_lossy()
+                    );
+
+                    let entry = proc
+                        .process_raw_to_derived(
+                            &path,
+                            &smap,
+                            &file_names,
+                            sink.as_ref(),
+                            geometry_owners,
+                        )
+                        .await?;
+
+                    if let (Some(hashes), Some(hash)) = (hashes, stops_hash) {
+                        hashes.lock().unwrap().insert(path, hash);
+                    }
+                    Ok(Outcome::Derived(entry))
                 } else {
-                    Ok(())
+                    Ok(Outcome::Skipped)
                 }
             }
         })
-        .buffer_unordered(CONCURRENCY_SNAP);
+        .buffer_unordered(concurrency::bounded(CONCURRENCY_SNAP));
 
+    let mut summary = CycleSummary::default();
+    let mut route_index = BTreeMap::new();
     while let Some(res) = snap_stream.next().await {
-        if let Err(e) = res {
-            error!("Processing failed: {:?}", e);
+        match res {
+            Ok(Outcome::Derived(entry)) => {
+                summary.derived += 1;
+                route_index.insert(entry.route_id.clone(), entry);
+            }
+            Ok(Outcome::Unchanged) => summary.unchanged += 1,
+            Ok(Outcome::Skipped) => {}
+            // `derive` has already logged why (too few stops, clipped away, ...)
+            Err(e @ RouteError::NoGeometry(_)) => info!("Skipped: {}.", e),
+            // Dropping the stream cancels the routes still in flight
+            Err(e) if args.fail_fast => {
+                return Err(anyhow::Error::from(e)
+                    .context("--fail-fast: stopped at the first failed route"));
+            }
+            Err(e) => {
+                summary.failed += 1;
+                error!("Processing failed: {:?}", e);
+            }
+        }
+    }
+
+    // Dropping the last sink handle closes the writers' channels
+    drop(snap_stream);
+    drop(sink);
+    if let Some(writer) = file_writer {
+        let count = writer.await??;
+        match &args.archive {
+            Some(path) => info!("Archive {} written with {} routes.", path.display(), count),
+            None => info!("Writer pool wrote {} route files.", count),
         }
     }
+    if let Some(writer) = combined_writer {
+        let count = writer.await??;
+        info!("Combined output written with {} features.", count);
+    }
+
+    if !args.stdout && !route_index.is_empty() {
+        processor.save_route_index(&route_index).await?;
+        info!("Route index updated with {} routes.", route_index.len());
+    }
+
+    // Which OSRM server and data answered, so geometry changes between runs can be attributed
+    let osrm_instances = processor.osrm_instances.take();
+    if !args.stdout && !osrm_instances.is_empty() {
+        for (instance, responses) in &osrm_instances {
+            debug!(
+                "OSRM instance {:?} answered {} requests.",
+                instance, responses
+            );
+        }
+        processor.save_run_manifest(&osrm_instances).await?;
+    }
+
+    let issues = processor.quality.take();
+    if args.strict && !issues.is_empty() {
+        let mut routes: Vec<&str> = issues.iter().map(|(id, _)| id.as_str()).collect();
+        routes.sort();
+        routes.dedup();
+        anyhow::bail!(
+            "--strict: {} data-quality warning(s) on {} route(s): {}",
+            issues.len(),
+            routes.len(),
+            routes.join(", ")
+        );
+    }
 
     info!("Pipeline Complete.");
 
-    Ok(())
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        args: RouteArgs,
+    }
+
+    /// Route id, route number and stops as `(lon, lat, updowncd)`
+    type FixtureRoute = (&'static str, &'static str, Vec<(f64, f64, i64)>);
+
+    /// An out-and-back route, a one-way route with a short variant, and a short loop
+    fn fixture_routes() -> Vec<FixtureRoute> {
+        vec![
+            (
+                "WJB251000001",
+                "1",
+                vec![
+                    (127.920, 37.340, 0),
+                    (127.925, 37.342, 0),
+                    (127.930, 37.344, 0),
+                    (127.925, 37.343, 1),
+                    (127.920, 37.341, 1),
+                ],
+            ),
+            (
+                "WJB251000002",
+                "2",
+                vec![
+                    (127.940, 37.350, 0),
+                    (127.945, 37.352, 0),
+                    (127.950, 37.354, 0),
+                ],
+            ),
+            (
+                "WJB251000004",
+                "2",
+                vec![(127.940, 37.350, 0), (127.950, 37.354, 0)],
+            ),
+            (
+                "WJB251000003",
+                "34-1",
+                vec![
+                    (127.960, 37.360, 0),
+                    (127.965, 37.362, 0),
+                    (127.960, 37.364, 1),
+                    (127.958, 37.361, 1),
+                ],
+            ),
+        ]
+    }
+
+    fn tago_items(items: Vec<Value>) -> Value {
+        json!({ "response": { "body": { "items": { "item": items } } } })
+    }
+
+    /// Answers a single HTTP request as either TAGO or OSRM, based on the path
+    async fn serve(mut socket: TcpStream) {
+        let mut buf = Vec::new();
+        let mut chunk = [0; 4096];
+        while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            match socket.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            }
+        }
+
+        let request = String::from_utf8_lossy(&buf);
+        let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+
+        let body = if path.starts_with("/tago/getRouteNoList") {
+            tago_items(
+                fixture_routes()
+                    .iter()
+                    .map(|(id, no, _)| json!({ "routeid": id, "routeno": no }))
+                    .collect(),
+            )
+        } else if path.starts_with("/tago/getRouteAcctoThrghSttnList") {
+            let routes = fixture_routes();
+            let (id, _, stops) = routes
+                .iter()
+                .find(|(id, _, _)| path.contains(id))
+                .expect("unknown route id");
+            tago_items(
+                stops
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (lon, lat, ud))| {
+                        json!({
+                            "nodeid": format!("{}_{}", id, i), "nodenm": format!("Stop {}", i),
+                            "nodeord": i + 1, "nodeno": 1000 + i,
+                            "gpslati": lat, "gpslong": lon, "updowncd": ud
+                        })
+                    })
+                    .collect(),
+            )
+        } else {
+            // OSRM: return a straight line through the requested waypoints
+            let coords_part = path
+                .trim_start_matches("/osrm/")
+                .split('?')
+                .next()
+                .unwrap_or_default();
+            let coords: Vec<Vec<f64>> = coords_part
+                .split(';')
+                .map(|c| c.split(',').map(|v| v.parse().unwrap()).collect())
+                .collect();
+            json!({ "routes": [{
+                "geometry": { "coordinates": coords },
+                "distance": 100.0 * (coords.len() - 1) as f64,
+                "duration": 10.0 * (coords.len() - 1) as f64
+            }] })
+        }
+        .to_string();
+
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let _ = socket.write_all(response.as_bytes()).await;
+    }
+
+    #[test]
+    fn test_cached_route_no_matches_whole_route_numbers() {
+        let route_no = |name: &str| cached_route_no(Path::new(name)).map(str::to_string);
+        let target = Some(normalize_route_no("34–1 (평일)"));
+        assert_eq!(route_no("cache/34-1_WJB251000003.json"), target);
+        // Route 2 must not pick up route 12 or route 2-1
+        assert_eq!(route_no("cache/12_WJB251000012.json").unwrap(), "12");
+        assert_eq!(route_no("cache/2-1_WJB251000021.json").unwrap(), "2-1");
+        assert_eq!(route_no("cache/stray.json"), None);
+    }
+
+    #[test]
+    fn test_run_version_increments() {
+        let dir = std::env::temp_dir().join(format!("polly-run-version-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        assert_eq!(next_run_version(&dir).unwrap(), 1);
+        assert_eq!(next_run_version(&dir).unwrap(), 2);
+        fs::write(dir.join(".run_version"), "41\n").unwrap();
+        assert_eq!(next_run_version(&dir).unwrap(), 42);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_station_map_only_writes_station_map() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(serve(socket));
+            }
+        });
+
+        let output_dir =
+            std::env::temp_dir().join(format!("polly-station-only-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&output_dir);
+
+        let dir = output_dir.to_str().unwrap();
+        let cli = TestCli::parse_from(["polly", "--output-dir", dir, "--station-map-only"]);
+        let config = RouteConfig {
+            service_key: "test-key".to_string(),
+            tago_base_url: format!("http://{}/tago", addr),
+            osrm_base_url: format!("http://{}/osrm", addr),
+        };
+        run_with_config(cli.args, config).await.unwrap();
+
+        assert!(output_dir.join("stationMap.json").exists());
+        for file in ["routeMap.json", "routeDetails.json", "routeStops.json"] {
+            assert!(!output_dir.join(file).exists(), "{} was written", file);
+        }
+
+        let emit = TestCli::parse_from(["polly", "--emit", "routeMap,stationMap"]);
+        assert_eq!(emit.args.emit, [MapFile::RouteMap, MapFile::StationMap]);
+        assert!(
+            TestCli::try_parse_from(["polly", "--station-map-only", "--emit", "routeMap"]).is_err()
+        );
+
+        let _ = fs::remove_dir_all(&output_dir);
+    }
+
+    #[test]
+    fn test_service_key_precedence_cli_profile_env() {
+        let dir = std::env::temp_dir().join(format!("polly-precedence-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let profile = dir.join("profile.json");
+        fs::write(
+            &profile,
+            r#"{ "cities": { "32020": { "service_key": "profile-key" } } }"#,
+        )
+        .unwrap();
+        // No other test reads this variable
+        unsafe { std::env::set_var("DATA_GO_KR_SERVICE_KEY", "env-key") };
+
+        let key = |args: &[&str], city: &str| {
+            let cli = TestCli::parse_from(args);
+            RouteConfig::resolve(
+                cli.args.service_key.as_deref(),
+                cli.args.profile.as_deref(),
+                city,
+            )
+            .unwrap()
+            .service_key
+        };
+        let profile = profile.to_str().unwrap();
+
+        // The command line wins over the profile and the environment
+        assert_eq!(
+            key(
+                &["polly", "--service-key", "cli-key", "--profile", profile],
+                "32020"
+            ),
+            "cli-key"
+        );
+        // A city listed in the profile wins over the environment
+        assert_eq!(
+            key(&["polly", "--profile", profile], "32020"),
+            "profile-key"
+        );
+        // Unlisted cities and runs without a profile fall back to the environment
+        assert_eq!(key(&["polly", "--profile", profile], "32010"), "env-key");
+        assert_eq!(key(&["polly"], "32020"), "env-key");
+
+        let cli = TestCli::parse_from(["polly", "--tago-url", "http://localhost:1/tago"]);
+        let config = RouteConfig::resolve(None, None, "32020")
+            .unwrap()
+            .with_urls(cli.args.tago_url.as_deref(), None);
+        assert_eq!(config.tago_base_url, "http://localhost:1/tago");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_fail_fast_stops_at_the_first_failed_route() {
+        let output_dir =
+            std::env::temp_dir().join(format!("polly-fail-fast-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&output_dir);
+        fs::create_dir_all(output_dir.join("cache")).unwrap();
+        fs::write(output_dir.join("cache/1_WJB1.json"), "{ truncated").unwrap();
+
+        let dir = output_dir.to_str().unwrap();
+        let config = || RouteConfig {
+            service_key: String::new(),
+            tago_base_url: "http://127.0.0.1:9".to_string(),
+            osrm_base_url: "http://127.0.0.1:9".to_string(),
+        };
+        let cli = TestCli::parse_from(["polly", "--output-dir", dir, "--osrm-only"]);
+        run_with_config(cli.args, config()).await.unwrap();
+
+        let cli = TestCli::parse_from(["polly", "--output-dir", dir, "--osrm-only", "--fail-fast"]);
+        let err = run_with_config(cli.args, config()).await.unwrap_err();
+        assert_eq!(FailureKind::of(&err).0, FailureKind::Parse);
+
+        let _ = fs::remove_dir_all(&output_dir);
+    }
+
+    #[tokio::test]
+    async fn test_gtfs_input_derives_routes() {
+        let output_dir = std::env::temp_dir().join(format!("polly-gtfs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&output_dir);
+        let feed = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/route/testdata/gtfs");
+
+        let cli = TestCli::parse_from([
+            "polly",
+            "--output-dir",
+            output_dir.to_str().unwrap(),
+            "--input",
+            &format!("gtfs:{}", feed.display()),
+            "--no-snap",
+        ]);
+        // Nothing listens here; a GTFS run must not call TAGO or OSRM
+        let config = RouteConfig {
+            service_key: String::new(),
+            tago_base_url: "http://127.0.0.1:9".to_string(),
+            osrm_base_url: "http://127.0.0.1:9".to_string(),
+        };
+        run_with_config(cli.args, config).await.unwrap();
+
+        let read_json = |name: &str| -> Value {
+            serde_json::from_str(&fs::read_to_string(output_dir.join(name)).unwrap()).unwrap()
+        };
+        let route_map = read_json("routeMap.json");
+        assert_eq!(route_map["route_numbers"]["10"], json!(["R10"]));
+        assert_eq!(route_map["route_numbers"]["R20"], json!(["R20"]));
+        assert_eq!(
+            read_json("stationMap.json")["stations"]["S1"]["nodenm"],
+            "Terminal, Gate 1"
+        );
+
+        // The longest trip of each direction, ordered by stop_sequence
+        let feature = &read_json("polylines/R10.geojson")["features"][0];
+        let stops = feature["properties"]["stops"].as_array().unwrap();
+        let ids: Vec<&str> = stops.iter().map(|s| s["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, ["S1", "S2", "S3", "S3", "S2", "S1"]);
+        assert_eq!(stops[3]["ud"], 1);
+        assert_eq!(
+            feature["geometry"]["coordinates"].as_array().unwrap().len(),
+            6
+        );
+        assert!(output_dir.join("polylines/R20.geojson").exists());
+
+        let _ = fs::remove_dir_all(&output_dir);
+    }
+
+    #[tokio::test]
+    async fn test_route_pipeline_end_to_end() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(serve(socket));
+            }
+        });
+
+        let output_dir =
+            std::env::temp_dir().join(format!("polly-pipeline-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&output_dir);
+
+        let cli = TestCli::parse_from(["polly", "--output-dir", output_dir.to_str().unwrap()]);
+        let config = RouteConfig {
+            service_key: "test-key".to_string(),
+            tago_base_url: format!("http://{}/tago", addr),
+            osrm_base_url: format!("http://{}/osrm", addr),
+        };
+
+        run_with_config(cli.args, config).await.unwrap();
+
+        let read_json = |name: &str| -> Value {
+            serde_json::from_str(&fs::read_to_string(output_dir.join(name)).unwrap()).unwrap()
+        };
+
+        let route_map = read_json("routeMap.json");
+        let route_numbers = route_map["route_numbers"].as_object().unwrap();
+        assert_eq!(route_numbers.len(), 3);
+        assert_eq!(route_numbers["34-1"], json!(["WJB251000003"]));
+        assert_eq!(route_numbers["2"], json!(["WJB251000002", "WJB251000004"]));
+
+        // Both geometry variants of route number 2 are discoverable
+        let variants = route_map["route_variants"]["2"].as_array().unwrap();
+        assert_eq!(variants.len(), 2);
+        assert_eq!(variants[1]["route_id"], "WJB251000004");
+        assert_eq!(variants[1]["geojson"], "polylines/WJB251000004.geojson");
+        assert_eq!(variants[1]["stop_count"], 2);
+
+        let station_map = read_json("stationMap.json");
+        assert_eq!(station_map["stations"].as_object().unwrap().len(), 14);
+
+        // Stops of both variants of route number 2, each once
+        let route_stops = read_json("routeStops.json");
+        let stops_2 = route_stops["route_stops"]["2"].as_array().unwrap();
+        assert_eq!(stops_2.len(), 5);
+        assert_eq!(stops_2[0]["nodenm"], "Stop 0");
+
+        // Every derived route is listed with its bbox for the frontend picker
+        let route_index = read_json("routeIndex.json");
+        let routes = route_index["routes"].as_object().unwrap();
+        assert_eq!(routes.len(), 4);
+        assert_eq!(routes["WJB251000004"]["route_no"], "2");
+        assert_eq!(routes["WJB251000004"]["stop_count"], 2);
+        assert_eq!(routes["WJB251000004"]["bbox"].as_array().unwrap().len(), 4);
+        assert_eq!(
+            routes["WJB251000004"]["geojson"],
+            "polylines/WJB251000004.geojson"
+        );
+
+        for (id, _, stops) in fixture_routes() {
+            let geojson = read_json(&format!("polylines/{}.geojson", id));
+            let feature = &geojson["features"][0];
+            assert_eq!(geojson["type"], "FeatureCollection");
+            assert_eq!(feature["geometry"]["type"], "LineString");
+            assert_eq!(feature["properties"]["route_id"], id);
+
+            let stop_to_coord = feature["properties"]["stop_to_coord"].as_array().unwrap();
+            let coords = feature["geometry"]["coordinates"].as_array().unwrap();
+            assert_eq!(stop_to_coord.len(), stops.len());
+            assert!(
+                stop_to_coord
+                    .iter()
+                    .all(|i| (i.as_u64().unwrap() as usize) < coords.len())
+            );
+        }
+
+        // The mapping files can be restored from the raw cache alone
+        fs::remove_file(output_dir.join("routeMap.json")).unwrap();
+        rebuild_map(RebuildMapArgs {
+            output_dir: output_dir.clone(),
+            layout: LayoutArgs::default(),
+            city_code: None,
+            name_by: NameBy::RouteId,
+        })
+        .await
+        .unwrap();
+        let rebuilt = read_json("routeMap.json");
+        assert_eq!(rebuilt["route_numbers"], route_map["route_numbers"]);
+        assert_eq!(rebuilt["route_variants"], route_map["route_variants"]);
+
+        let _ = fs::remove_dir_all(&output_dir);
+    }
 }
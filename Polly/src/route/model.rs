@@ -4,10 +4,23 @@
 //! raw and derived bus route information, including GeoJSON
 //! formats for frontend consumption.
 
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use anyhow::Context;
 use serde::{Deserialize, Serialize, Serializer};
-use serde_json::Value;
+use serde_json::{Map, Value};
+
+use crate::config::{OSRM_TIMEOUT_SECS, OSRM_URL, TAGO_TIMEOUT_SECS, TAGO_URL};
+use crate::utils::boundary::Boundary;
+use crate::utils::geo;
+use crate::utils::layout::Layout;
+use crate::utils::s3::S3Target;
+use crate::utils::safe_file_name;
+use crate::utils::service_key::ServiceKey;
 
 // ============================================================================
 // Raw Data Models (Saved to cache)
@@ -25,12 +38,58 @@ pub struct RawStop {
     pub up_down_cd: i64,
 }
 
+/// TAGO item keys used to fill each `RawStop` field
+///
+/// Defaults to the keys of the Wonju feed; other city feeds can remap them
+/// with a JSON file passed to `--field-map`. Missing entries keep their default.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TagoFieldMap {
+    pub node_id: String,
+    pub node_nm: String,
+    pub node_ord: String,
+    pub node_no: String,
+    pub gps_lat: String,
+    pub gps_long: String,
+    pub up_down_cd: String,
+}
+
+impl Default for TagoFieldMap {
+    fn default() -> Self {
+        Self {
+            node_id: "nodeid".to_string(),
+            node_nm: "nodenm".to_string(),
+            node_ord: "nodeord".to_string(),
+            node_no: "nodeno".to_string(),
+            gps_lat: "gpslati".to_string(),
+            gps_long: "gpslong".to_string(),
+            up_down_cd: "updowncd".to_string(),
+        }
+    }
+}
+
+impl TagoFieldMap {
+    /// Keys that must be present for a stop to be usable
+    pub fn required_keys(&self) -> [&str; 5] {
+        [
+            &self.node_id,
+            &self.node_nm,
+            &self.node_ord,
+            &self.gps_lat,
+            &self.gps_long,
+        ]
+    }
+}
+
 /// Raw file save format
 #[derive(Serialize, Deserialize)]
 pub struct RawRouteFile {
     pub route_id: String,
     pub route_no: String,
     pub fetched_at: String,
+    /// TAGO route type (`routetp`, e.g. "간선버스"), when the route list gives one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route_tp: Option<String>,
     pub stops: Vec<RawStop>,
 }
 
@@ -39,66 +98,559 @@ pub struct RawRouteFile {
 // ============================================================================
 
 /// GeoJSON FeatureCollection
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct RouteFeatureCollection {
     #[serde(rename = "type")]
     pub type_: String, // "FeatureCollection"
+
+    /// Named CRS member; omitted for the default WGS84 lon/lat per the GeoJSON spec
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crs: Option<Value>,
+
     pub features: Vec<RouteFeature>,
 }
 
-#[derive(Serialize)]
+impl RouteFeatureCollection {
+    /// Whether both collections hold the same routes, feature by feature (see `RouteFeature::equivalent_to`)
+    pub fn equivalent_to(&self, other: &RouteFeatureCollection, tolerance_m: f64) -> bool {
+        self.crs == other.crs
+            && self.features.len() == other.features.len()
+            && self
+                .features
+                .iter()
+                .zip(&other.features)
+                .all(|(a, b)| a.equivalent_to(b, tolerance_m))
+    }
+
+    /// Reads a derived file for its geometry.
+    ///
+    /// A feature written as a reference by `--dedup-geometry` gets the coordinates of the file its
+    /// `geometry_ref` names, relative to `root` (the directory holding the mapping files).
+    pub fn read_resolved(path: &Path, root: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut collection: Self = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid route file {}", path.display()))?;
+
+        for feature in &mut collection.features {
+            let Some(geometry_ref) = feature.extra.get("geometry_ref").and_then(Value::as_str)
+            else {
+                continue;
+            };
+            let owner_path = root.join(geometry_ref);
+            let owner: Self = serde_json::from_str(
+                &std::fs::read_to_string(&owner_path)
+                    .with_context(|| format!("Failed to read {}", owner_path.display()))?,
+            )
+            .with_context(|| format!("Invalid route file {}", owner_path.display()))?;
+            feature.geometry.coordinates = owner
+                .features
+                .into_iter()
+                .next()
+                .map(|owner| owner.geometry.coordinates)
+                .with_context(|| format!("{} has no features", owner_path.display()))?;
+        }
+
+        Ok(collection)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct RouteFeature {
     #[serde(rename = "type")]
     pub type_: String, // "Feature"
     pub id: String, // Root ID (e.g., Route ID)
 
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub bbox: Option<Vec<f64>>,
 
     pub properties: RouteProperties,
     pub geometry: RouteGeometry,
+
+    /// Foreign members added by other tools, kept so reprocessing does not drop them
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Serialize)]
+impl RouteFeature {
+    /// Swaps every coordinate pair (geometry, bbox, label point) to the non-standard `[lat, lon]` order.
+    pub fn swap_to_latlon(&mut self) {
+        self.swap_all_axes();
+        self.properties.meta.coord_order = Some("latlon".to_string());
+    }
+
+    /// Restores GeoJSON `[lon, lat]` order in a feature written with `--latlon-order`.
+    pub fn swap_to_lonlat(&mut self) {
+        if self.is_latlon() {
+            self.swap_all_axes();
+            self.properties.meta.coord_order = None;
+        }
+    }
+
+    fn is_latlon(&self) -> bool {
+        self.properties.meta.coord_order.as_deref() == Some("latlon")
+    }
+
+    fn swap_all_axes(&mut self) {
+        swap_axes(&mut self.geometry.coordinates);
+        if let Some(bbox) = &mut self.bbox
+            && bbox.len() == 4
+        {
+            bbox.swap(0, 1);
+            bbox.swap(2, 3);
+        }
+        self.properties.label_point.swap(0, 1);
+        if let Some(turn_point) = &mut self.properties.meta.turn_point {
+            turn_point.swap(0, 1);
+        }
+    }
+
+    /// Whether `other` describes the same route, allowing each vertex to move up to `tolerance_m`.
+    ///
+    /// The bbox and the [`VOLATILE_PROPERTIES`] are not compared; every other property must match.
+    pub fn equivalent_to(&self, other: &RouteFeature, tolerance_m: f64) -> bool {
+        self.id == other.id
+            && self.extra == other.extra
+            && self.properties.meta.coord_order == other.properties.meta.coord_order
+            && geo::lines_within(&self.lon_lat(), &other.lon_lat(), tolerance_m)
+            && self.comparable_properties() == other.comparable_properties()
+    }
+
+    /// The geometry in `[lon, lat]` order, whatever order it is written in
+    pub fn lon_lat(&self) -> Vec<Vec<f64>> {
+        let mut coords = self.geometry.coordinates.clone();
+        if self.is_latlon() {
+            swap_axes(&mut coords);
+        }
+        coords
+    }
+
+    /// The properties with every [`VOLATILE_PROPERTIES`] value blanked out, keeping which keys are present
+    fn comparable_properties(&self) -> Value {
+        let mut props = serde_json::to_value(&self.properties).unwrap_or_default();
+        for key in VOLATILE_PROPERTIES {
+            if let Some(value) = props.get_mut(key) {
+                *value = Value::Null;
+            }
+        }
+        for stop in props["stops"].as_array_mut().into_iter().flatten() {
+            for key in VOLATILE_STOP_PROPERTIES {
+                if let Some(value) = stop.get_mut(key) {
+                    *value = Value::Null;
+                }
+            }
+        }
+        props
+    }
+
+    /// Switches `stop_to_coord` to the delta-encoded `stop_to_coord_delta` key on output.
+    pub fn encode_stop_to_coord_delta(&mut self) {
+        self.properties.indices.delta_encoded = true;
+        self.properties.meta.stop_to_coord_encoding = Some("delta".to_string());
+    }
+}
+
+/// Swaps each coordinate pair between `[lon, lat]` and `[lat, lon]` order.
+pub fn swap_axes(coords: &mut [Vec<f64>]) {
+    coords
+        .iter_mut()
+        .filter(|pt| pt.len() >= 2)
+        .for_each(|pt| pt.swap(0, 1));
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct RouteGeometry {
     #[serde(rename = "type")]
     pub type_: String, // "LineString"
     pub coordinates: Vec<Vec<f64>>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct RouteProperties {
     pub route_id: String,
     pub route_no: String,
     pub stops: Vec<FrontendStop>,
+    /// Label anchor `[lon, lat]` at the arc-length midpoint of the geometry
+    #[serde(default)]
+    pub label_point: [f64; 2],
+    /// OSRM segment annotations (`--osrm-annotations`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<RouteAnnotations>,
+    /// One entry per stretch between consecutive stops: `"express"` when it is longer than
+    /// `--express-threshold-m`, else `null`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub segment_flags: Option<Vec<Option<String>>>,
     #[serde(flatten)]
     pub indices: RouteIndices,
     #[serde(flatten)]
     pub meta: FrontendMeta,
+
+    /// Unknown properties, preserved through reprocessing (must stay the last flattened field)
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Serialize)]
+/// OSRM annotations aligned to the geometry: one value per segment between consecutive coordinates
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RouteAnnotations {
+    /// Meters per second
+    pub speed: Vec<f64>,
+    /// Seconds
+    pub duration: Vec<f64>,
+    /// Meters
+    pub distance: Vec<f64>,
+    /// OSM node id of each coordinate, when the server reported one per coordinate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<Vec<u64>>,
+}
+
+impl RouteAnnotations {
+    /// No segments yet, collecting node ids
+    pub fn new() -> Self {
+        Self {
+            nodes: Some(Vec::new()),
+            ..Default::default()
+        }
+    }
+
+    /// Appends the annotations of a chunk whose first coordinate repeats the last one so far
+    pub fn append_chunk(&mut self, chunk: RouteAnnotations, first_chunk: bool) {
+        self.speed.extend(chunk.speed);
+        self.duration.extend(chunk.duration);
+        self.distance.extend(chunk.distance);
+        self.nodes = match (self.nodes.take(), chunk.nodes) {
+            (Some(mut nodes), Some(chunk_nodes)) => {
+                let skip = if first_chunk { 0 } else { 1 };
+                nodes.extend(chunk_nodes.into_iter().skip(skip));
+                Some(nodes)
+            }
+            _ => None,
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct FrontendStop {
     pub id: String,
     pub name: String,
     pub ord: i64,
     #[serde(rename = "ud")]
     pub up_down: i64,
+    /// Estimated seconds from the first stop at a constant speed (`--speed-kmh`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eta_offset_s: Option<f64>,
+    /// Index of the same `id` in the other direction, on out-and-back routes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paired_stop_idx: Option<usize>,
+    /// Side of the road relative to travel direction: `1` left, `-1` right, `0` on the line (snapped routes only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub side: Option<i8>,
 }
 
-#[derive(Serialize)]
+impl From<RawStop> for FrontendStop {
+    fn from(s: RawStop) -> Self {
+        Self {
+            id: s.node_id,
+            name: s.node_nm,
+            ord: s.node_ord,
+            up_down: s.up_down_cd,
+            eta_offset_s: None,
+            paired_stop_idx: None,
+            side: None,
+        }
+    }
+}
+
+/// Geometry indices of a route; `stop_to_coord` is always absolute in memory
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(into = "RouteIndicesRepr", try_from = "RouteIndicesRepr")]
 pub struct RouteIndices {
     pub turn_idx: usize,
     pub stop_to_coord: Vec<usize>,
+
+    /// Cumulative distance along the geometry in meters at each coordinate (GTFS `shape_dist_traveled`)
+    pub cumulative_dist: Option<Vec<f64>>,
+
+    /// Write `stop_to_coord` delta-encoded as `stop_to_coord_delta` (`--delta-stop-to-coord`)
+    pub delta_encoded: bool,
 }
 
-#[derive(Serialize)]
+/// On-disk form of `RouteIndices`, with `stop_to_coord` under one of two keys
+#[derive(Serialize, Deserialize)]
+struct RouteIndicesRepr {
+    turn_idx: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stop_to_coord: Option<Vec<usize>>,
+    /// First index followed by the difference to the previous one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stop_to_coord_delta: Option<Vec<i64>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cumulative_dist: Option<Vec<f64>>,
+}
+
+impl From<RouteIndices> for RouteIndicesRepr {
+    fn from(i: RouteIndices) -> Self {
+        let (stop_to_coord, stop_to_coord_delta) = if i.delta_encoded {
+            (None, Some(delta_encode(&i.stop_to_coord)))
+        } else {
+            (Some(i.stop_to_coord), None)
+        };
+        Self {
+            turn_idx: i.turn_idx,
+            stop_to_coord,
+            stop_to_coord_delta,
+            cumulative_dist: i.cumulative_dist,
+        }
+    }
+}
+
+impl TryFrom<RouteIndicesRepr> for RouteIndices {
+    type Error = anyhow::Error;
+
+    fn try_from(r: RouteIndicesRepr) -> anyhow::Result<Self> {
+        let delta_encoded = r.stop_to_coord_delta.is_some();
+        let stop_to_coord = match r.stop_to_coord_delta {
+            Some(deltas) => delta_decode(&deltas)?,
+            None => r.stop_to_coord.unwrap_or_default(),
+        };
+        Ok(Self {
+            turn_idx: r.turn_idx,
+            stop_to_coord,
+            cumulative_dist: r.cumulative_dist,
+            delta_encoded,
+        })
+    }
+}
+
+/// Encodes ascending indices as the first value followed by differences
+pub fn delta_encode(values: &[usize]) -> Vec<i64> {
+    let mut previous = 0;
+    values
+        .iter()
+        .map(|&v| {
+            let delta = v as i64 - previous;
+            previous = v as i64;
+            delta
+        })
+        .collect()
+}
+
+/// Inverse of `delta_encode`; fails on a running sum that is negative or overflows, which
+/// only a corrupt file can contain
+pub fn delta_decode(deltas: &[i64]) -> anyhow::Result<Vec<usize>> {
+    let mut current = 0i64;
+    deltas
+        .iter()
+        .enumerate()
+        .map(|(i, &d)| {
+            current = current
+                .checked_add(d)
+                .with_context(|| format!("stop_to_coord_delta overflows at stop {}", i))?;
+            usize::try_from(current).with_context(|| {
+                format!(
+                    "stop_to_coord_delta decodes to index {} at stop {}",
+                    current, i
+                )
+            })
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct FrontendMeta {
     #[serde(serialize_with = "round_f64_1")]
     pub total_dist: f64,
+    /// Distance from the start to the turning point (`turn_idx`); equals `total_dist` on loop routes
+    #[serde(default, serialize_with = "round_f64_1")]
+    pub oneway_dist: f64,
     #[serde(serialize_with = "round_f64_1")]
     pub total_time: f64,
     pub source_ver: String,
+    /// Fetch time of the raw data as UTC RFC3339; `source_ver` keeps the raw string
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fetched_at: Option<String>,
+    /// Run counter of the output directory when the route was last derived (`--run-version`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    #[serde(default)]
+    pub units: RouteUnits,
+    /// Set to `"no_geometry"` on stub routes written by `--include-empty-routes`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// Set to `"latlon"` when written with `--latlon-order`; absent means GeoJSON `[lon, lat]`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coord_order: Option<String>,
+    /// Set to `"delta"` when `stop_to_coord` is written as `stop_to_coord_delta`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_to_coord_encoding: Option<String>,
+    /// The geometry's coordinate at `turn_idx`, where the bus turns around (`--turn-point`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub turn_point: Option<[f64; 2]>,
+    /// `data_version` of the OSRM data the route was snapped on, if the server reports one (`--osrm-version-in-meta`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub osrm_data_version: Option<String>,
+}
+
+/// Properties that differ between two derivations of an unchanged route, because they are
+/// computed from the exact geometry or record the run that wrote the file.
+///
+/// `RouteFeature::equivalent_to` ignores these and compares every other property exactly, so a
+/// new property is compared unless it is listed here.
+const VOLATILE_PROPERTIES: [&str; 12] = [
+    // Run metadata
+    "source_ver",
+    "fetched_at",
+    "version",
+    "osrm_data_version",
+    // Computed from the geometry
+    "total_dist",
+    "oneway_dist",
+    "total_time",
+    "label_point",
+    "turn_point",
+    "cumulative_dist",
+    "annotations",
+    "segment_flags",
+];
+
+/// Stop properties computed from the exact geometry, ignored like [`VOLATILE_PROPERTIES`]
+const VOLATILE_STOP_PROPERTIES: [&str; 2] = ["eta_offset_s", "side"];
+
+/// A raw `fetched_at` timestamp as UTC RFC3339 with second precision, if it parses
+pub fn utc_timestamp(raw: &str) -> Option<String> {
+    let time = chrono::DateTime::parse_from_rfc3339(raw).ok()?;
+    Some(
+        time.with_timezone(&chrono::Utc)
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+    )
+}
+
+/// Units of the numeric metadata fields
+#[derive(Serialize, Deserialize)]
+pub struct RouteUnits {
+    pub distance: String,
+    pub time: String,
+}
+
+impl Default for RouteUnits {
+    fn default() -> Self {
+        Self {
+            distance: "m".to_string(),
+            time: "s".to_string(),
+        }
+    }
+}
+
+/// One route's entry in `routeIndex.json`, enough to list it and fit the map without its geometry
+#[derive(Serialize)]
+pub struct RouteIndexEntry {
+    pub route_id: String,
+    pub route_no: String,
+    pub stop_count: usize,
+    #[serde(serialize_with = "round_f64_1")]
+    pub total_dist: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bbox: Option<Vec<f64>>,
+    /// Path of the derived file relative to the output directory
+    pub geojson: String,
+    /// With `--dedup-geometry`, the file holding this route's coordinates when another variant's are identical
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub geometry_ref: Option<String>,
+}
+
+impl RouteIndexEntry {
+    /// The entry of a derived feature written to `geojson` (see [`Layout::derived_path`])
+    pub fn new(f: &RouteFeature, geojson: String) -> Self {
+        Self {
+            route_id: f.properties.route_id.clone(),
+            route_no: f.properties.route_no.clone(),
+            stop_count: f.properties.stops.len(),
+            total_dist: f.properties.meta.total_dist,
+            bbox: f.bbox.clone(),
+            geojson,
+            geometry_ref: None,
+        }
+    }
+}
+
+/// How derived files are named
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum NameBy {
+    /// `<route_id>.geojson`
+    #[default]
+    RouteId,
+    /// `<route_no>.geojson`, with `_1`, `_2`, ... when a route number has several variants
+    RouteNo,
+}
+
+/// Mapping files written by Phase 1 (`--emit`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MapFile {
+    #[value(name = "routeMap")]
+    RouteMap,
+    #[value(name = "routeDetails")]
+    RouteDetails,
+    #[value(name = "routeStops")]
+    RouteStops,
+    #[value(name = "stationMap")]
+    StationMap,
+}
+
+impl MapFile {
+    pub const ALL: [MapFile; 4] = [
+        MapFile::RouteMap,
+        MapFile::RouteDetails,
+        MapFile::RouteStops,
+        MapFile::StationMap,
+    ];
+
+    pub fn file_name(self) -> &'static str {
+        match self {
+            MapFile::RouteMap => "routeMap.json",
+            MapFile::RouteDetails => "routeDetails.json",
+            MapFile::RouteStops => "routeStops.json",
+            MapFile::StationMap => "stationMap.json",
+        }
+    }
+}
+
+/// Derived file stem of each route id; routes without an entry are named by route id
+#[derive(Debug, Default)]
+pub struct FileNames(HashMap<String, String>);
+
+impl FileNames {
+    /// Names every route in `route_numbers` (route number -> sorted route ids) by the chosen scheme.
+    ///
+    /// A route whose name is already taken by another route number keeps its route id.
+    pub fn new(name_by: NameBy, route_numbers: &BTreeMap<String, Vec<String>>) -> Self {
+        let mut names = HashMap::new();
+        if name_by == NameBy::RouteId {
+            return Self(names);
+        }
+
+        let mut taken = HashSet::new();
+        for (route_no, ids) in route_numbers {
+            let base = safe_file_name(route_no);
+            for (i, id) in ids.iter().enumerate() {
+                let stem = if ids.len() == 1 {
+                    base.clone()
+                } else {
+                    format!("{}_{}", base, i + 1)
+                };
+                if !taken.insert(stem.clone()) {
+                    log::warn!("File name {:?} is taken; route {} keeps its id.", stem, id);
+                    continue;
+                }
+                names.insert(id.clone(), stem);
+            }
+        }
+        Self(names)
+    }
+
+    /// File stem of `route_id`
+    pub fn stem<'a>(&'a self, route_id: &'a str) -> &'a str {
+        self.0.get(route_id).map_or(route_id, String::as_str)
+    }
 }
 
 // --------------------------------------------------------
@@ -123,17 +675,460 @@ pub struct RouteProcessData {
     pub route_id: String,
     pub route_no: String,
     pub details: Value,
+    /// `route_variants` entry in routeMap.json
+    pub variant: Value,
     pub stops_map: Vec<(String, Value)>,
 }
 
+/// Contents of the mapping files, collected route by route
+#[derive(Default)]
+pub struct RouteMapData {
+    /// Route ids by route number
+    pub route_numbers: BTreeMap<String, Vec<String>>,
+    pub variants: BTreeMap<String, Vec<Value>>,
+    pub details: HashMap<String, Value>,
+    pub stops: BTreeMap<String, Value>,
+}
+
+impl RouteMapData {
+    pub fn add(&mut self, data: RouteProcessData) {
+        self.details.insert(data.route_id.clone(), data.details);
+        self.variants
+            .entry(data.route_no.clone())
+            .or_default()
+            .push(data.variant);
+        self.route_numbers
+            .entry(data.route_no)
+            .or_default()
+            .push(data.route_id);
+        self.stops.extend(data.stops_map);
+    }
+
+    /// Sorts routes by id, so the files stay stable between runs, and sets each variant's file path.
+    pub fn finish(&mut self, name_by: NameBy, layout: &Layout) {
+        for ids in self.route_numbers.values_mut() {
+            ids.sort();
+        }
+        let file_names = FileNames::new(name_by, &self.route_numbers);
+        for variants in self.variants.values_mut() {
+            variants.sort_by(|a, b| a["route_id"].as_str().cmp(&b["route_id"].as_str()));
+            for variant in variants.iter_mut() {
+                let stem = file_names.stem(variant["route_id"].as_str().unwrap_or_default());
+                variant["geojson"] = layout.derived_path(stem).into();
+            }
+        }
+    }
+}
+
+/// Which leg of a route to derive, by TAGO `updowncd`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Direction {
+    /// Up direction only (`updowncd` 0)
+    Up,
+    /// Down direction only (`updowncd` 1)
+    Down,
+    /// Both directions
+    #[default]
+    Both,
+}
+
+impl Direction {
+    /// The `updowncd` value to keep, or `None` to keep all stops
+    pub fn up_down_cd(self) -> Option<i64> {
+        match self {
+            Direction::Up => Some(0),
+            Direction::Down => Some(1),
+            Direction::Both => None,
+        }
+    }
+}
+
+/// Optional output toggles for the derive step
+#[derive(Debug, Clone, Default)]
+pub struct RouteOptions {
+    pub cumulative_dist: bool,
+    pub check_geometry: bool,
+    pub direction: Direction,
+    pub prefetch_chunks: bool,
+    pub include_empty_routes: bool,
+    pub speed_kmh: Option<f64>,
+    pub no_round: bool,
+    pub clip: Option<Arc<Boundary>>,
+    pub latlon_order: bool,
+    pub delta_stop_to_coord: bool,
+    pub turn_point: bool,
+    pub osrm_annotations: bool,
+    pub no_snap: bool,
+    pub both_directions: bool,
+    pub version: Option<u64>,
+    pub max_dist_km: Option<f64>,
+    pub skip_over_max_dist: bool,
+    pub max_gap_m: Option<f64>,
+    /// Largest vertex shift still treated as an unchanged route on re-derivation
+    pub equal_tolerance_m: f64,
+    pub express_threshold_m: Option<f64>,
+    pub osrm_version_in_meta: bool,
+}
+
+/// Data-quality warnings recorded during a run, as `(route_id, message)`
+#[derive(Default)]
+pub struct QualityLog(Mutex<Vec<(String, String)>>);
+
+impl QualityLog {
+    /// Logs a warning and records it against a route for `--strict`
+    pub fn warn(&self, route_id: &str, message: String) {
+        log::warn!("{}", message);
+        self.0.lock().unwrap().push((route_id.to_string(), message));
+    }
+
+    /// Removes and returns all recorded warnings
+    pub fn take(&self) -> Vec<(String, String)> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+/// Which OSRM server and map data answered a request, as far as the response tells
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct OsrmInstance {
+    /// `data_version` of the response, present when the server's data was extracted with one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_version: Option<String>,
+    /// `Server` response header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+    /// `Via` response header, naming the proxies or load balancer in between
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub via: Option<String>,
+}
+
+/// OSRM instances that answered during a run, with their response counts
+#[derive(Default)]
+pub struct OsrmInstances(Mutex<BTreeMap<OsrmInstance, usize>>);
+
+impl OsrmInstances {
+    pub fn record(&self, instance: &OsrmInstance) {
+        *self.0.lock().unwrap().entry(instance.clone()).or_default() += 1;
+    }
+
+    /// Removes and returns the recorded instances, most used first
+    pub fn take(&self) -> Vec<(OsrmInstance, usize)> {
+        let mut instances: Vec<_> = std::mem::take(&mut *self.0.lock().unwrap())
+            .into_iter()
+            .collect();
+        instances.sort_by_key(|(_, responses)| std::cmp::Reverse(*responses));
+        instances
+    }
+}
+
+/// Geometries written during a run (`--dedup-geometry`), keyed by a hash of their coordinates
+#[derive(Default)]
+pub struct GeometryOwners(Mutex<HashMap<u64, OwnedGeometry>>);
+
+struct OwnedGeometry {
+    stem: String,
+    coordinates: Vec<Vec<f64>>,
+}
+
+impl GeometryOwners {
+    /// Returns the stem of the file already holding exactly these coordinates,
+    /// or records `stem` as their owner and returns `None`
+    pub fn claim(&self, stem: &str, coordinates: &[Vec<f64>]) -> Option<String> {
+        let mut hasher = DefaultHasher::new();
+        for pt in coordinates {
+            pt.iter().for_each(|c| c.to_bits().hash(&mut hasher));
+            // Separates [a, b], [c] from [a], [b, c]
+            pt.len().hash(&mut hasher);
+        }
+
+        let mut owners = self.0.lock().unwrap();
+        match owners.get(&hasher.finish()) {
+            Some(owned) if owned.coordinates == coordinates => Some(owned.stem.clone()),
+            Some(_) => None,
+            None => {
+                let owned = OwnedGeometry {
+                    stem: stem.to_string(),
+                    coordinates: coordinates.to_vec(),
+                };
+                owners.insert(hasher.finish(), owned);
+                None
+            }
+        }
+    }
+}
+
 /// Main processor structure
 pub struct BusRouteProcessor {
     pub client: reqwest::Client,
-    pub service_key: String,
+    pub service_key: ServiceKey,
     pub city_code: String,
     pub raw_dir: PathBuf,
     pub derived_dir: PathBuf,
     pub mapping_file: PathBuf,
+    /// Subdirectory names, for the paths recorded in the mapping files
+    pub layout: Layout,
     pub tago_base_url: String,
     pub osrm_base_url: String,
+    pub osrm_timeout: Duration,
+    pub tago_timeout: Duration,
+    pub options: RouteOptions,
+    pub field_map: TagoFieldMap,
+    pub s3: Option<Arc<S3Target>>,
+    pub quality: QualityLog,
+    /// OSRM instances that answered this run, for `runManifest.json`
+    pub osrm_instances: OsrmInstances,
+}
+
+impl BusRouteProcessor {
+    /// A processor with the public TAGO and OSRM URLs, the default timeouts and options, no
+    /// service key and no directories. Commands fill in what they use with struct update syntax.
+    pub fn new(client: reqwest::Client) -> Self {
+        Self {
+            client,
+            service_key: "".into(),
+            city_code: String::new(),
+            raw_dir: PathBuf::new(),
+            derived_dir: PathBuf::new(),
+            mapping_file: PathBuf::new(),
+            layout: Layout::default(),
+            tago_base_url: TAGO_URL.to_string(),
+            osrm_base_url: OSRM_URL.to_string(),
+            osrm_timeout: Duration::from_secs(OSRM_TIMEOUT_SECS),
+            tago_timeout: Duration::from_secs(TAGO_TIMEOUT_SECS),
+            options: RouteOptions::default(),
+            field_map: TagoFieldMap::default(),
+            s3: None,
+            quality: QualityLog::default(),
+            osrm_instances: OsrmInstances::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_foreign_members_survive_round_trip() {
+        let input = json!({
+            "type": "Feature",
+            "id": "R1",
+            "properties": {
+                "route_id": "R1", "route_no": "1", "stops": [],
+                "label_point": [127.9, 37.3],
+                "turn_idx": 0, "stop_to_coord": [],
+                "total_dist": 1000.0, "total_time": 60.0, "source_ver": "",
+                "units": { "distance": "m", "time": "s" },
+                "color": "#ff0000"
+            },
+            "geometry": { "type": "LineString", "coordinates": [[127.9, 37.3], [127.9, 37.31]] },
+            "title": "Downtown loop"
+        });
+
+        let feature: RouteFeature = serde_json::from_value(input).unwrap();
+        assert_eq!(feature.properties.extra["color"], "#ff0000");
+        assert!(!feature.properties.extra.contains_key("turn_idx"));
+
+        let output = serde_json::to_value(&feature).unwrap();
+        assert_eq!(output["properties"]["color"], "#ff0000");
+        assert_eq!(output["title"], "Downtown loop");
+    }
+
+    #[test]
+    fn test_utc_timestamp_normalizes_offset() {
+        assert_eq!(
+            utc_timestamp("2025-03-01T09:30:15.123456+09:00").as_deref(),
+            Some("2025-03-01T00:30:15Z")
+        );
+        assert_eq!(utc_timestamp(""), None);
+    }
+
+    #[test]
+    fn test_swap_to_latlon_marks_output() {
+        let mut feature: RouteFeature = serde_json::from_value(json!({
+            "type": "Feature",
+            "id": "R1",
+            "bbox": [127.9, 37.3, 127.95, 37.31],
+            "properties": {
+                "route_id": "R1", "route_no": "1", "stops": [],
+                "label_point": [127.92, 37.305],
+                "turn_idx": 0, "stop_to_coord": [],
+                "total_dist": 1000.0, "total_time": 60.0, "source_ver": ""
+            },
+            "geometry": { "type": "LineString", "coordinates": [[127.9, 37.3], [127.95, 37.31]] }
+        }))
+        .unwrap();
+
+        feature.swap_to_latlon();
+
+        let output = serde_json::to_value(&feature).unwrap();
+        assert_eq!(
+            output["geometry"]["coordinates"],
+            json!([[37.3, 127.9], [37.31, 127.95]])
+        );
+        assert_eq!(output["bbox"], json!([37.3, 127.9, 37.31, 127.95]));
+        assert_eq!(output["properties"]["label_point"], json!([37.305, 127.92]));
+        assert_eq!(output["properties"]["coord_order"], "latlon");
+    }
+
+    #[test]
+    fn test_swap_to_lonlat_restores_geojson_order() {
+        let mut feature: RouteFeature = serde_json::from_value(json!({
+            "type": "Feature",
+            "id": "R1",
+            "bbox": [37.3, 127.9, 37.31, 127.95],
+            "properties": {
+                "route_id": "R1", "route_no": "1", "stops": [],
+                "label_point": [37.305, 127.92],
+                "turn_idx": 0, "stop_to_coord": [],
+                "total_dist": 1000.0, "total_time": 60.0, "source_ver": "",
+                "coord_order": "latlon"
+            },
+            "geometry": { "type": "LineString", "coordinates": [[37.3, 127.9], [37.31, 127.95]] }
+        }))
+        .unwrap();
+
+        feature.swap_to_lonlat();
+        feature.swap_to_lonlat();
+
+        let output = serde_json::to_value(&feature).unwrap();
+        assert_eq!(
+            output["geometry"]["coordinates"],
+            json!([[127.9, 37.3], [127.95, 37.31]])
+        );
+        assert_eq!(output["bbox"], json!([127.9, 37.3, 127.95, 37.31]));
+        assert_eq!(output["properties"]["label_point"], json!([127.92, 37.305]));
+        assert!(output["properties"].get("coord_order").is_none());
+    }
+
+    #[test]
+    fn test_read_resolved_follows_geometry_ref() {
+        let root = std::env::temp_dir().join(format!("polly-resolved-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("polylines")).unwrap();
+        let file = |route_id: &str, coordinates: Value, geometry_ref: Option<&str>| {
+            let mut feature = json!({
+                "type": "Feature",
+                "id": route_id,
+                "properties": {
+                    "route_id": route_id, "route_no": "1", "stops": [],
+                    "turn_idx": 0, "stop_to_coord": [],
+                    "total_dist": 1000.0, "total_time": 60.0, "source_ver": ""
+                },
+                "geometry": { "type": "LineString", "coordinates": coordinates }
+            });
+            if let Some(geometry_ref) = geometry_ref {
+                feature["geometry_ref"] = json!(geometry_ref);
+            }
+            json!({ "type": "FeatureCollection", "features": [feature] }).to_string()
+        };
+        let line = json!([[127.9, 37.3], [127.95, 37.31]]);
+        std::fs::write(
+            root.join("polylines/R1.geojson"),
+            file("R1", line.clone(), None),
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("polylines/R2.geojson"),
+            file("R2", json!([]), Some("polylines/R1.geojson")),
+        )
+        .unwrap();
+
+        let collection =
+            RouteFeatureCollection::read_resolved(&root.join("polylines/R2.geojson"), &root)
+                .unwrap();
+        let feature = &collection.features[0];
+        assert_eq!(feature.properties.route_id, "R2");
+        assert_eq!(json!(feature.geometry.coordinates), line);
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_delta_stop_to_coord_decodes_to_absolute() {
+        let stop_to_coord = vec![0, 4, 9, 9, 15, 3, 8, 20];
+        let mut feature: RouteFeature = serde_json::from_value(json!({
+            "type": "Feature",
+            "id": "R1",
+            "properties": {
+                "route_id": "R1", "route_no": "1", "stops": [],
+                "turn_idx": 15, "stop_to_coord": stop_to_coord,
+                "total_dist": 1000.0, "total_time": 60.0, "source_ver": ""
+            },
+            "geometry": { "type": "LineString", "coordinates": [] }
+        }))
+        .unwrap();
+
+        feature.encode_stop_to_coord_delta();
+
+        let output = serde_json::to_value(&feature).unwrap();
+        assert!(output["properties"].get("stop_to_coord").is_none());
+        assert_eq!(output["properties"]["stop_to_coord_encoding"], "delta");
+        assert_eq!(
+            output["properties"]["stop_to_coord_delta"],
+            json!([0, 4, 5, 0, 6, -12, 5, 12])
+        );
+
+        let decoded: RouteFeature = serde_json::from_value(output.clone()).unwrap();
+        assert_eq!(decoded.properties.indices.stop_to_coord, stop_to_coord);
+
+        // A running sum below zero is a corrupt file, not index 0
+        let mut corrupt = output;
+        corrupt["properties"]["stop_to_coord_delta"] = json!([0, 4, -9, 2]);
+        let err = serde_json::from_value::<RouteFeature>(corrupt)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("index -5 at stop 2"), "{}", err);
+    }
+
+    #[test]
+    fn test_equivalent_to_ignores_only_volatile_properties() {
+        let feature = |changes: Value| -> RouteFeature {
+            let mut value = json!({
+                "type": "Feature",
+                "id": "R1",
+                "properties": {
+                    "route_id": "R1", "route_no": "1",
+                    "stops": [{ "id": "A", "name": "A", "ord": 1, "ud": 0, "side": 1 }],
+                    "turn_idx": 1, "stop_to_coord": [0],
+                    "total_dist": 1000.0, "total_time": 60.0, "source_ver": "2025-01-01"
+                },
+                "geometry": { "type": "LineString", "coordinates": [[127.92, 37.34], [127.93, 37.34]] }
+            });
+            for (key, change) in changes.as_object().unwrap() {
+                value["properties"][key] = change.clone();
+            }
+            serde_json::from_value(value).unwrap()
+        };
+        let base = feature(json!({}));
+
+        let rerun = feature(json!({
+            "total_dist": 1000.4, "total_time": 61.0, "source_ver": "2025-01-02",
+            "stops": [{ "id": "A", "name": "A", "ord": 1, "ud": 0, "side": -1 }]
+        }));
+        assert!(base.equivalent_to(&rerun, 1.0));
+
+        // Stable and unknown properties are compared, so a new field is never silently ignored
+        assert!(!base.equivalent_to(&feature(json!({ "route_no": "2" })), 1.0));
+        assert!(!base.equivalent_to(&feature(json!({ "operator": "태창운수" })), 1.0));
+    }
+
+    #[test]
+    fn test_file_names_by_route_no_suffix_variants() {
+        let route_numbers = BTreeMap::from([
+            ("34-1".to_string(), vec!["R1".to_string()]),
+            ("2".to_string(), vec!["R2".to_string(), "R3".to_string()]),
+            ("2_1".to_string(), vec!["R4".to_string()]),
+        ]);
+
+        let names = FileNames::new(NameBy::RouteNo, &route_numbers);
+        assert_eq!(names.stem("R1"), "34-1");
+        assert_eq!(names.stem("R2"), "2_1");
+        assert_eq!(names.stem("R3"), "2_2");
+        // Already taken by the first variant of route 2
+        assert_eq!(names.stem("R4"), "R4");
+
+        let names = FileNames::new(NameBy::RouteId, &route_numbers);
+        assert_eq!(names.stem("R1"), "R1");
+    }
 }
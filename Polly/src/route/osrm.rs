@@ -1,52 +1,159 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
 use serde_json::Value;
 
-use crate::config::{OSRM_CONTINUE_STRAIGHT, OSRM_GEOMETRIES, OSRM_OVERVIEW, OSRM_SNAP_RADIUS};
-use crate::route::model::{BusRouteProcessor, RawStop};
+use crate::config::{
+    OSRM_CHUNK_SIZE, OSRM_CONTINUE_STRAIGHT, OSRM_CORRIDOR_MAX_SHIFT, OSRM_GEOMETRIES,
+    OSRM_MAX_RETRY_AFTER, OSRM_OVERVIEW, OSRM_SANITIZE_MIN_OFFSET, OSRM_SNAP_RADIUS,
+};
+use crate::route::model::{BusRouteProcessor, OsrmInstance, RawStop, RouteAnnotations};
 use crate::utils::geo::closest_point_on_polyline;
 
+/// The first route of an OSRM response
+pub struct OsrmRoute {
+    pub coordinates: Vec<Vec<f64>>,
+    /// Meters
+    pub distance: f64,
+    /// Seconds
+    pub duration: f64,
+    /// Per-segment values, when requested and the server sent them
+    pub annotations: Option<RouteAnnotations>,
+    /// The server and map data that answered
+    pub instance: OsrmInstance,
+}
+
 impl BusRouteProcessor {
+    /// Moves interior stops that drifted off the road onto the route between their neighbours.
+    ///
+    /// Rather than one request per stop, the route is requested through every other stop: the
+    /// leg from stop `i - 1` to `i + 1` is stop `i`'s corridor. That takes two requests (odd and
+    /// even stops) per `OSRM_CHUNK_SIZE` waypoints. A chunk whose legs cannot be split falls back
+    /// to one request per stop. Corridors are built from the original stop positions.
+    ///
+    /// Stops within `OSRM_SANITIZE_MIN_OFFSET` of the straight line between their neighbours are
+    /// left alone, and only runs of waypoints around the remaining stops are requested, so a
+    /// well-aligned route costs few or no requests.
     pub async fn sanitize_stops_to_corridor(&self, stops: &mut [RawStop]) {
         if stops.len() < 3 {
             return;
         }
 
-        for i in 1..stops.len() - 1 {
-            if stops[i - 1].up_down_cd != stops[i].up_down_cd
-                || stops[i].up_down_cd != stops[i + 1].up_down_cd
-            {
-                continue;
+        // Cheap pre-check: interior stops of one direction that sit off the chord of their neighbours
+        let needs_corridor: Vec<bool> = (0..stops.len())
+            .map(|i| {
+                if i == 0 || i + 1 == stops.len() {
+                    return false;
+                }
+                let (prev, stop, next) = (&stops[i - 1], &stops[i], &stops[i + 1]);
+                if prev.up_down_cd != stop.up_down_cd || stop.up_down_cd != next.up_down_cd {
+                    return false;
+                }
+                let chord = [
+                    vec![prev.gps_long, prev.gps_lat],
+                    vec![next.gps_long, next.gps_lat],
+                ];
+                // Coincident neighbours give no chord; let OSRM decide
+                closest_point_on_polyline((stop.gps_long, stop.gps_lat), &chord)
+                    .is_none_or(|(_, offset)| offset > OSRM_SANITIZE_MIN_OFFSET)
+            })
+            .collect();
+
+        let mut moved = Vec::new();
+        for parity in 0..2 {
+            // Runs of waypoints whose in-between stops all need a corridor
+            let mut runs: Vec<Vec<usize>> = Vec::new();
+            for w in (parity..stops.len() - 2).step_by(2) {
+                if !needs_corridor[w + 1] {
+                    continue;
+                }
+                match runs.last_mut() {
+                    Some(run) if run.last() == Some(&w) => run.push(w + 2),
+                    _ => runs.push(vec![w, w + 2]),
+                }
             }
 
-            let corr = self
-                .fetch_osrm_route_between(&stops[i - 1], &stops[i + 1])
-                .await;
-            if let Some((corr, _, _)) = corr {
+            for waypoints in runs {
+                self.correct_run(stops, &waypoints, &mut moved).await;
+            }
+        }
+
+        for (i, (lon, lat)) in moved {
+            stops[i].gps_long = lon;
+            stops[i].gps_lat = lat;
+        }
+    }
+
+    /// Requests the corridors of the stops between consecutive `waypoints`, in chunks of
+    /// `OSRM_CHUNK_SIZE`, and records in `moved` where each stop should go.
+    async fn correct_run(
+        &self,
+        stops: &[RawStop],
+        waypoints: &[usize],
+        moved: &mut Vec<(usize, (f64, f64))>,
+    ) {
+        let mut start = 0;
+        while start + 1 < waypoints.len() {
+            let end = (start + OSRM_CHUNK_SIZE).min(waypoints.len());
+            let chunk = &waypoints[start..end];
+            let legs = self.fetch_osrm_legs(stops, chunk).await;
+
+            for (leg_idx, pair) in chunk.windows(2).enumerate() {
+                let i = pair[0] + 1;
+                let fallback;
+                let corridor = match &legs {
+                    Some(legs) => legs.get(leg_idx),
+                    None => {
+                        fallback = self
+                            .fetch_osrm_route_between(&stops[i - 1], &stops[i + 1])
+                            .await;
+                        fallback.as_ref().map(|route| &route.coordinates)
+                    }
+                };
                 let p = (stops[i].gps_long, stops[i].gps_lat);
-                if let Some(((cx, cy), d)) = closest_point_on_polyline(p, &corr)
-                    && d <= 90.0
+                if let Some(corridor) = corridor
+                    && let Some((snapped, d)) = closest_point_on_polyline(p, corridor)
+                    && d <= OSRM_CORRIDOR_MAX_SHIFT
                 {
-                    stops[i].gps_long = cx;
-                    stops[i].gps_lat = cy;
+                    moved.push((i, snapped));
                 }
             }
+            start = end - 1;
         }
     }
 
-    pub async fn fetch_osrm_route_between(
+    /// Requests one route through `stops[waypoints]` and splits its geometry into legs.
+    ///
+    /// Leg boundaries come from the number of annotated segments per leg, so this returns
+    /// `None` if the server does not support annotations.
+    async fn fetch_osrm_legs(
         &self,
-        a: &RawStop,
-        b: &RawStop,
-    ) -> Option<(Vec<Vec<f64>>, f64, f64)> {
+        stops: &[RawStop],
+        waypoints: &[usize],
+    ) -> Option<Vec<Vec<Vec<f64>>>> {
+        let coords = waypoints
+            .iter()
+            .map(|&i| format!("{:.6},{:.6}", stops[i].gps_long, stops[i].gps_lat))
+            .collect::<Vec<_>>()
+            .join(";");
+        let radiuses = vec![format!("{:.0}", OSRM_SNAP_RADIUS); waypoints.len()].join(";");
+
+        let (route, _) = self.request_osrm(&coords, Some(&radiuses), true).await?;
+        let legs = split_legs(&route)?;
+        (legs.len() + 1 == waypoints.len()).then_some(legs)
+    }
+
+    pub async fn fetch_osrm_route_between(&self, a: &RawStop, b: &RawStop) -> Option<OsrmRoute> {
         let coords = format!(
             "{:.6},{:.6};{:.6},{:.6}",
             a.gps_long, a.gps_lat, b.gps_long, b.gps_lat
         );
 
         let radiuses = format!("{:.0};{:.0}", OSRM_SNAP_RADIUS, OSRM_SNAP_RADIUS);
-        self.call_osrm(&coords, Some(&radiuses)).await
+        self.call_osrm(&coords, Some(&radiuses), false).await
     }
 
-    pub async fn fetch_osrm_route(&self, stops: &[RawStop]) -> Option<(Vec<Vec<f64>>, f64, f64)> {
+    pub async fn fetch_osrm_route(&self, stops: &[RawStop]) -> Option<OsrmRoute> {
         let coords = stops
             .iter()
             .map(|s| format!("{:.6},{:.6}", s.gps_long, s.gps_lat))
@@ -55,14 +162,45 @@ impl BusRouteProcessor {
 
         let radiuses = vec![format!("{:.0}", OSRM_SNAP_RADIUS); stops.len()].join(";");
 
-        self.call_osrm(&coords, Some(&radiuses)).await
+        self.call_osrm(&coords, Some(&radiuses), self.options.osrm_annotations)
+            .await
     }
 
+    /// Requests a route through `coords_param`, with segment annotations if `annotations` is set.
     pub async fn call_osrm(
         &self,
         coords_param: &str,
         radiuses_param: Option<&str>,
-    ) -> Option<(Vec<Vec<f64>>, f64, f64)> {
+        annotations: bool,
+    ) -> Option<OsrmRoute> {
+        let (route, instance) = self
+            .request_osrm(coords_param, radiuses_param, annotations)
+            .await?;
+        let coordinates = route_coordinates(&route)?;
+        let annotations = if annotations {
+            parse_annotations(&route, coordinates.len())
+        } else {
+            None
+        };
+        Some(OsrmRoute {
+            distance: route["distance"].as_f64().unwrap_or(0.0),
+            duration: route["duration"].as_f64().unwrap_or(0.0),
+            coordinates,
+            annotations,
+            instance,
+        })
+    }
+
+    /// Sends an OSRM route request, retrying on rate limits, `NoSegment` and network errors.
+    ///
+    /// Returns the first route of the response and the instance that answered, which is also
+    /// recorded for the run manifest.
+    async fn request_osrm(
+        &self,
+        coords_param: &str,
+        radiuses_param: Option<&str>,
+        annotations: bool,
+    ) -> Option<(Value, OsrmInstance)> {
         let mut attempts = 0;
         let max_attempts = 5;
         let mut current_radius = OSRM_SNAP_RADIUS;
@@ -83,36 +221,73 @@ impl BusRouteProcessor {
             if let Some(ref r) = custom_radiuses {
                 url.push_str(&format!("&radiuses={}", r));
             }
+            if annotations {
+                url.push_str("&annotations=true");
+            }
 
-            match self.client.get(&url).send().await {
+            match self
+                .client
+                .get(&url)
+                .timeout(self.osrm_timeout)
+                .send()
+                .await
+            {
                 Ok(resp) => {
                     let status = resp.status();
                     if status.is_success() {
-                        let json: Value = match resp.json().await {
+                        let header = |name| {
+                            resp.headers()
+                                .get(name)
+                                .and_then(|v| v.to_str().ok())
+                                .map(str::to_string)
+                        };
+                        let (server, via) = (
+                            header(reqwest::header::SERVER),
+                            header(reqwest::header::VIA),
+                        );
+                        let mut json: Value = match resp.json().await {
                             Ok(v) => v,
                             Err(e) => {
                                 log::error!("Failed to parse OSRM JSON: {}", e);
                                 return None;
                             }
                         };
-
-                        let route = &json["routes"][0];
-
-                        let coords: Vec<Vec<f64>> = match serde_json::from_value(
-                            route["geometry"]["coordinates"].clone(),
-                        ) {
-                            Ok(c) => c,
-                            Err(_) => return None,
+                        let instance = OsrmInstance {
+                            data_version: json["data_version"].as_str().map(str::to_string),
+                            server,
+                            via,
                         };
-
-                        let distance = route["distance"].as_f64().unwrap_or(0.0);
-                        let duration = route["duration"].as_f64().unwrap_or(0.0);
-
-                        if coords.is_empty() {
-                            log::error!("OSRM returned empty coordinates array.");
-                            return None;
+                        self.osrm_instances.record(&instance);
+                        return Some((json["routes"][0].take(), instance));
+                    } else if status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                        || status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+                    {
+                        attempts += 1;
+                        if attempts < max_attempts {
+                            let wait = resp
+                                .headers()
+                                .get(reqwest::header::RETRY_AFTER)
+                                .and_then(|v| v.to_str().ok())
+                                .and_then(parse_retry_after)
+                                .unwrap_or(Duration::from_secs(1))
+                                .min(OSRM_MAX_RETRY_AFTER);
+                            log::warn!(
+                                "OSRM returned {} (attempt {}/{}). Retrying in {:?}...",
+                                status,
+                                attempts,
+                                max_attempts,
+                                wait
+                            );
+                            tokio::time::sleep(wait).await;
+                            continue;
                         } else {
-                            return Some((coords, distance, duration));
+                            log::error!(
+                                "OSRM returned {} after {} attempts for URL: {}",
+                                status,
+                                max_attempts,
+                                url
+                            );
+                            return None;
                         }
                     } else if status == reqwest::StatusCode::BAD_REQUEST {
                         let err_text = resp.text().await.unwrap_or_default();
@@ -176,91 +351,262 @@ impl BusRouteProcessor {
     }
 }
 
+/// Splits the geometry of an annotated OSRM route into the geometry of each leg
+fn split_legs(route: &Value) -> Option<Vec<Vec<Vec<f64>>>> {
+    let coords = route_coordinates(route)?;
+    let mut legs = Vec::new();
+    let mut start = 0;
+    for leg in route["legs"].as_array()? {
+        let segments = leg["annotation"]["distance"].as_array()?.len();
+        legs.push(coords.get(start..=start + segments)?.to_vec());
+        start += segments;
+    }
+    (start + 1 == coords.len()).then_some(legs)
+}
+
+/// Geometry of an OSRM route; `None` if it is missing or empty
+fn route_coordinates(route: &Value) -> Option<Vec<Vec<f64>>> {
+    let coords: Vec<Vec<f64>> =
+        serde_json::from_value(route["geometry"]["coordinates"].clone()).ok()?;
+    if coords.is_empty() {
+        log::error!("OSRM returned empty coordinates array.");
+        return None;
+    }
+    Some(coords)
+}
+
+/// Concatenates the per-leg `annotation` blocks of an OSRM route.
+///
+/// Returns `None` when the server sent none (no annotation support) or when they do not
+/// line up with the `num_coords` coordinates of the route geometry.
+fn parse_annotations(route: &Value, num_coords: usize) -> Option<RouteAnnotations> {
+    let mut annotations = RouteAnnotations::new();
+    for (i, leg) in route["legs"].as_array()?.iter().enumerate() {
+        let leg = &leg["annotation"];
+        let values =
+            |key: &str| -> Option<Vec<f64>> { serde_json::from_value(leg[key].clone()).ok() };
+        let chunk = RouteAnnotations {
+            speed: values("speed")?,
+            duration: values("duration")?,
+            distance: values("distance")?,
+            nodes: serde_json::from_value(leg["nodes"].clone()).ok(),
+        };
+        annotations.append_chunk(chunk, i == 0);
+    }
+
+    let aligned = |values: &[f64]| values.len() == num_coords.saturating_sub(1);
+    if !(aligned(&annotations.speed)
+        && aligned(&annotations.duration)
+        && aligned(&annotations.distance))
+    {
+        log::warn!("OSRM annotations do not match the geometry; dropping them.");
+        return None;
+    }
+    if annotations
+        .nodes
+        .as_ref()
+        .is_some_and(|nodes| nodes.len() != num_coords)
+    {
+        annotations.nodes = None;
+    }
+    Some(annotations)
+}
+
+/// Parses a `Retry-After` header value given either as delay seconds or as an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (at.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::path::PathBuf;
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
-    use tokio::net::TcpListener;
+    use crate::route::model::RouteOptions;
+    use crate::route::test_support::{json_response, mock_server, response, test_processor};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A two-point OSRM route of 100 m and 10 s
+    const ROUTE: &str = "{\"routes\":[{\"geometry\":{\"coordinates\":[[127.0,37.0],[127.1,37.1]]},\"distance\":100.0,\"duration\":10.0}]}";
 
     #[tokio::test]
     async fn test_call_osrm_retry_on_nosegment() {
         let _ = env_logger::builder().is_test(true).try_init();
-        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let addr = listener.local_addr().unwrap();
-        let osrm_url = format!("http://{}", addr);
-
-        let processor = BusRouteProcessor {
-            client: reqwest::Client::new(),
-            service_key: "".to_string(),
-            city_code: "".to_string(),
-            raw_dir: PathBuf::new(),
-            derived_dir: PathBuf::new(),
-            mapping_file: PathBuf::new(),
-            tago_base_url: "".to_string(),
-            osrm_base_url: osrm_url,
-        };
-
-        // Spawn a task to mock the OSRM server
-        tokio::spawn(async move {
-            // First request: return 400 NoSegment
-            if let Ok((mut socket, _)) = listener.accept().await {
-                let mut buf = [0; 4096];
-                let n = socket.read(&mut buf).await.unwrap();
-                println!(
-                    "Mock Server received 1st request: {}",
-                    String::from_utf8_lossy(&buf[..n])
-                );
+        let url = mock_server(|i, request| {
+            let json = [("Content-Type", "application/json")];
+            if i == 0 {
                 let body =
                     "{\"code\":\"NoSegment\",\"message\":\"Could not find a matching segment\"}";
-                let response = format!(
-                    "HTTP/1.1 400 Bad Request\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
-                    body.len(),
-                    body
-                );
-                socket.write_all(response.as_bytes()).await.unwrap();
+                return response("400 Bad Request", &json, body);
             }
-
-            // Second request: return success
-            if let Ok((mut socket, _)) = listener.accept().await {
-                let mut buf = [0; 4096];
-                let n = socket.read(&mut buf).await.unwrap();
-                println!(
-                    "Mock Server received 2nd request: {}",
-                    String::from_utf8_lossy(&buf[..n])
-                );
-                let request_str = String::from_utf8_lossy(&buf[..n]);
-                // Check if radius was increased (initial 30 + 100 = 130)
-                if request_str.contains("radiuses=130%3B130")
-                    || request_str.contains("radiuses=130;130")
-                {
-                    let body = "{\"routes\":[{\"geometry\":{\"coordinates\":[[127.0,37.0],[127.1,37.1]]},\"distance\":100.0,\"duration\":10.0}]}";
-                    let response = format!(
-                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
-                        body.len(),
-                        body
-                    );
-                    socket.write_all(response.as_bytes()).await.unwrap();
-                } else {
-                    println!("Radius check failed!");
-                    let body = "{\"code\":\"Error\",\"message\":\"Radius not increased\"}";
-                    let response = format!(
-                        "HTTP/1.1 400 Bad Request\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
-                        body.len(),
-                        body
-                    );
-                    socket.write_all(response.as_bytes()).await.unwrap();
-                }
+            // The radius was increased (initial 30 + 100 = 130)
+            if request.contains("radiuses=130%3B130") || request.contains("radiuses=130;130") {
+                json_response(ROUTE)
+            } else {
+                let body = "{\"code\":\"Error\",\"message\":\"Radius not increased\"}";
+                response("400 Bad Request", &json, body)
             }
-        });
+        })
+        .await;
+        let processor = test_processor(&url, RouteOptions::default());
+
+        let result = processor
+            .call_osrm("127.0,37.0;127.1,37.1", Some("30;30"), false)
+            .await;
+        let route = result.unwrap();
+        assert_eq!(route.coordinates.len(), 2);
+        assert_eq!(route.distance, 100.0);
+        assert_eq!(route.duration, 10.0);
+    }
+
+    #[tokio::test]
+    async fn test_call_osrm_retry_after_on_429() {
+        let url = mock_server(|i, _| match i {
+            // Rate limited first, then a success naming the data and the server
+            0 => response("429 Too Many Requests", &[("Retry-After", "0")], ""),
+            _ => response(
+                "200 OK",
+                &[("Server", "nginx"), ("Via", "1.1 osrm-2")],
+                "{\"data_version\":\"2025-01-06\",\"routes\":[{\"geometry\":{\"coordinates\":[[127.0,37.0],[127.1,37.1]]},\"distance\":100.0,\"duration\":10.0}]}",
+            ),
+        })
+        .await;
+        let processor = test_processor(&url, RouteOptions::default());
 
         let result = processor
-            .call_osrm("127.0,37.0;127.1,37.1", Some("30;30"))
+            .call_osrm("127.0,37.0;127.1,37.1", Some("30;30"), false)
             .await;
-        assert!(result.is_some());
-        let (coords, dist, dur) = result.unwrap();
-        assert_eq!(coords.len(), 2);
-        assert_eq!(dist, 100.0);
-        assert_eq!(dur, 10.0);
+        let instance = OsrmInstance {
+            data_version: Some("2025-01-06".to_string()),
+            server: Some("nginx".to_string()),
+            via: Some("1.1 osrm-2".to_string()),
+        };
+        assert_eq!(result.unwrap().instance, instance);
+        assert_eq!(processor.osrm_instances.take(), vec![(instance, 1)]);
+    }
+
+    /// An OSRM server answering with a straight line through the waypoints, one annotated
+    /// segment per leg, and a processor using it. `requests` counts the requests.
+    async fn corridor_processor(requests: &Arc<AtomicUsize>) -> BusRouteProcessor {
+        let counter = Arc::clone(requests);
+        let url = mock_server(move |_, request| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let path = request.split_whitespace().nth(1).unwrap();
+            let coords: Vec<Vec<f64>> = path
+                .trim_start_matches('/')
+                .split('?')
+                .next()
+                .unwrap()
+                .split(';')
+                .map(|c| c.split(',').map(|v| v.parse().unwrap()).collect())
+                .collect();
+            let legs: Vec<Value> = (1..coords.len())
+                .map(|_| serde_json::json!({ "annotation": { "distance": [100.0] } }))
+                .collect();
+            json_response(
+                &serde_json::json!({ "routes": [{
+                    "geometry": { "coordinates": coords },
+                    "legs": legs, "distance": 100.0, "duration": 10.0
+                }] })
+                .to_string(),
+            )
+        })
+        .await;
+        test_processor(&url, RouteOptions::default())
+    }
+
+    /// Twelve stops along one street at `lat(i)`
+    fn street_stops(lat: impl Fn(i64) -> f64) -> Vec<RawStop> {
+        (0..12)
+            .map(|i| RawStop {
+                node_id: i.to_string(),
+                node_nm: i.to_string(),
+                node_ord: i,
+                node_no: i.to_string(),
+                gps_lat: lat(i),
+                gps_long: 127.9 + i as f64 * 0.001,
+                up_down_cd: 0,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_sanitize_batches_corridor_requests() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let processor = corridor_processor(&requests).await;
+
+        // Every other stop drifted about 22 m north, so every interior stop is off the chord of
+        // its neighbours and needs its corridor
+        let mut stops = street_stops(|i| if i % 2 == 1 { 37.3002 } else { 37.3 });
+
+        processor.sanitize_stops_to_corridor(&mut stops).await;
+
+        // One request per parity instead of one per interior stop (10)
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert!((stops[5].gps_lat - 37.3).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_sanitize_skips_stops_in_line_with_their_neighbours() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let processor = corridor_processor(&requests).await;
+
+        // Stop 5 drifted about 22 m north of the street
+        let mut stops = street_stops(|i| if i == 5 { 37.3002 } else { 37.3 });
+
+        processor.sanitize_stops_to_corridor(&mut stops).await;
+
+        // Only stop 5 is off its neighbours' chord: a single request for its corridor
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!((stops[5].gps_lat - 37.3).abs() < 1e-9);
+
+        // An aligned route needs no requests at all
+        requests.store(0, Ordering::SeqCst);
+        processor.sanitize_stops_to_corridor(&mut stops).await;
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_parse_annotations_joins_legs() {
+        let route = serde_json::json!({
+            "legs": [
+                { "annotation": {
+                    "speed": [10.0, 12.0], "duration": [1.0, 2.0], "distance": [10.0, 24.0],
+                    "nodes": [1, 2, 3]
+                } },
+                { "annotation": {
+                    "speed": [8.0, 9.0], "duration": [3.0, 4.0], "distance": [24.0, 36.0],
+                    "nodes": [3, 4, 5]
+                } }
+            ]
+        });
+
+        let annotations = parse_annotations(&route, 5).unwrap();
+        assert_eq!(annotations.speed, vec![10.0, 12.0, 8.0, 9.0]);
+        assert_eq!(annotations.distance.len(), 4);
+        assert_eq!(annotations.nodes, Some(vec![1, 2, 3, 4, 5]));
+
+        // Geometry of another length, or a server without annotations
+        assert!(parse_annotations(&route, 6).is_none());
+        assert!(parse_annotations(&serde_json::json!({ "legs": [{}] }), 5).is_none());
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("5"), Some(Duration::from_secs(5)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
     }
 }
@@ -0,0 +1,245 @@
+//! Output destinations for derived routes.
+//!
+//! `run` assembles the destinations once into a single `OutputSink`, so the
+//! processing code never needs to know where its output ends up.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use tokio::sync::mpsc;
+
+use crate::route::model::{BusRouteProcessor, RouteFeatureCollection};
+use crate::route::writer::WriterFailure;
+use crate::utils::s3::S3Target;
+
+/// A destination for derived routes
+pub trait OutputSink: Send + Sync {
+    /// Writes one derived route under the file stem `name`
+    fn write_route<'a>(
+        &'a self,
+        name: &'a str,
+        collection: &'a RouteFeatureCollection,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Writes a route whose content is unchanged from the previous output. Remote sinks already
+    /// hold it and skip the upload.
+    fn write_unchanged_route<'a>(
+        &'a self,
+        name: &'a str,
+        collection: &'a RouteFeatureCollection,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.write_route(name, collection)
+    }
+
+    /// Local directory the routes end up in as `<name>.geojson`, if the sink writes one
+    fn local_dir(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// Writes each route to `<dir>/<name>.geojson`
+pub struct FileSink {
+    pub dir: PathBuf,
+}
+
+impl OutputSink for FileSink {
+    fn write_route<'a>(
+        &'a self,
+        name: &'a str,
+        collection: &'a RouteFeatureCollection,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let path = self.dir.join(format!("{}.geojson", name));
+            tokio::fs::write(path, serde_json::to_string(collection)?).await?;
+            Ok(())
+        })
+    }
+
+    fn local_dir(&self) -> Option<&Path> {
+        Some(&self.dir)
+    }
+}
+
+/// Queues each route as `<dir>/<name>.geojson` for the `--writers` pool
+pub struct PooledFileSink {
+    pub dir: PathBuf,
+    pub tx: mpsc::Sender<(PathBuf, Vec<u8>)>,
+}
+
+impl OutputSink for PooledFileSink {
+    fn write_route<'a>(
+        &'a self,
+        name: &'a str,
+        collection: &'a RouteFeatureCollection,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let path = self.dir.join(format!("{}.geojson", name));
+            self.tx
+                .send((path, serde_json::to_vec(collection)?))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn local_dir(&self) -> Option<&Path> {
+        Some(&self.dir)
+    }
+}
+
+/// Adds each route to the `--archive` tar as `<name>.geojson`
+pub struct ArchiveSink {
+    pub tx: mpsc::Sender<(String, Vec<u8>)>,
+}
+
+impl OutputSink for ArchiveSink {
+    fn write_route<'a>(
+        &'a self,
+        name: &'a str,
+        collection: &'a RouteFeatureCollection,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let entry = format!("{}.geojson", name);
+            self.tx
+                .send((entry, serde_json::to_vec(collection)?))
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+/// Uploads each route to `<prefix>/<name>.geojson` in the S3 target
+pub struct S3Sink {
+    pub target: Arc<S3Target>,
+    pub prefix: String,
+}
+
+impl OutputSink for S3Sink {
+    fn write_route<'a>(
+        &'a self,
+        name: &'a str,
+        collection: &'a RouteFeatureCollection,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let key = format!("{}/{}.geojson", self.prefix, name);
+            self.target.put(&key, serde_json::to_vec(collection)?).await
+        })
+    }
+
+    fn write_unchanged_route<'a>(
+        &'a self,
+        _name: &'a str,
+        _collection: &'a RouteFeatureCollection,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Prints each route as one line of GeoJSON to stdout
+pub struct StdoutSink;
+
+impl OutputSink for StdoutSink {
+    fn write_route<'a>(
+        &'a self,
+        _name: &'a str,
+        collection: &'a RouteFeatureCollection,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            // `println!` locks stdout, so concurrent routes never interleave within a line
+            println!("{}", serde_json::to_string(collection)?);
+            Ok(())
+        })
+    }
+}
+
+/// Feeds serialized features to the combined output writer task
+///
+/// The channel is bounded, so a slow writer makes `write_route` wait. If the writer has
+/// stopped, `write_route` fails with the error it stopped on.
+pub struct CombinedSink {
+    pub tx: mpsc::Sender<Vec<u8>>,
+    pub failure: WriterFailure,
+}
+
+impl OutputSink for CombinedSink {
+    fn write_route<'a>(
+        &'a self,
+        _name: &'a str,
+        collection: &'a RouteFeatureCollection,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            for feature in &collection.features {
+                let bytes = serde_json::to_vec(feature)?;
+                if self.tx.send(bytes).await.is_err() {
+                    return Err(self.failure.send_error());
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Writes each route to every inner sink in order
+pub struct MultiSink(pub Vec<Box<dyn OutputSink>>);
+
+impl OutputSink for MultiSink {
+    fn write_route<'a>(
+        &'a self,
+        name: &'a str,
+        collection: &'a RouteFeatureCollection,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            for sink in &self.0 {
+                sink.write_route(name, collection).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn write_unchanged_route<'a>(
+        &'a self,
+        name: &'a str,
+        collection: &'a RouteFeatureCollection,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            for sink in &self.0 {
+                sink.write_unchanged_route(name, collection).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn local_dir(&self) -> Option<&Path> {
+        self.0.iter().find_map(|sink| sink.local_dir())
+    }
+}
+
+impl BusRouteProcessor {
+    /// Uploads a file that was already written locally to the S3 target, if one is configured.
+    ///
+    /// The object key is the file's path relative to the output directory.
+    pub async fn mirror_output(&self, path: &Path, contents: String) -> Result<()> {
+        let base_dir = self.mapping_file.parent().unwrap_or(Path::new(""));
+        mirror_output(self.s3.as_deref(), base_dir, path, contents).await
+    }
+}
+
+/// Uploads a file written locally under `base_dir` to `s3`, keyed by its path relative to `base_dir`
+pub async fn mirror_output(
+    s3: Option<&S3Target>,
+    base_dir: &Path,
+    path: &Path,
+    contents: String,
+) -> Result<()> {
+    if let Some(s3) = s3 {
+        let key = path
+            .strip_prefix(base_dir)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .replace('\\', "/");
+        s3.put(&key, contents.into_bytes()).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,171 @@
+//! GTFS-like `stop_times` preview for a single trip.
+//!
+//! Joins a route's derived geometry with its crawled schedule: the first
+//! departure of the day in the requested direction is taken from the schedule,
+//! and the leg's travel time is distributed over the stops in proportion to the
+//! distance travelled along the geometry.
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+use crate::route::model::RouteFeatureCollection;
+use crate::route::process::find_turn_index;
+use crate::utils::geo::cumulative_distances;
+use crate::utils::layout::LayoutArgs;
+use crate::utils::{normalize_route_no, read_json, safe_file_name};
+
+/// Arguments for previewing one trip's stop times
+#[derive(clap::Args)]
+pub struct TripPreviewArgs {
+    /// Route number (e.g. "34-1")
+    route: String,
+
+    /// Day type as used in the schedule output (e.g. "weekday", "weekend", "general")
+    day_type: String,
+
+    /// Direction as used in the schedule output (the departure terminus)
+    direction: String,
+
+    /// Output directory containing `routeMap.json`, `polylines/` and `schedules/`
+    #[arg(short, long, default_value = "./storage")]
+    output_dir: PathBuf,
+
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// City of the route, with a layout that nests files by city
+    #[arg(long)]
+    city_code: Option<String>,
+}
+
+/// Finds the first departure of the day (minutes after midnight) for a day type and direction.
+fn first_departure(schedule: &Value, day_type: &str, direction: &str) -> Option<u32> {
+    // Hour keys are zero-padded, so the object's key order is chronological
+    schedule["schedule"][day_type]
+        .as_object()?
+        .iter()
+        .find_map(|(hour, dirs)| {
+            let minute = dirs[direction].as_array()?.first()?["minute"].as_str()?;
+            Some(hour.parse::<u32>().ok()? * 60 + minute.parse::<u32>().ok()?)
+        })
+}
+
+fn format_minutes(minutes: f64) -> String {
+    let total = minutes.round() as u32;
+    format!("{:02}:{:02}", total / 60, total % 60)
+}
+
+/// Prints the stops of the first trip of the day with interpolated times.
+pub fn trip_preview(args: TripPreviewArgs) -> Result<()> {
+    let route_no = normalize_route_no(&args.route);
+    let layout = args.layout.resolve()?;
+    let root = layout.root(&args.output_dir, args.city_code.as_deref())?;
+
+    let schedule_path = layout
+        .schedules_dir(&root)
+        .join(format!("{}.json", safe_file_name(&route_no)));
+    let schedule = read_json(&schedule_path)?;
+
+    let departure =
+        first_departure(&schedule, &args.day_type, &args.direction).with_context(|| {
+            format!(
+                "No departures for route {} on {} towards direction {}",
+                route_no, args.day_type, args.direction
+            )
+        })?;
+
+    // Direction position in the schedule, used when stop names don't identify the leg
+    let direction_pos = schedule["directions"]
+        .as_array()
+        .and_then(|dirs| dirs.iter().position(|d| d == args.direction.as_str()))
+        .unwrap_or(0);
+
+    let route_map = read_json(&root.join("routeMap.json"))?;
+    let route_ids: Vec<String> =
+        serde_json::from_value(route_map["route_numbers"][&route_no].clone()).unwrap_or_default();
+    let route_id = route_ids
+        .first()
+        .with_context(|| format!("Route {} not found in routeMap.json", route_no))?;
+
+    // Files may be named by route number (`--name-by`); routeMap.json records the path
+    let geojson = route_map["route_variants"][&route_no]
+        .as_array()
+        .and_then(|variants| variants.iter().find(|v| v["route_id"] == route_id.as_str()))
+        .and_then(|v| v["geojson"].as_str())
+        .map_or_else(|| layout.derived_path(route_id), str::to_string);
+    let mut collection = RouteFeatureCollection::read_resolved(&root.join(geojson), &root)?;
+    let feature = collection
+        .features
+        .first_mut()
+        .context("Derived file has no features")?;
+    feature.swap_to_lonlat();
+    let props = &feature.properties;
+    let coords = &feature.geometry.coordinates;
+
+    if coords.is_empty() || props.indices.stop_to_coord.len() != props.stops.len() {
+        anyhow::bail!("Route {} has no usable geometry", route_id);
+    }
+
+    // Split into legs at the turning point and pick the one leaving from `direction`
+    let up_down: Vec<i64> = props.stops.iter().map(|s| s.up_down).collect();
+    let turn = find_turn_index(&up_down);
+    let legs = [0..turn + 1, turn + 1..props.stops.len()];
+    let leg = legs
+        .iter()
+        .find(|leg| {
+            props.stops.get(leg.start).is_some_and(|s| {
+                s.name.contains(&args.direction) || args.direction.contains(&s.name)
+            })
+        })
+        .unwrap_or(&legs[direction_pos.min(1)])
+        .clone();
+
+    if leg.is_empty() {
+        anyhow::bail!("Route {} has no stops in that direction", route_id);
+    }
+
+    // Distance at each stop along the geometry, and the leg's share of the total travel time
+    let cumulative = cumulative_distances(coords);
+    let total_dist = cumulative.last().copied().unwrap_or(0.0);
+    let stop_dist: Vec<f64> = props.indices.stop_to_coord[leg.clone()]
+        .iter()
+        .map(|&i| cumulative[i.min(cumulative.len() - 1)])
+        .collect();
+    let leg_dist = stop_dist.last().unwrap() - stop_dist[0];
+    let leg_minutes = if total_dist > 0.0 {
+        props.meta.total_time / 60.0 * leg_dist / total_dist
+    } else {
+        0.0
+    };
+
+    println!(
+        "Route {} ({}) {} towards {}: first trip at {}",
+        route_no,
+        route_id,
+        args.day_type,
+        args.direction,
+        format_minutes(departure as f64)
+    );
+    println!("{:<5} {:<8} {:<12} STOP", "SEQ", "TIME", "STOP_ID");
+
+    for (seq, (stop, dist)) in props.stops[leg].iter().zip(&stop_dist).enumerate() {
+        let fraction = if leg_dist > 0.0 {
+            (dist - stop_dist[0]) / leg_dist
+        } else {
+            0.0
+        };
+        let time = departure as f64 + leg_minutes * fraction;
+
+        println!(
+            "{:<5} {:<8} {:<12} {}",
+            seq + 1,
+            format_minutes(time),
+            stop.id,
+            stop.name
+        );
+    }
+
+    Ok(())
+}
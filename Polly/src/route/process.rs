@@ -1,78 +1,291 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::path::Path;
 
-use anyhow::Result;
-use serde_json::Value;
+use anyhow::{Context, Result};
+use futures::stream::{self, StreamExt};
+use serde_json::{Map, Value, json};
 
-use crate::config::OSRM_CHUNK_SIZE;
+use crate::config::{
+    GEOMETRY_MAX_SELF_INTERSECTIONS, GEOMETRY_TURN_WINDOW, OSRM_CHUNK_SIZE,
+    STATION_MAP_MIN_MATCH_RATE,
+};
+use crate::route::error::{self, RouteError};
 use crate::route::model::{
-    BusRouteProcessor, FrontendMeta, FrontendStop, RawRouteFile, RouteFeature,
-    RouteFeatureCollection, RouteGeometry, RouteIndices, RouteProperties,
+    BusRouteProcessor, FileNames, FrontendMeta, FrontendStop, GeometryOwners, RawRouteFile,
+    RawStop, RouteAnnotations, RouteFeature, RouteFeatureCollection, RouteGeometry,
+    RouteIndexEntry, RouteIndices, RouteProperties, RouteUnits, utc_timestamp,
+};
+use crate::route::output::OutputSink;
+use crate::route::stations::StationMap;
+use crate::utils::geo::{
+    calculate_metrics, count_self_intersections, cumulative_distances, find_nearest_coord_index,
+    longest_step, representative_point, side_of_line,
 };
-use crate::utils::geo::{calculate_metrics, find_nearest_coord_index};
+use crate::utils::parse_flexible_f64;
 
 impl BusRouteProcessor {
+    /// Derives a route from its raw file and hands the result to `sink`.
+    ///
+    /// With `geometry_owners`, a route whose coordinates match a route written earlier in the run
+    /// is written without them and points at that route's file instead.
+    ///
+    /// Returns the route's `routeIndex.json` entry, or `RouteError::NoGeometry` if nothing was derived.
     pub async fn process_raw_to_derived(
         &self,
         raw_path: &Path,
-        station_map: &HashMap<String, Value>,
-    ) -> Result<()> {
+        station_map: &StationMap,
+        file_names: &FileNames,
+        sink: &dyn OutputSink,
+        geometry_owners: Option<&GeometryOwners>,
+    ) -> error::Result<RouteIndexEntry> {
         // Read Raw File
-        let content = tokio::fs::read_to_string(raw_path).await?;
-        let raw_data: RawRouteFile = serde_json::from_str(&content)?;
+        let content = tokio::fs::read_to_string(raw_path)
+            .await
+            .with_context(|| format!("Failed to read {}", raw_path.display()))?;
+        let raw_data: RawRouteFile = serde_json::from_str(&content)
+            .map_err(|e| RouteError::ParseFailed(raw_path.to_path_buf(), e))?;
+        let route_id = raw_data.route_id.clone();
+
+        let Some(mut derived_data) = self.derive(raw_data, station_map).await? else {
+            return Err(RouteError::NoGeometry(route_id));
+        };
+        // The index stays in GeoJSON order even with `--latlon-order`
+        let stem = file_names.stem(&route_id);
+        let mut entry = derived_data
+            .features
+            .first()
+            .map(|f| RouteIndexEntry::new(f, self.layout.derived_path(stem)))
+            .ok_or(RouteError::NoGeometry(route_id.clone()))?;
+
+        if self.options.latlon_order {
+            derived_data
+                .features
+                .iter_mut()
+                .for_each(RouteFeature::swap_to_latlon);
+        }
+        if self.options.delta_stop_to_coord {
+            derived_data
+                .features
+                .iter_mut()
+                .for_each(RouteFeature::encode_stop_to_coord_delta);
+        }
+
+        if let Some(owners) = geometry_owners
+            && let [feature] = derived_data.features.as_mut_slice()
+            && !feature.geometry.coordinates.is_empty()
+            && let Some(owner) = owners.claim(stem, &feature.geometry.coordinates)
+        {
+            log::debug!(
+                "Route {} has the geometry of {}; writing a reference.",
+                route_id,
+                owner
+            );
+            let owner_path = self.layout.derived_path(&owner);
+            feature.geometry.coordinates.clear();
+            feature
+                .extra
+                .insert("geometry_ref".to_string(), json!(owner_path));
+            entry.geometry_ref = Some(owner_path);
+        }
+
+        // Float noise from OSRM must not republish an otherwise identical route
+        let tolerance_m = self.options.equal_tolerance_m;
+        match previous_output(sink, stem).await {
+            Some(previous) if previous.equivalent_to(&derived_data, tolerance_m) => {
+                log::debug!(
+                    "Route {} is unchanged within {} m; keeping the previous output.",
+                    route_id,
+                    tolerance_m
+                );
+                sink.write_unchanged_route(stem, &previous).await?;
+            }
+            _ => sink.write_route(stem, &derived_data).await?,
+        }
+
+        Ok(entry)
+    }
 
+    /// Derives the GeoJSON structure for a route without touching the disk.
+    ///
+    /// Returns `None` when the route has fewer than two stops and no geometry can be built,
+    /// unless `include_empty_routes` is set, in which case a stub route is returned.
+    pub async fn derive(
+        &self,
+        raw_data: RawRouteFile,
+        station_map: &StationMap,
+    ) -> Result<Option<RouteFeatureCollection>> {
         let mut stops = raw_data.stops;
 
+        // Feeds disagree on the updowncd encoding; turn detection and --direction expect 0/1
+        if let Some(observed) = normalize_up_down(&mut stops) {
+            log::info!(
+                "Route {} ({}): normalized updowncd values {:?} to 0/1.",
+                raw_data.route_no,
+                raw_data.route_id,
+                observed
+            );
+        }
+
+        // Caches fetched before per-direction sorting may hold interleaved directions
+        if sort_stops(&mut stops) {
+            log::info!(
+                "Route {} ({}): node_ord restarts per direction; sorted each direction separately.",
+                raw_data.route_no,
+                raw_data.route_id
+            );
+        }
+
         // Apply coordinates from stationMap for accuracy
+        let mut matched = 0usize;
         for stop in &mut stops {
             if let Some(station_info) = station_map.get(&stop.node_id) {
-                if let Some(lat) = station_info.get("gpslati").and_then(|v| v.as_f64()) {
+                matched += 1;
+                if let Some(lat) = station_coord(&station_info, "gpslati", &stop.node_id) {
                     stop.gps_lat = lat;
                 }
-                if let Some(lon) = station_info.get("gpslong").and_then(|v| v.as_f64()) {
+                if let Some(lon) = station_coord(&station_info, "gpslong", &stop.node_id) {
                     stop.gps_long = lon;
                 }
             }
         }
 
+        if !station_map.is_empty()
+            && !stops.is_empty()
+            && (matched as f64) < stops.len() as f64 * STATION_MAP_MIN_MATCH_RATE
+        {
+            self.quality.warn(
+                &raw_data.route_id,
+                format!(
+                    "Route {} ({}): only {}/{} stops found in stationMap.json.",
+                    raw_data.route_no,
+                    raw_data.route_id,
+                    matched,
+                    stops.len()
+                ),
+            );
+        }
+
+        // Coordinates outside WGS84 bounds (or the 0,0 placeholder) would snap to nonsense
+        let invalid = stops
+            .iter()
+            .filter(|s| {
+                !(-90.0..=90.0).contains(&s.gps_lat)
+                    || !(-180.0..=180.0).contains(&s.gps_long)
+                    || (s.gps_lat == 0.0 && s.gps_long == 0.0)
+            })
+            .count();
+        if invalid > 0 {
+            self.quality.warn(
+                &raw_data.route_id,
+                format!(
+                    "Route {} ({}) has {} stop(s) with out-of-range coordinates.",
+                    raw_data.route_no, raw_data.route_id, invalid
+                ),
+            );
+        }
+
+        // Keep a single leg if requested, before any OSRM calls are spent on the other
+        if let Some(cd) = self.options.direction.up_down_cd() {
+            stops.retain(|s| s.up_down_cd == cd);
+            if stops.is_empty() {
+                log::warn!(
+                    "Route {} ({}) has no stops for direction {:?}.",
+                    raw_data.route_no,
+                    raw_data.route_id,
+                    self.options.direction
+                );
+            }
+        }
+
+        // Stop positions before drift correction moves them onto the road, for the boarding side
+        let original_coords: Vec<(f64, f64)> =
+            stops.iter().map(|s| (s.gps_long, s.gps_lat)).collect();
+
         // Sanitize coordinates (drift correction)
-        self.sanitize_stops_to_corridor(&mut stops).await;
+        if !self.options.no_snap {
+            self.sanitize_stops_to_corridor(&mut stops).await;
+        }
 
         if stops.len() < 2 {
-            return Ok(());
+            log::info!(
+                "Route {} ({}) has fewer than two stops.",
+                raw_data.route_no,
+                raw_data.route_id
+            );
+            if self.options.include_empty_routes {
+                return Ok(Some(no_geometry_route(
+                    raw_data.route_id,
+                    raw_data.route_no,
+                    stops,
+                    raw_data.fetched_at,
+                    self.options.version,
+                )));
+            }
+            return Ok(None);
         }
 
         let route_id = raw_data.route_id;
         let route_no = raw_data.route_no;
 
         // Identify Turning Point
-        let mut turn_idx = stops.len() - 1;
-        for i in 0..stops.len() - 1 {
-            if stops[i].up_down_cd != stops[i + 1].up_down_cd {
-                turn_idx = i;
-                break;
-            }
-        }
+        let up_down: Vec<i64> = stops.iter().map(|s| s.up_down_cd).collect();
+        let turn_idx = find_turn_index(&up_down);
+        let is_loop = up_down.windows(2).all(|w| w[0] == w[1]);
 
         // OSRM Logic (Merging)
         let mut full_coordinates: Vec<Vec<f64>> = Vec::new();
         let mut stop_to_coord: Vec<usize> = Vec::with_capacity(stops.len());
         let mut total_osrm_dist = 0.0;
         let mut total_osrm_duration = 0.0;
-        let mut start_idx = 0;
+        // Kept only while every chunk comes with annotations
+        let mut annotations =
+            (self.options.osrm_annotations && !self.options.no_snap).then(RouteAnnotations::new);
+        // Distinct OSRM data versions the chunks were snapped on
+        let mut data_versions: Vec<String> = Vec::new();
 
-        while start_idx < stops.len() - 1 {
-            let end_idx = (start_idx + OSRM_CHUNK_SIZE).min(stops.len());
-            let chunk = &stops[start_idx..end_idx];
+        // `--no-snap` connects the stops directly: one vertex per stop, no OSRM chunks
+        if self.options.no_snap {
+            full_coordinates = stops.iter().map(|s| vec![s.gps_long, s.gps_lat]).collect();
+            stop_to_coord = (0..stops.len()).collect();
+        }
 
-            if chunk.len() < 2 {
-                break;
-            }
+        let chunk_ranges = if self.options.no_snap {
+            Vec::new()
+        } else {
+            osrm_chunk_ranges(stops.len(), OSRM_CHUNK_SIZE)
+        };
 
-            if let Some((coords, chunk_dist, chunk_dur)) = self.fetch_osrm_route(chunk).await {
+        // With prefetch, the next chunk's request is in flight while the current
+        // one is merged. `buffered` yields results in order either way.
+        let depth = if self.options.prefetch_chunks { 2 } else { 1 };
+        let stops_ref = &stops;
+        let mut osrm_chunks = stream::iter(chunk_ranges)
+            .map(|(start_idx, end_idx)| async move {
+                let result = self.fetch_osrm_route(&stops_ref[start_idx..end_idx]).await;
+                (start_idx, end_idx, result)
+            })
+            .buffered(depth);
+
+        while let Some((start_idx, end_idx, result)) = osrm_chunks.next().await {
+            let chunk = &stops[start_idx..end_idx];
+
+            if let Some(route) = result {
+                if let Some(version) = route.instance.data_version
+                    && !data_versions.contains(&version)
+                {
+                    data_versions.push(version);
+                }
+                let coords = route.coordinates;
                 let current_total = full_coordinates.len();
-                total_osrm_dist += chunk_dist;
-                total_osrm_duration += chunk_dur;
+                total_osrm_dist += route.distance;
+                total_osrm_duration += route.duration;
+                annotations = match (annotations, route.annotations) {
+                    (Some(mut merged), Some(chunk)) => {
+                        merged.append_chunk(chunk, current_total == 0);
+                        Some(merged)
+                    }
+                    _ => None,
+                };
 
                 // Merge Geometry
                 let (to_append, _offset) = if current_total > 0 {
@@ -108,11 +321,15 @@ impl BusRouteProcessor {
 
                 full_coordinates.extend_from_slice(to_append);
             } else {
-                log::warn!(
-                    "OSRM failed for chunk {}..{} (route_no: {}). Falling back to straight lines.",
-                    start_idx,
-                    end_idx - 1,
-                    route_no
+                annotations = None;
+                self.quality.warn(
+                    &route_id,
+                    format!(
+                        "OSRM failed for chunk {}..{} (route_no: {}). Falling back to straight lines.",
+                        start_idx,
+                        end_idx - 1,
+                        route_no
+                    ),
                 );
 
                 for (i, stop) in chunk.iter().enumerate() {
@@ -141,28 +358,144 @@ impl BusRouteProcessor {
                     }
                 }
             }
-            start_idx = end_idx - 1;
         }
+        drop(osrm_chunks);
 
-        while stop_to_coord.len() < stops.len() {
-            stop_to_coord.push(full_coordinates.len().saturating_sub(1));
+        // Every stop is in a chunk, so this only catches a stop that was still not mapped: it
+        // gets a straight segment to its own coordinate instead of sharing the last vertex
+        while let Some(stop) = stops.get(stop_to_coord.len()) {
+            if let Some(last) = full_coordinates.last() {
+                total_osrm_dist += crate::utils::geo::meters_between(
+                    last[0],
+                    last[1],
+                    stop.gps_long,
+                    stop.gps_lat,
+                );
+            }
+            full_coordinates.push(vec![stop.gps_long, stop.gps_lat]);
+            stop_to_coord.push(full_coordinates.len() - 1);
+        }
+
+        // Clip to the boundary; stops on dropped vertices move to the nearest kept vertex
+        if let Some(boundary) = &self.options.clip {
+            let (clipped, index_map) = boundary.clip_line(&full_coordinates);
+            if clipped.is_empty() {
+                if self.options.include_empty_routes {
+                    log::warn!(
+                        "Route {} ({}) lies entirely outside the clip boundary; writing it without geometry.",
+                        route_no,
+                        route_id
+                    );
+                    return Ok(Some(no_geometry_route(
+                        route_id,
+                        route_no,
+                        stops,
+                        raw_data.fetched_at,
+                        self.options.version,
+                    )));
+                }
+                log::warn!(
+                    "Route {} ({}) lies entirely outside the clip boundary; skipping.",
+                    route_no,
+                    route_id
+                );
+                return Ok(None);
+            }
+            if clipped.len() != full_coordinates.len() || index_map.contains(&None) {
+                for idx in &mut stop_to_coord {
+                    *idx = match index_map.get(*idx).copied().flatten() {
+                        Some(new_idx) => new_idx,
+                        None => full_coordinates
+                            .get(*idx)
+                            .and_then(|pt| find_nearest_coord_index((pt[0], pt[1]), &clipped))
+                            .unwrap_or(0),
+                    };
+                }
+
+                // OSRM's figures covered the removed parts; scale the time to the kept length
+                let (_, full_len) = calculate_metrics(&full_coordinates);
+                let (_, clipped_len) = calculate_metrics(&clipped);
+                if full_len > 0.0 {
+                    total_osrm_duration *= clipped_len / full_len;
+                }
+                total_osrm_dist = 0.0;
+                full_coordinates = clipped;
+                annotations = None;
+            }
         }
 
         // [OPTIMIZATION] Round coordinates to 6 decimal places to reduce file size
-        // This is important for web performance
-        for pt in &mut full_coordinates {
-            for c in pt.iter_mut() {
-                *c = (*c * 1_000_000.0).round() / 1_000_000.0;
+        // This is important for web performance. `--no-round` leaves them untouched.
+        if !self.options.no_round {
+            for pt in &mut full_coordinates {
+                for c in pt.iter_mut() {
+                    *c = (*c * 1_000_000.0).round() / 1_000_000.0;
+                }
             }
         }
         let optimized_coordinates = full_coordinates;
 
+        if self.options.osrm_annotations && !self.options.no_snap && annotations.is_none() {
+            log::warn!(
+                "Route {} ({}) has no usable OSRM annotations (unsupported, fallback or clipped).",
+                route_no,
+                route_id
+            );
+        }
+
         // Derive Indices & Metrics
         let turn_coord_idx = stop_to_coord
             .get(turn_idx)
             .cloned()
             .unwrap_or(optimized_coordinates.len() / 2);
 
+        // A stop mapped behind its predecessor breaks "next stop" logic in frontends
+        let backward = backward_stops(&stop_to_coord, turn_idx);
+        if !backward.is_empty() {
+            self.quality.warn(
+                &route_id,
+                format!(
+                    "Route {} ({}) stop_to_coord goes backward at stop(s) {:?}; check snapping.",
+                    route_no, route_id, backward
+                ),
+            );
+        }
+
+        // Flag likely snapping errors (wrong turns) outside the turnaround region
+        if self.options.check_geometry {
+            let skip = turn_coord_idx.saturating_sub(GEOMETRY_TURN_WINDOW)
+                ..turn_coord_idx + GEOMETRY_TURN_WINDOW;
+            let crossings = count_self_intersections(&optimized_coordinates, skip);
+            if crossings > GEOMETRY_MAX_SELF_INTERSECTIONS {
+                self.quality.warn(
+                    &route_id,
+                    format!(
+                        "Route {} ({}) geometry crosses itself {} times; check snapping.",
+                        route_no, route_id, crossings
+                    ),
+                );
+            }
+        }
+
+        // A long jump between vertices means two pieces of the line were stitched with a hole between them
+        if let Some(max_gap) = self.options.max_gap_m
+            && !self.options.no_snap
+            && let Some((idx, gap)) = longest_step(&optimized_coordinates)
+            && gap > max_gap
+        {
+            self.quality.warn(
+                &route_id,
+                format!(
+                    "Route {} ({}) geometry jumps {:.0} m between vertices {} and {}; check snapping.",
+                    route_no,
+                    route_id,
+                    gap,
+                    idx,
+                    idx + 1
+                ),
+            );
+        }
+
         // Calculate BBox & Distance
         // We use OSRM reported distance if available, otherwise fallback to polyline calculation
         let (bbox, geom_dist) = calculate_metrics(&optimized_coordinates);
@@ -172,23 +505,114 @@ impl BusRouteProcessor {
             geom_dist
         };
 
+        // A local route hundreds of kilometers long was snapped to the wrong place somewhere
+        if let Some(max_km) = self.options.max_dist_km
+            && final_dist > max_km * 1000.0
+        {
+            self.quality.warn(
+                &route_id,
+                format!(
+                    "Route {} ({}) is {:.1} km long, over --max-dist-km {}; check snapping.",
+                    route_no,
+                    route_id,
+                    final_dist / 1000.0,
+                    max_km
+                ),
+            );
+            if self.options.skip_over_max_dist {
+                anyhow::bail!("route {} exceeds --max-dist-km; not written", route_id);
+            }
+        }
+
+        // One-way distance as the geometry's share up to the turning point, so it agrees with
+        // `total_dist` whether that came from OSRM or from the polyline
+        let geometry_dist = cumulative_distances(&optimized_coordinates);
+        let oneway_dist = match (geometry_dist.get(turn_coord_idx), geometry_dist.last()) {
+            (Some(&to_turn), Some(&full)) if !is_loop && full > 0.0 => final_dist * to_turn / full,
+            _ => final_dist,
+        };
+
+        // Long stop-free stretches (e.g. a highway run), for frontends to draw differently
+        let segment_flags = self
+            .options
+            .express_threshold_m
+            .map(|threshold| express_segments(&geometry_dist, &stop_to_coord, threshold));
+
+        // Per-vertex distance, scaled like `oneway_dist` so it ends at `total_dist` and rounded
+        // like it
+        let cumulative_dist = self.options.cumulative_dist.then(|| {
+            let scale = match geometry_dist.last() {
+                Some(&full) if full > 0.0 => final_dist / full,
+                _ => 1.0,
+            };
+            geometry_dist
+                .iter()
+                .map(|d| (d * scale * 10.0).round() / 10.0)
+                .collect()
+        });
+
+        let (label_lon, label_lat) = representative_point(&optimized_coordinates);
+        let label_point = if self.options.no_round {
+            [label_lon, label_lat]
+        } else {
+            [
+                (label_lon * 1_000_000.0).round() / 1_000_000.0,
+                (label_lat * 1_000_000.0).round() / 1_000_000.0,
+            ]
+        };
+
+        // Where the bus turns around, as a point of the (already rounded) geometry
+        let turn_point = self
+            .options
+            .turn_point
+            .then(|| optimized_coordinates.get(turn_coord_idx))
+            .flatten()
+            .map(|pt| [pt[0], pt[1]]);
+
+        let osrm_data_version = (self.options.osrm_version_in_meta && !data_versions.is_empty())
+            .then(|| data_versions.join(", "));
+
         // Build Frontend Data Structures
-        let frontend_stops: Vec<FrontendStop> = stops
-            .into_iter()
-            .map(|s| FrontendStop {
-                id: s.node_id,
-                name: s.node_nm,
-                ord: s.node_ord,
-                up_down: s.up_down_cd,
-            })
-            .collect();
+        let mut frontend_stops: Vec<FrontendStop> =
+            stops.into_iter().map(FrontendStop::from).collect();
+        pair_stops(&mut frontend_stops);
+
+        // Without snapping the line runs through the stops, so there is no side to tell
+        if !self.options.no_snap {
+            let last = optimized_coordinates.len().saturating_sub(1);
+            let point = |idx: usize| {
+                let pt = &optimized_coordinates[idx];
+                (pt[0], pt[1])
+            };
+            for ((stop, &coord_idx), &original) in frontend_stops
+                .iter_mut()
+                .zip(&stop_to_coord)
+                .zip(&original_coords)
+            {
+                let (prev, next) = (coord_idx.saturating_sub(1), (coord_idx + 1).min(last));
+                if prev < next {
+                    stop.side = Some(side_of_line(original, point(prev), point(next)));
+                }
+            }
+        }
+
+        // Constant-speed arrival estimate from each stop's distance along the geometry
+        if let Some(speed_kmh) = self.options.speed_kmh {
+            let meters_per_sec = speed_kmh * 1000.0 / 3600.0;
+            for (stop, &coord_idx) in frontend_stops.iter_mut().zip(&stop_to_coord) {
+                let stop_dist = geometry_dist.get(coord_idx).copied().unwrap_or_default();
+                stop.eta_offset_s = Some((stop_dist / meters_per_sec).round());
+            }
+        }
 
         let derived_data = RouteFeatureCollection {
             type_: "FeatureCollection".to_string(),
+            crs: None,
             features: vec![RouteFeature {
                 type_: "Feature".to_string(),
                 id: route_id.clone(),
                 bbox: Some(bbox.to_vec()),
+                extra: Map::new(),
                 geometry: RouteGeometry {
                     type_: "LineString".to_string(),
                     coordinates: optimized_coordinates,
@@ -197,23 +621,982 @@ impl BusRouteProcessor {
                     route_id: route_id.clone(),
                     route_no,
                     stops: frontend_stops,
+                    label_point,
+                    annotations,
+                    segment_flags,
+                    extra: Map::new(),
                     indices: RouteIndices {
                         turn_idx: turn_coord_idx,
                         stop_to_coord,
+                        cumulative_dist,
+                        delta_encoded: false,
                     },
                     meta: FrontendMeta {
                         total_dist: final_dist,
+                        oneway_dist,
                         total_time: total_osrm_duration,
+                        fetched_at: utc_timestamp(&raw_data.fetched_at),
+                        version: self.options.version,
                         source_ver: raw_data.fetched_at,
+                        units: RouteUnits::default(),
+                        status: None,
+                        coord_order: None,
+                        stop_to_coord_encoding: None,
+                        turn_point,
+                        osrm_data_version,
                     },
                 },
             }],
         };
 
-        // Save Derived File
-        let output_path = self.derived_dir.join(format!("{}.geojson", route_id));
-        tokio::fs::write(output_path, serde_json::to_string(&derived_data)?).await?;
+        Ok(Some(derived_data))
+    }
+}
+
+/// The route's existing file where `sink` writes locally, if it can be read.
+///
+/// Sinks that keep no local file (`--stdout`, `--archive`) have nothing to compare against.
+async fn previous_output(sink: &dyn OutputSink, stem: &str) -> Option<RouteFeatureCollection> {
+    let path = sink.local_dir()?.join(format!("{}.geojson", stem));
+    let content = tokio::fs::read_to_string(path).await.ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+/// Reads a stationMap coordinate, accepting numbers stored as strings
+pub fn station_coord(station_info: &Value, key: &str, node_id: &str) -> Option<f64> {
+    let v = station_info.get(key)?;
+    let coord = parse_flexible_f64(v)?;
+    if v.is_string() {
+        log::debug!("Coerced string {} of station {} to a number.", key, node_id);
+    }
+    Some(coord)
+}
+
+/// Rewrites the route's direction codes to the canonical `0`/`1`.
+///
+/// With two distinct codes (e.g. `1`/`2`), the lower becomes `0` and the higher `1`. A single
+/// `0` or `1` is kept, since a one-direction route may be either leg; a lone `2` is the down leg
+/// of a `1`/`2` feed and becomes `1`, and any other lone code becomes `0`. More than two codes
+/// are left untouched since no mapping is safe. Returns the distinct codes observed if anything
+/// was rewritten.
+fn normalize_up_down(stops: &mut [RawStop]) -> Option<Vec<i64>> {
+    let mut observed: Vec<i64> = stops.iter().map(|s| s.up_down_cd).collect();
+    observed.sort_unstable();
+    observed.dedup();
+
+    let canonical: &[i64] = match observed.as_slice() {
+        [] | [0] | [1] => return None,
+        [2] => &[1],
+        [_] => &[0],
+        [_, _] => &[0, 1],
+        _ => {
+            log::warn!(
+                "Unexpected updowncd values {:?}; leaving them as is.",
+                observed
+            );
+            return None;
+        }
+    };
+    if observed == canonical {
+        return None;
+    }
+
+    for stop in stops.iter_mut() {
+        let pos = observed.binary_search(&stop.up_down_cd).unwrap();
+        stop.up_down_cd = canonical[pos];
+    }
+    Some(observed)
+}
+
+/// Sorts stops by `node_ord`, or by direction and then `node_ord` if each direction numbers its stops
+/// from the start (the directions' `node_ord` ranges overlap).
+///
+/// A global sort would interleave such directions. Returns whether the stops were sorted per direction.
+pub fn sort_stops(stops: &mut [RawStop]) -> bool {
+    let mut ranges: BTreeMap<i64, (i64, i64)> = BTreeMap::new();
+    for stop in stops.iter() {
+        let range = ranges
+            .entry(stop.up_down_cd)
+            .or_insert((stop.node_ord, stop.node_ord));
+        range.0 = range.0.min(stop.node_ord);
+        range.1 = range.1.max(stop.node_ord);
+    }
+
+    let restarts = ranges
+        .values()
+        .zip(ranges.values().skip(1))
+        .any(|(first, next)| next.0 <= first.1);
+    if restarts {
+        stops.sort_by_key(|s| (s.up_down_cd, s.node_ord));
+    } else {
+        stops.sort_by_key(|s| s.node_ord);
+    }
+    restarts
+}
+
+/// Stop index ranges of the OSRM requests for `len` stops, at most `size` stops each.
+///
+/// Consecutive ranges share their boundary stop. Each range starts before the last stop, so
+/// it holds at least two stops and no single stop is left over at the tail, whatever `len` is.
+pub fn osrm_chunk_ranges(len: usize, size: usize) -> Vec<(usize, usize)> {
+    let size = size.max(2);
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    while start + 1 < len {
+        let end = (start + size).min(len);
+        ranges.push((start, end));
+        start = end - 1;
+    }
+    ranges
+}
+
+/// `"express"` for each stretch between consecutive stops longer than `threshold_m` along the line, else `None`
+pub fn express_segments(
+    cumulative_dist: &[f64],
+    stop_to_coord: &[usize],
+    threshold_m: f64,
+) -> Vec<Option<String>> {
+    let dist_at = |idx: usize| cumulative_dist.get(idx).copied().unwrap_or_default();
+    stop_to_coord
+        .windows(2)
+        .map(|w| (dist_at(w[1]) - dist_at(w[0]) > threshold_m).then(|| "express".to_string()))
+        .collect()
+}
+
+/// Index of the last stop before the direction code changes (the last stop if it never does)
+pub fn find_turn_index(up_down: &[i64]) -> usize {
+    up_down
+        .windows(2)
+        .position(|w| w[0] != w[1])
+        .unwrap_or(up_down.len().saturating_sub(1))
+}
+
+/// Stops whose coordinate index is lower than the previous stop's.
+///
+/// One reset right after the turning stop `turn_idx` is allowed, since a route whose return
+/// leg reuses the outbound geometry may map back onto it.
+pub fn backward_stops(stop_to_coord: &[usize], turn_idx: usize) -> Vec<usize> {
+    stop_to_coord
+        .windows(2)
+        .enumerate()
+        .filter(|&(i, w)| w[1] < w[0] && i != turn_idx)
+        .map(|(i, _)| i + 1)
+        .collect()
+}
+
+/// Links each stop to the same `id` in the other direction, preferring the closest in the list
+pub fn pair_stops(stops: &mut [FrontendStop]) {
+    let pairs: Vec<Option<usize>> = (0..stops.len())
+        .map(|i| {
+            (0..stops.len())
+                .filter(|&j| stops[j].id == stops[i].id && stops[j].up_down != stops[i].up_down)
+                .min_by_key(|&j| j.abs_diff(i))
+        })
+        .collect();
+    for (stop, pair) in stops.iter_mut().zip(pairs) {
+        stop.paired_stop_idx = pair;
+    }
+}
+
+/// Stub route with an empty `LineString` that keeps the stop list and metadata
+fn no_geometry_route(
+    route_id: String,
+    route_no: String,
+    stops: Vec<RawStop>,
+    source_ver: String,
+    version: Option<u64>,
+) -> RouteFeatureCollection {
+    let label_point = stops
+        .first()
+        .map_or([0.0, 0.0], |s| [s.gps_long, s.gps_lat]);
+    let mut frontend_stops: Vec<FrontendStop> = stops.into_iter().map(FrontendStop::from).collect();
+    pair_stops(&mut frontend_stops);
+
+    RouteFeatureCollection {
+        type_: "FeatureCollection".to_string(),
+        crs: None,
+        features: vec![RouteFeature {
+            type_: "Feature".to_string(),
+            id: route_id.clone(),
+            bbox: None,
+            extra: Map::new(),
+            geometry: RouteGeometry {
+                type_: "LineString".to_string(),
+                coordinates: Vec::new(),
+            },
+            properties: RouteProperties {
+                route_id,
+                route_no,
+                stops: frontend_stops,
+                label_point,
+                annotations: None,
+                segment_flags: None,
+                extra: Map::new(),
+                indices: RouteIndices {
+                    turn_idx: 0,
+                    stop_to_coord: Vec::new(),
+                    cumulative_dist: None,
+                    delta_encoded: false,
+                },
+                meta: FrontendMeta {
+                    total_dist: 0.0,
+                    oneway_dist: 0.0,
+                    total_time: 0.0,
+                    fetched_at: utc_timestamp(&source_ver),
+                    version,
+                    source_ver,
+                    units: RouteUnits::default(),
+                    status: Some("no_geometry".to_string()),
+                    coord_order: None,
+                    stop_to_coord_encoding: None,
+                    turn_point: None,
+                    osrm_data_version: None,
+                },
+            },
+        }],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GEOMETRY_EQUAL_TOLERANCE_M;
+    use crate::route::model::RouteOptions;
+    use crate::route::test_support::{json_response, mock_server, test_processor};
+
+    fn raw_stop(id: &str, ord: i64, lon: f64, lat: f64) -> RawStop {
+        RawStop {
+            node_id: id.to_string(),
+            node_nm: id.to_string(),
+            node_ord: ord,
+            node_no: ord.to_string(),
+            gps_lat: lat,
+            gps_long: lon,
+            up_down_cd: 0,
+        }
+    }
+
+    #[test]
+    fn test_osrm_chunk_ranges_leave_no_single_stop_tail() {
+        // One stop past a full chunk: the tail chunk still holds two stops
+        assert_eq!(osrm_chunk_ranges(121, 120), vec![(0, 120), (119, 121)]);
+        assert_eq!(osrm_chunk_ranges(1, 120), vec![]);
+
+        for size in [2, 3, 120] {
+            for len in 2..=3 * size + 2 {
+                let ranges = osrm_chunk_ranges(len, size);
+                assert_eq!(ranges.first().unwrap().0, 0);
+                assert_eq!(ranges.last().unwrap().1, len, "len {} size {}", len, size);
+                assert!(ranges.iter().all(|(s, e)| e - s >= 2 && e - s <= size));
+                assert!(ranges.windows(2).all(|w| w[1].0 == w[0].1 - 1));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_derive_flags_express_segments() {
+        let processor = test_processor(
+            "http://127.0.0.1:9",
+            RouteOptions {
+                no_snap: true,
+                express_threshold_m: Some(1000.0),
+                ..RouteOptions::default()
+            },
+        );
+        let raw = RawRouteFile {
+            route_id: "WJB251000001".to_string(),
+            route_no: "1".to_string(),
+            fetched_at: "".to_string(),
+            route_tp: None,
+            // About 90 m, 1.8 km and 90 m apart
+            stops: vec![
+                raw_stop("A", 1, 127.920, 37.34),
+                raw_stop("B", 2, 127.921, 37.34),
+                raw_stop("C", 3, 127.941, 37.34),
+                raw_stop("D", 4, 127.942, 37.34),
+            ],
+        };
+
+        let derived = processor
+            .derive(raw, &StationMap::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            derived.features[0].properties.segment_flags,
+            Some(vec![None, Some("express".to_string()), None])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_derive_flags_routes_over_max_dist() {
+        let options = RouteOptions {
+            no_snap: true,
+            max_dist_km: Some(1.0),
+            ..RouteOptions::default()
+        };
+        let raw = || RawRouteFile {
+            route_id: "WJB251000001".to_string(),
+            route_no: "1".to_string(),
+            fetched_at: "".to_string(),
+            route_tp: None,
+            // About 1.8 km apart
+            stops: vec![
+                raw_stop("A", 1, 127.92, 37.34),
+                raw_stop("B", 2, 127.94, 37.34),
+            ],
+        };
+
+        let processor = test_processor("http://127.0.0.1:9", options.clone());
+        let derived = processor.derive(raw(), &StationMap::default()).await;
+        assert!(derived.unwrap().is_some());
+        let issues = processor.quality.take();
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].1.contains("over --max-dist-km 1"));
+
+        let processor = test_processor(
+            "http://127.0.0.1:9",
+            RouteOptions {
+                skip_over_max_dist: true,
+                ..options
+            },
+        );
+        assert!(
+            processor
+                .derive(raw(), &StationMap::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_derive_maps_last_stop_after_full_chunk() {
+        // Nothing listens here, so every chunk falls back to straight lines
+        let processor = test_processor("http://127.0.0.1:9", RouteOptions::default());
+        let stops: Vec<RawStop> = (0..OSRM_CHUNK_SIZE + 1)
+            .map(|i| {
+                raw_stop(
+                    &format!("S{}", i),
+                    i as i64 + 1,
+                    127.9 + 0.001 * i as f64,
+                    37.34,
+                )
+            })
+            .collect();
+        let last = (
+            stops[OSRM_CHUNK_SIZE].gps_long,
+            stops[OSRM_CHUNK_SIZE].gps_lat,
+        );
+        let raw = RawRouteFile {
+            route_id: "WJB251000001".to_string(),
+            route_no: "1".to_string(),
+            fetched_at: "".to_string(),
+            route_tp: None,
+            stops,
+        };
+
+        let derived = processor
+            .derive(raw, &StationMap::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let feature = &derived.features[0];
+        let coords = &feature.geometry.coordinates;
+        assert_eq!(coords.len(), OSRM_CHUNK_SIZE + 1);
+        assert_eq!(
+            feature.properties.indices.stop_to_coord,
+            (0..OSRM_CHUNK_SIZE + 1).collect::<Vec<_>>()
+        );
+        let end = coords.last().unwrap();
+        assert!((end[0] - last.0).abs() < 1e-6 && (end[1] - last.1).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn test_derive_no_snap_connects_stops() {
+        // Nothing listens here, so any OSRM request would fall back and be recorded
+        let processor = test_processor(
+            "http://127.0.0.1:9",
+            RouteOptions {
+                no_snap: true,
+                ..RouteOptions::default()
+            },
+        );
+        let raw = RawRouteFile {
+            route_id: "WJB251000001".to_string(),
+            route_no: "1".to_string(),
+            fetched_at: "".to_string(),
+            route_tp: None,
+            stops: vec![
+                raw_stop("A", 1, 127.92, 37.34),
+                raw_stop("B", 2, 127.93, 37.34),
+                raw_stop("C", 3, 127.93, 37.35),
+            ],
+        };
+
+        let derived = processor
+            .derive(raw, &StationMap::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let feature = &derived.features[0];
+        assert_eq!(feature.geometry.coordinates.len(), 3);
+        assert_eq!(feature.properties.indices.stop_to_coord, vec![0, 1, 2]);
+        assert!(feature.properties.meta.total_dist > 1000.0);
+        assert!(processor.quality.take().is_empty());
+    }
+
+    /// Out along one street and back to the first stop, turning at B
+    fn out_and_back() -> RawRouteFile {
+        RawRouteFile {
+            route_id: "WJB251000002".to_string(),
+            route_no: "2".to_string(),
+            fetched_at: "".to_string(),
+            route_tp: None,
+            stops: vec![
+                raw_stop("A", 1, 127.92, 37.34),
+                raw_stop("B", 2, 127.93, 37.34),
+                RawStop {
+                    up_down_cd: 1,
+                    ..raw_stop("A", 3, 127.92, 37.34)
+                },
+            ],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_derive_oneway_dist_runs_to_the_turn() {
+        let processor = test_processor(
+            "http://127.0.0.1:9",
+            RouteOptions {
+                no_snap: true,
+                ..RouteOptions::default()
+            },
+        );
+
+        // One direction only: a loop, so one way is the whole route
+        let raw = RawRouteFile {
+            route_id: "WJB251000001".to_string(),
+            route_no: "1".to_string(),
+            fetched_at: "".to_string(),
+            route_tp: None,
+            stops: vec![
+                raw_stop("A", 1, 127.92, 37.34),
+                raw_stop("B", 2, 127.93, 37.34),
+                raw_stop("C", 3, 127.93, 37.35),
+            ],
+        };
+        let derived = processor
+            .derive(raw, &StationMap::default())
+            .await
+            .unwrap()
+            .unwrap();
+        let meta = &derived.features[0].properties.meta;
+        assert_eq!(meta.oneway_dist, meta.total_dist);
+
+        // Out and back along the same street: one way is half the route
+        let derived = processor
+            .derive(out_and_back(), &StationMap::default())
+            .await
+            .unwrap()
+            .unwrap();
+        let meta = &derived.features[0].properties.meta;
+        assert!(meta.oneway_dist > 0.0);
+        assert!((meta.oneway_dist * 2.0 - meta.total_dist).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn test_derive_turn_point_is_the_coordinate_at_the_turn() {
+        let processor = test_processor(
+            "http://127.0.0.1:9",
+            RouteOptions {
+                no_snap: true,
+                turn_point: true,
+                ..RouteOptions::default()
+            },
+        );
+
+        let derived = processor
+            .derive(out_and_back(), &StationMap::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let feature = &derived.features[0];
+        let turn_idx = feature.properties.indices.turn_idx;
+        let turn_point = feature.properties.meta.turn_point.unwrap();
+        assert_eq!(turn_point.to_vec(), feature.geometry.coordinates[turn_idx]);
+        assert_eq!(turn_point, [127.93, 37.34]);
+    }
+
+    #[tokio::test]
+    async fn test_rederive_keeps_output_within_tolerance() {
+        use crate::route::output::{ArchiveSink, FileSink};
+
+        let dir = std::env::temp_dir().join(format!("polly-tolerance-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut processor = test_processor(
+            "http://127.0.0.1:9",
+            RouteOptions {
+                no_snap: true,
+                equal_tolerance_m: GEOMETRY_EQUAL_TOLERANCE_M,
+                ..RouteOptions::default()
+            },
+        );
+        processor.derived_dir = dir.clone();
+        let sink = FileSink { dir: dir.clone() };
+        let raw_path = dir.join("1_WJB251000001.json");
+        let output_path = dir.join("WJB251000001.geojson");
+        let (station_map, file_names) = (StationMap::default(), FileNames::default());
+
+        let write_raw_with_b_at = |lon: f64, fetched_at: &str| {
+            let raw = RawRouteFile {
+                route_id: "WJB251000001".to_string(),
+                route_no: "1".to_string(),
+                fetched_at: fetched_at.to_string(),
+                route_tp: None,
+                stops: vec![
+                    raw_stop("A", 1, 127.92, 37.34),
+                    raw_stop("B", 2, lon, 37.34),
+                    raw_stop("C", 3, 127.93, 37.35),
+                ],
+            };
+            std::fs::write(&raw_path, serde_json::to_string(&raw).unwrap()).unwrap();
+        };
+        let derive_with_b_at = |lon: f64, fetched_at: &str| {
+            write_raw_with_b_at(lon, fetched_at);
+            processor.process_raw_to_derived(&raw_path, &station_map, &file_names, &sink, None)
+        };
+
+        derive_with_b_at(127.93, "2025-01-01").await.unwrap();
+        let first = std::fs::read_to_string(&output_path).unwrap();
+
+        // About 0.3 m: the previous output is written back byte for byte
+        derive_with_b_at(127.930_003, "2025-01-02").await.unwrap();
+        assert_eq!(std::fs::read_to_string(&output_path).unwrap(), first);
+
+        // About 9 m: a real change
+        derive_with_b_at(127.930_1, "2025-01-03").await.unwrap();
+        let moved = std::fs::read_to_string(&output_path).unwrap();
+        assert_ne!(moved, first);
+        assert!(moved.contains("2025-01-03"));
+
+        // An archive keeps no local files, so the one left in the directory is not its previous output
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let archive = ArchiveSink { tx };
+        write_raw_with_b_at(127.930_1, "2025-01-04");
+        processor
+            .process_raw_to_derived(&raw_path, &station_map, &file_names, &archive, None)
+            .await
+            .unwrap();
+        let (_, archived) = rx.try_recv().unwrap();
+        assert!(String::from_utf8(archived).unwrap().contains("2025-01-04"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_route_is_not_uploaded_again() {
+        use crate::route::output::{FileSink, MultiSink, OutputSink};
+        use futures::future::BoxFuture;
+        use std::sync::{Arc, Mutex};
+
+        /// Records uploads, and skips unchanged routes like `S3Sink`
+        struct RemoteSink(Arc<Mutex<Vec<String>>>);
+        impl OutputSink for RemoteSink {
+            fn write_route<'a>(
+                &'a self,
+                name: &'a str,
+                _collection: &'a RouteFeatureCollection,
+            ) -> BoxFuture<'a, Result<()>> {
+                self.0.lock().unwrap().push(name.to_string());
+                Box::pin(async { Ok(()) })
+            }
+
+            fn write_unchanged_route<'a>(
+                &'a self,
+                _name: &'a str,
+                _collection: &'a RouteFeatureCollection,
+            ) -> BoxFuture<'a, Result<()>> {
+                Box::pin(async { Ok(()) })
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("polly-unchanged-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let processor = test_processor(
+            "http://127.0.0.1:9",
+            RouteOptions {
+                no_snap: true,
+                equal_tolerance_m: GEOMETRY_EQUAL_TOLERANCE_M,
+                ..RouteOptions::default()
+            },
+        );
+        let uploads = Arc::new(Mutex::new(Vec::new()));
+        let sink = MultiSink(vec![
+            Box::new(FileSink { dir: dir.clone() }),
+            Box::new(RemoteSink(uploads.clone())),
+        ]);
+        let raw_path = dir.join("1_WJB251000001.json");
+        let raw = RawRouteFile {
+            route_id: "WJB251000001".to_string(),
+            route_no: "1".to_string(),
+            fetched_at: "".to_string(),
+            route_tp: None,
+            stops: vec![
+                raw_stop("A", 1, 127.92, 37.34),
+                raw_stop("B", 2, 127.93, 37.34),
+            ],
+        };
+        std::fs::write(&raw_path, serde_json::to_string(&raw).unwrap()).unwrap();
+        let (station_map, file_names) = (StationMap::default(), FileNames::default());
+
+        for _ in 0..2 {
+            processor
+                .process_raw_to_derived(&raw_path, &station_map, &file_names, &sink, None)
+                .await
+                .unwrap();
+        }
+        assert_eq!(*uploads.lock().unwrap(), ["WJB251000001"]);
+        assert!(dir.join("WJB251000001.geojson").exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_dedup_geometry_references_first_variant() {
+        use crate::route::output::FileSink;
+
+        let dir = std::env::temp_dir().join(format!("polly-dedup-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut processor = test_processor(
+            "http://127.0.0.1:9",
+            RouteOptions {
+                no_snap: true,
+                ..RouteOptions::default()
+            },
+        );
+        processor.derived_dir = dir.clone();
+        let sink = FileSink { dir: dir.clone() };
+        let (station_map, file_names) = (StationMap::default(), FileNames::default());
+        let owners = GeometryOwners::default();
+
+        let mut entries = Vec::new();
+        for (route_id, last_lat) in [("R1", 37.35), ("R2", 37.35), ("R3", 37.36)] {
+            let raw = RawRouteFile {
+                route_id: route_id.to_string(),
+                route_no: "1".to_string(),
+                fetched_at: "".to_string(),
+                route_tp: None,
+                stops: vec![
+                    raw_stop("A", 1, 127.92, 37.34),
+                    raw_stop("B", 2, 127.93, last_lat),
+                ],
+            };
+            let raw_path = dir.join(format!("1_{}.json", route_id));
+            std::fs::write(&raw_path, serde_json::to_string(&raw).unwrap()).unwrap();
+            let entry = processor
+                .process_raw_to_derived(&raw_path, &station_map, &file_names, &sink, Some(&owners))
+                .await
+                .unwrap();
+            entries.push(entry);
+        }
+
+        assert_eq!(entries[0].geometry_ref, None);
+        assert_eq!(
+            entries[1].geometry_ref.as_deref(),
+            Some("polylines/R1.geojson")
+        );
+        assert_eq!(entries[2].geometry_ref, None);
+
+        let read = |name: &str| -> Value {
+            serde_json::from_str(&std::fs::read_to_string(dir.join(name)).unwrap()).unwrap()
+        };
+        let r2 = read("R2.geojson");
+        assert_eq!(r2["features"][0]["geometry"]["coordinates"], json!([]));
+        assert_eq!(r2["features"][0]["geometry_ref"], "polylines/R1.geojson");
+        assert_eq!(
+            r2["features"][0]["properties"]["stops"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            read("R1.geojson")["features"][0]["geometry"]["coordinates"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_backward_stops_allows_reset_at_turn() {
+        // Stop 2 is the turn, so stop 3 may restart; stop 5 going back is a mismatch
+        let stop_to_coord = [0, 4, 9, 2, 6, 5, 8];
+        assert_eq!(backward_stops(&stop_to_coord, 2), vec![5]);
+        assert_eq!(backward_stops(&stop_to_coord, 0), vec![3, 5]);
+        assert!(backward_stops(&[0, 0, 3], 2).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_restarting_node_ord_keeps_direction_legs() {
+        // Each direction numbers its stops from 1, so a global sort interleaves them
+        let stop = |id: &str, ord: i64, up_down_cd: i64, lon: f64| RawStop {
+            up_down_cd,
+            ..raw_stop(id, ord, lon, 37.34)
+        };
+        let mut stops = vec![
+            stop("A", 1, 0, 127.920),
+            stop("B", 2, 0, 127.925),
+            stop("C", 3, 0, 127.930),
+            stop("c", 1, 1, 127.931),
+            stop("b", 2, 1, 127.926),
+            stop("a", 3, 1, 127.921),
+        ];
+        stops.sort_by_key(|s| s.node_ord);
+        assert!(sort_stops(&mut stops));
+        let ids: Vec<&str> = stops.iter().map(|s| s.node_id.as_str()).collect();
+        assert_eq!(ids, ["A", "B", "C", "c", "b", "a"]);
+
+        // Consecutive numbering across directions keeps the global order
+        let mut numbered: Vec<RawStop> = (1..=4)
+            .map(|ord| stop("S", ord, i64::from(ord > 2), 127.92))
+            .collect();
+        assert!(!sort_stops(&mut numbered));
+
+        // An interleaved cache file is repaired when derived
+        let processor = test_processor(
+            "http://127.0.0.1:9",
+            RouteOptions {
+                no_snap: true,
+                ..RouteOptions::default()
+            },
+        );
+        stops.sort_by_key(|s| s.node_ord);
+        let raw = RawRouteFile {
+            route_id: "WJB251000001".to_string(),
+            route_no: "1".to_string(),
+            fetched_at: "".to_string(),
+            route_tp: None,
+            stops,
+        };
+        let derived = processor
+            .derive(raw, &StationMap::default())
+            .await
+            .unwrap()
+            .unwrap();
+        let properties = &derived.features[0].properties;
+        let up_down: Vec<i64> = properties.stops.iter().map(|s| s.up_down).collect();
+        assert_eq!(up_down, [0, 0, 0, 1, 1, 1]);
+        assert_eq!(properties.indices.turn_idx, 2);
+    }
+
+    #[test]
+    fn test_normalize_up_down_one_two_encoding() {
+        let mut stops: Vec<RawStop> = (1..=5)
+            .map(|ord| RawStop {
+                up_down_cd: if ord <= 3 { 1 } else { 2 },
+                ..raw_stop("S", ord, 127.9, 37.3)
+            })
+            .collect();
+
+        assert_eq!(normalize_up_down(&mut stops), Some(vec![1, 2]));
+        let up_down: Vec<i64> = stops.iter().map(|s| s.up_down_cd).collect();
+        assert_eq!(up_down, vec![0, 0, 0, 1, 1]);
+        assert_eq!(find_turn_index(&up_down), 2);
+
+        // Already canonical: nothing to report
+        assert_eq!(normalize_up_down(&mut stops), None);
+
+        // A down-only route keeps its direction on a 0/1 feed, and maps to it on a 1/2 feed
+        let mut down_only: Vec<RawStop> = (1..=3)
+            .map(|ord| RawStop {
+                up_down_cd: 1,
+                ..raw_stop("S", ord, 127.9, 37.3)
+            })
+            .collect();
+        assert_eq!(normalize_up_down(&mut down_only), None);
+        down_only.iter_mut().for_each(|s| s.up_down_cd = 2);
+        assert_eq!(normalize_up_down(&mut down_only), Some(vec![2]));
+        assert!(down_only.iter().all(|s| s.up_down_cd == 1));
+    }
+
+    #[tokio::test]
+    async fn test_derive_clipped_out_route_honours_include_empty_routes() {
+        let boundary = crate::utils::boundary::Boundary::from_geojson(&serde_json::json!({
+            "type": "Polygon",
+            "coordinates": [[[126.0, 35.0], [126.1, 35.0], [126.1, 35.1], [126.0, 35.1], [126.0, 35.0]]]
+        }))
+        .unwrap();
+        let raw = || RawRouteFile {
+            route_id: "WJB251000001".to_string(),
+            route_no: "1".to_string(),
+            fetched_at: "".to_string(),
+            route_tp: None,
+            stops: vec![
+                raw_stop("A", 1, 127.92, 37.34),
+                raw_stop("B", 2, 127.93, 37.34),
+            ],
+        };
+        let options = RouteOptions {
+            no_snap: true,
+            clip: Some(std::sync::Arc::new(boundary)),
+            version: Some(7),
+            ..RouteOptions::default()
+        };
+
+        let processor = test_processor("http://127.0.0.1:9", options.clone());
+        let derived = processor
+            .derive(raw(), &StationMap::default())
+            .await
+            .unwrap();
+        assert!(derived.is_none());
+
+        let processor = test_processor(
+            "http://127.0.0.1:9",
+            RouteOptions {
+                include_empty_routes: true,
+                ..options
+            },
+        );
+        let derived = processor
+            .derive(raw(), &StationMap::default())
+            .await
+            .unwrap()
+            .unwrap();
+        let feature = &derived.features[0];
+        assert!(feature.geometry.coordinates.is_empty());
+        assert_eq!(feature.properties.meta.version, Some(7));
+        assert_eq!(feature.properties.stops.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_derive_no_round_keeps_osrm_coordinates() {
+        let osrm_coords: Vec<Vec<f64>> = vec![
+            vec![127.920_123_456_789_01, 37.340_987_654_321_09],
+            vec![127.922_222_222_222_22, 37.341_111_111_111_11],
+            vec![127.925_000_000_000_03, 37.342_999_999_999_99],
+        ];
+
+        let body = serde_json::json!({ "routes": [{
+            "geometry": { "coordinates": osrm_coords },
+            "distance": 500.0,
+            "duration": 60.0
+        }] })
+        .to_string();
+        let url = mock_server(move |_, _| json_response(&body)).await;
+
+        let processor = test_processor(
+            &url,
+            RouteOptions {
+                no_round: true,
+                ..RouteOptions::default()
+            },
+        );
+        let raw = RawRouteFile {
+            route_id: "WJB251000001".to_string(),
+            route_no: "1".to_string(),
+            fetched_at: "".to_string(),
+            route_tp: None,
+            stops: vec![
+                raw_stop("A", 1, 127.9201, 37.3410),
+                raw_stop("B", 2, 127.9250, 37.3430),
+            ],
+        };
+
+        let derived = processor
+            .derive(raw, &StationMap::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let coords = &derived.features[0].geometry.coordinates;
+        assert_eq!(coords.len(), osrm_coords.len());
+        for (got, want) in coords.iter().zip(&osrm_coords) {
+            for (g, w) in got.iter().zip(want) {
+                assert_eq!(g.to_bits(), w.to_bits());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_derive_cumulative_dist_ends_at_osrm_distance() {
+        // About 440 m of polyline, for which OSRM reports 500 m
+        let body = serde_json::json!({ "routes": [{
+            "geometry": { "coordinates": [[127.920, 37.341], [127.922, 37.341], [127.925, 37.341]] },
+            "distance": 500.0,
+            "duration": 60.0
+        }] })
+        .to_string();
+        let url = mock_server(move |_, _| json_response(&body)).await;
+
+        let processor = test_processor(
+            &url,
+            RouteOptions {
+                cumulative_dist: true,
+                ..RouteOptions::default()
+            },
+        );
+        let raw = RawRouteFile {
+            route_id: "WJB251000001".to_string(),
+            route_no: "1".to_string(),
+            fetched_at: "".to_string(),
+            route_tp: None,
+            stops: vec![
+                raw_stop("A", 1, 127.920, 37.341),
+                raw_stop("B", 2, 127.925, 37.341),
+            ],
+        };
+
+        let derived = processor
+            .derive(raw, &StationMap::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let props = &derived.features[0].properties;
+        let cumulative = props.indices.cumulative_dist.as_ref().unwrap();
+        assert_eq!(props.meta.total_dist, 500.0);
+        assert_eq!(cumulative.last(), Some(&props.meta.total_dist));
+        assert_eq!(cumulative[0], 0.0);
+    }
+
+    #[test]
+    fn test_pair_stops_links_directions() {
+        let stop = |id: &str, up_down| FrontendStop {
+            id: id.to_string(),
+            name: String::new(),
+            ord: 0,
+            up_down,
+            eta_offset_s: None,
+            paired_stop_idx: None,
+            side: None,
+        };
+        let mut stops = vec![
+            stop("A", 0),
+            stop("B", 0),
+            stop("C", 0),
+            stop("C", 1),
+            stop("B", 1),
+            stop("A", 1),
+            stop("D", 1),
+        ];
+
+        pair_stops(&mut stops);
 
-        Ok(())
+        let pairs: Vec<_> = stops.iter().map(|s| s.paired_stop_idx).collect();
+        assert_eq!(
+            pairs,
+            vec![Some(5), Some(4), Some(3), Some(2), Some(1), Some(0), None]
+        );
     }
 }
@@ -0,0 +1,127 @@
+//! Per-city TAGO service keys (`--profile`).
+//!
+//! A profile is a JSON file mapping city codes to the service key registered for them,
+//! either inline or in a separate key file (relative paths are resolved against the
+//! profile's directory):
+//!
+//! ```json
+//! { "cities": {
+//!     "32020": { "service_key_file": "keys/wonju.key" },
+//!     "32010": { "service_key": "..." }
+//! } }
+//! ```
+//!
+//! A city listed in the profile overrides `DATA_GO_KR_SERVICE_KEY`; other cities fall
+//! back to it. Keys never appear in logs or error messages.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde::Deserialize;
+
+/// Where one city's service key comes from
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CityProfile {
+    service_key: Option<String>,
+    service_key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Profile {
+    #[serde(default)]
+    cities: HashMap<String, CityProfile>,
+    /// Directory of the profile file, for relative key files
+    #[serde(skip)]
+    base_dir: PathBuf,
+}
+
+impl Profile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read profile {}", path.display()))?;
+        // serde_json messages may quote a misplaced key ("invalid type: string ..."), so only
+        // the position is reported
+        let mut profile: Self = serde_json::from_str(&content).map_err(|e| {
+            anyhow!(
+                "Failed to parse profile {} at line {}, column {}",
+                path.display(),
+                e.line(),
+                e.column()
+            )
+        })?;
+        profile.base_dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        Ok(profile)
+    }
+
+    /// The service key registered for `city_code`, if the profile lists the city
+    pub fn service_key(&self, city_code: &str) -> Result<Option<String>> {
+        let Some(city) = self.cities.get(city_code) else {
+            return Ok(None);
+        };
+
+        let key = match (&city.service_key, &city.service_key_file) {
+            (Some(key), None) => key.trim().to_string(),
+            (None, Some(file)) => {
+                let path = self.base_dir.join(file);
+                fs::read_to_string(&path)
+                    .with_context(|| {
+                        format!(
+                            "Failed to read the service key file of city {}: {}",
+                            city_code,
+                            path.display()
+                        )
+                    })?
+                    .trim()
+                    .to_string()
+            }
+            _ => bail!(
+                "City {} in the profile needs exactly one of `service_key` and `service_key_file`",
+                city_code
+            ),
+        };
+        if key.is_empty() {
+            bail!("The profile's service key for city {} is empty", city_code);
+        }
+        Ok(Some(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_resolves_inline_and_file_keys() {
+        let dir = std::env::temp_dir().join(format!("polly-profile-{}", std::process::id()));
+        fs::create_dir_all(dir.join("keys")).unwrap();
+        fs::write(dir.join("keys/wonju.key"), "FILE-KEY\n").unwrap();
+        let path = dir.join("profile.json");
+        fs::write(
+            &path,
+            r#"{ "cities": {
+                "32020": { "service_key_file": "keys/wonju.key" },
+                "32010": { "service_key": "INLINE-KEY" },
+                "32030": { "service_key": "A", "service_key_file": "keys/wonju.key" }
+            } }"#,
+        )
+        .unwrap();
+
+        let profile = Profile::load(&path).unwrap();
+        assert_eq!(profile.service_key("32020").unwrap().unwrap(), "FILE-KEY");
+        assert_eq!(profile.service_key("32010").unwrap().unwrap(), "INLINE-KEY");
+        assert_eq!(profile.service_key("11").unwrap(), None);
+
+        let err = format!("{:#}", profile.service_key("32030").unwrap_err());
+        assert!(!err.contains("FILE-KEY") && !err.contains("\"A\""));
+
+        // A key in the wrong place is not echoed back
+        fs::write(&path, r#"{ "cities": { "32020": "SECRET-KEY" } }"#).unwrap();
+        let err = format!("{:#}", Profile::load(&path).unwrap_err());
+        assert!(!err.contains("SECRET-KEY"), "{}", err);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
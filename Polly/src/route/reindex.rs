@@ -0,0 +1,227 @@
+//! Repair of `stop_to_coord` / `turn_idx` in already derived GeoJSON files.
+//!
+//! Recomputes the indices against the existing geometry using stop coordinates
+//! from `stationMap.json`, so bad indices can be fixed without re-snapping.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::route::model::RouteFeatureCollection;
+use crate::route::process::{backward_stops, find_turn_index, station_coord};
+use crate::route::stations::StationMap;
+use crate::utils::geo::find_nearest_coord_index;
+
+/// Recomputes the indices of a derived file in place. Returns whether the file changed.
+pub async fn reindex_file(path: &Path, station_map: &StationMap) -> Result<bool> {
+    let content = tokio::fs::read_to_string(path).await?;
+    let mut collection: RouteFeatureCollection = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    let mut changed = false;
+
+    for feature in &mut collection.features {
+        // Stations are matched in `[lon, lat]`; the indices hold in either order
+        let line = &feature.lon_lat();
+        let props = &mut feature.properties;
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let points: Vec<Option<(f64, f64)>> = props
+            .stops
+            .iter()
+            .map(|stop| {
+                let point = station_map.get(&stop.id).and_then(|info| {
+                    Some((
+                        station_coord(&info, "gpslong", &stop.id)?,
+                        station_coord(&info, "gpslati", &stop.id)?,
+                    ))
+                });
+                if point.is_none() {
+                    log::warn!(
+                        "Stop {} of route {} not in stationMap; keeping previous index.",
+                        stop.id,
+                        props.route_id
+                    );
+                }
+                point
+            })
+            .collect();
+
+        // Search forward from the previous stop so that stops on the return leg
+        // are not matched to the same road on the outbound leg.
+        let mut stop_to_coord = Vec::with_capacity(points.len());
+        let mut from = 0;
+        for (i, point) in points.iter().enumerate() {
+            let nearest = |p, end: usize| {
+                find_nearest_coord_index(p, &line[from..end]).map_or(from, |i| from + i)
+            };
+            // A stop never matches past the next one. Otherwise a stop with a wrong coordinate,
+            // or one matched to a later pass of a looping or out-and-back line, would drag every
+            // following stop along with it.
+            let idx = match (*point, points[i + 1..].iter().flatten().next()) {
+                (Some(p), Some(&next)) => nearest(p, nearest(next, line.len()) + 1),
+                (Some(p), None) => nearest(p, line.len()),
+                (None, _) => from,
+            };
+
+            stop_to_coord.push(idx);
+            from = idx;
+        }
+
+        let up_down: Vec<i64> = props.stops.iter().map(|s| s.up_down).collect();
+        let turn_stop = find_turn_index(&up_down);
+
+        let backward = backward_stops(&props.indices.stop_to_coord, turn_stop);
+        if !backward.is_empty() {
+            log::warn!(
+                "Route {}: existing stop_to_coord goes backward at stop(s) {:?}.",
+                props.route_id,
+                backward
+            );
+        }
+
+        let turn_idx = stop_to_coord
+            .get(turn_stop)
+            .cloned()
+            .unwrap_or(line.len() / 2);
+
+        if stop_to_coord != props.indices.stop_to_coord || turn_idx != props.indices.turn_idx {
+            props.indices.stop_to_coord = stop_to_coord;
+            props.indices.turn_idx = turn_idx;
+            changed = true;
+        }
+    }
+
+    if changed {
+        tokio::fs::write(path, serde_json::to_string(&collection)?).await?;
+    }
+
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
+    use std::collections::HashMap;
+
+    /// Reindexes a single-feature file with `line` and `stops` as `(id, lon, lat, ud)`
+    async fn reindex(line: &[[f64; 2]], stops: &[(&str, f64, f64, i64)]) -> Vec<usize> {
+        reindex_written_as(line, stops, None).await
+    }
+
+    /// Like [`reindex`], with the line written in `coord_order`
+    async fn reindex_written_as(
+        line: &[[f64; 2]],
+        stops: &[(&str, f64, f64, i64)],
+        coord_order: Option<&str>,
+    ) -> Vec<usize> {
+        let path = std::env::temp_dir().join(format!(
+            "polly-reindex-{}-{}-{}.geojson",
+            std::process::id(),
+            line.len() + stops.len(),
+            coord_order.unwrap_or("lonlat")
+        ));
+        let collection = json!({
+            "type": "FeatureCollection",
+            "features": [{
+                "type": "Feature",
+                "id": "R1",
+                "geometry": { "type": "LineString", "coordinates": line },
+                "properties": {
+                    "route_id": "R1",
+                    "route_no": "1",
+                    "stops": stops.iter().enumerate().map(|(i, (id, _, _, ud))| {
+                        json!({ "id": id, "name": id, "ord": i + 1, "ud": ud })
+                    }).collect::<Vec<_>>(),
+                    "turn_idx": 0,
+                    "stop_to_coord": vec![0; stops.len()],
+                    "total_dist": 0.0,
+                    "oneway_dist": 0.0,
+                    "total_time": 0.0,
+                    "source_ver": "",
+                    "coord_order": coord_order,
+                },
+            }],
+        });
+        std::fs::write(&path, collection.to_string()).unwrap();
+        let stations: HashMap<String, Value> = stops
+            .iter()
+            .map(|(id, lon, lat, _)| (id.to_string(), json!({ "gpslong": lon, "gpslati": lat })))
+            .collect();
+
+        assert!(
+            reindex_file(&path, &StationMap::Memory(stations))
+                .await
+                .unwrap()
+        );
+        let fixed: RouteFeatureCollection =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        fixed.features[0].properties.indices.stop_to_coord.clone()
+    }
+
+    /// Out along one carriageway to 127.910 and back along the other, 2 m to the north
+    fn out_and_back() -> Vec<[f64; 2]> {
+        let out = (0..=10).map(|i| [127.900 + i as f64 * 0.001, 37.34]);
+        let back = (0..=10).map(|i| [127.910 - i as f64 * 0.001, 37.34002]);
+        out.chain(back).collect()
+    }
+
+    #[tokio::test]
+    async fn test_reindex_out_and_back_keeps_stops_on_their_leg() {
+        // The middle outbound stop sits a little closer to the return carriageway
+        let stops = [
+            ("A", 127.902, 37.339995, 0),
+            ("B", 127.905, 37.340012, 0),
+            ("C", 127.908, 37.339995, 0),
+            ("C2", 127.908, 37.34004, 1),
+            ("B2", 127.905, 37.34004, 1),
+            ("A2", 127.902, 37.34004, 1),
+        ];
+        assert_eq!(
+            reindex(&out_and_back(), &stops).await,
+            [2, 5, 8, 13, 16, 19]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reindex_bad_stop_does_not_drag_the_rest() {
+        // Stop B's coordinate points at the far end of the line
+        let line: Vec<[f64; 2]> = (0..=10)
+            .map(|i| [127.900 + i as f64 * 0.001, 37.34])
+            .collect();
+        let stops = [
+            ("A", 127.901, 37.34, 0),
+            ("B", 127.910, 37.34, 0),
+            ("C", 127.905, 37.34, 0),
+            ("D", 127.908, 37.34, 0),
+        ];
+        let stop_to_coord = reindex(&line, &stops).await;
+        assert_eq!(stop_to_coord[2..], [5, 8]);
+        assert!(stop_to_coord[1] <= 5);
+    }
+
+    #[tokio::test]
+    async fn test_reindex_matches_latlon_files_in_lonlat() {
+        let stops = [
+            ("A", 127.902, 37.339995, 0),
+            ("B", 127.905, 37.340012, 0),
+            ("C", 127.908, 37.339995, 0),
+            ("C2", 127.908, 37.34004, 1),
+            ("B2", 127.905, 37.34004, 1),
+            ("A2", 127.902, 37.34004, 1),
+        ];
+        let latlon: Vec<[f64; 2]> = out_and_back()
+            .iter()
+            .map(|&[lon, lat]| [lat, lon])
+            .collect();
+        assert_eq!(
+            reindex_written_as(&latlon, &stops, Some("latlon")).await,
+            [2, 5, 8, 13, 16, 19]
+        );
+    }
+}
@@ -0,0 +1,310 @@
+//! Station lookups for the derive step.
+//!
+//! By default the `stations` section of `stationMap.json` is loaded into memory.
+//! For very large networks, `--station-index` instead streams it once into
+//! `stationMap.idx`, a file with one `node_id<TAB>json` line per station sorted
+//! by `node_id`, and keeps only every `STATION_INDEX_STRIDE`th key in memory.
+//! A lookup binary-searches those keys and scans one block of the file, so
+//! memory stays a small fraction of the network size. The index is rebuilt
+//! whenever `stationMap.json` is newer than it.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, Visitor};
+use serde_json::Value;
+
+use crate::config::STATION_INDEX_STRIDE;
+
+/// Station info by `node_id`, in memory or backed by an on-disk index
+pub enum StationMap {
+    Memory(HashMap<String, Value>),
+    Disk(DiskStationIndex),
+}
+
+impl Default for StationMap {
+    fn default() -> Self {
+        StationMap::Memory(HashMap::new())
+    }
+}
+
+impl StationMap {
+    /// Loads `stationMap.json`, or an empty map if the file is absent.
+    ///
+    /// With `on_disk`, stations are served from `stationMap.idx` next to it instead.
+    /// Stations only refine stop coordinates, so a malformed file (e.g. truncated by an
+    /// interrupted run) is logged and treated as empty rather than failing the run.
+    pub async fn load(path: &Path, on_disk: bool) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        if on_disk {
+            let json_path = path.to_path_buf();
+            let index =
+                tokio::task::spawn_blocking(move || DiskStationIndex::open(&json_path)).await?;
+            return Ok(match index {
+                Ok(index) => StationMap::Disk(index),
+                Err(e) => malformed(path, e),
+            });
+        }
+
+        let content = tokio::fs::read_to_string(path).await?;
+        let json: Value = match serde_json::from_str(&content) {
+            Ok(json) => json,
+            Err(e) => return Ok(malformed(path, e.into())),
+        };
+        Ok(StationMap::Memory(
+            serde_json::from_value(json["stations"].clone()).unwrap_or_default(),
+        ))
+    }
+
+    pub fn get(&self, node_id: &str) -> Option<Cow<'_, Value>> {
+        match self {
+            StationMap::Memory(map) => map.get(node_id).map(Cow::Borrowed),
+            StationMap::Disk(index) => index.get(node_id).map(Cow::Owned),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            StationMap::Memory(map) => map.is_empty(),
+            StationMap::Disk(index) => index.len == 0,
+        }
+    }
+}
+
+/// Falls back to raw stop coordinates when `stationMap.json` cannot be read
+fn malformed(path: &Path, e: anyhow::Error) -> StationMap {
+    warn!(
+        "{} is malformed ({:#}); using raw stop coordinates.",
+        path.display(),
+        e
+    );
+    StationMap::default()
+}
+
+/// Sorted station file with a sparse in-memory key index
+pub struct DiskStationIndex {
+    file: Mutex<BufReader<File>>,
+    /// First key and byte offset of each block of `STATION_INDEX_STRIDE` lines
+    blocks: Vec<(String, u64)>,
+    len: usize,
+}
+
+impl DiskStationIndex {
+    /// Opens the index for `json_path`, building it first if it is missing or stale.
+    fn open(json_path: &Path) -> Result<Self> {
+        let idx_path = index_path(json_path);
+        let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+        if modified(&idx_path).is_none_or(|idx| modified(json_path).is_some_and(|json| json > idx))
+        {
+            let count = build_index(json_path, &idx_path)?;
+            info!(
+                "Built station index with {} stations at {}",
+                count,
+                idx_path.display()
+            );
+        }
+
+        let mut reader = BufReader::new(File::open(&idx_path)?);
+        let mut blocks = Vec::new();
+        let mut len = 0;
+        let mut offset = 0u64;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            if len % STATION_INDEX_STRIDE == 0 {
+                let key = line.split_once('\t').map_or("", |(k, _)| k);
+                blocks.push((key.to_string(), offset));
+            }
+            offset += read as u64;
+            len += 1;
+        }
+
+        Ok(Self {
+            file: Mutex::new(reader),
+            blocks,
+            len,
+        })
+    }
+
+    fn get(&self, node_id: &str) -> Option<Value> {
+        let block = self
+            .blocks
+            .partition_point(|(key, _)| key.as_str() <= node_id)
+            .checked_sub(1)?;
+
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(self.blocks[block].1)).ok()?;
+        let mut line = String::new();
+        for _ in 0..STATION_INDEX_STRIDE {
+            line.clear();
+            if file.read_line(&mut line).ok()? == 0 {
+                break;
+            }
+            let (key, json) = line.trim_end_matches('\n').split_once('\t')?;
+            match key.cmp(node_id) {
+                std::cmp::Ordering::Less => continue,
+                std::cmp::Ordering::Equal => return serde_json::from_str(json).ok(),
+                std::cmp::Ordering::Greater => break,
+            }
+        }
+        None
+    }
+}
+
+/// Streams the `stations` of `json_path` into a sorted index file. Returns the station count.
+fn build_index(json_path: &Path, idx_path: &Path) -> Result<usize> {
+    let tmp_path = idx_path.with_extension("idx.tmp");
+    let mut out = BufWriter::new(File::create(&tmp_path)?);
+
+    let reader = BufReader::new(File::open(json_path)?);
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let count = match deserializer.deserialize_map(StationFile { out: &mut out }) {
+        Ok(count) => count,
+        Err(e) => {
+            drop(out);
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e).with_context(|| format!("Failed to index {}", json_path.display()));
+        }
+    };
+
+    out.flush()?;
+    drop(out);
+    fs::rename(&tmp_path, idx_path)?;
+    Ok(count)
+}
+
+/// Visits the top level of `stationMap.json`, indexing `stations` and skipping the rest
+struct StationFile<'a, W: Write> {
+    out: &'a mut W,
+}
+
+impl<'de, W: Write> Visitor<'de> for StationFile<'_, W> {
+    type Value = usize;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a stationMap.json object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<usize, A::Error> {
+        let mut count = 0;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "stations" {
+                count = map.next_value_seed(Stations { out: self.out })?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// Writes each station as one line, one entry at a time
+struct Stations<'a, W: Write> {
+    out: &'a mut W,
+}
+
+impl<'de, W: Write> DeserializeSeed<'de> for Stations<'_, W> {
+    type Value = usize;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<usize, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, W: Write> Visitor<'de> for Stations<'_, W> {
+    type Value = usize;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map of stations by node id")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<usize, A::Error> {
+        let mut count = 0;
+        let mut previous: Option<String> = None;
+        while let Some((node_id, info)) = map.next_entry::<String, Value>()? {
+            if node_id.contains(['\t', '\n']) {
+                return Err(de::Error::custom(format!("invalid node id {:?}", node_id)));
+            }
+            // The file is written from a sorted map; anything else was edited by hand
+            if previous.as_ref().is_some_and(|p| *p >= node_id) {
+                return Err(de::Error::custom("stations are not sorted by node id"));
+            }
+            writeln!(self.out, "{}\t{}", node_id, info).map_err(de::Error::custom)?;
+            previous = Some(node_id);
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/// Path of the on-disk index kept next to `stationMap.json`
+fn index_path(json_path: &Path) -> PathBuf {
+    json_path.with_extension("idx")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_disk_index_matches_stations() {
+        let dir = std::env::temp_dir().join(format!("polly-stations-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // More stations than one block, so lookups cross block boundaries
+        let stations: serde_json::Map<String, Value> = (0..STATION_INDEX_STRIDE * 3 + 7)
+            .map(|i| {
+                (
+                    format!("WJB{:06}", i),
+                    json!({ "gpslati": 37.0 + i as f64 * 1e-4 }),
+                )
+            })
+            .collect();
+        let path = dir.join("stationMap.json");
+        let file = json!({ "lastUpdated": "2025-01-01", "stations": stations });
+        fs::write(&path, file.to_string()).unwrap();
+
+        let index = DiskStationIndex::open(&path).unwrap();
+        assert!(index_path(&path).exists());
+        assert_eq!(index.len, stations.len());
+        for id in ["WJB000000", "WJB000255", "WJB000256", "WJB000774"] {
+            assert_eq!(index.get(id).as_ref(), stations.get(id));
+        }
+        assert_eq!(index.get("WJB000775"), None);
+        assert_eq!(index.get("AAA"), None);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_station_map_loads_empty() {
+        let dir = std::env::temp_dir().join(format!("polly-stations-bad-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // Truncated by an interrupted run
+        let path = dir.join("stationMap.json");
+        fs::write(&path, r#"{"stations": {"WJB000001": {"gpslati": 37."#).unwrap();
+
+        assert!(StationMap::load(&path, false).await.unwrap().is_empty());
+        assert!(StationMap::load(&path, true).await.unwrap().is_empty());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
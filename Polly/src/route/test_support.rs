@@ -0,0 +1,64 @@
+//! Helpers shared by the route tests: a processor for mock servers, and the mock server itself.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use crate::route::model::{BusRouteProcessor, RouteOptions};
+
+/// A processor sending both its TAGO and OSRM requests to `base_url`, with `options`
+pub fn test_processor(base_url: &str, options: RouteOptions) -> BusRouteProcessor {
+    BusRouteProcessor {
+        service_key: "test-key".into(),
+        city_code: "32020".to_string(),
+        tago_base_url: base_url.to_string(),
+        osrm_base_url: base_url.to_string(),
+        osrm_timeout: Duration::from_secs(5),
+        tago_timeout: Duration::from_secs(5),
+        options,
+        ..BusRouteProcessor::new(reqwest::Client::new())
+    }
+}
+
+/// Starts an HTTP server on a free local port and returns its base URL.
+///
+/// Every connection carries one request; `respond` gets the request's index (from 0) and text
+/// and returns the full response, see [`response`]. The server runs until the test ends.
+pub async fn mock_server(respond: impl Fn(usize, &str) -> String + Send + 'static) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(AtomicUsize::new(0));
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let mut buf = [0u8; 16384];
+            let n = socket.read(&mut buf).await.unwrap_or(0);
+            let request = String::from_utf8_lossy(&buf[..n]);
+            let response = respond(requests.fetch_add(1, Ordering::SeqCst), &request);
+            let _ = socket.write_all(response.as_bytes()).await;
+        }
+    });
+    format!("http://{}", addr)
+}
+
+/// An HTTP response with `status` (e.g. `"429 Too Many Requests"`), extra `headers` and `body`
+pub fn response(status: &str, headers: &[(&str, &str)], body: &str) -> String {
+    let headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
+    format!(
+        "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        headers,
+        body.len(),
+        body
+    )
+}
+
+/// A `200 OK` response with a JSON `body`
+pub fn json_response(body: &str) -> String {
+    response("200 OK", &[("Content-Type", "application/json")], body)
+}
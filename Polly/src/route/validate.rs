@@ -0,0 +1,297 @@
+//! Consistency check between the two route phases (`validate`).
+//!
+//! Phase 1 records each route's stop sequence in `routeDetails.json`, while Phase 2
+//! writes the stops again into the derived GeoJSON. When the two drift apart (e.g. the
+//! cache was refetched or the station map changed without re-deriving), frontends that
+//! combine both files show the wrong stops. This cross-references them route by route
+//! and lists the stops present in only one of the two.
+//!
+//! It also checks that each derived line is connected: a step between consecutive
+//! vertices longer than `--max-gap-m` means OSRM chunks were stitched with a hole, and
+//! that `stop_to_coord` does not go backward other than right after the turning stop.
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use serde_json::Value;
+
+use crate::config::GEOMETRY_MAX_GAP_M;
+use crate::route::model::{delta_decode, swap_axes};
+use crate::route::process::{backward_stops, find_turn_index};
+use crate::utils::geo::longest_step;
+use crate::utils::layout::LayoutArgs;
+use crate::utils::read_json;
+
+/// Arguments for validating the route outputs
+#[derive(clap::Args)]
+pub struct ValidateArgs {
+    /// Output directory containing `routeDetails.json`, `routeMap.json` and `polylines/`
+    #[arg(short, long, default_value = "./storage")]
+    output_dir: PathBuf,
+
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// City whose files to validate, with a layout that nests files by city
+    #[arg(long)]
+    city_code: Option<String>,
+
+    /// Longest step between consecutive vertices, in meters, before a line counts as having a gap
+    #[arg(long, value_name = "METERS", default_value_t = GEOMETRY_MAX_GAP_M)]
+    max_gap_m: f64,
+}
+
+/// Stop ids in `a` that are not in `b`, in the order of `a`, each listed once
+fn only_in<'a>(a: &[&'a str], b: &[&str]) -> Vec<&'a str> {
+    let b: HashSet<&str> = b.iter().copied().collect();
+    let mut seen = HashSet::new();
+    a.iter()
+        .copied()
+        .filter(|id| !b.contains(id) && seen.insert(*id))
+        .collect()
+}
+
+/// Issues between a route's Phase 1 `sequence` and the `stops` of its derived feature.
+///
+/// Stops are compared by id only, since Phase 2 may normalize `updowncd` and reorder stops.
+fn sequence_issues(sequence: &Value, derived: &Value) -> Vec<String> {
+    let phase1: Vec<&str> = sequence
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|s| s["nodeid"].as_str())
+        .collect();
+    let phase2: Vec<&str> = derived["features"][0]["properties"]["stops"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|s| s["id"].as_str())
+        .collect();
+
+    let mut issues = Vec::new();
+    for (ids, only_where) in [
+        (only_in(&phase1, &phase2), "routeDetails.json"),
+        (only_in(&phase2, &phase1), "the derived file"),
+    ] {
+        if !ids.is_empty() {
+            issues.push(format!(
+                "{} stop(s) only in {}: {}",
+                ids.len(),
+                only_where,
+                ids.join(", ")
+            ));
+        }
+    }
+    issues
+}
+
+/// A gap in the derived line: a step between consecutive vertices longer than `max_gap_m`.
+///
+/// Files written with `--latlon-order` are measured with their coordinates swapped back.
+fn gap_issue(derived: &Value, max_gap_m: f64) -> Option<String> {
+    let feature = &derived["features"][0];
+    let mut coords: Vec<Vec<f64>> = feature["geometry"]["coordinates"]
+        .as_array()?
+        .iter()
+        .filter_map(|c| Some(vec![c[0].as_f64()?, c[1].as_f64()?]))
+        .collect();
+    if feature["properties"]["coord_order"] == "latlon" {
+        swap_axes(&mut coords);
+    }
+    let (idx, gap) = longest_step(&coords)?;
+    (gap > max_gap_m).then(|| {
+        format!(
+            "line jumps {:.0} m between vertices {} and {} (over {} m)",
+            gap,
+            idx,
+            idx + 1,
+            max_gap_m
+        )
+    })
+}
+
+/// Stops whose `stop_to_coord` entry is behind the previous stop's, other than right after the
+/// turning stop. Delta-encoded files are decoded first.
+fn order_issue(derived: &Value) -> Option<String> {
+    let props = &derived["features"][0]["properties"];
+    let stop_to_coord: Vec<usize> = match props["stop_to_coord_delta"].as_array() {
+        Some(deltas) => {
+            let deltas: Vec<i64> = deltas.iter().filter_map(Value::as_i64).collect();
+            match delta_decode(&deltas) {
+                Ok(decoded) => decoded,
+                Err(e) => return Some(format!("{:#}", e)),
+            }
+        }
+        None => props["stop_to_coord"]
+            .as_array()?
+            .iter()
+            .filter_map(|i| i.as_u64().map(|i| i as usize))
+            .collect(),
+    };
+    let up_down: Vec<i64> = props["stops"]
+        .as_array()?
+        .iter()
+        .map(|s| s["ud"].as_i64().unwrap_or_default())
+        .collect();
+
+    let backward = backward_stops(&stop_to_coord, find_turn_index(&up_down));
+    (!backward.is_empty()).then(|| format!("stop_to_coord goes backward at stop(s) {:?}", backward))
+}
+
+/// Prints the routes whose derived stops differ from their `routeDetails.json` sequence.
+pub fn validate(args: ValidateArgs) -> Result<()> {
+    let layout = args.layout.resolve()?;
+    let root = layout.root(&args.output_dir, args.city_code.as_deref())?;
+    let details = read_json(&root.join("routeDetails.json"))?;
+    let details = details["route_details"]
+        .as_object()
+        .context("routeDetails.json has no `route_details` section")?;
+
+    // Derived file of each route id as recorded in routeMap.json (it knows `--name-by`)
+    let route_map = read_json(&root.join("routeMap.json")).unwrap_or_default();
+    let derived_files: BTreeMap<&str, &str> = route_map["route_variants"]
+        .as_object()
+        .into_iter()
+        .flat_map(|variants| variants.values())
+        .filter_map(Value::as_array)
+        .flatten()
+        .filter_map(|v| Some((v["route_id"].as_str()?, v["geojson"].as_str()?)))
+        .collect();
+
+    let mut checked = 0usize;
+    let mut not_derived = 0usize;
+    let mut issues = Vec::new();
+    for (route_id, detail) in details {
+        let relative = derived_files
+            .get(route_id.as_str())
+            .map_or_else(|| layout.derived_path(route_id), |path| path.to_string());
+        let path = root.join(&relative);
+        if !path.exists() {
+            debug!("{} has no derived file at {}", route_id, relative);
+            not_derived += 1;
+            continue;
+        }
+
+        let derived = match read_json(&path) {
+            Ok(derived) => derived,
+            Err(e) => {
+                warn!("Skipping {}: {:#}", route_id, e);
+                continue;
+            }
+        };
+        checked += 1;
+        let gap = gap_issue(&derived, args.max_gap_m);
+        let order = order_issue(&derived);
+        for issue in sequence_issues(&detail["sequence"], &derived)
+            .into_iter()
+            .chain(gap)
+            .chain(order)
+        {
+            issues.push((route_id.as_str(), issue));
+        }
+    }
+
+    if !issues.is_empty() {
+        println!("{:<16} ISSUE", "ROUTE_ID");
+        for (route_id, issue) in &issues {
+            println!("{:<16} {}", route_id, issue);
+        }
+    }
+    info!(
+        "Checked {} routes against routeDetails.json ({} not derived); {} issue(s) found.",
+        checked,
+        not_derived,
+        issues.len()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_sequence_issues_lists_stops_in_one_phase_only() {
+        let sequence = json!([
+            { "nodeid": "A", "nodeord": 1, "updowncd": 1 },
+            { "nodeid": "B", "nodeord": 2, "updowncd": 1 },
+            { "nodeid": "C", "nodeord": 3, "updowncd": 2 },
+            { "nodeid": "A", "nodeord": 4, "updowncd": 2 }
+        ]);
+        let derived = |ids: &[&str]| {
+            let stops: Vec<Value> = ids.iter().map(|id| json!({ "id": id })).collect();
+            json!({ "features": [{ "properties": { "stops": stops } }] })
+        };
+
+        // Same stops with normalized direction codes: consistent
+        assert!(sequence_issues(&sequence, &derived(&["A", "B", "C", "A"])).is_empty());
+
+        assert_eq!(
+            sequence_issues(&sequence, &derived(&["A", "D", "C", "A"])),
+            vec![
+                "1 stop(s) only in routeDetails.json: B",
+                "1 stop(s) only in the derived file: D"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_order_issue_allows_one_reset_at_the_turn() {
+        let stops: Vec<Value> = [0, 0, 0, 1, 1, 1]
+            .iter()
+            .map(|ud| json!({ "ud": ud }))
+            .collect();
+        let derived = |key: &str, indices: Value| {
+            let props = json!({ "stops": stops, key: indices });
+            json!({ "features": [{ "properties": props }] })
+        };
+
+        // Stop 3 restarts after the turning stop 2; stop 5 going back is a mismatch
+        let plain = derived("stop_to_coord", json!([0, 4, 9, 2, 6, 5]));
+        assert_eq!(
+            order_issue(&plain).as_deref(),
+            Some("stop_to_coord goes backward at stop(s) [5]")
+        );
+        let delta = derived("stop_to_coord_delta", json!([0, 4, 5, -7, 4, -1]));
+        assert_eq!(order_issue(&delta), order_issue(&plain));
+        assert!(order_issue(&derived("stop_to_coord", json!([0, 4, 9, 2, 6, 8]))).is_none());
+
+        let corrupt = derived("stop_to_coord_delta", json!([0, -3, 5, 1, 1, 1]));
+        assert!(
+            order_issue(&corrupt)
+                .unwrap()
+                .contains("index -3 at stop 1")
+        );
+    }
+
+    #[test]
+    fn test_gap_issue_flags_a_stitching_hole() {
+        let line = |coords: Value, coord_order: Option<&str>| {
+            json!({ "features": [{
+                "properties": { "coord_order": coord_order },
+                "geometry": { "coordinates": coords }
+            }] })
+        };
+        // About 90 m, then about 1.8 km
+        let coords = json!([[127.920, 37.34], [127.921, 37.34], [127.941, 37.34]]);
+
+        assert_eq!(
+            gap_issue(&line(coords.clone(), None), 500.0).as_deref(),
+            Some("line jumps 1768 m between vertices 1 and 2 (over 500 m)")
+        );
+        assert_eq!(gap_issue(&line(coords, None), 2000.0), None);
+
+        let latlon = json!([[37.34, 127.920], [37.34, 127.921], [37.34, 127.941]]);
+        assert!(
+            gap_issue(&line(latlon, Some("latlon")), 500.0)
+                .unwrap()
+                .starts_with("line jumps 1768 m")
+        );
+        // A file pointing at another file's geometry has none to check
+        assert_eq!(gap_issue(&line(json!([]), None), 500.0), None);
+    }
+}
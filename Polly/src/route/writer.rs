@@ -0,0 +1,367 @@
+//! Streaming writers for route outputs.
+//!
+//! Serialized routes or features are received over a bounded channel and written
+//! as they arrive, so memory stays bounded no matter how many routes a city has.
+//! The bounded channel also makes the processing stream wait whenever a writer
+//! falls behind.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, anyhow, bail};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::{Mutex, mpsc};
+use tokio::task::JoinHandle;
+
+use crate::config::FILE_WRITE_BATCH;
+
+/// Size of a tar header and of the blocks entry contents are padded to
+const TAR_BLOCK: usize = 512;
+
+/// Layout of the combined output file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CombinedFormat {
+    /// A single GeoJSON FeatureCollection
+    Collection,
+    /// Newline-delimited GeoJSON, one Feature per line
+    Lines,
+}
+
+impl CombinedFormat {
+    /// Picks the format from the file extension (`.geojsonl`/`.ndjson` for lines)
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("geojsonl") | Some("ndjson") | Some("jsonl") => CombinedFormat::Lines,
+            _ => CombinedFormat::Collection,
+        }
+    }
+}
+
+/// The error a writer task stopped on, kept for senders that then find its channel closed
+#[derive(Debug, Clone, Default)]
+pub struct WriterFailure(Arc<OnceLock<String>>);
+
+impl WriterFailure {
+    fn record(&self, err: &anyhow::Error) {
+        let _ = self.0.set(format!("{:#}", err));
+    }
+
+    /// Error for a send rejected because the writer task has stopped
+    pub fn send_error(&self) -> anyhow::Error {
+        match self.0.get() {
+            Some(reason) => anyhow!("Combined output writer failed: {}", reason),
+            None => anyhow!("Combined output writer stopped"),
+        }
+    }
+}
+
+/// Spawns a task writing every received (serialized) feature to `path`.
+/// Resolves to the number of features written; an error is also recorded in `failure`
+/// before the channel closes.
+pub fn spawn_combined_writer(
+    path: PathBuf,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+    failure: WriterFailure,
+) -> JoinHandle<Result<usize>> {
+    tokio::spawn(async move {
+        let result = write_combined(&path, &mut rx).await;
+        if let Err(e) = &result {
+            failure.record(e);
+        }
+        result
+    })
+}
+
+async fn write_combined(path: &Path, rx: &mut mpsc::Receiver<Vec<u8>>) -> Result<usize> {
+    let format = CombinedFormat::from_path(path);
+    let file = tokio::fs::File::create(path)
+        .await
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    let mut count = 0usize;
+
+    if format == CombinedFormat::Collection {
+        out.write_all(br#"{"type":"FeatureCollection","features":["#)
+            .await?;
+    }
+
+    while let Some(bytes) = rx.recv().await {
+        match format {
+            CombinedFormat::Collection => {
+                if count > 0 {
+                    out.write_all(b",").await?;
+                }
+                out.write_all(&bytes).await?;
+            }
+            CombinedFormat::Lines => {
+                out.write_all(&bytes).await?;
+                out.write_all(b"\n").await?;
+            }
+        }
+        count += 1;
+    }
+
+    if format == CombinedFormat::Collection {
+        out.write_all(b"]}").await?;
+    }
+    out.flush().await?;
+
+    Ok(count)
+}
+
+/// Spawns `count` tasks writing each received `(path, contents)` pair.
+///
+/// Each task takes up to `FILE_WRITE_BATCH` queued files at a time and writes them in a
+/// single blocking call, instead of one thread-pool round trip per file.
+/// Resolves to the number of files written.
+pub fn spawn_file_writers(
+    count: usize,
+    rx: mpsc::Receiver<(PathBuf, Vec<u8>)>,
+) -> JoinHandle<Result<usize>> {
+    let rx = Arc::new(Mutex::new(rx));
+    let tasks: Vec<JoinHandle<Result<usize>>> = (0..count)
+        .map(|_| {
+            let rx = Arc::clone(&rx);
+            tokio::spawn(async move {
+                let mut written = 0;
+                let mut batch = Vec::with_capacity(FILE_WRITE_BATCH);
+                while rx
+                    .lock()
+                    .await
+                    .recv_many(&mut batch, FILE_WRITE_BATCH)
+                    .await
+                    > 0
+                {
+                    written += batch.len();
+                    let files = std::mem::take(&mut batch);
+                    tokio::task::spawn_blocking(move || {
+                        files.iter().try_for_each(|(path, contents)| {
+                            std::fs::write(path, contents)
+                                .with_context(|| format!("Failed to write {}", path.display()))
+                        })
+                    })
+                    .await??;
+                }
+                Ok(written)
+            })
+        })
+        .collect();
+
+    tokio::spawn(async move {
+        let mut written = 0;
+        for task in tasks {
+            written += task.await??;
+        }
+        Ok(written)
+    })
+}
+
+/// A ustar header for a regular file entry
+fn tar_header(name: &str, size: usize, mtime: u64) -> Result<[u8; TAR_BLOCK]> {
+    if name.len() > 100 {
+        bail!("Archive entry name longer than 100 bytes: {}", name);
+    }
+
+    let mut header = [0u8; TAR_BLOCK];
+    let mut field = |offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    };
+    field(0, name.as_bytes());
+    field(100, b"0000644\0");
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{:011o}\0", size).as_bytes());
+    field(136, format!("{:011o}\0", mtime).as_bytes());
+    field(148, b"        ");
+    field(156, b"0");
+    field(257, b"ustar\0");
+    field(263, b"00");
+
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    Ok(header)
+}
+
+/// Spawns a task adding each received `(name, contents)` pair as a file entry of the tar
+/// archive at `path`. Resolves to the number of entries written.
+pub fn spawn_archive_writer(
+    path: PathBuf,
+    mut rx: mpsc::Receiver<(String, Vec<u8>)>,
+) -> JoinHandle<Result<usize>> {
+    tokio::spawn(async move {
+        let file = tokio::fs::File::create(&path)
+            .await
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        let mtime = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut count = 0usize;
+
+        while let Some((name, contents)) = rx.recv().await {
+            out.write_all(&tar_header(&name, contents.len(), mtime)?)
+                .await?;
+            out.write_all(&contents).await?;
+            let padding = contents.len().next_multiple_of(TAR_BLOCK) - contents.len();
+            out.write_all(&[0u8; TAR_BLOCK][..padding]).await?;
+            count += 1;
+        }
+
+        // Two zero blocks end the archive
+        out.write_all(&[0u8; 2 * TAR_BLOCK]).await?;
+        out.flush().await?;
+
+        Ok(count)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route::model::{
+        FrontendMeta, RouteFeature, RouteGeometry, RouteIndices, RouteProperties, RouteUnits,
+    };
+    use serde_json::Map;
+
+    fn synthetic_feature(i: usize) -> RouteFeature {
+        let lon = 127.9 + i as f64 * 1e-4;
+        RouteFeature {
+            type_: "Feature".to_string(),
+            id: format!("R{}", i),
+            bbox: None,
+            extra: Map::new(),
+            geometry: RouteGeometry {
+                type_: "LineString".to_string(),
+                coordinates: vec![vec![lon, 37.3], vec![lon, 37.31]],
+            },
+            properties: RouteProperties {
+                route_id: format!("R{}", i),
+                route_no: i.to_string(),
+                stops: Vec::new(),
+                label_point: [lon, 37.305],
+                annotations: None,
+                segment_flags: None,
+                extra: Map::new(),
+                indices: RouteIndices {
+                    turn_idx: 1,
+                    stop_to_coord: Vec::new(),
+                    cumulative_dist: None,
+                    delta_encoded: false,
+                },
+                meta: FrontendMeta {
+                    total_dist: 1000.0,
+                    oneway_dist: 1000.0,
+                    total_time: 60.0,
+                    source_ver: String::new(),
+                    fetched_at: None,
+                    version: None,
+                    units: RouteUnits::default(),
+                    status: None,
+                    coord_order: None,
+                    stop_to_coord_encoding: None,
+                    turn_point: None,
+                    osrm_data_version: None,
+                },
+            },
+        }
+    }
+
+    async fn write_synthetic(path: &Path, n: usize) -> usize {
+        // A tiny buffer forces the sender to wait on the writer throughout
+        let (tx, rx) = mpsc::channel(2);
+        let writer = spawn_combined_writer(path.to_path_buf(), rx, WriterFailure::default());
+
+        for i in 0..n {
+            let bytes = serde_json::to_vec(&synthetic_feature(i)).unwrap();
+            tx.send(bytes).await.unwrap();
+        }
+        drop(tx);
+
+        writer.await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_combined_writer_receives_all_features() {
+        let dir = std::env::temp_dir();
+        let collection_path = dir.join(format!("polly-combined-{}.geojson", std::process::id()));
+        let lines_path = dir.join(format!("polly-combined-{}.geojsonl", std::process::id()));
+
+        assert_eq!(write_synthetic(&collection_path, 1000).await, 1000);
+        let collection: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&collection_path).unwrap()).unwrap();
+        assert_eq!(collection["features"].as_array().unwrap().len(), 1000);
+
+        assert_eq!(write_synthetic(&lines_path, 1000).await, 1000);
+        let lines = std::fs::read_to_string(&lines_path).unwrap();
+        assert_eq!(lines.lines().count(), 1000);
+
+        let _ = std::fs::remove_file(collection_path);
+        let _ = std::fs::remove_file(lines_path);
+    }
+
+    #[tokio::test]
+    async fn test_combined_writer_failure_reaches_senders() {
+        let path = std::env::temp_dir()
+            .join(format!("polly-missing-{}", std::process::id()))
+            .join("combined.geojson");
+        let (tx, rx) = mpsc::channel(2);
+        let failure = WriterFailure::default();
+        let writer = spawn_combined_writer(path.clone(), rx, failure.clone());
+
+        // Sends fill the buffer until the failed writer drops its end of the channel
+        let bytes = serde_json::to_vec(&synthetic_feature(0)).unwrap();
+        while tx.send(bytes.clone()).await.is_ok() {}
+        let reason = failure.send_error().to_string();
+        assert!(reason.contains(&format!("Failed to create {}", path.display())));
+        assert!(writer.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_archive_contains_every_route() {
+        let path = std::env::temp_dir().join(format!("polly-archive-{}.tar", std::process::id()));
+        let (tx, rx) = mpsc::channel(2);
+        let writer = spawn_archive_writer(path.clone(), rx);
+
+        let routes: Vec<(String, Vec<u8>)> = (0..3)
+            .map(|i| {
+                let bytes = serde_json::to_vec(&synthetic_feature(i)).unwrap();
+                (format!("R{}.geojson", i), bytes)
+            })
+            .collect();
+        for route in &routes {
+            tx.send(route.clone()).await.unwrap();
+        }
+        drop(tx);
+        assert_eq!(writer.await.unwrap().unwrap(), 3);
+
+        // Walk the entries back: header, contents padded to a block, then two zero blocks
+        let tar = std::fs::read(&path).unwrap();
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while tar[offset] != 0 {
+            let header = &tar[offset..offset + TAR_BLOCK];
+            let checksum: u32 = header[..148]
+                .iter()
+                .chain(b"        ")
+                .chain(&header[156..])
+                .map(|&b| b as u32)
+                .sum();
+            let field = |range: std::ops::Range<usize>| {
+                std::str::from_utf8(&header[range])
+                    .unwrap()
+                    .trim_end_matches(['\0', ' '])
+                    .to_string()
+            };
+            assert_eq!(u32::from_str_radix(&field(148..156), 8).unwrap(), checksum);
+            assert_eq!(field(257..263), "ustar");
+
+            let size = usize::from_str_radix(&field(124..136), 8).unwrap();
+            let start = offset + TAR_BLOCK;
+            entries.push((field(0..100), tar[start..start + size].to_vec()));
+            offset = start + size.next_multiple_of(TAR_BLOCK);
+        }
+        assert_eq!(entries, routes);
+        assert_eq!(tar.len(), offset + 2 * TAR_BLOCK);
+
+        let _ = std::fs::remove_file(path);
+    }
+}
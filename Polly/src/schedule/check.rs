@@ -0,0 +1,284 @@
+//! Coverage check for crawled schedules.
+//!
+//! A parse bug can leave a route with, say, only its morning times while the
+//! crawl still counts as a success. This loads every `schedules/*.json` and
+//! flags day types whose first or last departure falls outside the expected
+//! service hours, or whose number of times is far below that of the other
+//! routes in the same route-number family (e.g. `34` and `34-1`).
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde_json::Value;
+
+use crate::config::SCHEDULE_MIN_PEER_RATIO;
+use crate::schedule::COMBINED_SCHEDULE_FILE;
+use crate::schedule::merge::minutes_of;
+use crate::utils::layout::LayoutArgs;
+
+/// Arguments for checking schedule coverage
+#[derive(clap::Args)]
+pub struct CheckSchedulesArgs {
+    /// Output directory containing `schedules/`
+    #[arg(short, long, default_value = "./storage")]
+    output_dir: PathBuf,
+
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// City whose schedules to check, with a layout that nests files by city
+    #[arg(long)]
+    city_code: Option<String>,
+
+    /// Earliest plausible departure (HH:MM)
+    #[arg(long, default_value = "05:00", value_parser = parse_hhmm)]
+    service_start: u32,
+
+    /// Latest plausible departure (HH:MM, up to 24:00 or later for after-midnight service)
+    #[arg(long, default_value = "24:00", value_parser = parse_hhmm)]
+    service_end: u32,
+
+    /// Flag a day type with fewer times than this share of its family's median
+    #[arg(long, default_value_t = SCHEDULE_MIN_PEER_RATIO)]
+    min_peer_ratio: f64,
+}
+
+/// Parses `HH:MM` into minutes after midnight.
+pub fn parse_hhmm(s: &str) -> Result<u32, String> {
+    s.split_once(':')
+        .and_then(|(h, m)| {
+            let (h, m) = (h.trim().parse::<u32>().ok()?, m.trim().parse::<u32>().ok()?);
+            (m < 60).then_some(h * 60 + m)
+        })
+        .ok_or_else(|| format!("invalid time `{}`, expected HH:MM", s))
+}
+
+pub fn format_hhmm(minutes: u32) -> String {
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+/// Departures of one day type of a route
+struct DayCoverage {
+    route_no: String,
+    day_type: String,
+    /// Minutes after midnight, sorted
+    times: Vec<u32>,
+}
+
+/// Collects the departure times of every day type in a schedule file.
+fn day_coverage(route_no: &str, schedule: &Value) -> Vec<DayCoverage> {
+    let Some(day_types) = schedule["schedule"].as_object() else {
+        return Vec::new();
+    };
+
+    day_types
+        .iter()
+        .map(|(day_type, hours)| {
+            let mut times: Vec<u32> = hours
+                .as_object()
+                .into_iter()
+                .flatten()
+                .filter_map(|(hour, dirs)| Some((hour.parse::<u32>().ok()?, dirs.as_object()?)))
+                .flat_map(|(hour, dirs)| {
+                    dirs.values()
+                        .flat_map(minutes_of)
+                        .filter_map(move |m| Some(hour * 60 + m.parse::<u32>().ok()?))
+                })
+                .collect();
+            times.sort_unstable();
+            DayCoverage {
+                route_no: route_no.to_string(),
+                day_type: day_type.clone(),
+                times,
+            }
+        })
+        .collect()
+}
+
+/// The route-number family, i.e. the number before any `-` suffix
+fn family(route_no: &str) -> &str {
+    route_no.split('-').next().unwrap_or(route_no)
+}
+
+/// Finds suspicious day types, as `(route_no, day_type, issue)`.
+fn find_issues(
+    days: &[DayCoverage],
+    service: (u32, u32),
+    min_peer_ratio: f64,
+) -> Vec<(String, String, String)> {
+    // Median time count per family and day type, to compare each route against its peers
+    let mut peer_counts: HashMap<(&str, &str), Vec<usize>> = HashMap::new();
+    for day in days {
+        peer_counts
+            .entry((family(&day.route_no), &day.day_type))
+            .or_default()
+            .push(day.times.len());
+    }
+    let medians: HashMap<(&str, &str), (usize, usize)> = peer_counts
+        .into_iter()
+        .map(|(key, mut counts)| {
+            counts.sort_unstable();
+            (key, (counts[counts.len() / 2], counts.len()))
+        })
+        .collect();
+
+    let mut issues = Vec::new();
+    for day in days {
+        let mut flag = |issue: String| {
+            issues.push((day.route_no.clone(), day.day_type.clone(), issue));
+        };
+
+        let (Some(&first), Some(&last)) = (day.times.first(), day.times.last()) else {
+            flag("no departure times".to_string());
+            continue;
+        };
+        if first < service.0 {
+            flag(format!(
+                "first departure {} before service start",
+                format_hhmm(first)
+            ));
+        }
+        if last > service.1 {
+            flag(format!(
+                "last departure {} after service end",
+                format_hhmm(last)
+            ));
+        }
+
+        let (median, peers) = medians[&(family(&day.route_no), day.day_type.as_str())];
+        if peers > 1 && (day.times.len() as f64) < median as f64 * min_peer_ratio {
+            flag(format!(
+                "only {} departures, family median is {}",
+                day.times.len(),
+                median
+            ));
+        }
+    }
+
+    issues.sort();
+    issues
+}
+
+/// The route schedule files in `dir`, sorted by name.
+pub fn schedule_paths(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+        // `_`-prefixed files (e.g. the route list cache) and the combined file are not schedules
+        .filter(|p| {
+            p.file_name().is_some_and(|name| {
+                let name = name.to_string_lossy();
+                !name.starts_with('_') && name != COMBINED_SCHEDULE_FILE
+            })
+        })
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Prints a report of schedules with implausible coverage.
+pub fn check_schedules(args: CheckSchedulesArgs) -> Result<()> {
+    let layout = args.layout.resolve()?;
+    let root = layout.root(&args.output_dir, args.city_code.as_deref())?;
+    let paths = schedule_paths(&layout.schedules_dir(&root))?;
+
+    let mut days = Vec::new();
+    for path in &paths {
+        let schedule: Value = match fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|s| Ok(serde_json::from_str(&s)?))
+        {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Skipping unreadable {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let route_no = schedule["routeId"]
+            .as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| path.file_stem().unwrap().to_string_lossy().into_owned());
+        days.extend(day_coverage(&route_no, &schedule));
+    }
+
+    let issues = find_issues(
+        &days,
+        (args.service_start, args.service_end),
+        args.min_peer_ratio,
+    );
+
+    if !issues.is_empty() {
+        println!("{:<12} {:<10} ISSUE", "ROUTE_NO", "DAY_TYPE");
+        for (route_no, day_type, issue) in &issues {
+            println!("{:<12} {:<10} {}", route_no, day_type, issue);
+        }
+    }
+    info!(
+        "Checked {} day types in {} schedules; {} issue(s) found.",
+        days.len(),
+        paths.len(),
+        issues.len()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schedule(hours: &[(&str, usize)]) -> Value {
+        let day: serde_json::Map<String, Value> = hours
+            .iter()
+            .map(|&(hour, n)| {
+                let minutes: Vec<Value> = (0..n)
+                    .map(|i| json!({ "minute": format!("{:02}", i * 5) }))
+                    .collect();
+                (hour.to_string(), json!({ "A": minutes }))
+            })
+            .collect();
+        json!({ "schedule": { "weekday": day } })
+    }
+
+    #[test]
+    fn test_find_issues_flags_partial_parse() {
+        let full = schedule(&[("06", 4), ("12", 4), ("21", 4)]);
+        let mut days = day_coverage("34", &full);
+        days.extend(day_coverage("34-1", &full));
+        // Only the morning made it through the parser
+        days.extend(day_coverage("34-2", &schedule(&[("06", 2)])));
+        // Starts suspiciously early
+        days.extend(day_coverage("7", &schedule(&[("03", 1), ("12", 1)])));
+
+        let issues = find_issues(&days, (300, 1440), 0.5);
+
+        assert_eq!(
+            issues,
+            vec![
+                (
+                    "34-2".to_string(),
+                    "weekday".to_string(),
+                    "only 2 departures, family median is 12".to_string()
+                ),
+                (
+                    "7".to_string(),
+                    "weekday".to_string(),
+                    "first departure 03:00 before service start".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_hhmm() {
+        assert_eq!(parse_hhmm("05:00"), Ok(300));
+        assert_eq!(parse_hhmm("24:00"), Ok(1440));
+        assert!(parse_hhmm("5").is_err());
+    }
+}
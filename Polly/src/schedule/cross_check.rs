@@ -0,0 +1,170 @@
+//! Coverage check between the route and schedule outputs (`cross-check`).
+//!
+//! The two pipelines run independently, so a route number can end up with a
+//! timetable but no geometry, or the other way round, which a frontend showing
+//! both only notices as a 404. This compares the route numbers of the derived
+//! routes with those in `schedules/`, after normalizing both.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use log::{info, warn};
+
+use crate::schedule::check::schedule_paths;
+use crate::utils::layout::LayoutArgs;
+use crate::utils::{normalize_route_no, read_json};
+
+/// Arguments for cross-checking routes and schedules
+#[derive(clap::Args)]
+pub struct CrossCheckArgs {
+    /// Output directory containing `routeIndex.json` or `routeMap.json`, and `schedules/`
+    #[arg(short, long, default_value = "./storage")]
+    output_dir: PathBuf,
+
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// City whose outputs to compare, with a layout that nests files by city
+    #[arg(long)]
+    city_code: Option<String>,
+
+    /// Exit with an error if any route number is missing from either side
+    #[arg(long)]
+    strict: bool,
+}
+
+/// Normalized route numbers with geometry.
+///
+/// `routeIndex.json` lists only routes that were derived, so it is preferred; `routeMap.json`
+/// also lists routes whose derivation failed. Both are read from the city root `root`.
+fn geometry_route_numbers(root: &Path) -> Result<BTreeSet<String>> {
+    let index_path = root.join("routeIndex.json");
+    if index_path.exists() {
+        let index = read_json(&index_path)?;
+        return Ok(index["routes"]
+            .as_object()
+            .into_iter()
+            .flat_map(|routes| routes.values())
+            .filter_map(|route| route["route_no"].as_str())
+            .map(normalize_route_no)
+            .collect());
+    }
+
+    let route_map = read_json(&root.join("routeMap.json"))?;
+    Ok(route_map["route_numbers"]
+        .as_object()
+        .context("routeMap.json has no `route_numbers` section")?
+        .keys()
+        .map(|route_no| normalize_route_no(route_no))
+        .collect())
+}
+
+/// Normalized route numbers with a schedule file
+fn schedule_route_numbers(schedule_dir: &Path) -> Result<BTreeSet<String>> {
+    let mut numbers = BTreeSet::new();
+    for path in schedule_paths(schedule_dir)? {
+        let schedule = match read_json(&path) {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                warn!("Skipping {:#}", e);
+                continue;
+            }
+        };
+        let route_no = schedule["routeId"]
+            .as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| path.file_stem().unwrap().to_string_lossy().into_owned());
+        numbers.insert(normalize_route_no(&route_no));
+    }
+    Ok(numbers)
+}
+
+/// Route numbers only in `geometry` and only in `schedules`
+fn coverage_gaps<'a>(
+    geometry: &'a BTreeSet<String>,
+    schedules: &'a BTreeSet<String>,
+) -> (Vec<&'a str>, Vec<&'a str>) {
+    (
+        geometry.difference(schedules).map(String::as_str).collect(),
+        schedules.difference(geometry).map(String::as_str).collect(),
+    )
+}
+
+/// Prints the route numbers that have geometry but no schedule, or a schedule but no geometry.
+pub fn cross_check(args: CrossCheckArgs) -> Result<()> {
+    let layout = args.layout.resolve()?;
+    let root = layout.root(&args.output_dir, args.city_code.as_deref())?;
+    let geometry = geometry_route_numbers(&root)?;
+    let schedules = schedule_route_numbers(&layout.schedules_dir(&root))?;
+    let (no_schedule, no_geometry) = coverage_gaps(&geometry, &schedules);
+
+    if !no_schedule.is_empty() || !no_geometry.is_empty() {
+        println!("{:<10} MISSING", "ROUTE");
+        for route_no in &no_schedule {
+            println!("{:<10} schedule", route_no);
+        }
+        for route_no in &no_geometry {
+            println!("{:<10} geometry", route_no);
+        }
+    }
+    info!(
+        "{} route numbers with geometry, {} with a schedule: {} without schedule, {} without geometry.",
+        geometry.len(),
+        schedules.len(),
+        no_schedule.len(),
+        no_geometry.len()
+    );
+
+    if args.strict && !(no_schedule.is_empty() && no_geometry.is_empty()) {
+        bail!(
+            "{} route number(s) lack a schedule or geometry",
+            no_schedule.len() + no_geometry.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+
+    #[test]
+    fn test_cross_check_normalizes_route_numbers() {
+        let dir = std::env::temp_dir().join(format!("polly-cross-check-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("schedules")).unwrap();
+        let index = json!({ "routes": {
+            "WJB1": { "route_no": "34-1" },
+            "WJB2": { "route_no": "34-1" },
+            "WJB3": { "route_no": "2" }
+        } });
+        fs::write(dir.join("routeIndex.json"), index.to_string()).unwrap();
+        for (file, route_id) in [("34-1.json", "34-1"), ("41.json", "41")] {
+            fs::write(
+                dir.join("schedules").join(file),
+                json!({ "routeId": route_id }).to_string(),
+            )
+            .unwrap();
+        }
+
+        let geometry = geometry_route_numbers(&dir).unwrap();
+        let schedules = schedule_route_numbers(&dir.join("schedules")).unwrap();
+        assert_eq!(
+            coverage_gaps(&geometry, &schedules),
+            (vec!["2"], vec!["41"])
+        );
+
+        let strict = cross_check(CrossCheckArgs {
+            output_dir: dir.clone(),
+            layout: LayoutArgs::default(),
+            city_code: None,
+            strict: true,
+        });
+        assert!(strict.is_err());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,32 +1,128 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
 use std::time::Duration;
 
 use anyhow::Result;
 use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
 use reqwest::{header, Client};
+use log::warn;
+use tokio::time::Instant;
 
 use crate::config::{BASE_URL, DETAIL_URL};
+use crate::utils::http;
 
 pub struct ScheduleClient {
     client: Client,
+    /// Minimum spacing between requests to the same host.
+    min_interval: Duration,
+    /// Earliest time the next request to each host may be sent.
+    next_slot: Mutex<HashMap<String, Instant>>,
+    /// Per-request timeout for the (large) main page.
+    main_timeout: Duration,
+    /// Per-request timeout for the (small) detail pages.
+    detail_timeout: Duration,
+    /// Number of retries after a failed request.
+    max_retries: u32,
+    /// Main schedule page, which also hands out the session cookie.
+    main_url: String,
+    /// Detail page the route id is posted to.
+    detail_url: String,
 }
 
 impl ScheduleClient {
-    pub fn new() -> Result<Self> {
+    pub fn new(
+        min_interval: Duration,
+        main_timeout: Duration,
+        detail_timeout: Duration,
+        max_retries: u32,
+    ) -> Result<Self> {
         // Initialize an HTTP client that mimics a web browser.
         // Cookie store is enabled to automatically handle session cookies (JSESSIONID),
         // which is crucial for making subsequent requests to the detail page.
-        let client = Client::builder()
+        let client = http::client_builder()
             .cookie_store(true)
             .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36")
-            .timeout(Duration::from_secs(30))
             .build()?;
 
-        Ok(Self { client })
+        Ok(Self {
+            client,
+            min_interval,
+            next_slot: Mutex::new(HashMap::new()),
+            main_timeout,
+            detail_timeout,
+            max_retries,
+            main_url: BASE_URL.to_string(),
+            detail_url: DETAIL_URL.to_string(),
+        })
+    }
+
+    /// Points the client at another server, e.g. a local mock.
+    #[cfg(test)]
+    pub fn with_urls(mut self, main_url: String, detail_url: String) -> Self {
+        self.main_url = main_url;
+        self.detail_url = detail_url;
+        self
+    }
+
+    /// Waits until at least `min_interval` has passed since the last request to the URL's host.
+    ///
+    /// Each caller reserves the host's next free slot and sleeps after releasing the lock, so
+    /// concurrent callers targeting the same host are spaced by the interval without blocking
+    /// callers of other hosts.
+    async fn throttle(&self, url: &str) {
+        let host = url::Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(str::to_string))
+            .unwrap_or_default();
+
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let now = Instant::now();
+            let slot = next_slot.get(&host).map_or(now, |&next| next.max(now));
+            next_slot.insert(host, slot + self.min_interval);
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+
+    /// Sends a request built by `build`, retrying failures and error statuses up to `max_retries` times.
+    async fn send_with_retry(
+        &self,
+        url: &str,
+        timeout: Duration,
+        build: impl Fn() -> reqwest::RequestBuilder,
+    ) -> Result<String> {
+        let mut attempt = 0;
+        loop {
+            self.throttle(url).await;
+
+            let result = async {
+                let resp = build().timeout(timeout).send().await?;
+                resp.error_for_status_ref()?;
+                resp.text().await
+            }
+            .await;
+
+            match result {
+                Ok(html) => return Ok(html),
+                Err(e) if attempt < self.max_retries => {
+                    attempt += 1;
+                    warn!(
+                        "Request to {} failed (retry {}/{}): {}",
+                        url, attempt, self.max_retries, e
+                    );
+                    tokio::time::sleep(Duration::from_millis(500 * attempt as u64)).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
     }
 
     pub async fn fetch_main_page(&self) -> Result<String> {
-        let resp = self.client.get(BASE_URL).send().await?.text().await?;
-        Ok(resp)
+        self.send_with_retry(&self.main_url, self.main_timeout, || {
+            self.client.get(&self.main_url)
+        })
+        .await
     }
 
     pub async fn fetch_detail_page(&self, route_id: &str) -> Result<String> {
@@ -37,18 +133,45 @@ impl ScheduleClient {
         // Send a POST request to get the detailed schedule for the specific route_id.
         // It's crucial to set the correct headers (Referer, Origin, Content-Type)
         // to simulate a legitimate request originating from the website.
-        let resp = self
-            .client
-            .post(DETAIL_URL)
-            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-            .header(header::REFERER, BASE_URL)
-            .header(header::ORIGIN, "http://its.wonju.go.kr")
-            .body(body_str)
-            .send()
-            .await?;
-
-        resp.error_for_status_ref()?;
-        let html = resp.text().await?;
-        Ok(html)
+        self.send_with_retry(&self.detail_url, self.detail_timeout, || {
+            self.client
+                .post(&self.detail_url)
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .header(header::REFERER, &self.main_url)
+                .header(header::ORIGIN, "http://its.wonju.go.kr")
+                .body(body_str.clone())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_throttle_spaces_one_host_without_blocking_others() {
+        let interval = Duration::from_millis(200);
+        let client = ScheduleClient::new(interval, interval, interval, 0).unwrap();
+        let start = Instant::now();
+
+        // Three requests to one host in flight at once, then one to another host
+        let same_host = futures::future::join_all((0..3).map(|_| async {
+            client.throttle("http://a.example/x").await;
+            start.elapsed()
+        }));
+        let other_host = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.throttle("http://b.example/x").await;
+            start.elapsed()
+        };
+        let (mut same_host, other_host) = tokio::join!(same_host, other_host);
+
+        same_host.sort();
+        assert!(same_host[0] < interval);
+        assert!(same_host[1] >= interval);
+        assert!(same_host[2] >= interval * 2);
+        // The other host is not held up behind the first host's queue
+        assert!(other_host < interval);
     }
 }
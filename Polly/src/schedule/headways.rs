@@ -0,0 +1,268 @@
+//! Headway summary of crawled schedules.
+//!
+//! For each route, day type and direction, this measures the gaps between
+//! consecutive departures in `schedules/*.json` and writes their median,
+//! minimum and maximum, plus medians for the morning and evening peaks and
+//! the rest of the day, to `headways.json`. Times before `--day-start` belong
+//! to the previous service day, so a 00:10 departure follows 23:50. Trips whose
+//! note matches an `--exclude-note` pattern (e.g. "운휴") are left out.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use chrono::Local;
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::{Value, json};
+
+use crate::schedule::check::{parse_hhmm, schedule_paths};
+use crate::utils::layout::LayoutArgs;
+use crate::utils::write_atomic;
+
+/// Peak windows as `(label, start, end)` in minutes after midnight, end exclusive
+const PEAK_WINDOWS: [(&str, u32, u32); 2] = [("07-09", 420, 540), ("17-19", 1020, 1140)];
+
+/// Arguments for computing headways
+#[derive(clap::Args)]
+pub struct HeadwaysArgs {
+    /// Output directory containing `schedules/`; `headways.json` is written beside the mapping files
+    #[arg(short, long, default_value = "./storage")]
+    output_dir: PathBuf,
+
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// City whose schedules to read, with a layout that nests files by city
+    #[arg(long)]
+    city_code: Option<String>,
+
+    /// Departures before this time (HH:MM) run after midnight at the end of the previous day
+    #[arg(long, default_value = "03:00", value_parser = parse_hhmm)]
+    day_start: u32,
+
+    /// Leave out trips whose note contains this text (repeatable)
+    #[arg(long, value_name = "TEXT", default_values_t = ["운휴".to_string()])]
+    exclude_note: Vec<String>,
+}
+
+/// Headways of one direction, in minutes
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Headway {
+    trips: usize,
+    median: Option<f64>,
+    min: Option<u32>,
+    max: Option<u32>,
+    /// Median of the gaps starting in each peak window
+    peak: BTreeMap<&'static str, Option<f64>>,
+    /// Median of the gaps starting outside the peak windows
+    off_peak: Option<f64>,
+}
+
+fn median(values: &mut [u32]) -> Option<f64> {
+    values.sort_unstable();
+    let mid = values.len() / 2;
+    match values.len() {
+        0 => None,
+        n if n % 2 == 1 => Some(values[mid] as f64),
+        _ => Some((values[mid - 1] + values[mid]) as f64 / 2.0),
+    }
+}
+
+/// Headways between sorted, distinct departure times
+fn headway(times: &[u32]) -> Headway {
+    let gaps: Vec<(u32, u32)> = times.windows(2).map(|w| (w[0], w[1] - w[0])).collect();
+    let in_window = |start: u32, end: u32| {
+        move |&(from, _): &&(u32, u32)| (start..end).contains(&(from % 1440))
+    };
+
+    let mut all: Vec<u32> = gaps.iter().map(|&(_, gap)| gap).collect();
+    let peak = PEAK_WINDOWS
+        .iter()
+        .map(|&(label, start, end)| {
+            let mut window: Vec<u32> = gaps
+                .iter()
+                .filter(in_window(start, end))
+                .map(|&(_, gap)| gap)
+                .collect();
+            (label, median(&mut window))
+        })
+        .collect();
+    let mut off_peak: Vec<u32> = gaps
+        .iter()
+        .filter(|gap| {
+            !PEAK_WINDOWS
+                .iter()
+                .any(|&(_, start, end)| in_window(start, end)(gap))
+        })
+        .map(|&(_, gap)| gap)
+        .collect();
+
+    Headway {
+        trips: times.len(),
+        min: all.iter().min().copied(),
+        max: all.iter().max().copied(),
+        median: median(&mut all),
+        peak,
+        off_peak: median(&mut off_peak),
+    }
+}
+
+/// Departures of each direction of a schedule file, keyed by day type and direction.
+///
+/// Reads both the verbose and the compact (`--compact-schedule`) form.
+pub fn departures(
+    schedule: &Value,
+    exclude_note: &[String],
+    day_start: u32,
+) -> BTreeMap<String, BTreeMap<String, Vec<u32>>> {
+    let excluded_ids: HashSet<&str> = schedule["notes"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter(|(_, text)| {
+            let text = text.as_str().unwrap_or_default();
+            exclude_note
+                .iter()
+                .any(|pattern| text.contains(pattern.as_str()))
+        })
+        .map(|(id, _)| id.as_str())
+        .collect();
+
+    let mut result: BTreeMap<String, BTreeMap<String, Vec<u32>>> = BTreeMap::new();
+    for (day_type, hours) in schedule["schedule"].as_object().into_iter().flatten() {
+        let directions = result.entry(day_type.clone()).or_default();
+        for (hour, dirs) in hours.as_object().into_iter().flatten() {
+            let Ok(hour) = hour.parse::<u32>() else {
+                continue;
+            };
+            for (direction, times) in dirs.as_object().into_iter().flatten() {
+                let compact_notes = &schedule["scheduleNotes"][day_type][format!("{:02}", hour)]
+                    [direction.as_str()];
+                let trips: Vec<(&str, Option<&str>)> = match times {
+                    Value::Array(entries) => entries
+                        .iter()
+                        .filter_map(|e| Some((e["minute"].as_str()?, e["noteId"].as_str())))
+                        .collect(),
+                    Value::String(joined) => joined
+                        .split(',')
+                        .filter(|m| !m.is_empty())
+                        .map(|m| (m, compact_notes[m].as_str()))
+                        .collect(),
+                    _ => Vec::new(),
+                };
+
+                let list = directions.entry(direction.clone()).or_default();
+                for (minute, note_id) in trips {
+                    if note_id.is_some_and(|id| excluded_ids.contains(id)) {
+                        continue;
+                    }
+                    let Ok(minute) = minute.parse::<u32>() else {
+                        continue;
+                    };
+                    let time = hour * 60 + minute;
+                    list.push(if time < day_start { time + 1440 } else { time });
+                }
+            }
+        }
+        for list in directions.values_mut() {
+            list.sort_unstable();
+            list.dedup();
+        }
+    }
+    result
+}
+
+/// Writes `headways.json` with the headways of every crawled schedule.
+pub fn headways(args: HeadwaysArgs) -> Result<()> {
+    let layout = args.layout.resolve()?;
+    let root = layout.root(&args.output_dir, args.city_code.as_deref())?;
+    let paths = schedule_paths(&layout.schedules_dir(&root))?;
+
+    let mut routes: BTreeMap<String, BTreeMap<String, BTreeMap<String, Headway>>> = BTreeMap::new();
+    for path in &paths {
+        let schedule: Value = match fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|s| Ok(serde_json::from_str(&s)?))
+        {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Skipping unreadable {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let route_no = schedule["routeId"]
+            .as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| path.file_stem().unwrap().to_string_lossy().into_owned());
+
+        let days = departures(&schedule, &args.exclude_note, args.day_start)
+            .into_iter()
+            .map(|(day_type, dirs)| {
+                let dirs = dirs
+                    .into_iter()
+                    .map(|(direction, times)| (direction, headway(&times)))
+                    .collect();
+                (day_type, dirs)
+            })
+            .collect();
+        routes.insert(route_no, days);
+    }
+
+    let out_path = root.join("headways.json");
+    let output = json!({
+        "lastUpdated": Local::now().format("%Y-%m-%d").to_string(),
+        "unit": "minutes",
+        "routes": routes,
+    });
+    write_atomic(&out_path, serde_json::to_string_pretty(&output)?.as_bytes())
+        .with_context(|| format!("Failed to write {}", out_path.display()))?;
+
+    info!(
+        "Wrote headways of {} routes to {}.",
+        routes.len(),
+        out_path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::merge::compact_schedule;
+
+    #[test]
+    fn test_headways_wrap_midnight_and_skip_noted_trips() {
+        let schedule = json!({
+            "schedule": { "weekday": {
+                "00": { "A": [{ "minute": "10" }] },
+                "07": { "A": [{ "minute": "00" }, { "minute": "10" }, { "minute": "30", "noteId": "1" }] },
+                "08": { "A": [{ "minute": "00" }] },
+                "12": { "A": [{ "minute": "00" }] },
+                "23": { "A": [{ "minute": "50" }] }
+            } },
+            "notes": { "1": "토요일 운휴" }
+        });
+        let exclude = vec!["운휴".to_string()];
+
+        let days = departures(&schedule, &exclude, 180);
+        let times = &days["weekday"]["A"];
+        // 07:30 is excluded; 00:10 follows 23:50
+        assert_eq!(times, &[420, 430, 480, 720, 1430, 1450]);
+
+        let result = headway(times);
+        assert_eq!(result.trips, 6);
+        assert_eq!((result.min, result.max), (Some(10), Some(710)));
+        assert_eq!(result.median, Some(50.0));
+        assert_eq!(result.peak["07-09"], Some(50.0));
+        assert_eq!(result.peak["17-19"], None);
+        assert_eq!(result.off_peak, Some(365.0));
+
+        // The compact form gives the same departures
+        let mut compact = schedule.clone();
+        compact_schedule(&mut compact);
+        assert_eq!(departures(&compact, &exclude, 180), days);
+    }
+}
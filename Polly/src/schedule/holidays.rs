@@ -0,0 +1,245 @@
+//! Public holiday calendar from the data.go.kr special-day API.
+//!
+//! The timetables only say which schedule applies on holidays; this records which
+//! calendar dates actually are holidays. Each fetched year is cached in
+//! `holidays.json` (merged with the years already there) for calendar exports.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::Local;
+use log::info;
+use serde_json::{Value, json};
+
+use crate::config::{HOLIDAY_URL, TAGO_TIMEOUT_SECS};
+use crate::route::{RouteConfig, data_go_kr_get};
+use crate::utils::service_key::ServiceKey;
+use crate::utils::{
+    ensure_dir, extract_items, http, parse_flexible_string, resolve_url, write_atomic,
+};
+
+/// Arguments for fetching a year's public holidays
+#[derive(clap::Args)]
+pub struct FetchHolidaysArgs {
+    /// Year to fetch (e.g. 2025)
+    year: i32,
+
+    /// Output directory for `holidays.json`
+    #[arg(short, long, default_value = "./storage")]
+    output_dir: PathBuf,
+
+    /// City whose `--profile` service key to use (default: Wonju -> 32020)
+    #[arg(long, default_value = "32020")]
+    city_code: String,
+
+    /// JSON profile mapping city codes to TAGO service keys; a listed city overrides `DATA_GO_KR_SERVICE_KEY`
+    #[arg(long, value_name = "PATH")]
+    profile: Option<PathBuf>,
+
+    /// data.go.kr service key; takes precedence over `--profile`, `DATA_GO_KR_SERVICE_KEY` and `.env`
+    #[arg(long, value_name = "KEY")]
+    service_key: Option<String>,
+}
+
+/// Holidays by `YYYY-MM-DD` date. Names of holidays falling on the same date are joined.
+fn parse_holidays(items: &[Value]) -> BTreeMap<String, String> {
+    let mut holidays: BTreeMap<String, String> = BTreeMap::new();
+    for item in items {
+        if item["isHoliday"].as_str() != Some("Y") {
+            continue;
+        }
+        let locdate = parse_flexible_string(&item["locdate"]);
+        if locdate.len() != 8 || !locdate.chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        let date = format!("{}-{}-{}", &locdate[..4], &locdate[4..6], &locdate[6..]);
+        let name = item["dateName"].as_str().unwrap_or_default().trim();
+
+        holidays
+            .entry(date)
+            .and_modify(|names| {
+                if !name.is_empty() && !names.split(", ").any(|n| n == name) {
+                    names.push_str(", ");
+                    names.push_str(name);
+                }
+            })
+            .or_insert_with(|| name.to_string());
+    }
+    holidays
+}
+
+/// The public holidays of `year` from the special-day API at `base_url`
+async fn fetch_year(
+    base_url: &str,
+    service_key: &ServiceKey,
+    year: &str,
+) -> Result<BTreeMap<String, String>> {
+    let params = [("solYear", year), ("numOfRows", "100")];
+    let url = format!("{}/getRestDeInfo", base_url);
+    let json = data_go_kr_get(
+        &http::client()?,
+        &url,
+        &params,
+        service_key,
+        Duration::from_secs(TAGO_TIMEOUT_SECS),
+    )
+    .await
+    .context("Holiday API request failed")?
+    .context("Holiday API response is not valid JSON")?;
+
+    let header = &json["response"]["header"];
+    if let Some(code) = header["resultCode"].as_str()
+        && code != "00"
+    {
+        anyhow::bail!(
+            "Holiday API returned {}: {}",
+            code,
+            header["resultMsg"].as_str().unwrap_or_default()
+        );
+    }
+
+    Ok(parse_holidays(&extract_items(&json)?))
+}
+
+/// Fetches the public holidays of a year and caches them in `holidays.json`.
+pub async fn fetch_holidays(args: FetchHolidaysArgs) -> Result<()> {
+    let config = RouteConfig::resolve(
+        args.service_key.as_deref(),
+        args.profile.as_deref(),
+        &args.city_code,
+    )?;
+
+    let year = args.year.to_string();
+    let holidays = fetch_year(
+        &resolve_url("HOLIDAY_API_URL", HOLIDAY_URL),
+        &ServiceKey::new(config.service_key),
+        &year,
+    )
+    .await?;
+    if holidays.is_empty() {
+        log::warn!("No holidays returned for {}.", year);
+    }
+
+    ensure_dir(&args.output_dir)?;
+    let path = args.output_dir.join("holidays.json");
+    let mut file = std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|s| serde_json::from_str::<Value>(&s).ok())
+        .filter(Value::is_object)
+        .unwrap_or_else(|| json!({}));
+    file["lastUpdated"] = json!(Local::now().format("%Y-%m-%d %H:%M:%S").to_string());
+    file["years"][&year] = serde_json::to_value(&holidays)?;
+    write_atomic(&path, serde_json::to_string_pretty(&file)?.as_bytes())?;
+
+    info!(
+        "Saved {} holidays for {} to {}",
+        holidays.len(),
+        year,
+        path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_holidays() {
+        let items = vec![
+            json!({ "dateKind": "01", "dateName": "설날", "isHoliday": "Y", "locdate": 20250129 }),
+            json!({ "dateKind": "01", "dateName": "임시공휴일", "isHoliday": "Y", "locdate": "20250127" }),
+            json!({ "dateKind": "01", "dateName": "삼일절", "isHoliday": "Y", "locdate": 20250301 }),
+            json!({ "dateKind": "01", "dateName": "대체공휴일", "isHoliday": "Y", "locdate": 20250301 }),
+            json!({ "dateKind": "01", "dateName": "제헌절", "isHoliday": "N", "locdate": 20250717 }),
+        ];
+
+        let holidays = parse_holidays(&items);
+
+        assert_eq!(holidays.len(), 3);
+        assert_eq!(holidays["2025-01-27"], "임시공휴일");
+        assert_eq!(holidays["2025-03-01"], "삼일절, 대체공휴일");
+        assert!(!holidays.contains_key("2025-07-17"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_year_retries_an_encoded_key_decoded() {
+        use std::sync::{Arc, Mutex};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0u8; 4096];
+                let n = socket.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]).into_owned();
+                // The gateway rejects the encoded key the way data.go.kr does
+                let body = if request.contains("serviceKey=abc%252B") {
+                    "<OpenAPI_ServiceResponse><cmmMsgHeader>\
+                     <returnReasonCode>30</returnReasonCode>\
+                     </cmmMsgHeader></OpenAPI_ServiceResponse>"
+                        .to_string()
+                } else {
+                    json!({ "response": {
+                        "header": { "resultCode": "00", "resultMsg": "NORMAL SERVICE." },
+                        "body": { "items": { "item": [
+                            { "dateName": "삼일절", "isHoliday": "Y", "locdate": 20250301 }
+                        ] } }
+                    } })
+                    .to_string()
+                };
+                seen.lock().unwrap().push(request);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+
+        let holidays = fetch_year(
+            &format!("http://{}", addr),
+            &ServiceKey::new("abc%2Bdef"),
+            "2025",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(holidays["2025-03-01"], "삼일절");
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].contains("serviceKey=abc%2Bdef"));
+    }
+
+    #[test]
+    fn test_fetch_holidays_accepts_key_options() {
+        use clap::Parser;
+
+        #[derive(Parser)]
+        struct TestCli {
+            #[command(flatten)]
+            args: FetchHolidaysArgs,
+        }
+
+        let cli = TestCli::parse_from([
+            "polly",
+            "2025",
+            "--service-key",
+            "cli-key",
+            "--profile",
+            "profile.json",
+            "--city-code",
+            "32010",
+        ]);
+        assert_eq!(cli.args.service_key.as_deref(), Some("cli-key"));
+        assert_eq!(cli.args.profile, Some(PathBuf::from("profile.json")));
+        assert_eq!(cli.args.city_code, "32010");
+    }
+}
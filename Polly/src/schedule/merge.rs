@@ -1,20 +1,30 @@
 use std::collections::hash_map::Entry;
 use std::collections::{BTreeMap, HashMap};
 
-use serde_json::json;
+use log::warn;
+use serde_json::{Map, Value, json};
 
-use crate::schedule::model::{ParsedSchedule, RouteMeta};
+use crate::schedule::model::{ParsedSchedule, RouteMeta, TimeEntry};
 
 /// Merges multiple `ParsedSchedule` structs into a single, comprehensive JSON object per route.
 /// For example, it combines weekday and weekend schedules for the same bus route.
+///
+/// Day types and directions are visited in sorted order and hours are keyed numerically, so the
+/// same input always yields the same keys and note ids.
+///
+/// Each route lists under `"sources"` the crawled route ids that supplied its day types, with
+/// their crawl times, so a missing day type can be traced back to a variant that was not crawled.
+/// Stop lists found on the pages (`--stop-sequences`) are kept under `"stopSequences"`.
 pub fn merge_schedules(
-    schedules: Vec<ParsedSchedule>,
+    mut schedules: Vec<ParsedSchedule>,
     route_meta_map: &HashMap<String, RouteMeta>,
 ) -> HashMap<String, serde_json::Value> {
     let mut merged_routes: HashMap<String, serde_json::Value> = HashMap::new();
     let mut route_note_maps: HashMap<String, HashMap<String, String>> = HashMap::new();
     let mut route_note_counters: HashMap<String, usize> = HashMap::new();
 
+    schedules.sort_by(|a, b| (&a.route_number, &a.day_type).cmp(&(&b.route_number, &b.day_type)));
+
     for schedule in schedules {
         let r_no = schedule.route_number.clone();
 
@@ -39,7 +49,8 @@ pub fn merge_schedules(
                 "routeDetails": [],
                 "featuredStops": { "general": [] },
                 "schedule": {},
-                "notes": {}
+                "notes": {},
+                "sources": []
             });
             merged_routes.insert(r_no.clone(), initial_json);
             route_note_maps.insert(r_no.clone(), HashMap::new());
@@ -54,8 +65,46 @@ pub fn merge_schedules(
         let day_type_schedule = json!({});
         route_json["schedule"][&schedule.day_type] = day_type_schedule;
 
-        for (direction, entries) in schedule.times_by_direction {
-            let mut times_by_hour: BTreeMap<String, Vec<serde_json::Value>> = BTreeMap::new();
+        // A later page for the same day type replaces the earlier one, and so does its source.
+        let mut source = json!({ "routeId": schedule.route_id, "dayType": schedule.day_type });
+        if let Some(crawled_at) = &schedule.crawled_at {
+            source["crawledAt"] = json!(crawled_at);
+        }
+        if let Some(sources) = route_json["sources"].as_array_mut() {
+            sources.retain(|s| s["dayType"] != json!(schedule.day_type));
+            sources.push(source);
+        }
+
+        // Record headline figures from the page summary, if any were found.
+        if schedule.daily_trip_count.is_some() || schedule.operating_hours.is_some() {
+            let mut summary = json!({});
+            if let Some(count) = schedule.daily_trip_count {
+                summary["dailyTripCount"] = json!(count);
+            }
+            if let Some(hours) = &schedule.operating_hours {
+                summary["operatingHours"] = json!(hours);
+            }
+            route_json["summary"][&schedule.day_type] = summary;
+        }
+
+        // Day types usually list the same stops, so each distinct sequence is kept once per route.
+        for stops in &schedule.stop_sequences {
+            let sequences = route_json
+                .as_object_mut()
+                .unwrap()
+                .entry("stopSequences")
+                .or_insert_with(|| json!([]));
+            if let Some(list) = sequences.as_array_mut()
+                && !list.contains(&json!(stops))
+            {
+                list.push(json!(stops));
+            }
+        }
+
+        let times_by_direction: BTreeMap<String, Vec<TimeEntry>> =
+            schedule.times_by_direction.into_iter().collect();
+        for (direction, entries) in times_by_direction {
+            let mut times_by_hour: BTreeMap<u32, Vec<serde_json::Value>> = BTreeMap::new();
 
             for entry in entries {
                 // Handle notes: assign a unique ID to each note text.
@@ -76,9 +125,10 @@ pub fn merge_schedules(
 
                 // Group times by the hour.
                 let parts: Vec<&str> = entry.time.split(':').collect();
-                if parts.len() == 2 {
-                    let hour = format!("{:0>2}", parts[0]);
-                    let minute = format!("{:0>2}", parts[1]);
+                if let [hour, minute] = parts[..]
+                    && let Ok(hour) = hour.trim().parse::<u32>()
+                {
+                    let minute = format!("{:0>2}", minute);
 
                     let mut minute_obj = json!({ "minute": minute });
                     if let Some(nid) = note_id {
@@ -86,11 +136,17 @@ pub fn merge_schedules(
                     }
 
                     times_by_hour.entry(hour).or_default().push(minute_obj);
+                } else {
+                    warn!(
+                        "Route {} ({}, {}): skipped time {:?} without a valid hour",
+                        r_no, schedule.day_type, direction, entry.time
+                    );
                 }
             }
 
             // Add the hour-grouped times to the final JSON structure.
             for (hour, minutes) in times_by_hour {
+                let hour = format!("{:02}", hour);
                 if route_json["schedule"][&schedule.day_type][&hour].is_null() {
                     route_json["schedule"][&schedule.day_type][&hour] = json!({});
                 }
@@ -101,3 +157,373 @@ pub fn merge_schedules(
 
     merged_routes
 }
+
+/// Wraps every route's schedule, keyed by route number, into one city-wide file (`--combined`).
+///
+/// The route values are kept as they are, so each keeps its own `notes` and note ids.
+pub fn combine_schedules(routes: BTreeMap<String, Value>) -> Value {
+    json!({
+        "lastUpdated": chrono::Local::now().format("%Y-%m-%d").to_string(),
+        "routeCount": routes.len(),
+        "routes": routes,
+    })
+}
+
+/// Rewrites each hour's `[{minute, noteId}]` lists into sorted, comma-joined minute strings.
+///
+/// Notes move to a parallel `scheduleNotes` tree (`day type → hour → direction → {minute: noteId}`)
+/// holding only the minutes that have one, and the file is marked with `"scheduleFormat": "compact"`.
+pub fn compact_schedule(route: &mut Value) {
+    let Some(schedule) = route.get_mut("schedule").and_then(Value::as_object_mut) else {
+        return;
+    };
+
+    let mut notes = Map::new();
+    for (day_type, hours) in schedule.iter_mut() {
+        for (hour, dirs) in hours.as_object_mut().into_iter().flatten() {
+            for (direction, times) in dirs.as_object_mut().into_iter().flatten() {
+                let Some(entries) = times.as_array() else {
+                    continue;
+                };
+
+                let mut minutes = Vec::with_capacity(entries.len());
+                for entry in entries {
+                    let Some(minute) = entry["minute"].as_str() else {
+                        continue;
+                    };
+                    if let Some(note_id) = entry.get("noteId") {
+                        let day_notes = notes.entry(day_type.clone()).or_insert_with(|| json!({}));
+                        day_notes[hour][direction][minute] = note_id.clone();
+                    }
+                    minutes.push(minute.to_string());
+                }
+                minutes.sort();
+                *times = json!(minutes.join(","));
+            }
+        }
+    }
+
+    if !notes.is_empty() {
+        route["scheduleNotes"] = Value::Object(notes);
+    }
+    route["scheduleFormat"] = json!("compact");
+}
+
+/// The minutes of one hour and direction, from either the verbose or the compact form.
+pub fn minutes_of(times: &Value) -> Vec<String> {
+    match times {
+        Value::Array(entries) => entries
+            .iter()
+            .filter_map(|e| e["minute"].as_str().map(str::to_string))
+            .collect(),
+        Value::String(joined) => joined
+            .split(',')
+            .filter(|m| !m.is_empty())
+            .map(str::to_string)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Re-applies hand-edited content from a previously saved schedule file onto freshly crawled data.
+///
+/// Precedence, from highest to lowest:
+/// 1. Entries under the existing file's `"manualOverrides"` section.
+/// 2. The rest of the existing file, only if it is flagged with `"manual": true`.
+/// 3. The freshly crawled data.
+///
+/// Objects are merged key by key; any other value (including arrays) is replaced wholesale.
+/// The `manual` flag and `manualOverrides` section are carried over so they survive the next run.
+pub fn apply_manual_overrides(fresh: &mut Value, existing: &Value) {
+    if existing["manual"].as_bool() == Some(true) {
+        deep_merge(fresh, existing);
+    }
+
+    if let Some(overrides) = existing.get("manualOverrides") {
+        deep_merge(fresh, overrides);
+        fresh["manualOverrides"] = overrides.clone();
+    }
+}
+
+/// Recursively merges `patch` into `target`, with `patch` taking precedence.
+fn deep_merge(target: &mut Value, patch: &Value) {
+    match (target, patch) {
+        (Value::Object(t), Value::Object(p)) => {
+            for (key, value) in p {
+                deep_merge(t.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+        (t, p) => *t = p.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_schedules() -> Vec<ParsedSchedule> {
+        let entry = |time: &str, note: Option<&str>| TimeEntry {
+            time: time.to_string(),
+            note: note.map(str::to_string),
+        };
+        ["weekend", "weekday"]
+            .into_iter()
+            .map(|day_type| ParsedSchedule {
+                route_id: format!("34({})", day_type),
+                route_number: "34".to_string(),
+                day_type: day_type.to_string(),
+                directions: vec!["기업도시".to_string(), "터미널".to_string()],
+                times_by_direction: HashMap::from([
+                    (
+                        "터미널".to_string(),
+                        vec![entry("9:10", Some("학교 경유")), entry("10:05", None)],
+                    ),
+                    (
+                        "기업도시".to_string(),
+                        vec![entry("06:40", Some("차고지 출발")), entry("23:55", None)],
+                    ),
+                ]),
+                daily_trip_count: None,
+                operating_hours: None,
+                crawled_at: None,
+                stop_sequences: Vec::new(),
+            })
+            .collect()
+    }
+
+    /// Two routes: one with metadata, notes shared across day types, past-midnight times and a
+    /// repeated day type, and one without metadata
+    fn snapshot_schedules() -> Vec<ParsedSchedule> {
+        let entry = |time: &str, note: Option<&str>| TimeEntry {
+            time: time.to_string(),
+            note: note.map(str::to_string),
+        };
+        let schedule =
+            |route: &str, day_type: &str, times: Vec<(&str, Vec<TimeEntry>)>| ParsedSchedule {
+                route_id: format!("{}({})", route, day_type),
+                route_number: route.to_string(),
+                day_type: day_type.to_string(),
+                directions: times.iter().map(|(dir, _)| dir.to_string()).collect(),
+                times_by_direction: times
+                    .into_iter()
+                    .map(|(dir, entries)| (dir.to_string(), entries))
+                    .collect(),
+                daily_trip_count: None,
+                operating_hours: None,
+                crawled_at: Some("2025-01-01T05:00:00+09:00".to_string()),
+                stop_sequences: Vec::new(),
+            };
+
+        vec![
+            schedule(
+                "34",
+                "weekday",
+                vec![
+                    (
+                        "기업도시",
+                        vec![
+                            entry("5:30", Some("학교 경유")),
+                            entry("05:50", Some("임시 운행")),
+                            entry("23:40", None),
+                        ],
+                    ),
+                    ("원주역", vec![entry("06:00", None)]),
+                ],
+            ),
+            ParsedSchedule {
+                daily_trip_count: Some(3),
+                operating_hours: Some("07:00~24:10".to_string()),
+                ..schedule(
+                    "34",
+                    "weekend",
+                    vec![
+                        (
+                            "기업도시",
+                            vec![
+                                entry("07:00", Some("학교 경유")),
+                                entry("24:10", Some("심야 연장")),
+                            ],
+                        ),
+                        (
+                            "원주역",
+                            vec![entry("08:15", Some("주말 한정")), entry("00:30", None)],
+                        ),
+                    ],
+                )
+            },
+            // A second weekday page replaces the first one's times; its notes keep their ids
+            ParsedSchedule {
+                route_id: "34-1(평일)".to_string(),
+                ..schedule(
+                    "34",
+                    "weekday",
+                    vec![(
+                        "기업도시",
+                        vec![entry("05:30", Some("학교 경유")), entry("06:10", None)],
+                    )],
+                )
+            },
+            schedule("2", "weekday", vec![("터미널", vec![entry("10:05", None)])]),
+        ]
+    }
+
+    #[test]
+    fn test_merge_schedules_snapshot() {
+        let meta = HashMap::from([(
+            "34".to_string(),
+            RouteMeta {
+                origin: "기업도시".to_string(),
+                destination: "원주역".to_string(),
+                directions: vec!["기업도시".to_string(), "원주역".to_string()],
+            },
+        )]);
+
+        let mut merged: BTreeMap<String, Value> = merge_schedules(snapshot_schedules(), &meta)
+            .into_iter()
+            .collect();
+        for route in merged.values_mut() {
+            route["lastUpdated"] = json!("2025-01-01");
+        }
+        insta::assert_json_snapshot!("merged_schedules", merged);
+    }
+
+    #[test]
+    fn test_merge_output_is_byte_stable() {
+        let meta = HashMap::new();
+        let first = serde_json::to_string_pretty(&merge_schedules(sample_schedules(), &meta)["34"]);
+        let second =
+            serde_json::to_string_pretty(&merge_schedules(sample_schedules(), &meta)["34"]);
+        assert_eq!(first.unwrap(), second.unwrap());
+
+        let merged = merge_schedules(sample_schedules(), &meta);
+        let hours: Vec<&String> = merged["34"]["schedule"]["weekday"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .collect();
+        assert_eq!(hours, ["06", "09", "10", "23"]);
+        // Directions are visited alphabetically, so note ids follow that order
+        assert_eq!(merged["34"]["notes"]["1"], "차고지 출발");
+        assert_eq!(merged["34"]["notes"]["2"], "학교 경유");
+    }
+
+    #[test]
+    fn test_combined_schedules_shape() {
+        let meta = HashMap::new();
+        let mut schedules = snapshot_schedules();
+        schedules.extend(sample_schedules().into_iter().map(|s| ParsedSchedule {
+            route_number: "34-1".to_string(),
+            ..s
+        }));
+        let routes: BTreeMap<String, Value> =
+            merge_schedules(schedules, &meta).into_iter().collect();
+        let per_route_size: usize = routes
+            .values()
+            .map(|route| serde_json::to_string(route).unwrap().len())
+            .sum();
+
+        let combined = combine_schedules(routes.clone());
+
+        assert_eq!(combined["routeCount"], 3);
+        let keys: Vec<&String> = combined["routes"].as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["2", "34", "34-1"]);
+        assert_eq!(combined["routes"]["34"], routes["34"]);
+        // Only the wrapper is added on top of the per-route files
+        let size = serde_json::to_string(&combined).unwrap().len();
+        assert!(size > per_route_size && size < per_route_size + 100);
+    }
+
+    #[test]
+    fn test_compact_schedule_round_trip() {
+        let verbose = json!({
+            "schedule": {
+                "weekday": {
+                    "05": { "평일방면": [
+                        { "minute": "10" },
+                        { "minute": "25", "noteId": "1" },
+                        { "minute": "40" }
+                    ] },
+                    "06": { "평일방면": [{ "minute": "05" }] }
+                }
+            },
+            "notes": { "1": "학교 경유" }
+        });
+
+        let mut compact = verbose.clone();
+        compact_schedule(&mut compact);
+
+        assert_eq!(compact["schedule"]["weekday"]["05"]["평일방면"], "10,25,40");
+        assert_eq!(
+            compact["scheduleNotes"]["weekday"]["05"]["평일방면"]["25"],
+            "1"
+        );
+        assert_eq!(compact["scheduleFormat"], "compact");
+
+        for hour in ["05", "06"] {
+            assert_eq!(
+                minutes_of(&compact["schedule"]["weekday"][hour]["평일방면"]),
+                minutes_of(&verbose["schedule"]["weekday"][hour]["평일방면"])
+            );
+        }
+    }
+
+    #[test]
+    fn test_manual_overrides_take_precedence() {
+        let mut fresh = json!({
+            "routeNo": "34",
+            "directions": ["fresh"],
+            "notes": { "1": "fresh", "2": "fresh" }
+        });
+        let existing = json!({
+            "manual": true,
+            "directions": ["manual"],
+            "notes": { "1": "manual", "3": "manual" },
+            "manualOverrides": { "notes": { "3": "override" } }
+        });
+
+        apply_manual_overrides(&mut fresh, &existing);
+
+        assert_eq!(fresh["routeNo"], "34");
+        assert_eq!(fresh["directions"], json!(["manual"]));
+        assert_eq!(
+            fresh["notes"],
+            json!({ "1": "manual", "2": "fresh", "3": "override" })
+        );
+        assert_eq!(fresh["manual"], true);
+        assert_eq!(fresh["manualOverrides"], existing["manualOverrides"]);
+    }
+
+    #[test]
+    fn test_manual_overrides_ignore_unflagged_files() {
+        let mut fresh = json!({ "directions": ["fresh"], "notes": { "1": "fresh" } });
+        let existing = json!({
+            "directions": ["edited"],
+            "manualOverrides": { "notes": { "1": "override" } }
+        });
+
+        apply_manual_overrides(&mut fresh, &existing);
+
+        assert_eq!(fresh["directions"], json!(["fresh"]));
+        assert_eq!(fresh["notes"]["1"], "override");
+        assert!(fresh.get("manual").is_none());
+    }
+
+    #[test]
+    fn test_deep_merge_replaces_arrays_and_keeps_unrelated_sections() {
+        let mut target = json!({
+            "schedule": { "weekday": { "05": { "A": ["10", "25", "40"] } } },
+            "sources": [{ "routeId": "34(weekday)" }],
+            "notes": { "1": "학교 경유" }
+        });
+        let patch = json!({
+            "schedule": { "weekday": { "05": { "A": ["15"] } } },
+            "sources": []
+        });
+
+        deep_merge(&mut target, &patch);
+
+        assert_eq!(target["schedule"]["weekday"]["05"]["A"], json!(["15"]));
+        assert_eq!(target["sources"], json!([]));
+        assert_eq!(target["notes"], json!({ "1": "학교 경유" }));
+    }
+}
@@ -5,24 +5,48 @@
 //! handle session cookies and parse HTML responses to extract schedule
 //! information. The extracted data is then organized and saved as JSON files.
 
+mod check;
+mod cross_check;
 mod fetch;
+mod headways;
+mod holidays;
 mod merge;
 mod model;
 mod parse;
+mod route_summary;
+mod stop_order;
 
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::path::PathBuf;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 
 use anyhow::Result;
+use chrono::{DateTime, Local};
 use log::{error, info, warn};
-use tokio::time::sleep;
+use serde::{Deserialize, Serialize};
 
+use crate::config::{
+    SCHEDULE_DETAIL_HANDLERS, SCHEDULE_DETAIL_TIMEOUT_SECS, SCHEDULE_MAIN_TIMEOUT_SECS,
+    SCHEDULE_MAX_RETRIES, SCHEDULE_ROUTE_ID_ATTRS,
+};
 use crate::schedule::fetch::ScheduleClient;
-use crate::schedule::merge::merge_schedules;
-use crate::schedule::model::ParsedSchedule;
-use crate::schedule::parse::{extract_route_info, parse_detail_schedule};
+use crate::schedule::merge::{
+    apply_manual_overrides, combine_schedules, compact_schedule, merge_schedules,
+};
+use crate::schedule::model::{ParsedSchedule, RouteMeta};
+use crate::schedule::parse::{RouteIdExtractor, extract_route_info, parse_detail_schedule};
 use crate::utils;
+use crate::utils::layout::LayoutArgs;
+
+pub use check::{CheckSchedulesArgs, check_schedules};
+pub use cross_check::{CrossCheckArgs, cross_check};
+pub use headways::{HeadwaysArgs, headways};
+pub use holidays::{FetchHolidaysArgs, fetch_holidays};
+pub use route_summary::{ExportRouteSummaryCsvArgs, export_route_summary_csv};
+pub use stop_order::{CheckStopOrderArgs, check_stop_order};
 
 // ============================================================================
 // Schedule Arguments
@@ -37,6 +61,125 @@ pub struct ScheduleArgs {
     /// Output directory for saving the schedule JSON files.
     #[arg(short, long, default_value = "./storage")]
     pub output_dir: PathBuf,
+
+    #[command(flatten)]
+    pub layout: LayoutArgs,
+
+    /// City code the schedules are filed under with a layout that nests files by city.
+    #[arg(long, default_value = "32020")]
+    pub city_code: String,
+
+    /// Preserve manual edits in existing schedule files (`"manual": true` or `"manualOverrides"`).
+    #[arg(long, visible_alias = "preserve-manual")]
+    pub append: bool,
+
+    /// Minimum interval between requests to the same host, in milliseconds.
+    #[arg(long, default_value_t = 300)]
+    pub min_interval_ms: u64,
+
+    /// Timeout for the main schedule page request, in seconds.
+    #[arg(long, default_value_t = SCHEDULE_MAIN_TIMEOUT_SECS)]
+    pub main_timeout: u64,
+
+    /// Timeout for each detail page request, in seconds.
+    #[arg(long, default_value_t = SCHEDULE_DETAIL_TIMEOUT_SECS)]
+    pub detail_timeout: u64,
+
+    /// Number of retries for a failed main or detail page request.
+    #[arg(long, default_value_t = SCHEDULE_MAX_RETRIES)]
+    pub max_retries: u32,
+
+    /// Save every route's detail HTML to this directory (named by route id) for parser debugging.
+    /// Pages that parse to zero times are always saved here, or to `<output_dir>/debug_html`.
+    #[arg(long, value_name = "DIR")]
+    pub save_html: Option<PathBuf>,
+
+    /// Write each hour's minutes as a comma-joined string (e.g. `"10,25,40"`), with notes in a
+    /// parallel `scheduleNotes` section. Roughly halves file size.
+    #[arg(long)]
+    pub compact_schedule: bool,
+
+    /// Write each schedule JSON file on a single line instead of indented.
+    /// Keys are sorted either way, so reruns on unchanged data produce identical files.
+    #[arg(long)]
+    pub no_pretty_schedule: bool,
+
+    /// Reuse the route list from the previous run's main page if it is younger than this
+    /// (e.g. `30m`, `12h`), skipping the main page fetch and parse.
+    #[arg(long, value_name = "INTERVAL", value_parser = utils::parse_interval)]
+    pub routes_cache_ttl: Option<Duration>,
+
+    /// Fetch the main page even if the cached route list is still fresh.
+    #[arg(long)]
+    pub refresh_routes: bool,
+
+    /// Number of independent cookie sessions to crawl detail pages with in parallel. Each session gets
+    /// its own main page hit and request spacing, so a server that ties one JSESSIONID to one request
+    /// at a time is never sent concurrent requests on the same session.
+    #[arg(long, default_value = "1", value_name = "N")]
+    pub sessions: NonZeroUsize,
+
+    /// `onclick` handler name (a regex fragment) whose first argument is the route id on the main
+    /// page. Tried in order; replaces the defaults when given.
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        default_values_t = SCHEDULE_DETAIL_HANDLERS.iter().map(|s| s.to_string())
+    )]
+    pub detail_handler: Vec<String>,
+
+    /// Attribute holding the route id when no `onclick` handler matches. Replaces the defaults when given.
+    #[arg(
+        long,
+        value_name = "ATTR",
+        default_values_t = SCHEDULE_ROUTE_ID_ATTRS.iter().map(|s| s.to_string())
+    )]
+    pub route_id_attr: Vec<String>,
+
+    /// Also write every route into one city-wide `schedules/all.json`, keyed by route number.
+    #[arg(long)]
+    pub combined: bool,
+
+    /// Write only the combined file, not one file per route.
+    #[arg(long, requires = "combined")]
+    pub no_route_files: bool,
+
+    /// Keep stop lists shown on detail pages (e.g. "A → B → C") as `stopSequences`, for
+    /// cross-checking the TAGO stop order with `check-stop-order`.
+    #[arg(long)]
+    pub stop_sequences: bool,
+}
+
+/// File in `schedules/` holding the route list parsed from the main page
+const ROUTES_CACHE_FILE: &str = "_routes_cache.json";
+
+/// File in `schedules/` holding every route with `--combined`
+pub const COMBINED_SCHEDULE_FILE: &str = "all.json";
+
+/// Route list parsed from the main page, cached between runs
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RoutesCache {
+    fetched_at: String,
+    /// The `--route` filter the list was built with
+    filter: Option<String>,
+    route_meta: HashMap<String, RouteMeta>,
+    targets: Vec<String>,
+}
+
+/// Loads the cached route list if it was built with the same filter and is younger than `ttl`.
+fn load_routes_cache(path: &Path, filter: Option<&str>, ttl: Duration) -> Option<RoutesCache> {
+    let cache: RoutesCache = serde_json::from_str(&fs::read_to_string(path).ok()?).ok()?;
+    if cache.filter.as_deref() != filter {
+        return None;
+    }
+
+    let fetched_at = DateTime::parse_from_rfc3339(&cache.fetched_at).ok()?;
+    let age = Local::now()
+        .signed_duration_since(fetched_at)
+        .to_std()
+        .ok()?;
+    (age < ttl).then_some(cache)
 }
 
 /// Main entry point for the schedule crawler.
@@ -50,90 +193,441 @@ pub struct ScheduleArgs {
 /// 6. Saves the final, structured data as JSON files.
 ///
 pub async fn run(args: ScheduleArgs) -> Result<()> {
-    let schedule_dir = args.output_dir.join("schedules");
+    let layout = args.layout.resolve()?;
+    let root = layout.root(&args.output_dir, Some(&args.city_code))?;
+    let schedule_dir = layout.schedules_dir(&root);
 
     utils::ensure_dir(&schedule_dir)?;
 
     info!("Starting Bus Schedule Crawler (Browser Mimic Mode)");
 
-    // Initialize an HTTP client that mimics a web browser.
-    let client = ScheduleClient::new()?;
+    // Initialize HTTP clients that mimic a web browser, each with its own cookie jar.
+    let clients = (0..args.sessions.get())
+        .map(|_| {
+            ScheduleClient::new(
+                Duration::from_millis(args.min_interval_ms),
+                Duration::from_secs(args.main_timeout),
+                Duration::from_secs(args.detail_timeout),
+                args.max_retries,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let client = &clients[0];
+
+    let cache_path = schedule_dir.join(ROUTES_CACHE_FILE);
+    let cached = args
+        .routes_cache_ttl
+        .filter(|_| !args.refresh_routes)
+        .and_then(|ttl| load_routes_cache(&cache_path, args.route.as_deref(), ttl));
 
-    // Fetch the main schedule page to acquire session cookies and the list of all routes.
-    info!("Fetching main page (Initializing Session)...");
+    // Without the main page there are no session cookies yet; get them on the first failure
+    let first_session_ready = cached.is_none();
 
-    let resp = client.fetch_main_page().await?;
+    let (route_meta_map, targets) = match cached {
+        Some(cache) => {
+            info!("Using route list cached at {}", cache.fetched_at);
+            (cache.route_meta, cache.targets)
+        }
+        None => {
+            // Fetch the main schedule page to acquire session cookies and the list of all routes.
+            info!("Fetching main page (Initializing Session)...");
 
-    // Extract basic route information and the target route IDs to crawl.
-    let (route_meta_map, targets) = extract_route_info(&resp, args.route.as_deref())?;
+            let resp = client.fetch_main_page().await?;
+
+            // Extract basic route information and the target route IDs to crawl.
+            let extractor = RouteIdExtractor::new(&args.detail_handler, &args.route_id_attr)?;
+            let (route_meta_map, targets) =
+                extract_route_info(&resp, args.route.as_deref(), &extractor)?;
+
+            let cache = RoutesCache {
+                fetched_at: Local::now().to_rfc3339(),
+                filter: args.route.clone(),
+                route_meta: route_meta_map,
+                targets,
+            };
+            let contents = serde_json::to_string(&cache)?;
+            if let Err(e) = utils::write_atomic(&cache_path, contents.as_bytes()) {
+                warn!("Could not save route list cache: {}", e);
+            }
+            (cache.route_meta, cache.targets)
+        }
+    };
 
     info!("Found info for {} routes", route_meta_map.len());
     info!("Found {} route schedules to process", targets.len());
 
-    let mut collected_schedules: Vec<ParsedSchedule> = Vec::new();
+    let html_dir = args
+        .save_html
+        .clone()
+        .unwrap_or_else(|| args.output_dir.join("debug_html"));
+    if args.save_html.is_some() {
+        utils::ensure_dir(&html_dir)?;
+    }
+
+    let crawl = Crawl {
+        args: &args,
+        route_meta_map: &route_meta_map,
+        html_dir: &html_dir,
+    };
+    let collected_schedules =
+        crawl_sessions(&crawl, &clients, &targets, first_session_ready).await?;
+
+    // Merge the collected schedules and save them to JSON files.
+    info!("Organizing and saving schedules...");
+
+    let merged_routes = merge_schedules(collected_schedules, &route_meta_map);
+
+    let mut combined_routes = BTreeMap::new();
+    for (route_number, mut data) in merged_routes {
+        if args.compact_schedule {
+            compact_schedule(&mut data);
+        }
+        let path = schedule_dir.join(format!("{}.json", utils::safe_file_name(&route_number)));
+        if args.append {
+            preserve_manual_edits(&path, &mut data);
+        }
+        if !args.no_route_files {
+            save_route_schedule(&path, &route_number, &data, !args.no_pretty_schedule)?;
+        }
+        if args.combined {
+            combined_routes.insert(route_number, data);
+        }
+    }
+
+    if args.combined {
+        let path = schedule_dir.join(COMBINED_SCHEDULE_FILE);
+        let count = combined_routes.len();
+        save_route_schedule(
+            &path,
+            "combined schedules",
+            &combine_schedules(combined_routes),
+            !args.no_pretty_schedule,
+        )?;
+        info!("Combined file holds {} routes.", count);
+    }
+
+    Ok(())
+}
+
+/// Route indices left to crawl. Each session claims the next one, so a slow session does not hold
+/// up the others; a dropped session gives its claimed route back.
+struct RouteQueue {
+    len: usize,
+    next: AtomicUsize,
+    returned: std::sync::Mutex<Vec<usize>>,
+}
 
-    // Iterate through each target route and fetch its detailed schedule.
-    for (i, route_id) in targets.iter().enumerate() {
-        info!("Processing route {}/{}: {}", i + 1, targets.len(), route_id);
-        sleep(Duration::from_millis(300)).await; // Politeness delay.
+impl RouteQueue {
+    fn claim(&self) -> Option<usize> {
+        if let Some(i) = self.returned.lock().unwrap().pop() {
+            return Some(i);
+        }
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        (i < self.len).then_some(i)
+    }
 
-        let detail_html = match client.fetch_detail_page(route_id).await {
+    fn give_back(&self, i: usize) {
+        self.returned.lock().unwrap().push(i);
+    }
+}
+
+/// Crawls `targets` with one worker per session and returns the parsed pages in crawl order.
+///
+/// A session whose main page hit fails (after the client's retries) is dropped and the others
+/// carry on with its routes; the crawl fails only if every session is dropped.
+async fn crawl_sessions(
+    crawl: &Crawl<'_>,
+    clients: &[ScheduleClient],
+    targets: &[String],
+    first_session_ready: bool,
+) -> Result<Vec<ParsedSchedule>> {
+    let queue = RouteQueue {
+        len: targets.len(),
+        next: AtomicUsize::new(0),
+        returned: Default::default(),
+    };
+    let workers = clients.iter().enumerate().map(|(session, client)| {
+        let queue = &queue;
+        async move {
+            let mut collected = Vec::new();
+            let mut session_ready = session == 0 && first_session_ready;
+            if session > 0 {
+                info!("Initializing session {}...", session + 1);
+                if let Err(e) = client.fetch_main_page().await {
+                    warn!("Dropping session {}: {:#}", session + 1, e);
+                    return (collected, Some(e));
+                }
+                session_ready = true;
+            }
+
+            while let Some(i) = queue.claim() {
+                let route_id = &targets[i];
+                info!("Processing route {}/{}: {}", i + 1, targets.len(), route_id);
+                match crawl.route(client, &mut session_ready, route_id).await {
+                    Ok(Some(parsed)) => collected.push((i, parsed)),
+                    Ok(None) => {}
+                    Err(e) => {
+                        warn!("Dropping session {}: {:#}", session + 1, e);
+                        queue.give_back(i);
+                        return (collected, Some(e));
+                    }
+                }
+            }
+            (collected, None)
+        }
+    });
+
+    // Keep the crawl order whichever session fetched a route
+    let mut collected: Vec<(usize, ParsedSchedule)> = Vec::new();
+    let mut dropped = Vec::new();
+    for (found, failure) in futures::future::join_all(workers).await {
+        collected.extend(found);
+        dropped.extend(failure);
+    }
+    if dropped.len() == clients.len()
+        && let Some(e) = dropped.pop()
+    {
+        return Err(e.context("Every session failed to load the main page"));
+    }
+    let missed = queue.returned.into_inner().unwrap();
+    if !missed.is_empty() {
+        warn!(
+            "{} route(s) were not crawled because their session was dropped",
+            missed.len()
+        );
+    }
+
+    collected.sort_by_key(|(i, _)| *i);
+    Ok(collected.into_iter().map(|(_, parsed)| parsed).collect())
+}
+
+/// What every session needs to crawl and parse a detail page
+struct Crawl<'a> {
+    args: &'a ScheduleArgs,
+    route_meta_map: &'a HashMap<String, RouteMeta>,
+    html_dir: &'a Path,
+}
+
+impl Crawl<'_> {
+    /// Fetches and parses one route's detail page on `client`.
+    ///
+    /// Returns `None` when the page failed to load or parse; only a failed main page hit is an error.
+    async fn route(
+        &self,
+        client: &ScheduleClient,
+        session_ready: &mut bool,
+        route_id: &str,
+    ) -> Result<Option<ParsedSchedule>> {
+        let _permit = utils::concurrency::acquire().await;
+
+        let mut detail = client.fetch_detail_page(route_id).await;
+        if detail.is_err() && !*session_ready {
+            info!("No session with a cached route list; fetching main page.");
+            client.fetch_main_page().await?;
+            detail = client.fetch_detail_page(route_id).await;
+        }
+        *session_ready = true;
+
+        let crawled_at = Local::now().to_rfc3339();
+        let detail_html = match detail {
             Ok(html) => html,
             Err(e) => {
                 error!("Failed (Network/Status): {}", e);
-                continue;
+                return Ok(None);
             }
         };
 
-        // The route number is the part of the route_id before any parentheses.
-        let route_number = route_id.split('(').next().unwrap_or(route_id).to_string();
-        let meta = route_meta_map.get(&route_number);
+        if self.args.save_html.is_some() {
+            let path = self
+                .html_dir
+                .join(format!("{}.html", utils::safe_file_name(route_id)));
+            fs::write(path, &detail_html).ok();
+        }
+
+        // The route number is the normalized part of the route_id before any parentheses.
+        let route_number = utils::normalize_route_no(route_id);
+        let meta = self.route_meta_map.get(&route_number);
 
         // Parse the returned HTML to extract the schedule.
         match parse_detail_schedule(&detail_html, route_id, meta) {
-            Ok(parsed) => {
+            Ok(mut parsed) => {
+                parsed.crawled_at = Some(crawled_at);
+                if !self.args.stop_sequences {
+                    parsed.stop_sequences.clear();
+                }
                 let count: usize = parsed.times_by_direction.values().map(|v| v.len()).sum();
                 if count > 0 {
-                    info!("({} times)", count);
-                    collected_schedules.push(parsed);
-                } else {
-                    // If parsing yields no times, save the HTML for debugging.
-                    warn!("Warning: 0 times. (HTML Check Saved)");
-                    fs::write(format!("debug_empty_{}.html", i), &detail_html).ok();
+                    info!("{}: {} times", route_id, count);
+                    return Ok(Some(parsed));
+                }
+                // If parsing yields no times, save the HTML for debugging.
+                warn!("Warning: 0 times for {}. (HTML Check Saved)", route_id);
+                let path = self
+                    .html_dir
+                    .join(format!("empty_{}.html", utils::safe_file_name(route_id)));
+                if utils::ensure_dir(self.html_dir).is_ok() {
+                    fs::write(path, &detail_html).ok();
                 }
             }
             Err(e) => {
                 error!("Error: {}", e);
             }
         }
+        Ok(None)
     }
+}
 
-    // Merge the collected schedules and save them to JSON files.
-    info!("Organizing and saving schedules...");
-
-    let merged_routes = merge_schedules(collected_schedules, &route_meta_map);
-
-    for (route_number, data) in merged_routes {
-        save_route_schedule(&schedule_dir, &route_number, &data)?;
+/// Merges manual edits in the existing schedule file at `path`, if any, over the new data.
+fn preserve_manual_edits(path: &Path, data: &mut serde_json::Value) {
+    if !path.exists() {
+        return;
+    }
+    let existing = fs::read_to_string(path)
+        .ok()
+        .and_then(|s| serde_json::from_str::<serde_json::Value>(&s).ok());
+    match existing {
+        Some(existing) => apply_manual_overrides(data, &existing),
+        None => warn!("Could not read {:?}; manual edits not applied", path),
     }
-
-    Ok(())
 }
 
-/// Saves the final merged schedule data for a route to a JSON file.
+/// Saves the final merged schedule data to a JSON file.
 fn save_route_schedule(
-    base_dir: &std::path::Path,
-    route_number: &str,
+    path: &Path,
+    name: &str,
     data: &serde_json::Value,
+    pretty: bool,
 ) -> Result<()> {
-    // Sanitize the route number to create a valid filename.
-    let safe_name = route_number.replace(|c: char| !c.is_alphanumeric() && c != '-', "_");
-    let filename = format!("{}.json", safe_name);
-    let path = base_dir.join(filename);
-
-    let json_str = serde_json::to_string_pretty(data)?;
-    fs::write(&path, json_str)?;
+    let json_str = if pretty {
+        serde_json::to_string_pretty(data)?
+    } else {
+        serde_json::to_string(data)?
+    };
+    fs::write(path, json_str)?;
 
-    info!("Saved {} to {:?}", route_number, path.file_name().unwrap());
+    info!("Saved {} to {:?}", name, path.file_name().unwrap());
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        args: ScheduleArgs,
+    }
+
+    const DETAIL_HTML: &str = r#"
+        <table>
+            <tr><th>운행순번</th><th>기업도시발</th><th>비고</th></tr>
+            <tr><td>1</td><td>05:30</td><td></td></tr>
+        </table>
+    "#;
+
+    /// Session cookies the detail requests arrived with, and the number of main page hits
+    #[derive(Default)]
+    struct Server {
+        cookies: Vec<String>,
+        main_hits: usize,
+    }
+
+    /// Hands every main page hit its own session cookie, except the second, which fails.
+    /// Detail pages need a session cookie.
+    async fn serve(mut socket: TcpStream, server: Arc<Mutex<Server>>) {
+        let mut buf = Vec::new();
+        let mut chunk = [0; 4096];
+        while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            match socket.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            }
+        }
+        let request = String::from_utf8_lossy(&buf).to_lowercase();
+        let cookie = request
+            .lines()
+            .find_map(|line| line.strip_prefix("cookie: jsessionid="))
+            .map(str::to_string);
+
+        let (status, headers, body) = if request.starts_with("get /main") {
+            let mut server = server.lock().unwrap();
+            server.main_hits += 1;
+            match server.main_hits {
+                2 => ("500 Internal Server Error", String::new(), ""),
+                n => ("200 OK", format!("Set-Cookie: JSESSIONID=s{}\r\n", n), ""),
+            }
+        } else if let Some(cookie) = cookie {
+            server.lock().unwrap().cookies.push(cookie);
+            ("200 OK", String::new(), DETAIL_HTML)
+        } else {
+            ("403 Forbidden", String::new(), "")
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\n{}Content-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            headers,
+            body.len(),
+            body
+        );
+        let _ = socket.write_all(response.as_bytes()).await;
+    }
+
+    #[tokio::test]
+    async fn test_sessions_keep_their_own_cookie_and_survive_a_dropped_one() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(Mutex::new(Server::default()));
+        let state = Arc::clone(&server);
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(serve(socket, Arc::clone(&state)));
+            }
+        });
+
+        let clients: Vec<ScheduleClient> = (0..3)
+            .map(|_| {
+                // Spacing each session's requests lets every session claim some routes
+                ScheduleClient::new(
+                    Duration::from_millis(50),
+                    Duration::from_secs(5),
+                    Duration::from_secs(5),
+                    0,
+                )
+                .unwrap()
+                .with_urls(
+                    format!("http://{}/main", addr),
+                    format!("http://{}/detail", addr),
+                )
+            })
+            .collect();
+        // `run` loads the main page on the first session before crawling
+        clients[0].fetch_main_page().await.unwrap();
+
+        let args = TestCli::parse_from(["polly"]).args;
+        let route_meta_map = HashMap::new();
+        let crawl = Crawl {
+            args: &args,
+            route_meta_map: &route_meta_map,
+            html_dir: &std::env::temp_dir(),
+        };
+        let targets: Vec<String> = (1..=6).map(|n| format!("{}(평일)", n)).collect();
+
+        let parsed = crawl_sessions(&crawl, &clients, &targets, true)
+            .await
+            .unwrap();
+        let route_ids: Vec<&str> = parsed.iter().map(|p| p.route_id.as_str()).collect();
+        assert_eq!(route_ids, targets);
+
+        // The session whose main page failed sent nothing; the other two kept their own cookie
+        let server = server.lock().unwrap();
+        assert_eq!(server.main_hits, 3);
+        assert_eq!(server.cookies.len(), targets.len());
+        assert!(server.cookies.iter().all(|c| c == "s1" || c == "s3"));
+        assert!(server.cookies.iter().any(|c| c == "s3"));
+    }
+}
@@ -5,9 +5,11 @@
 
 use std::collections::HashMap;
 
+use serde::{Deserialize, Serialize};
+
 /// Holds metadata for a bus route, such as its start and end points
 /// and a list of all unique directions (termini) it serves.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RouteMeta {
     pub origin: String,
     pub destination: String,
@@ -24,8 +26,18 @@ pub struct TimeEntry {
 /// Represents the fully parsed schedule for a specific route on a specific day type.
 #[derive(Debug)]
 pub struct ParsedSchedule {
+    /// The crawled route id this page was fetched for (e.g. "34-1(평일)").
+    pub route_id: String,
     pub route_number: String,
     pub day_type: String,
     pub directions: Vec<String>,
     pub times_by_direction: HashMap<String, Vec<TimeEntry>>,
+    /// Trip count from a summary line such as "1일 운행횟수 42회", if present.
+    pub daily_trip_count: Option<u32>,
+    /// Operating hours from a "운행시간" summary line (e.g. "05:30~22:40"), if present.
+    pub operating_hours: Option<String>,
+    /// When the detail page was fetched (RFC 3339); `None` for pages not fetched by the crawler.
+    pub crawled_at: Option<String>,
+    /// Stop names of each route list on the page (e.g. "운행경로: A → B → C"), in page order.
+    pub stop_sequences: Vec<Vec<String>>,
 }
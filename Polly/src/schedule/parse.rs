@@ -1,23 +1,231 @@
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::LazyLock;
 
 use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use percent_encoding::percent_decode_str;
 use regex::Regex;
-use scraper::{Html, Selector};
+use scraper::{ElementRef, Html, Selector};
 
 use crate::schedule::model::{ParsedSchedule, RouteMeta, TimeEntry};
+use crate::utils::normalize_route_no;
 
 // Compile regexes once at program start instead of on every function call.
-static ONCLICK_RE: LazyLock<Regex> =
-    LazyLock::new(|| Regex::new(r"goDetail\('([^']+)'\)").unwrap());
 static HOUR_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\d+시$").unwrap());
 static TIME_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^(\d{1,2}:\d{2})").unwrap());
+static TWELVE_HOUR_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(오전|오후|(?i:am|pm))\s*(\d{1,2})\s*(?::\s*(\d{2})|시(?:\s*(\d{1,2})\s*분)?)")
+        .unwrap()
+});
+static LEADING_NUM_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^(\d{1,2})").unwrap());
+static TRIP_COUNT_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"운행\s*횟수\s*[:：]?\s*(\d+)\s*회").unwrap());
+static OPERATING_HOURS_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"운행\s*시간\s*[:：]?\s*(\d{1,2}:\d{2})\s*[~∼〜\-–]\s*(\d{1,2}:\d{2})").unwrap()
+});
+// Not "-", which route numbers use, nor "↔", which joins the two termini of a description.
+static STOP_ARROW_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\s*(?:→|⇒|➔|▶|->|>)\s*").unwrap());
+
+/// Fewest stops in a list for it to count as a stop sequence rather than "A → B" prose.
+const MIN_SEQUENCE_STOPS: usize = 3;
+
+/// Symbols used as footnote markers on time cells, explained by a legend elsewhere on the page.
+const FOOTNOTE_SYMBOLS: &[char] = &['※', '*', '▲', '△', '●', '○', '◆', '◇', '★', '☆', '■', '□'];
+
+/// Rewrites a leading 12-hour time such as "오후 2:30", "오전 9시" or "PM 11:05" as 24-hour "HH:MM".
+///
+/// 오전 12 is midnight (00) and 오후 12 is noon (12). Text after the time, such as footnote markers, is kept.
+fn normalize_12_hour(text: &str) -> Cow<'_, str> {
+    let Some(caps) = TWELVE_HOUR_RE.captures(text) else {
+        return Cow::Borrowed(text);
+    };
+    let Ok(hour) = caps[2].parse::<u32>() else {
+        return Cow::Borrowed(text);
+    };
+    let minute: u32 = caps
+        .get(3)
+        .or(caps.get(4))
+        .and_then(|m| m.as_str().parse().ok())
+        .unwrap_or(0);
+    if hour > 12 || minute > 59 {
+        return Cow::Borrowed(text);
+    }
+
+    let pm = matches!(&caps[1], "오후") || caps[1].eq_ignore_ascii_case("pm");
+    let hour = match (pm, hour) {
+        (false, 12) => 0,
+        (true, h) if h < 12 => h + 12,
+        (_, h) => h,
+    };
+    let rest = &text[caps.get(0).unwrap().end()..];
+    Cow::Owned(format!("{:02}:{:02}{}", hour, minute, rest))
+}
+
+/// Collects footnote legends such as "※ 토요일 운휴" from the page text, keyed by symbol.
+fn extract_footnote_legend(document: &Html) -> HashMap<char, String> {
+    let mut legend = HashMap::new();
+    for piece in document.root_element().text() {
+        let piece = piece.trim();
+        let mut chars = piece.chars();
+        if let Some(symbol) = chars.next().filter(|c| FOOTNOTE_SYMBOLS.contains(c)) {
+            let text = chars.as_str().trim_start_matches([':', '：']).trim();
+            if !text.is_empty() {
+                legend.entry(symbol).or_insert_with(|| text.to_string());
+            }
+        }
+    }
+    legend
+}
+
+/// Combines the row's note with the legend text of any footnote symbols in `suffix`.
+fn note_with_footnotes(
+    suffix: &str,
+    row_note: Option<&String>,
+    legend: &HashMap<char, String>,
+) -> Option<String> {
+    let mut parts: Vec<String> = row_note.into_iter().cloned().collect();
+    for symbol in suffix.chars().filter(|c| FOOTNOTE_SYMBOLS.contains(c)) {
+        parts.push(
+            legend
+                .get(&symbol)
+                .cloned()
+                .unwrap_or_else(|| symbol.to_string()),
+        );
+    }
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(" / "))
+    }
+}
+
+/// Splits a route list such as "운행경로 : 터미널 → 시청 → 원주역" into its stop names.
+fn parse_stop_sequence(text: &str) -> Option<Vec<String>> {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    // Drop a leading label ("운행경로 :", "기업도시발:")
+    let list = match text.split_once([':', '：']) {
+        Some((label, rest)) if !STOP_ARROW_RE.is_match(label) => rest,
+        _ => text.as_str(),
+    };
+    let stops: Vec<String> = STOP_ARROW_RE
+        .split(list)
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    // A list of times ("05:30 → 06:10") is not a route
+    let is_route = stops.len() >= MIN_SEQUENCE_STOPS && !stops.iter().any(|s| TIME_RE.is_match(s));
+    is_route.then_some(stops)
+}
+
+/// Collects the stop sequences the page lists as arrow-separated stop names.
+///
+/// Only the innermost element holding such a list is read, so a list is not picked up
+/// again (mixed with other text) from the elements around it.
+fn extract_stop_sequences(document: &Html) -> Vec<Vec<String>> {
+    let selector = Selector::parse("p, li, dt, dd, td, th, span, div").unwrap();
+    let has_arrow =
+        |element: ElementRef| STOP_ARROW_RE.is_match(&element.text().collect::<String>());
+
+    let mut sequences: Vec<Vec<String>> = Vec::new();
+    for element in document.select(&selector) {
+        if !has_arrow(element) || element.select(&selector).any(has_arrow) {
+            continue;
+        }
+        if let Some(stops) = parse_stop_sequence(&element.text().collect::<String>())
+            && !sequences.contains(&stops)
+        {
+            sequences.push(stops);
+        }
+    }
+    sequences
+}
+
+/// Decodes a route id that the page may have percent-encoded (e.g. `34-1%28%ED%8F%89%EC%9D%BC%29`).
+fn decode_route_id(raw: &str) -> String {
+    percent_decode_str(raw)
+        .decode_utf8_lossy()
+        .trim()
+        .to_string()
+}
+
+/// Whether a (decoded) route id is selected by the user's `--route` filter.
+///
+/// A plain route number matches every day type of that route (`34-1` selects `34-1(평일)`
+/// but not `34-10(평일)`); a filter with a parenthetical must match the whole id.
+fn route_filter_matches(route_id: &str, filter: &str) -> bool {
+    let filter = decode_route_id(filter);
+    if filter.contains('(') {
+        let compact = |s: &str| s.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+        normalize_route_no(&filter) == normalize_route_no(route_id)
+            && compact(&filter) == compact(route_id)
+    } else {
+        normalize_route_no(&filter) == normalize_route_no(route_id)
+    }
+}
+
+/// Finds the route id of a main page row.
+///
+/// Each `onclick` handler pattern is tried in order on the first cell and its children,
+/// then each id attribute on the row and the first cell, so a renamed handler or an id
+/// moved to a `data-` attribute does not leave the crawler with zero routes.
+pub struct RouteIdExtractor {
+    handlers: Vec<(String, Regex)>,
+    attrs: Vec<String>,
+}
+
+impl RouteIdExtractor {
+    pub fn new(handlers: &[String], attrs: &[String]) -> Result<Self> {
+        let handlers = handlers
+            .iter()
+            .map(|pattern| {
+                let re = Regex::new(&format!(r#"\b(?:{})\s*\(\s*['"]([^'"]+)['"]"#, pattern))
+                    .with_context(|| format!("Invalid detail handler pattern: {}", pattern))?;
+                Ok((pattern.clone(), re))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            handlers,
+            attrs: attrs.to_vec(),
+        })
+    }
+
+    /// The raw route id of a row and the strategy that found it
+    fn extract(&self, row: ElementRef, first_cell: ElementRef) -> Option<(String, String)> {
+        let cell_elements: Vec<ElementRef> = first_cell
+            .descendants()
+            .filter_map(ElementRef::wrap)
+            .collect();
+
+        for (pattern, re) in &self.handlers {
+            for element in &cell_elements {
+                if let Some(onclick) = element.value().attr("onclick")
+                    && let Some(caps) = re.captures(onclick)
+                {
+                    return Some((caps[1].to_string(), format!("onclick {}", pattern)));
+                }
+            }
+        }
+        for attr in &self.attrs {
+            for element in std::iter::once(&row).chain(&cell_elements) {
+                if let Some(id) = element.value().attr(attr).map(str::trim)
+                    && !id.is_empty()
+                {
+                    return Some((id.to_string(), format!("attribute {}", attr)));
+                }
+            }
+        }
+        None
+    }
+}
 
 /// Parses the main schedule page to extract a list of all available routes.
 /// It creates a map of route metadata and a list of `route_id`s used for fetching details.
 pub fn extract_route_info(
     html: &str,
     filter: Option<&str>,
+    extractor: &RouteIdExtractor,
 ) -> Result<(HashMap<String, RouteMeta>, Vec<String>)> {
     let document = Html::parse_document(html);
     let mut route_meta_map = HashMap::new();
@@ -27,29 +235,30 @@ pub fn extract_route_info(
     let cell_selector = Selector::parse("td").unwrap();
 
     let mut temp_directions: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut strategies: BTreeMap<String, usize> = BTreeMap::new();
+    let mut route_rows = 0;
 
     // Iterate over each row in the main schedule table.
     for row in document.select(&row_selector) {
         let cells: Vec<_> = row.select(&cell_selector).collect();
         if cells.len() >= 6 {
-            let route_element = cells[0];
+            route_rows += 1;
 
-            // The route_id required for the POST request is in an `onclick` attribute.
-            if let Some(onclick) = route_element.value().attr("onclick")
-                && let Some(caps) = ONCLICK_RE.captures(onclick)
-            {
-                let route_id = caps.get(1).unwrap().as_str().to_string();
+            // The route_id required for the POST request is normally in an `onclick` attribute.
+            if let Some((raw_id, strategy)) = extractor.extract(row, cells[0]) {
+                *strategies.entry(strategy).or_default() += 1;
+                let route_id = decode_route_id(&raw_id);
 
                 // If a specific route is requested, filter out all others.
                 if let Some(f) = filter
-                    && !route_id.starts_with(f)
+                    && !route_filter_matches(&route_id, f)
                 {
                     continue;
                 }
 
                 targets.push(route_id.clone());
 
-                let route_no = route_id.split('(').next().unwrap_or(&route_id).to_string();
+                let route_no = normalize_route_no(&route_id);
                 let origin = cells[1].text().collect::<String>().trim().to_string();
                 let dest = cells[2].text().collect::<String>().trim().to_string();
 
@@ -68,6 +277,16 @@ pub fn extract_route_info(
         }
     }
 
+    for (strategy, count) in &strategies {
+        info!("Found {} route ids via {}", count, strategy);
+    }
+    if strategies.is_empty() && route_rows > 0 {
+        warn!(
+            "No route id found in {} route rows; the page markup may have changed",
+            route_rows
+        );
+    }
+
     // Assign the sorted, unique directions to each route in the metadata map.
     for (r_no, dirs_set) in temp_directions {
         if let Some(meta) = route_meta_map.get_mut(&r_no) {
@@ -109,15 +328,13 @@ pub fn parse_detail_schedule(
     let document = Html::parse_document(html);
 
     // Extract the route number and raw day type from the route_id string (e.g., "34-1(평일)").
-    let (route_number, raw_day_type) = if let Some(pos) = route_id.find('(') {
-        (
-            route_id[..pos].trim().to_string(),
-            route_id[pos..]
-                .trim_matches(|c| c == '(' || c == ')')
-                .to_string(),
-        )
+    let route_number = normalize_route_no(route_id);
+    let raw_day_type = if let Some(pos) = route_id.find('(') {
+        route_id[pos..]
+            .trim_matches(|c| c == '(' || c == ')')
+            .to_string()
     } else {
-        (route_id.to_string(), "general".to_string())
+        "general".to_string()
     };
 
     let day_type = normalize_day_type(&raw_day_type);
@@ -125,14 +342,30 @@ pub fn parse_detail_schedule(
     let table_selector = Selector::parse("table").unwrap();
     let th_selector = Selector::parse("th").unwrap();
 
-    // Find the correct schedule table by looking for a `th` element containing "발" (departure).
+    // Terminus names from the main page. Some detail pages label their columns with these
+    // names directly instead of "<terminus>발".
+    let termini: &[String] = meta.map(|m| m.directions.as_slice()).unwrap_or_default();
+    let terminus_of = |header: &str| -> Option<String> {
+        let header = header.trim();
+        let clean = header.trim_end_matches('발');
+        termini
+            .iter()
+            .find(|t| t.as_str() == header || t.as_str() == clean)
+            .cloned()
+    };
+
+    // Find the correct schedule table by looking for a `th` element containing "발" (departure)
+    // or naming one of the termini.
     let mut target_table = None;
     for table in document.select(&table_selector) {
         let headers: Vec<String> = table
             .select(&th_selector)
             .map(|th| th.text().collect::<String>())
             .collect();
-        if headers.iter().any(|h| h.contains("발")) {
+        if headers
+            .iter()
+            .any(|h| h.contains("발") || terminus_of(h).is_some())
+        {
             target_table = Some(table);
             break;
         }
@@ -148,37 +381,58 @@ pub fn parse_detail_schedule(
     let mut col_map: HashMap<usize, String> = HashMap::new(); // Maps column index to direction name.
     let mut directions: Vec<String> = Vec::new();
     let mut note_col_idx = None;
+    let mut hour_col_idx = None; // Set for tables with separate "시" (hour) and "분" (minute) columns.
 
     let tr_selector = Selector::parse("tr").unwrap();
     let header_rows: Vec<_> = table.select(&tr_selector).collect();
 
-    // Parse table headers to identify directions.
+    // Prefer headers that name a known terminus, with or without the "발" suffix.
     for row in &header_rows {
         let ths: Vec<_> = row.select(&th_selector).collect();
-        if ths.is_empty() {
-            continue;
-        }
-
         for (idx, th) in ths.iter().enumerate() {
-            let text = th.text().collect::<String>().trim().to_string();
-
-            if text == "비고" {
-                // "비고" means "Notes".
+            let text = th.text().collect::<String>();
+            if text.trim() == "비고" {
                 note_col_idx = Some(idx);
+            } else if text.trim() == "시" {
+                hour_col_idx = Some(idx);
+            } else if let Some(terminus) = terminus_of(&text) {
+                if !directions.contains(&terminus) {
+                    directions.push(terminus.clone());
+                }
+                col_map.insert(idx, terminus);
+            }
+        }
+    }
+
+    // Otherwise, parse table headers heuristically to identify directions.
+    if directions.is_empty() {
+        for row in &header_rows {
+            let ths: Vec<_> = row.select(&th_selector).collect();
+            if ths.is_empty() {
                 continue;
             }
 
-            // Extract direction names from headers. Headers for times often end with "발" (departure).
-            // We ignore irrelevant headers like "운행순번" (run order), "시" (hour), "분" (minute), etc.
-            let clean_text = text.trim_end_matches('발').to_string();
-            if !clean_text.is_empty()
-                && !["운행순번", "시", "분", "", "구분"].contains(&clean_text.as_str())
-                && !HOUR_RE.is_match(&clean_text)
-            {
-                if !directions.contains(&clean_text) {
-                    directions.push(clean_text.clone());
+            for (idx, th) in ths.iter().enumerate() {
+                let text = th.text().collect::<String>().trim().to_string();
+
+                if text == "비고" {
+                    // "비고" means "Notes".
+                    note_col_idx = Some(idx);
+                    continue;
+                }
+
+                // Extract direction names from headers. Headers for times often end with "발" (departure).
+                // We ignore irrelevant headers like "운행순번" (run order), "시" (hour), "분" (minute), etc.
+                let clean_text = text.trim_end_matches('발').to_string();
+                if !clean_text.is_empty()
+                    && !["운행순번", "시", "분", "", "구분"].contains(&clean_text.as_str())
+                    && !HOUR_RE.is_match(&clean_text)
+                {
+                    if !directions.contains(&clean_text) {
+                        directions.push(clean_text.clone());
+                    }
+                    col_map.insert(idx, clean_text);
                 }
-                col_map.insert(idx, clean_text);
             }
         }
     }
@@ -198,6 +452,25 @@ pub fn parse_detail_schedule(
     }
 
     let td_selector = Selector::parse("td").unwrap();
+    let cell_selector = Selector::parse("th, td").unwrap();
+    let legend = extract_footnote_legend(&document);
+
+    // Some pages repeat the header row mid-table to start an afternoon section, possibly as
+    // `td` cells and with extra text (e.g. "터미널발 12:00~"). Such a row names a column's own
+    // direction (or is "비고"/"시") where data rows hold times.
+    let is_header_cell = |idx: usize, text: &str| {
+        let text = text.trim();
+        if note_col_idx == Some(idx) {
+            text == "비고"
+        } else if hour_col_idx == Some(idx) {
+            text == "시"
+        } else {
+            col_map
+                .get(&idx)
+                .is_some_and(|dir| text.starts_with(dir.as_str()))
+        }
+    };
+    let mut sections = 1;
 
     let mut times_by_direction: HashMap<String, Vec<TimeEntry>> = HashMap::new();
     for dir in &directions {
@@ -212,6 +485,16 @@ pub fn parse_detail_schedule(
             continue;
         }
 
+        // A repeated header is a section break; the times after it keep the same columns.
+        if row
+            .select(&cell_selector)
+            .enumerate()
+            .any(|(idx, cell)| is_header_cell(idx, &cell.text().collect::<String>()))
+        {
+            sections += 1;
+            continue;
+        }
+
         // Extract note text if the note column exists.
         let note = if let Some(idx) = note_col_idx {
             if idx < cells.len() {
@@ -224,28 +507,325 @@ pub fn parse_detail_schedule(
             None
         };
 
+        // In the hour/minute layout, the hour cell applies to every minute in the row (e.g. "6시").
+        let row_hour: Option<u32> = hour_col_idx
+            .and_then(|idx| cells.get(idx))
+            .map(|cell| cell.text().collect::<String>())
+            .and_then(|text| {
+                LEADING_NUM_RE.captures(&normalize_12_hour(text.trim()))?[1]
+                    .parse()
+                    .ok()
+            });
+
         // Check each cell in the row for a time.
         for (col_idx, cell) in cells.iter().enumerate() {
             if let Some(dir_name) = col_map.get(&col_idx) {
-                let text = cell.text().collect::<String>().trim().to_string();
-                if let Some(caps) = TIME_RE.captures(&text) {
-                    let clean_time = caps.get(1).unwrap().as_str().to_string();
+                let text = cell.text().collect::<String>();
+                let text = normalize_12_hour(text.trim()).into_owned();
+                let Some(list) = times_by_direction.get_mut(dir_name) else {
+                    continue;
+                };
 
-                    if let Some(list) = times_by_direction.get_mut(dir_name) {
-                        list.push(TimeEntry {
-                            time: clean_time,
-                            note: note.clone(),
-                        });
+                if let Some(caps) = TIME_RE.captures(&text) {
+                    // Footnote markers may follow the time (e.g. "06:30※").
+                    let time = caps.get(1).unwrap();
+                    list.push(TimeEntry {
+                        time: time.as_str().to_string(),
+                        note: note_with_footnotes(&text[time.end()..], note.as_ref(), &legend),
+                    });
+                } else if let Some(hour) = row_hour {
+                    // Minute cell: space-separated minutes within the row's hour (e.g. "10 30 50").
+                    for token in text.split(|c: char| c.is_whitespace() || c == ',') {
+                        let Some(caps) = LEADING_NUM_RE.captures(token) else {
+                            continue;
+                        };
+                        let minute: Option<u32> = caps[1].parse().ok();
+                        if let Some(minute) = minute.filter(|m| *m < 60) {
+                            let suffix = &token[caps[1].len()..];
+                            list.push(TimeEntry {
+                                time: format!("{:02}:{:02}", hour, minute),
+                                note: note_with_footnotes(suffix, note.as_ref(), &legend),
+                            });
+                        }
                     }
                 }
             }
         }
     }
 
+    if sections > 1 {
+        debug!("{}: schedule table has {} sections", route_id, sections);
+    }
+
+    // Extract optional summary figures from anywhere on the page.
+    let page_text = document.root_element().text().collect::<Vec<_>>().join(" ");
+    let daily_trip_count = TRIP_COUNT_RE
+        .captures(&page_text)
+        .and_then(|caps| caps[1].parse().ok());
+    let operating_hours = OPERATING_HOURS_RE
+        .captures(&page_text)
+        .map(|caps| format!("{}~{}", &caps[1], &caps[2]));
+    let stop_sequences = extract_stop_sequences(&document);
+
     Ok(ParsedSchedule {
+        route_id: route_id.to_string(),
         route_number,
         day_type,
         directions,
         times_by_direction,
+        daily_trip_count,
+        operating_hours,
+        crawled_at: None,
+        stop_sequences,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_filter_matches() {
+        assert!(route_filter_matches("34-1(평일)", "34-1"));
+        assert!(route_filter_matches("34-1(주말)", "34-1"));
+        assert!(!route_filter_matches("34-10(평일)", "34-1"));
+        assert!(!route_filter_matches("34(평일)", "34-1"));
+
+        assert!(route_filter_matches("34-1(평일)", "34-1(평일)"));
+        assert!(!route_filter_matches("34-1(주말)", "34-1(평일)"));
+
+        // Encoded ids are decoded on both sides
+        let encoded = "34-1%28%ED%8F%89%EC%9D%BC%29";
+        assert_eq!(decode_route_id(encoded), "34-1(평일)");
+        assert!(route_filter_matches(&decode_route_id(encoded), "34-1"));
+        assert!(route_filter_matches("34-1(평일)", encoded));
+    }
+
+    #[test]
+    fn test_extract_route_info_fallbacks() {
+        let html = r##"
+            <table>
+            <tr><td onclick="goDetail('34(평일)')">34</td><td>A</td><td>B</td><td></td><td></td><td></td></tr>
+            <tr><td><a href="#" onclick="fnDetail( '41(평일)' ); return false;">41</a></td>
+                <td>C</td><td>D</td><td></td><td></td><td></td></tr>
+            <tr data-routeid="52(주말)"><td>52</td><td>E</td><td>F</td><td></td><td></td><td></td></tr>
+            </table>
+        "##;
+        let owned = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let extractor =
+            RouteIdExtractor::new(&owned(&["goDetail", "fnDetail"]), &owned(&["data-routeid"]))
+                .unwrap();
+
+        let (meta, targets) = extract_route_info(html, None, &extractor).unwrap();
+        assert_eq!(targets, vec!["34(평일)", "41(평일)", "52(주말)"]);
+        assert_eq!(meta["41"].directions, vec!["C", "D"]);
+
+        // Without the matching handler or attribute those rows are skipped
+        let only_go_detail = RouteIdExtractor::new(&owned(&["goDetail"]), &[]).unwrap();
+        let (_, targets) = extract_route_info(html, None, &only_go_detail).unwrap();
+        assert_eq!(targets, vec!["34(평일)"]);
+    }
+
+    #[test]
+    fn test_parse_detail_summary() {
+        let html = r#"
+            <html><body>
+            <p>운행시간 : 05:30 ~ 22:40 / 1일 운행횟수 42회</p>
+            <table>
+                <tr><th>운행순번</th><th>기업도시발</th><th>비고</th></tr>
+                <tr><td>1</td><td>05:30</td><td></td></tr>
+            </table>
+            </body></html>
+        "#;
+
+        let parsed = parse_detail_schedule(html, "34-1(평일)", None).unwrap();
+
+        assert_eq!(parsed.daily_trip_count, Some(42));
+        assert_eq!(parsed.operating_hours.as_deref(), Some("05:30~22:40"));
+        assert_eq!(parsed.times_by_direction["기업도시"].len(), 1);
+        assert!(parsed.stop_sequences.is_empty());
+    }
+
+    #[test]
+    fn test_parse_detail_stop_sequences() {
+        let html = r#"
+            <html><body>
+            <div class="route">
+                <p><b>기업도시발</b> : 기업도시 → 혁신도시 &gt; 원주역</p>
+                <p>원주역발 : 원주역 → 혁신도시 → 기업도시</p>
+                <p>기업도시 ↔ 원주역 (05:30 → 06:10)</p>
+            </div>
+            <table>
+                <tr><th>운행순번</th><th>기업도시발</th><th>비고</th></tr>
+                <tr><td>1</td><td>05:30</td><td></td></tr>
+            </table>
+            </body></html>
+        "#;
+
+        let parsed = parse_detail_schedule(html, "34-1(평일)", None).unwrap();
+
+        assert_eq!(
+            parsed.stop_sequences,
+            vec![
+                vec!["기업도시", "혁신도시", "원주역"],
+                vec!["원주역", "혁신도시", "기업도시"]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_detail_hour_minute_columns() {
+        let html = r#"
+            <html><body>
+            <table>
+                <tr><th>시</th><th>기업도시발</th><th>원주역발</th><th>비고</th></tr>
+                <tr><td>05</td><td>30 50</td><td>40</td><td></td></tr>
+                <tr><td>6시</td><td>10 30 50</td><td></td><td></td></tr>
+            </table>
+            </body></html>
+        "#;
+
+        let parsed = parse_detail_schedule(html, "34-1(평일)", None).unwrap();
+
+        let times = |dir: &str| -> Vec<String> {
+            parsed.times_by_direction[dir]
+                .iter()
+                .map(|t| t.time.clone())
+                .collect()
+        };
+        assert_eq!(parsed.directions, vec!["기업도시", "원주역"]);
+        assert_eq!(
+            times("기업도시"),
+            vec!["05:30", "05:50", "06:10", "06:30", "06:50"]
+        );
+        assert_eq!(times("원주역"), vec!["05:40"]);
+    }
+
+    #[test]
+    fn test_parse_detail_footnote_markers() {
+        let html = r#"
+            <html><body>
+            <table>
+                <tr><th>운행순번</th><th>기업도시발</th><th>비고</th></tr>
+                <tr><td>1</td><td>06:30※</td><td></td></tr>
+                <tr><td>2</td><td>07:10▲</td><td>경유</td></tr>
+                <tr><td>3</td><td>07:50</td><td></td></tr>
+            </table>
+            <p>※ 토요일 운휴</p>
+            <p>▲ 학교 경유</p>
+            </body></html>
+        "#;
+
+        let parsed = parse_detail_schedule(html, "34-1(평일)", None).unwrap();
+        let times = &parsed.times_by_direction["기업도시"];
+
+        assert_eq!(times[0].time, "06:30");
+        assert_eq!(times[0].note.as_deref(), Some("토요일 운휴"));
+        assert_eq!(times[1].time, "07:10");
+        assert_eq!(times[1].note.as_deref(), Some("경유 / 학교 경유"));
+        assert_eq!(times[2].note, None);
+    }
+
+    #[test]
+    fn test_normalize_12_hour_noon_and_midnight() {
+        assert_eq!(normalize_12_hour("오전 12:00"), "00:00");
+        assert_eq!(normalize_12_hour("오전 12시"), "00:00");
+        assert_eq!(normalize_12_hour("오후 12시"), "12:00");
+        assert_eq!(normalize_12_hour("오후 12:30"), "12:30");
+        assert_eq!(normalize_12_hour("AM 12:15"), "00:15");
+        assert_eq!(normalize_12_hour("pm 12:45"), "12:45");
+        assert_eq!(normalize_12_hour("오후 2:30"), "14:30");
+        assert_eq!(normalize_12_hour("오전 9시 5분※"), "09:05※");
+        // 24-hour times and other text pass through
+        assert_eq!(normalize_12_hour("14:30"), "14:30");
+        assert_eq!(normalize_12_hour("오후 13:00"), "오후 13:00");
+    }
+
+    #[test]
+    fn test_parse_detail_twelve_hour_times() {
+        let html = r#"
+            <html><body>
+            <table>
+                <tr><th>운행순번</th><th>기업도시발</th><th>비고</th></tr>
+                <tr><td>1</td><td>오전 6:30</td><td></td></tr>
+                <tr><td>2</td><td>오후 12:10</td><td></td></tr>
+                <tr><td>3</td><td>오후 2:30※</td><td></td></tr>
+                <tr><td>4</td><td>PM 11:05</td><td></td></tr>
+                <tr><td>5</td><td>오전 12:20</td><td></td></tr>
+            </table>
+            <p>※ 토요일 운휴</p>
+            </body></html>
+        "#;
+
+        let parsed = parse_detail_schedule(html, "34-1(평일)", None).unwrap();
+        let times = &parsed.times_by_direction["기업도시"];
+        let clock: Vec<&str> = times.iter().map(|t| t.time.as_str()).collect();
+
+        assert_eq!(clock, vec!["06:30", "12:10", "14:30", "23:05", "00:20"]);
+        assert_eq!(times[2].note.as_deref(), Some("토요일 운휴"));
+    }
+
+    #[test]
+    fn test_parse_detail_terminus_headers() {
+        // Column headers name the termini without "발", next to an unrelated interval column.
+        let html = r#"
+            <html><body>
+            <table><tr><th>안내</th></tr><tr><td>노선 변경 안내</td></tr></table>
+            <table>
+                <tr><th>운행순번</th><th>기업도시</th><th>원주역</th><th>배차간격</th><th>비고</th></tr>
+                <tr><td>1</td><td>05:30</td><td>06:10</td><td>20분</td><td></td></tr>
+                <tr><td>2</td><td>05:50</td><td>06:30</td><td>20분</td><td>경유</td></tr>
+            </table>
+            </body></html>
+        "#;
+        let meta = RouteMeta {
+            origin: "기업도시".to_string(),
+            destination: "원주역".to_string(),
+            directions: vec!["기업도시".to_string(), "원주역".to_string()],
+        };
+
+        let parsed = parse_detail_schedule(html, "34-1(평일)", Some(&meta)).unwrap();
+
+        assert_eq!(parsed.directions, vec!["기업도시", "원주역"]);
+        assert_eq!(parsed.times_by_direction["기업도시"].len(), 2);
+        assert_eq!(parsed.times_by_direction["원주역"][1].time, "06:30");
+        assert_eq!(
+            parsed.times_by_direction["원주역"][1].note.as_deref(),
+            Some("경유")
+        );
+    }
+
+    #[test]
+    fn test_parse_detail_repeated_header_row() {
+        // The afternoon section starts with the header repeated as data cells, with start times.
+        let html = r#"
+            <html><body>
+            <table>
+                <tr><th>운행순번</th><th>터미널발</th><th>기업도시발</th><th>비고</th></tr>
+                <tr><td>1</td><td>06:00</td><td>06:40</td><td></td></tr>
+                <tr><td>2</td><td>09:30</td><td>10:10</td><td>경유</td></tr>
+                <tr><td>운행순번</td><td>터미널발 12:00~</td><td>기업도시발 12:30~</td><td>비고</td></tr>
+                <tr><td>3</td><td>13:00</td><td>13:40</td><td></td></tr>
+                <tr><td>4</td><td>17:20</td><td>18:00</td><td></td></tr>
+            </table>
+            </body></html>
+        "#;
+
+        let parsed = parse_detail_schedule(html, "34(평일)", None).unwrap();
+
+        assert_eq!(parsed.directions, vec!["터미널", "기업도시"]);
+        let times = |dir: &str| -> Vec<&str> {
+            parsed.times_by_direction[dir]
+                .iter()
+                .map(|t| t.time.as_str())
+                .collect()
+        };
+        assert_eq!(times("터미널"), vec!["06:00", "09:30", "13:00", "17:20"]);
+        assert_eq!(times("기업도시"), vec!["06:40", "10:10", "13:40", "18:00"]);
+        assert!(
+            parsed.times_by_direction["터미널"]
+                .iter()
+                .all(|t| t.note.as_deref() != Some("비고"))
+        );
+    }
+}
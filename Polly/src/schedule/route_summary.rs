@@ -0,0 +1,264 @@
+//! One-table summary of all routes (`export-route-summary-csv`).
+//!
+//! Writes a row per derived route with its stop count and distances from `polylines/`, the
+//! route type (`routetp`) that Phase 1 records in `routeDetails.json` from the TAGO route list,
+//! and the first and last departure of its route number from `schedules/`. TAGO's route list
+//! carries no operator, so there is no operator column. The file is UTF-8 with a byte order
+//! mark and CRLF line endings, so Excel shows the Korean names correctly when it is opened
+//! directly.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde_json::Value;
+
+use crate::schedule::check::{format_hhmm, parse_hhmm, schedule_paths};
+use crate::schedule::headways::departures;
+use crate::utils::layout::LayoutArgs;
+use crate::utils::{normalize_route_no, read_json, write_atomic};
+
+const COLUMNS: [&str; 8] = [
+    "route_no",
+    "route_id",
+    "route_type",
+    "stop_count",
+    "total_dist",
+    "oneway_dist",
+    "first_bus",
+    "last_bus",
+];
+
+/// Arguments for exporting the route summary
+#[derive(clap::Args)]
+pub struct ExportRouteSummaryCsvArgs {
+    /// Output directory containing `polylines/`, and optionally `routeDetails.json` and `schedules/`
+    #[arg(short, long, default_value = "./storage")]
+    output_dir: PathBuf,
+
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// City whose routes to summarize, with a layout that nests files by city
+    #[arg(long)]
+    city_code: Option<String>,
+
+    /// Destination file (default: `routeSummary.csv` beside the mapping files)
+    #[arg(long, value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    /// Day type whose departures give `first_bus` and `last_bus`
+    #[arg(long, default_value = "weekday")]
+    day_type: String,
+
+    /// Departures before this time (HH:MM) run after midnight and count as the day's last
+    #[arg(long, default_value = "03:00", value_parser = parse_hhmm)]
+    day_start: u32,
+}
+
+/// Summary attributes of one derived route
+#[derive(Debug, PartialEq)]
+struct RouteRow {
+    route_no: String,
+    route_id: String,
+    stop_count: usize,
+    total_dist: f64,
+    oneway_dist: f64,
+}
+
+/// Rows of every feature in the derived files in `dir`, sorted by route number and id.
+///
+/// Properties are read as plain JSON, so stub routes and files with a shared geometry are included.
+fn route_rows(dir: &Path) -> Result<Vec<RouteRow>> {
+    let mut rows = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "geojson") {
+            continue;
+        }
+        let collection = match read_json(&path) {
+            Ok(collection) => collection,
+            Err(e) => {
+                warn!("Skipping {:#}", e);
+                continue;
+            }
+        };
+        for feature in collection["features"].as_array().into_iter().flatten() {
+            let props = &feature["properties"];
+            let Some(route_id) = props["route_id"].as_str() else {
+                continue;
+            };
+            rows.push(RouteRow {
+                route_no: props["route_no"].as_str().unwrap_or_default().to_string(),
+                route_id: route_id.to_string(),
+                stop_count: props["stops"].as_array().map_or(0, Vec::len),
+                total_dist: props["total_dist"].as_f64().unwrap_or_default(),
+                oneway_dist: props["oneway_dist"].as_f64().unwrap_or_default(),
+            });
+        }
+    }
+    rows.sort_by(|a, b| (&a.route_no, &a.route_id).cmp(&(&b.route_no, &b.route_id)));
+    rows.dedup_by(|a, b| a.route_id == b.route_id);
+    Ok(rows)
+}
+
+/// First and last departure of `day_type` over all directions, by normalized route number
+fn service_spans(
+    schedule_dir: &Path,
+    day_type: &str,
+    day_start: u32,
+) -> Result<HashMap<String, (u32, u32)>> {
+    let mut spans = HashMap::new();
+    if !schedule_dir.is_dir() {
+        return Ok(spans);
+    }
+    for path in schedule_paths(schedule_dir)? {
+        let schedule = match read_json(&path) {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                warn!("Skipping {:#}", e);
+                continue;
+            }
+        };
+        let route_no = schedule["routeId"]
+            .as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| path.file_stem().unwrap().to_string_lossy().into_owned());
+        let days = departures(&schedule, &[], day_start);
+        let times = days
+            .get(day_type)
+            .into_iter()
+            .flat_map(|dirs| dirs.values().flatten());
+        if let (Some(&first), Some(&last)) = (times.clone().min(), times.max()) {
+            spans.insert(normalize_route_no(&route_no), (first, last));
+        }
+    }
+    Ok(spans)
+}
+
+/// The CSV bytes: byte order mark, header and one CRLF-terminated record per row
+fn summary_csv(
+    rows: &[RouteRow],
+    details: &Value,
+    spans: &HashMap<String, (u32, u32)>,
+) -> Result<Vec<u8>> {
+    let mut writer = csv::WriterBuilder::new()
+        .terminator(csv::Terminator::CRLF)
+        .from_writer("\u{feff}".as_bytes().to_vec());
+    writer.write_record(COLUMNS)?;
+    for row in rows {
+        let route_type = details[row.route_id.as_str()]["routetp"]
+            .as_str()
+            .unwrap_or_default();
+        let span = spans.get(&normalize_route_no(&row.route_no));
+        writer.write_record([
+            row.route_no.as_str(),
+            row.route_id.as_str(),
+            route_type,
+            &row.stop_count.to_string(),
+            &format!("{:.1}", row.total_dist),
+            &format!("{:.1}", row.oneway_dist),
+            &span
+                .map(|&(first, _)| format_hhmm(first % 1440))
+                .unwrap_or_default(),
+            &span
+                .map(|&(_, last)| format_hhmm(last % 1440))
+                .unwrap_or_default(),
+        ])?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| anyhow::anyhow!("Failed to finish the CSV: {}", e.error()))
+}
+
+/// Writes one CSV row per derived route, joined with its details and schedule.
+pub fn export_route_summary_csv(args: ExportRouteSummaryCsvArgs) -> Result<()> {
+    let layout = args.layout.resolve()?;
+    let root = layout.root(&args.output_dir, args.city_code.as_deref())?;
+    let rows = route_rows(&layout.polylines_dir(&root))?;
+
+    let details_path = root.join("routeDetails.json");
+    let details = if details_path.exists() {
+        read_json(&details_path)?["route_details"].take()
+    } else {
+        Value::Null
+    };
+    let spans = service_spans(&layout.schedules_dir(&root), &args.day_type, args.day_start)?;
+
+    let out_path = args.output.unwrap_or_else(|| root.join("routeSummary.csv"));
+    write_atomic(&out_path, &summary_csv(&rows, &details, &spans)?)
+        .with_context(|| format!("Failed to write {}", out_path.display()))?;
+
+    let with_schedule = rows
+        .iter()
+        .filter(|row| spans.contains_key(&normalize_route_no(&row.route_no)))
+        .count();
+    info!(
+        "Wrote {} routes ({} with {} departures) to {}.",
+        rows.len(),
+        with_schedule,
+        args.day_type,
+        out_path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_summary_csv_joins_details_and_schedule() {
+        let dir = std::env::temp_dir().join(format!("polly-route-summary-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("schedules")).unwrap();
+        let schedule = json!({
+            "routeId": "34-1",
+            "schedule": { "weekday": {
+                "00": { "터미널": [{ "minute": "20" }] },
+                "06": { "터미널": [{ "minute": "05" }], "원주역": [{ "minute": "30" }] }
+            } }
+        });
+        fs::write(dir.join("schedules/34-1.json"), schedule.to_string()).unwrap();
+
+        let spans = service_spans(&dir.join("schedules"), "weekday", 180).unwrap();
+        assert_eq!(spans["34-1"], (365, 1460));
+
+        let rows = vec![
+            RouteRow {
+                route_no: "34-1".to_string(),
+                route_id: "WJB1".to_string(),
+                stop_count: 40,
+                total_dist: 12345.67,
+                oneway_dist: 6000.0,
+            },
+            RouteRow {
+                route_no: "2".to_string(),
+                route_id: "WJB2".to_string(),
+                stop_count: 3,
+                total_dist: 0.0,
+                oneway_dist: 0.0,
+            },
+        ];
+        let details = json!({ "WJB1": { "routeno": "34-1", "routetp": "간선버스, 급행" } });
+        let csv = String::from_utf8(summary_csv(&rows, &details, &spans).unwrap()).unwrap();
+
+        let lines: Vec<&str> = csv.split("\r\n").collect();
+        assert_eq!(
+            lines[0],
+            "\u{feff}route_no,route_id,route_type,stop_count,total_dist,oneway_dist,first_bus,last_bus"
+        );
+        // The 00:20 trip runs after midnight, so it is the last bus
+        assert_eq!(
+            lines[1],
+            "34-1,WJB1,\"간선버스, 급행\",40,12345.7,6000.0,06:05,00:20"
+        );
+        assert_eq!(lines[2], "2,WJB2,,3,0.0,0.0,,");
+        assert_eq!(lines[3], "");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,287 @@
+//! Cross-check of crawled stop sequences against the TAGO stop order (`check-stop-order`).
+//!
+//! Some detail pages list the stops of each direction (kept with `schedule --stop-sequences`).
+//! That list is a second source for the stop order next to TAGO's `nodeord`, which is not
+//! always reliable. Each crawled list is matched by stop name to the TAGO variant or direction
+//! that visits the most of its stops in sequence, and the stops TAGO does not serve or serves
+//! in another order are reported. Pages usually list only the main stops, so TAGO stops missing from the list
+//! are not an issue.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+
+use anyhow::Result;
+use log::{debug, info, warn};
+use regex::Regex;
+use serde_json::Value;
+
+use crate::schedule::check::schedule_paths;
+use crate::utils::layout::LayoutArgs;
+use crate::utils::{normalize_route_no, read_json};
+
+static PARENTHETICAL_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\([^)]*\)").unwrap());
+
+/// Arguments for cross-checking stop sequences
+#[derive(clap::Args)]
+pub struct CheckStopOrderArgs {
+    /// Output directory containing `schedules/`, `routeMap.json`, `routeDetails.json` and `stationMap.json`
+    #[arg(short, long, default_value = "./storage")]
+    output_dir: PathBuf,
+
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// City whose outputs to check, with a layout that nests files by city
+    #[arg(long)]
+    city_code: Option<String>,
+}
+
+/// A TAGO stop order to match crawled lists against: one direction, or a whole route
+struct TagoOrder {
+    label: String,
+    /// Stop names as [`name_key`]s
+    stops: Vec<String>,
+}
+
+/// Stop name for comparison: without whitespace and parenthesized remarks ("원주역 (버스)" → "원주역")
+fn name_key(name: &str) -> String {
+    PARENTHETICAL_RE
+        .replace_all(name, "")
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect()
+}
+
+/// Stop orders of a TAGO route: the whole sequence and, for routes with both directions, each direction.
+fn tago_orders(
+    route_id: &str,
+    sequence: &Value,
+    names: impl Fn(&str) -> Option<String>,
+) -> Vec<TagoOrder> {
+    let stops: Vec<(i64, String)> = sequence
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|s| {
+            let name = names(s["nodeid"].as_str()?)?;
+            Some((s["updowncd"].as_i64().unwrap_or(0), name_key(&name)))
+        })
+        .collect();
+
+    let mut orders = vec![TagoOrder {
+        label: route_id.to_string(),
+        stops: stops.iter().map(|(_, name)| name.clone()).collect(),
+    }];
+    if stops.windows(2).any(|w| w[0].0 != w[1].0) {
+        for run in stops.chunk_by(|a, b| a.0 == b.0) {
+            orders.push(TagoOrder {
+                label: format!("{} (updowncd {})", route_id, run[0].0),
+                stops: run.iter().map(|(_, name)| name.clone()).collect(),
+            });
+        }
+    }
+    orders
+}
+
+/// Positions in `keys` that `order` visits in sequence, walking it forward so a stop visited
+/// twice (e.g. a loop's terminus) matches its next visit.
+fn in_order(keys: &[String], order: &TagoOrder) -> Vec<usize> {
+    let mut pos = 0;
+    let mut matched = Vec::new();
+    for (i, key) in keys.iter().enumerate() {
+        if let Some(offset) = order.stops[pos..].iter().position(|s| s == key) {
+            pos += offset + 1;
+            matched.push(i);
+        }
+    }
+    matched
+}
+
+/// Issues between one crawled stop list and the TAGO order that fits it best.
+fn order_issues(sequence: &[String], orders: &[TagoOrder]) -> Vec<String> {
+    let keys: Vec<String> = sequence.iter().map(|name| name_key(name)).collect();
+    // The order visiting the most listed stops in sequence; of equally good ones the shortest,
+    // i.e. a direction rather than the whole route around it
+    let Some((best, matched)) = orders
+        .iter()
+        .map(|order| (order, in_order(&keys, order)))
+        .filter(|(_, matched)| !matched.is_empty())
+        .max_by_key(|(order, matched)| (matched.len(), Reverse(order.stops.len())))
+    else {
+        return vec!["no TAGO route shares a stop with this list".to_string()];
+    };
+
+    let mut missing = Vec::new();
+    let mut out_of_order = Vec::new();
+    for (i, (name, key)) in sequence.iter().zip(&keys).enumerate() {
+        if matched.contains(&i) {
+            continue;
+        }
+        if best.stops.contains(key) {
+            out_of_order.push(name.as_str());
+        } else {
+            missing.push(name.as_str());
+        }
+    }
+
+    let mut issues = Vec::new();
+    if !missing.is_empty() {
+        issues.push(format!(
+            "{} stop(s) not served by {}: {}",
+            missing.len(),
+            best.label,
+            missing.join(", ")
+        ));
+    }
+    if !out_of_order.is_empty() {
+        issues.push(format!(
+            "{} stop(s) out of order in {}: {}",
+            out_of_order.len(),
+            best.label,
+            out_of_order.join(", ")
+        ));
+    }
+    issues
+}
+
+/// Prints the crawled stop sequences that disagree with the TAGO stop order.
+pub fn check_stop_order(args: CheckStopOrderArgs) -> Result<()> {
+    let layout = args.layout.resolve()?;
+    let root = layout.root(&args.output_dir, args.city_code.as_deref())?;
+    let route_map = read_json(&root.join("routeMap.json"))?;
+    let details = read_json(&root.join("routeDetails.json"))?;
+    let stations = read_json(&root.join("stationMap.json"))?;
+    let station_name = |node_id: &str| {
+        Some(
+            stations["stations"][node_id]["nodenm"]
+                .as_str()?
+                .to_string(),
+        )
+    };
+
+    // TAGO route ids by normalized route number, to match the schedule site's numbering
+    let mut route_ids: HashMap<String, Vec<&str>> = HashMap::new();
+    for (route_no, ids) in route_map["route_numbers"].as_object().into_iter().flatten() {
+        route_ids
+            .entry(normalize_route_no(route_no))
+            .or_default()
+            .extend(
+                ids.as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(Value::as_str),
+            );
+    }
+
+    let mut checked = 0usize;
+    let mut unmatched = 0usize;
+    let mut issues = Vec::new();
+    for path in schedule_paths(&layout.schedules_dir(&root))? {
+        let schedule = match read_json(&path) {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                warn!("Skipping {:#}", e);
+                continue;
+            }
+        };
+        let Some(sequences) = schedule["stopSequences"].as_array() else {
+            continue;
+        };
+        let route_no = schedule["routeId"].as_str().unwrap_or_default().to_string();
+        let Some(ids) = route_ids.get(&normalize_route_no(&route_no)) else {
+            debug!("{} has stop sequences but no TAGO route", route_no);
+            unmatched += 1;
+            continue;
+        };
+
+        let orders: Vec<TagoOrder> = ids
+            .iter()
+            .flat_map(|id| {
+                tago_orders(id, &details["route_details"][*id]["sequence"], station_name)
+            })
+            .collect();
+        for sequence in sequences {
+            let stops: Vec<String> = sequence
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|s| s.as_str().map(str::to_string))
+                .collect();
+            let (Some(first), Some(last)) = (stops.first(), stops.last()) else {
+                continue;
+            };
+            checked += 1;
+            for issue in order_issues(&stops, &orders) {
+                issues.push((route_no.clone(), format!("{} → {}: {}", first, last, issue)));
+            }
+        }
+    }
+
+    if !issues.is_empty() {
+        println!("{:<10} ISSUE", "ROUTE");
+        for (route_no, issue) in &issues {
+            println!("{:<10} {}", route_no, issue);
+        }
+    }
+    info!(
+        "Checked {} stop sequences against the TAGO order ({} routes without a TAGO match); {} issue(s) found.",
+        checked,
+        unmatched,
+        issues.len()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_order_issues_matches_the_best_direction() {
+        let sequence = json!([
+            { "nodeid": "T", "updowncd": 0 },
+            { "nodeid": "H", "updowncd": 0 },
+            { "nodeid": "S", "updowncd": 0 },
+            { "nodeid": "W", "updowncd": 0 },
+            { "nodeid": "S", "updowncd": 1 },
+            { "nodeid": "T", "updowncd": 1 }
+        ]);
+        let names = |id: &str| {
+            let name = match id {
+                "T" => "터미널",
+                "H" => "혁신도시 (입구)",
+                "S" => "시청",
+                "W" => "원주역",
+                _ => return None,
+            };
+            Some(name.to_string())
+        };
+        let orders = tago_orders("WJB1", &sequence, names);
+        assert_eq!(orders.len(), 3);
+        let list = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        // The outbound direction, naming a stop without its remark
+        assert!(order_issues(&list(&["터미널", "혁신도시", "원주역"]), &orders).is_empty());
+        // The return trip starts before the inbound direction does, so only the whole route fits
+        assert!(order_issues(&list(&["원주역", "시청", "터미널"]), &orders).is_empty());
+
+        assert_eq!(
+            order_issues(
+                &list(&["터미널", "시청", "혁신도시", "원주역", "공항"]),
+                &orders
+            ),
+            vec![
+                "1 stop(s) not served by WJB1 (updowncd 0): 공항",
+                "1 stop(s) out of order in WJB1 (updowncd 0): 혁신도시"
+            ]
+        );
+        assert_eq!(
+            order_issues(&list(&["공항", "역전"]), &orders),
+            vec!["no TAGO route shares a stop with this list"]
+        );
+    }
+}
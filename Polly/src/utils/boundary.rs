@@ -0,0 +1,221 @@
+//! Boundary polygons for clipping route geometry.
+//!
+//! A boundary is read from a GeoJSON `Polygon` or `MultiPolygon` (bare, as a
+//! Feature, or as every feature of a FeatureCollection). Containment uses the
+//! even-odd rule, so holes are excluded.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+type Ring = Vec<(f64, f64)>;
+
+/// One or more polygons, each an outer ring followed by its holes
+#[derive(Debug, Clone)]
+pub struct Boundary {
+    polygons: Vec<Vec<Ring>>,
+}
+
+impl Boundary {
+    /// Loads a boundary from a GeoJSON file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read boundary {}", path.display()))?;
+        let boundary = Self::from_geojson(&serde_json::from_str(&content)?)
+            .with_context(|| format!("Invalid boundary {}", path.display()))?;
+        Ok(boundary)
+    }
+
+    pub fn from_geojson(value: &Value) -> Result<Self> {
+        let mut polygons = Vec::new();
+        collect_polygons(value, &mut polygons);
+        if polygons.is_empty() {
+            anyhow::bail!("No Polygon or MultiPolygon geometry found");
+        }
+        Ok(Self { polygons })
+    }
+
+    /// Whether `(lon, lat)` lies inside the boundary
+    pub fn contains(&self, point: (f64, f64)) -> bool {
+        self.polygons.iter().any(|rings| {
+            rings
+                .iter()
+                .filter(|ring| ring_contains(ring, point))
+                .count()
+                % 2
+                == 1
+        })
+    }
+
+    /// Clips a polyline to the boundary, keeping the vertices inside it.
+    ///
+    /// Where the line leaves or re-enters the boundary, the crossing point is inserted so
+    /// the kept portions end on the boundary. Consecutive portions stay joined in a single
+    /// line. Returns the clipped line and, for each input vertex, its index in the clipped
+    /// line (`None` if it was dropped).
+    pub fn clip_line(&self, line: &[Vec<f64>]) -> (Vec<Vec<f64>>, Vec<Option<usize>>) {
+        let mut clipped: Vec<Vec<f64>> = Vec::with_capacity(line.len());
+        let mut index_map = Vec::with_capacity(line.len());
+        let mut prev_inside = false;
+
+        for (i, pt) in line.iter().enumerate() {
+            let inside = self.contains((pt[0], pt[1]));
+
+            if i > 0 && inside != prev_inside {
+                let prev = &line[i - 1];
+                // Leaving: the first crossing after `prev`; entering: the last one before `pt`
+                let crossing = if inside {
+                    self.crossings(prev, pt).into_iter().reduce(f64::max)
+                } else {
+                    self.crossings(prev, pt).into_iter().reduce(f64::min)
+                };
+                if let Some(t) = crossing {
+                    clipped.push(vec![
+                        prev[0] + (pt[0] - prev[0]) * t,
+                        prev[1] + (pt[1] - prev[1]) * t,
+                    ]);
+                }
+            }
+
+            if inside {
+                clipped.push(pt.clone());
+                index_map.push(Some(clipped.len() - 1));
+            } else {
+                index_map.push(None);
+            }
+            prev_inside = inside;
+        }
+
+        (clipped, index_map)
+    }
+
+    /// Parameters `t` (0..=1 along `a`→`b`) where the segment crosses a boundary ring
+    fn crossings(&self, a: &[f64], b: &[f64]) -> Vec<f64> {
+        self.polygons
+            .iter()
+            .flatten()
+            .flat_map(|ring| ring.iter().zip(ring.iter().cycle().skip(1)))
+            .filter_map(|(&q1, &q2)| segment_intersection((a[0], a[1]), (b[0], b[1]), q1, q2))
+            .collect()
+    }
+}
+
+fn collect_polygons(value: &Value, out: &mut Vec<Vec<Ring>>) {
+    let parse_ring = |ring: &Value| -> Ring {
+        ring.as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|c| Some((c.get(0)?.as_f64()?, c.get(1)?.as_f64()?)))
+            .collect()
+    };
+    let parse_polygon = |polygon: &Value| -> Vec<Ring> {
+        polygon
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(parse_ring)
+            .filter(|ring| ring.len() >= 3)
+            .collect()
+    };
+
+    match value["type"].as_str() {
+        Some("FeatureCollection") => {
+            for feature in value["features"].as_array().into_iter().flatten() {
+                collect_polygons(feature, out);
+            }
+        }
+        Some("Feature") => collect_polygons(&value["geometry"], out),
+        Some("Polygon") => out.push(parse_polygon(&value["coordinates"])),
+        Some("MultiPolygon") => {
+            out.extend(
+                value["coordinates"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(parse_polygon),
+            );
+        }
+        _ => {}
+    }
+    out.retain(|rings| !rings.is_empty());
+}
+
+/// Ray-casting test for a single ring
+fn ring_contains(ring: &[(f64, f64)], (x, y): (f64, f64)) -> bool {
+    let mut inside = false;
+    let mut j = ring.len() - 1;
+    for i in 0..ring.len() {
+        let (xi, yi) = ring[i];
+        let (xj, yj) = ring[j];
+        if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+/// Parameter `t` along `p1`→`p2` where it crosses segment `q1`→`q2`, if it does
+fn segment_intersection(
+    p1: (f64, f64),
+    p2: (f64, f64),
+    q1: (f64, f64),
+    q2: (f64, f64),
+) -> Option<f64> {
+    let r = (p2.0 - p1.0, p2.1 - p1.1);
+    let s = (q2.0 - q1.0, q2.1 - q1.1);
+    let denom = r.0 * s.1 - r.1 * s.0;
+    if denom == 0.0 {
+        return None;
+    }
+    let d = (q1.0 - p1.0, q1.1 - p1.1);
+    let t = (d.0 * s.1 - d.1 * s.0) / denom;
+    let u = (d.0 * r.1 - d.1 * r.0) / denom;
+    ((0.0..=1.0).contains(&t) && (0.0..=1.0).contains(&u)).then_some(t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_clip_line_to_square() {
+        // Unit square with a hole in the upper-right corner
+        let boundary = Boundary::from_geojson(&json!({
+            "type": "Feature",
+            "geometry": {
+                "type": "Polygon",
+                "coordinates": [
+                    [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0], [0.0, 0.0]],
+                    [[0.8, 0.8], [0.9, 0.8], [0.9, 0.9], [0.8, 0.9], [0.8, 0.8]]
+                ]
+            }
+        }))
+        .unwrap();
+        assert!(boundary.contains((0.5, 0.5)));
+        assert!(!boundary.contains((0.85, 0.85)));
+        assert!(!boundary.contains((1.5, 0.5)));
+
+        // In, out past x = 1, and back in
+        let line = vec![
+            vec![0.5, 0.5],
+            vec![1.5, 0.5],
+            vec![1.5, 0.25],
+            vec![0.5, 0.25],
+        ];
+        let (clipped, index_map) = boundary.clip_line(&line);
+
+        assert_eq!(
+            clipped,
+            vec![
+                vec![0.5, 0.5],
+                vec![1.0, 0.5],
+                vec![1.0, 0.25],
+                vec![0.5, 0.25]
+            ]
+        );
+        assert_eq!(index_map, vec![Some(0), None, None, Some(3)]);
+    }
+}
@@ -0,0 +1,32 @@
+//! Process-wide cap on in-flight tasks (`--max-concurrency`).
+//!
+//! Every buffered task of the route and schedule pipelines takes a permit
+//! before doing its work, so the cap holds across phases and commands.
+//! Without a cap, taking a permit is free.
+//!
+//! Permits must not be held while waiting for another one, or a small cap
+//! would deadlock.
+
+use std::sync::{Arc, OnceLock};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+static LIMIT: OnceLock<(usize, Arc<Semaphore>)> = OnceLock::new();
+
+/// Sets the global cap. Only the first call has an effect.
+pub fn set_limit(max: usize) {
+    let _ = LIMIT.set((max, Arc::new(Semaphore::new(max))));
+}
+
+/// Bounds a per-phase concurrency setting by the global cap.
+pub fn bounded(phase_limit: usize) -> usize {
+    LIMIT
+        .get()
+        .map_or(phase_limit, |(max, _)| phase_limit.min(*max))
+}
+
+/// Waits for a permit. Returns `None` when no cap is set.
+pub async fn acquire() -> Option<OwnedSemaphorePermit> {
+    let (_, semaphore) = LIMIT.get()?;
+    Arc::clone(semaphore).acquire_owned().await.ok()
+}
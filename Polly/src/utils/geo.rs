@@ -13,6 +13,29 @@ pub fn meters_between(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
     (x * x + y * y).sqrt() * r
 }
 
+/// Whether two lines have the same vertex count and each vertex is within `tolerance_m` of its counterpart
+pub fn lines_within(a: &[Vec<f64>], b: &[Vec<f64>], tolerance_m: f64) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|(p, q)| {
+            p.len() >= 2 && q.len() >= 2 && meters_between(p[0], p[1], q[0], q[1]) <= tolerance_m
+        })
+}
+
+/// Which side of the direction of travel from `prev` to `next` a point lies on, from the sign
+/// of the cross product: `1` on the left, `-1` on the right, `0` on the line (or if `prev == next`).
+///
+/// Points are `(lon, lat)`; scaling longitude by `cos(lat)` would not change the sign.
+pub fn side_of_line(point: (f64, f64), prev: (f64, f64), next: (f64, f64)) -> i8 {
+    let cross = (next.0 - prev.0) * (point.1 - prev.1) - (next.1 - prev.1) * (point.0 - prev.0);
+    if cross > 0.0 {
+        1
+    } else if cross < 0.0 {
+        -1
+    } else {
+        0
+    }
+}
+
 /// Find the closest point on a polyline to a given point
 pub fn closest_point_on_polyline(
     point: (f64, f64),
@@ -117,3 +140,184 @@ pub fn calculate_metrics(coords: &[Vec<f64>]) -> ([f64; 4], f64) {
 
     ([min_lon, min_lat, max_lon, max_lat], dist)
 }
+
+/// Calculate the cumulative distance in meters at each coordinate of a polyline
+pub fn cumulative_distances(coords: &[Vec<f64>]) -> Vec<f64> {
+    let mut dist = 0.0;
+
+    coords
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            if i > 0 {
+                dist += meters_between(coords[i - 1][0], coords[i - 1][1], c[0], c[1]);
+            }
+            dist
+        })
+        .collect()
+}
+
+/// The longest step between consecutive coordinates, as the index of its first coordinate and its length in meters.
+///
+/// On a snapped line, a step much longer than a road segment is a gap where two pieces were stitched together.
+pub fn longest_step(coords: &[Vec<f64>]) -> Option<(usize, f64)> {
+    coords
+        .windows(2)
+        .map(|w| meters_between(w[0][0], w[0][1], w[1][0], w[1][1]))
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+/// Interpolate the point at `fraction` (0.0..=1.0) of a polyline's arc length
+pub fn point_at_fraction(coords: &[Vec<f64>], fraction: f64) -> Option<(f64, f64)> {
+    let first = coords.first()?;
+    let (_, total) = calculate_metrics(coords);
+    if total == 0.0 {
+        return Some((first[0], first[1]));
+    }
+
+    let target = total * fraction.clamp(0.0, 1.0);
+    let mut walked = 0.0;
+
+    for seg in coords.windows(2) {
+        let len = meters_between(seg[0][0], seg[0][1], seg[1][0], seg[1][1]);
+        if len > 0.0 && walked + len >= target {
+            let t = (target - walked) / len;
+            return Some((
+                seg[0][0] + t * (seg[1][0] - seg[0][0]),
+                seg[0][1] + t * (seg[1][1] - seg[0][1]),
+            ));
+        }
+        walked += len;
+    }
+
+    coords.last().map(|c| (c[0], c[1]))
+}
+
+/// Representative label point: the coordinate at the route's arc-length midpoint
+///
+/// Unlike the bbox center, this always lies on the line. Returns `(0.0, 0.0)` for an empty line.
+pub fn representative_point(coords: &[Vec<f64>]) -> (f64, f64) {
+    point_at_fraction(coords, 0.5).unwrap_or((0.0, 0.0))
+}
+
+/// Count proper crossings between non-adjacent segments of a polyline
+///
+/// Segments whose start index falls inside `skip` are ignored, which lets callers
+/// exclude a known turnaround region. Collinear overlaps (e.g. a route retracing
+/// the same road) are not counted. This is O(n²) in the number of segments.
+pub fn count_self_intersections(line: &[Vec<f64>], skip: std::ops::Range<usize>) -> usize {
+    fn orient(a: &[f64], b: &[f64], c: &[f64]) -> f64 {
+        (b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0])
+    }
+
+    if line.len() < 4 {
+        return 0;
+    }
+
+    let segs = line.len() - 1;
+    let mut count = 0;
+
+    for i in 0..segs {
+        if skip.contains(&i) {
+            continue;
+        }
+        let (p1, p2) = (&line[i], &line[i + 1]);
+
+        for j in (i + 2)..segs {
+            if skip.contains(&j) {
+                continue;
+            }
+            let (q1, q2) = (&line[j], &line[j + 1]);
+
+            let d1 = orient(p1, p2, q1);
+            let d2 = orient(p1, p2, q2);
+            let d3 = orient(q1, q2, p1);
+            let d4 = orient(q1, q2, p2);
+
+            if d1 * d2 < 0.0 && d3 * d4 < 0.0 {
+                count += 1;
+            }
+        }
+    }
+
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cumulative_distances_ends_at_total() {
+        let coords = vec![
+            vec![127.92, 37.34],
+            vec![127.93, 37.35],
+            vec![127.95, 37.35],
+        ];
+
+        let cumulative = cumulative_distances(&coords);
+        let (_, total) = calculate_metrics(&coords);
+
+        assert_eq!(cumulative.len(), coords.len());
+        assert_eq!(cumulative[0], 0.0);
+        assert!((cumulative.last().unwrap() - total).abs() < 1e-9);
+
+        let (index, meters) = longest_step(&coords).unwrap();
+        assert_eq!(index, 1);
+        assert!((meters - (cumulative[2] - cumulative[1])).abs() < 1e-9);
+        assert_eq!(longest_step(&coords[..1]), None);
+    }
+
+    #[test]
+    fn test_count_self_intersections() {
+        // East, north, west, then south across the first segment
+        let crossing = vec![
+            vec![127.90, 37.30],
+            vec![127.92, 37.30],
+            vec![127.92, 37.31],
+            vec![127.91, 37.31],
+            vec![127.91, 37.29],
+        ];
+        assert_eq!(count_self_intersections(&crossing, 0..0), 1);
+        // Skipping either crossing segment, as around a turnaround, hides the crossing
+        assert_eq!(count_self_intersections(&crossing, 3..4), 0);
+        assert_eq!(count_self_intersections(&crossing, 0..1), 0);
+        assert_eq!(count_self_intersections(&crossing, 1..3), 1);
+
+        // Retracing the same road is a collinear overlap, not a crossing
+        let out_and_back = vec![
+            vec![127.90, 37.30],
+            vec![127.91, 37.30],
+            vec![127.92, 37.30],
+            vec![127.91, 37.30],
+            vec![127.90, 37.30],
+        ];
+        assert_eq!(count_self_intersections(&out_and_back, 0..0), 0);
+        assert_eq!(count_self_intersections(&crossing[..3], 0..0), 0);
+    }
+
+    #[test]
+    fn test_side_of_line() {
+        // Heading east, north is on the left
+        let (west, east) = ((127.90, 37.30), (127.91, 37.30));
+        assert_eq!(side_of_line((127.905, 37.3001), west, east), 1);
+        assert_eq!(side_of_line((127.905, 37.2999), west, east), -1);
+        assert_eq!(side_of_line((127.905, 37.3001), east, west), -1);
+        assert_eq!(side_of_line((127.905, 37.30), west, east), 0);
+    }
+
+    #[test]
+    fn test_representative_point_straight_line() {
+        let coords = vec![
+            vec![127.90, 37.30],
+            vec![127.92, 37.30],
+            vec![127.94, 37.30],
+        ];
+
+        let (lon, lat) = representative_point(&coords);
+
+        assert!((lon - 127.92).abs() < 1e-9);
+        assert!((lat - 37.30).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,83 @@
+//! Process-wide HTTP proxy for outbound requests (`--proxy`).
+//!
+//! Every reqwest client already honors `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY`.
+//! `--proxy` overrides the first two for all TAGO, OSRM, holiday and schedule
+//! requests, whatever the method; hosts listed in `NO_PROXY` still go direct.
+
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+use reqwest::{ClientBuilder, NoProxy, Proxy};
+
+static PROXY: OnceLock<Proxy> = OnceLock::new();
+
+/// Sets the global proxy. Only the first call has an effect.
+pub fn set_proxy(url: &str) -> Result<()> {
+    let proxy = Proxy::all(url)
+        .with_context(|| format!("Invalid proxy URL {}", url))?
+        .no_proxy(NoProxy::from_env());
+    let _ = PROXY.set(proxy);
+    Ok(())
+}
+
+/// A client builder using the global proxy, if one is set
+pub fn client_builder() -> ClientBuilder {
+    with_proxy(reqwest::Client::builder(), PROXY.get())
+}
+
+/// A default client using the global proxy, if one is set
+pub fn client() -> Result<reqwest::Client> {
+    Ok(client_builder().build()?)
+}
+
+fn with_proxy(builder: ClientBuilder, proxy: Option<&Proxy>) -> ClientBuilder {
+    match proxy {
+        Some(proxy) => builder.proxy(proxy.clone()),
+        None => builder,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_proxy_applies_to_get_and_post() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = Proxy::all(format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let client = with_proxy(reqwest::Client::builder(), Some(&proxy))
+            .build()
+            .unwrap();
+
+        // The proxy sees each request in absolute form
+        let server = tokio::spawn(async move {
+            let mut request_lines = Vec::new();
+            for _ in 0..2 {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let n = socket.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                request_lines.push(request.lines().next().unwrap_or_default().to_string());
+                socket
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .await
+                    .unwrap();
+            }
+            request_lines
+        });
+
+        client.get("http://tago.invalid/list").send().await.unwrap();
+        client
+            .post("http://its.invalid/detail")
+            .body("routeId=1")
+            .send()
+            .await
+            .unwrap();
+
+        let request_lines = server.await.unwrap();
+        assert!(request_lines[0].starts_with("GET http://tago.invalid/list"));
+        assert!(request_lines[1].starts_with("POST http://its.invalid/detail"));
+    }
+}
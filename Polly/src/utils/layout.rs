@@ -0,0 +1,181 @@
+//! Output directory layout (`--layout`).
+//!
+//! By default everything goes under the output directory: the raw cache in `cache/`,
+//! derived GeoJSON in `polylines/`, crawled timetables in `schedules/` and the mapping
+//! files (`routeMap.json`, ...) at the top. `nested` puts the same tree under a
+//! directory per city code, and `custom` reads the subdirectory names from a JSON file:
+//!
+//! ```json
+//! { "cache": "raw", "polylines": "geojson", "schedules": "timetables", "by_city": true }
+//! ```
+//!
+//! Missing fields keep their default. Paths recorded in the mapping files (`geojson`,
+//! `geometry_ref`) are relative to the directory holding the mapping files.
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+/// Layout presets for `--layout`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LayoutKind {
+    /// `cache/`, `polylines/` and `schedules/` directly in the output directory
+    #[default]
+    Flat,
+    /// The flat layout inside `<output_dir>/<city code>/`
+    Nested,
+    /// Names and nesting read from `--layout-file`
+    Custom,
+}
+
+/// Arguments selecting the output layout
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct LayoutArgs {
+    /// Output directory layout: `flat`, `nested` (one directory per city code) or `custom`
+    #[arg(long, value_enum, default_value_t = LayoutKind::Flat)]
+    pub layout: LayoutKind,
+
+    /// JSON file with the subdirectory names for `--layout custom`
+    #[arg(long, value_name = "PATH", required_if_eq("layout", "custom"))]
+    pub layout_file: Option<PathBuf>,
+}
+
+impl LayoutArgs {
+    pub fn resolve(&self) -> Result<Layout> {
+        let layout = match self.layout {
+            LayoutKind::Flat => Layout::default(),
+            LayoutKind::Nested => Layout {
+                by_city: true,
+                ..Layout::default()
+            },
+            LayoutKind::Custom => {
+                let path = self.layout_file.as_deref().unwrap_or(Path::new(""));
+                let content = fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read layout {}", path.display()))?;
+                serde_json::from_str(&content)
+                    .with_context(|| format!("Invalid layout {}", path.display()))?
+            }
+        };
+        layout.validate()?;
+        Ok(layout)
+    }
+}
+
+/// Subdirectory names of the output tree
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Layout {
+    /// Raw TAGO responses, one file per route
+    pub cache: String,
+    /// Derived GeoJSON
+    pub polylines: String,
+    /// Crawled timetables
+    pub schedules: String,
+    /// Put each city's tree in a directory named by its city code
+    pub by_city: bool,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self {
+            cache: "cache".to_string(),
+            polylines: "polylines".to_string(),
+            schedules: "schedules".to_string(),
+            by_city: false,
+        }
+    }
+}
+
+impl Layout {
+    /// Subdirectories must stay inside the output tree
+    fn validate(&self) -> Result<()> {
+        for (field, dir) in [
+            ("cache", &self.cache),
+            ("polylines", &self.polylines),
+            ("schedules", &self.schedules),
+        ] {
+            let inside = Path::new(dir)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)));
+            if dir.is_empty() || !inside {
+                bail!(
+                    "Layout `{}` must be a relative path inside the output directory, got {:?}",
+                    field,
+                    dir
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Directory of one city's tree, which also holds its mapping files
+    pub fn root(&self, output_dir: &Path, city_code: Option<&str>) -> Result<PathBuf> {
+        match (self.by_city, city_code) {
+            (false, _) => Ok(output_dir.to_path_buf()),
+            (true, Some(city_code)) => Ok(output_dir.join(city_code)),
+            (true, None) => bail!("This layout nests files by city; pass --city-code"),
+        }
+    }
+
+    pub fn cache_dir(&self, root: &Path) -> PathBuf {
+        root.join(&self.cache)
+    }
+
+    pub fn polylines_dir(&self, root: &Path) -> PathBuf {
+        root.join(&self.polylines)
+    }
+
+    pub fn schedules_dir(&self, root: &Path) -> PathBuf {
+        root.join(&self.schedules)
+    }
+
+    /// Path of a derived file named `stem`, relative to the city root, as recorded in the mapping files
+    pub fn derived_path(&self, stem: &str) -> String {
+        format!("{}/{}.geojson", self.polylines, stem)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_presets_and_custom_file() {
+        let nested = LayoutArgs {
+            layout: LayoutKind::Nested,
+            layout_file: None,
+        }
+        .resolve()
+        .unwrap();
+        let root = nested.root(Path::new("out"), Some("32020")).unwrap();
+        assert_eq!(nested.cache_dir(&root), Path::new("out/32020/cache"));
+        assert!(nested.root(Path::new("out"), None).is_err());
+
+        let path = std::env::temp_dir().join(format!("polly-layout-{}.json", std::process::id()));
+        fs::write(&path, r#"{ "polylines": "data/geojson" }"#).unwrap();
+        let custom = LayoutArgs {
+            layout: LayoutKind::Custom,
+            layout_file: Some(path.clone()),
+        }
+        .resolve()
+        .unwrap();
+        assert_eq!(custom.cache, "cache");
+        assert_eq!(custom.derived_path("R1"), "data/geojson/R1.geojson");
+        assert_eq!(
+            custom.root(Path::new("out"), None).unwrap(),
+            Path::new("out")
+        );
+
+        // Nothing may point outside the output directory
+        fs::write(&path, r#"{ "schedules": "../timetables" }"#).unwrap();
+        let escaping = LayoutArgs {
+            layout: LayoutKind::Custom,
+            layout_file: Some(path.clone()),
+        };
+        assert!(escaping.resolve().is_err());
+
+        let _ = fs::remove_file(path);
+    }
+}
@@ -3,12 +3,20 @@
 //! This module itself contains general utility functions, while specific utilities
 //! are organized into submodules.
 
+pub mod boundary;
+pub mod concurrency;
 pub mod geo;
+pub mod http;
+pub mod layout;
+pub mod s3;
+pub mod service_key;
 
 use std::fs;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde_json::Value;
 
 pub fn ensure_dir(path: &Path) -> Result<()> {
@@ -18,6 +26,34 @@ pub fn ensure_dir(path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Writes a file by renaming a fully written temporary file over it,
+/// so readers never see a partially written file.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        file_name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    fs::write(&tmp_path, contents)?;
+    if let Err(e) = fs::rename(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Reads and parses a JSON file, naming the file in the error.
+pub fn read_json(path: &Path) -> Result<Value> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&content).with_context(|| format!("Invalid JSON in {}", path.display()))
+}
+
 pub fn get_env(key: &str) -> String {
     std::env::var(key).unwrap_or_else(|_| "".to_string())
 }
@@ -47,3 +83,72 @@ pub fn parse_flexible_string(v: &Value) -> String {
         "UNKNOWN".to_string()
     }
 }
+
+/// Reads a number that may have been stored as a string (e.g. `"37.34"`).
+pub fn parse_flexible_f64(v: &Value) -> Option<f64> {
+    v.as_f64()
+        .or_else(|| v.as_str().and_then(|s| s.trim().parse().ok()))
+}
+
+/// Sanitizes a route number or id to create a valid filename.
+pub fn safe_file_name(name: &str) -> String {
+    name.replace(|c: char| !c.is_alphanumeric() && c != '-', "_")
+}
+
+/// Canonicalizes a route number so TAGO `routeno` and schedule-site ids join.
+///
+/// Dash variants (en/em dash, minus sign, etc.) become `-`, whitespace is removed,
+/// and any parenthetical suffix such as `(평일)` is dropped: `"34–1 (평일)"` → `"34-1"`.
+pub fn normalize_route_no(raw: &str) -> String {
+    raw.split('(')
+        .next()
+        .unwrap_or(raw)
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| match c {
+            '\u{2010}'..='\u{2015}' | '\u{2212}' | '\u{FE63}' | '\u{FF0D}' => '-',
+            _ => c,
+        })
+        .collect()
+}
+
+/// Parses an interval given in seconds, optionally with an `s`, `m` or `h` suffix.
+pub fn parse_interval(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (value, unit_secs) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 3600),
+        _ => (s, 1),
+    };
+    match value.trim().parse::<u64>() {
+        Ok(n) if n > 0 => Ok(Duration::from_secs(n * unit_secs)),
+        _ => Err(format!("invalid interval `{}`", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_interval("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_interval("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_interval("6h"), Ok(Duration::from_secs(21600)));
+        assert!(parse_interval("0").is_err());
+        assert!(parse_interval("5d").is_err());
+    }
+
+    #[test]
+    fn test_normalize_route_no() {
+        assert_eq!(normalize_route_no("34-1"), "34-1");
+        assert_eq!(normalize_route_no("34–1"), "34-1");
+        assert_eq!(normalize_route_no("34—1"), "34-1");
+        assert_eq!(normalize_route_no("34 - 1"), "34-1");
+        assert_eq!(normalize_route_no("34-1(평일)"), "34-1");
+        assert_eq!(normalize_route_no("34–1 (주말)"), "34-1");
+        assert_eq!(normalize_route_no("2"), "2");
+    }
+}
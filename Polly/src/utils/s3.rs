@@ -0,0 +1,99 @@
+//! S3-compatible output target.
+//!
+//! Mirrors generated files to an object storage bucket. The real client is only
+//! compiled with the `s3` feature; without it, constructing a target fails with
+//! a clear error so the CLI flag can stay available in every build.
+
+use anyhow::Result;
+
+/// An upload destination given as `bucket/prefix`
+pub struct S3Target {
+    #[cfg(feature = "s3")]
+    client: aws_sdk_s3::Client,
+    #[cfg(feature = "s3")]
+    bucket: String,
+    #[cfg(feature = "s3")]
+    prefix: String,
+    #[cfg(feature = "s3")]
+    gzip: bool,
+}
+
+impl S3Target {
+    /// Creates a target from a `bucket/prefix` spec, using the standard AWS credential chain.
+    ///
+    /// `AWS_ENDPOINT_URL` can point the client at any S3-compatible service.
+    #[cfg(feature = "s3")]
+    pub async fn new(spec: &str, gzip: bool) -> Result<Self> {
+        let (bucket, prefix) = spec.split_once('/').unwrap_or((spec, ""));
+        if bucket.is_empty() {
+            anyhow::bail!("Invalid S3 target `{}`: expected `bucket/prefix`", spec);
+        }
+
+        let shared = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+        let config = aws_sdk_s3::config::Builder::from(&shared)
+            .force_path_style(true)
+            .build();
+
+        Ok(Self {
+            client: aws_sdk_s3::Client::from_conf(config),
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            gzip,
+        })
+    }
+
+    #[cfg(not(feature = "s3"))]
+    pub async fn new(_spec: &str, _gzip: bool) -> Result<Self> {
+        anyhow::bail!("S3 output requires building with `--features s3`")
+    }
+
+    /// Uploads `body` under `key` (relative to the prefix) with a content type derived from its extension.
+    #[cfg(feature = "s3")]
+    pub async fn put(&self, key: &str, body: Vec<u8>) -> Result<()> {
+        use std::io::Write;
+
+        use aws_sdk_s3::primitives::ByteStream;
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+
+        let full_key = if self.prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}/{}", self.prefix, key)
+        };
+
+        let content_type = if key.ends_with(".geojson") {
+            "application/geo+json"
+        } else if key.ends_with(".json") {
+            "application/json"
+        } else {
+            "application/octet-stream"
+        };
+
+        let mut req = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(&full_key)
+            .content_type(content_type);
+
+        let body = if self.gzip {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&body)?;
+            req = req.content_encoding("gzip");
+            encoder.finish()?
+        } else {
+            body
+        };
+
+        req.body(ByteStream::from(body)).send().await?;
+        log::debug!("Uploaded s3://{}/{}", self.bucket, full_key);
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "s3"))]
+    pub async fn put(&self, _key: &str, _body: Vec<u8>) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+//! data.go.kr service keys and their encoded forms.
+//!
+//! data.go.kr lists every key twice: "Decoding" (plain base64, e.g. `abc+def==`) and
+//! "Encoding" (URL-encoded, e.g. `abc%2Bdef%3D%3D`). Requests encode the key
+//! themselves, so only the decoded one works; the encoded one gets encoded again
+//! and is rejected as unregistered. `ServiceKey` tells the forms apart and can fall
+//! back to the decoded key once the API has rejected the configured one.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::RwLock;
+
+use percent_encoding::percent_decode_str;
+
+/// How a configured service key appears to be written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyForm {
+    /// Plain, as wanted ("Decoding" on data.go.kr)
+    Decoded,
+    /// URL-encoded once ("Encoding" on data.go.kr)
+    Encoded,
+    /// URL-encoded twice: `%` sequences that decode to another `%` sequence
+    DoubleEncoded,
+}
+
+impl fmt::Display for KeyForm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            KeyForm::Decoded => "decoded",
+            KeyForm::Encoded => "URL-encoded",
+            KeyForm::DoubleEncoded => "double URL-encoded",
+        })
+    }
+}
+
+/// The key with one level of `%XX` sequences decoded, if it has any
+fn decode_once(key: &str) -> Option<String> {
+    match percent_decode_str(key).decode_utf8().ok()? {
+        Cow::Owned(decoded) if decoded != key => Some(decoded),
+        _ => None,
+    }
+}
+
+/// Which form `key` appears to be written in
+pub fn key_form(key: &str) -> KeyForm {
+    match decode_once(key) {
+        None => KeyForm::Decoded,
+        Some(once) if decode_once(&once).is_some() => KeyForm::DoubleEncoded,
+        Some(_) => KeyForm::Encoded,
+    }
+}
+
+/// The fully decoded key, if it differs from `key`
+pub fn decoded_key(key: &str) -> Option<String> {
+    let mut decoded = decode_once(key)?;
+    // Stop after two levels; a real key is never encoded more often
+    if let Some(twice) = decode_once(&decoded) {
+        decoded = twice;
+    }
+    Some(decoded)
+}
+
+/// A service key that may switch to its decoded form after being rejected
+#[derive(Debug)]
+pub struct ServiceKey(RwLock<String>);
+
+impl ServiceKey {
+    pub fn new(key: impl Into<String>) -> Self {
+        Self(RwLock::new(key.into()))
+    }
+
+    /// The key to send
+    pub fn get(&self) -> String {
+        self.0.read().unwrap().clone()
+    }
+
+    /// After `rejected` was refused, switches to its decoded form.
+    ///
+    /// Returns whether a retry with `get()` can help: the key was decoded now, or by another
+    /// request in the meantime. A key that is already decoded gives `false`, so each request
+    /// is retried at most once.
+    pub fn switch_to_decoded(&self, rejected: &str) -> bool {
+        let mut current = self.0.write().unwrap();
+        if *current != rejected {
+            return true;
+        }
+        match decoded_key(rejected) {
+            Some(decoded) => {
+                *current = decoded;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl From<String> for ServiceKey {
+    fn from(key: String) -> Self {
+        Self::new(key)
+    }
+}
+
+impl From<&str> for ServiceKey {
+    fn from(key: &str) -> Self {
+        Self::new(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_forms() {
+        assert_eq!(key_form("abc+def/gh=="), KeyForm::Decoded);
+        assert_eq!(key_form("abc%2Bdef%2Fgh%3D%3D"), KeyForm::Encoded);
+        assert_eq!(
+            key_form("abc%252Bdef%252Fgh%253D%253D"),
+            KeyForm::DoubleEncoded
+        );
+
+        assert_eq!(decoded_key("abc+def/gh=="), None);
+        assert_eq!(
+            decoded_key("abc%252Bdef%252Fgh%253D%253D").as_deref(),
+            Some("abc+def/gh==")
+        );
+
+        let key = ServiceKey::new("abc%2Bdef%3D%3D");
+        assert!(key.switch_to_decoded("abc%2Bdef%3D%3D"));
+        assert_eq!(key.get(), "abc+def==");
+        // A request that still used the old key retries with the new one; the new one is final
+        assert!(key.switch_to_decoded("abc%2Bdef%3D%3D"));
+        assert!(!key.switch_to_decoded("abc+def=="));
+    }
+}